@@ -0,0 +1,1961 @@
+//! Applying the patches to copies of the crates in `target/patch`, and
+//! checking, reversing and downloading them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Ok, Result};
+use cargo::core::{
+    package::{Package, PackageSet},
+    PackageId, Resolve, Workspace,
+};
+
+use super::{
+    case_collisions, check_allowed_paths, check_duplicate_patches,
+    cli::{ChangedFilesFormat, Cli},
+    commit_copy, configured_crates, confirm_overwrite, conflicting_overrides, copy_dir,
+    copy_package, copy_packages, dependency_path, detect_upstreamed, discard_copy,
+    emit_patch_section, existing_patch_file, fill_patched_package, find_patch_files, git,
+    is_changed_path_source, is_current_copy, is_edited_copy, list_files,
+    lock::{
+        has_recorded_hash, lock_drift, lock_entry, read_patch_lock, tree_hash, verify_checksum,
+        write_patch_lock,
+    },
+    malformed_patches, matches_spec, patch, patch_file_key, patch_file_stem, patch_groups,
+    patch_registry, patch_stem, patches_changed, print_override, query_dependency,
+    query_git_package, record_patches, report, seal_copy, source_marker, source_stamp,
+    sparse_copy_package, split_patch_stem, stale_copies, tested_versions, touches_manifest,
+    version_range, warn_manifest_changes, write_manifest, yanked_crates, CrateEntry, Excludes,
+    PackageExt, PatchConfig, PatchWorkspace, ResolveOptions, Summary, Warnings, WorkspaceExt,
+    GIT_REV_PREFIX, HASHES_FILE, LOCK_FILE, PATCH_EXT, SHORT_REV_LEN,
+};
+
+/// Applies the patches in `patches_folder`. When `only` names some crates, just
+/// those are copied and patched again, whether or not they already exist.
+///
+/// With `--per-target-layout` this happens once for every configured target,
+/// into `target/patch/<triple>`, with the crates that are dependencies on it.
+pub fn apply_patches(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    patches_folder: &Path,
+    only: &[String],
+) -> Result<()> {
+    git::check_installed()?;
+    let options = ResolveOptions::new(args, workspace)?;
+    let targets = if args.apply.per_target_layout {
+        if options.targets.is_empty() {
+            return Err(anyhow!(
+                "`--per-target-layout` needs targets, given with `--target` or `resolve.targets` in patch metadata"
+            ));
+        }
+        options.targets.iter().map(|t| Some(t.as_str())).collect()
+    } else {
+        vec![None]
+    };
+    let before = match args.apply.changed_files {
+        Some(_) => Some(snapshot_files(&workspace.patch_target_folder())?),
+        None => None,
+    };
+    for target in targets {
+        if args.apply.reverse {
+            reverse_patches(
+                args,
+                workspace,
+                pkg_set,
+                resolve,
+                patches_folder,
+                only,
+                target,
+            )?;
+        } else {
+            apply_patches_to(
+                args,
+                workspace,
+                pkg_set,
+                resolve,
+                patches_folder,
+                only,
+                target,
+            )?;
+        }
+    }
+    if let (Some(format), Some(before)) = (args.apply.changed_files, before) {
+        let after = snapshot_files(&workspace.patch_target_folder())?;
+        print_changed_files(format, &before, &after)?;
+    }
+    Ok(())
+}
+
+/// Whether an argument of `apply` is a patch file rather than a crate name.
+pub fn is_patch_path(arg: &str) -> bool {
+    patch_stem(Path::new(arg)).is_some()
+}
+
+/// The crate and URL of an argument of `apply` given as `<crate>=<url>`, or
+/// no crate and the argument.
+fn split_patch_url(arg: &str) -> (Option<&str>, &str) {
+    match arg.split_once('=') {
+        Some((name, url)) if !name.contains("://") => (Some(name), url),
+        _ => (None, arg),
+    }
+}
+
+/// Whether an argument of `apply` is the URL of a patch, as `<url>` or
+/// `<crate>=<url>`.
+pub fn is_patch_url(arg: &str) -> bool {
+    let (_, url) = split_patch_url(arg);
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+/// Downloads the patch at the URL `arg` of `apply`, `[<crate>=]<url>[#sha256=<hex>]`,
+/// into [`WorkspaceExt::patch_download_folder`] and returns the file, named
+/// like the patches in the patches folder. A URL that was downloaded before is
+/// taken from there, so applying it again works offline. With a `sha256`, the
+/// patch must have that hash.
+pub fn download_patch(
+    args: &Cli,
+    workspace: &Workspace<'_>,
+    resolve: &Resolve,
+    arg: &str,
+    allow_http: bool,
+) -> Result<PathBuf> {
+    use sha2::{Digest, Sha256};
+    let (name, url) = split_patch_url(arg);
+    let (url, sha256) = match url.split_once("#sha256=") {
+        Some((url, sha256)) => (url, Some(sha256.to_ascii_lowercase())),
+        None => (url, None),
+    };
+    if !url.starts_with("https://") && !allow_http {
+        return Err(anyhow!(
+            "refusing to download the patch {} without https, pass `--allow-http` to allow it",
+            url
+        ));
+    }
+    let stem = match name {
+        Some(name) => patch_file_stem(query_dependency(resolve, name)?),
+        None => {
+            let file_name = url
+                .split(['?', '#'])
+                .next()
+                .and_then(|path| path.rsplit('/').next())
+                .unwrap_or_default();
+            match patch_stem(Path::new(file_name)).filter(|stem| stem.contains('+')) {
+                Some(stem) => stem,
+                None => {
+                    return Err(anyhow!(
+                        "the patch {} is not named after its crate, like `<crate>+<version>.patch`, give it as `<crate>={}`",
+                        url, url
+                    ))
+                }
+            }
+        }
+    };
+    let folder = workspace
+        .patch_download_folder()
+        .join(format!("{:x}", Sha256::digest(url)));
+    let patch_file = folder.join(format!("{}.{}", stem, PATCH_EXT));
+    if patch_file.exists() {
+        info!("using {}, downloaded before to {:?}", url, patch_file);
+    } else if args.resolving.offline {
+        return Err(anyhow!(
+            "the patch {} is not downloaded yet, which `--offline` forbids",
+            url
+        ));
+    } else {
+        let content = http_get(workspace, url, allow_http)?;
+        fs::create_dir_all(&folder)?;
+        fs::write(&patch_file, content)?;
+        info!("downloaded {} to {:?}", url, patch_file);
+    }
+    if let Some(sha256) = sha256 {
+        let actual = format!("{:x}", Sha256::digest(fs::read(&patch_file)?));
+        if actual != sha256 {
+            fs::remove_dir_all(&folder)?;
+            return Err(anyhow!(
+                "the patch {} has the sha256 {}, not {}, so it is not applied",
+                url,
+                actual,
+                sha256
+            ));
+        }
+    }
+    Ok(patch_file)
+}
+
+/// The body of a successful `GET` of `url`, made with the network settings of
+/// cargo, such as its proxy and timeouts. A redirect away from https fails
+/// unless `allow_http` is given, as `url` itself would.
+fn http_get(workspace: &Workspace<'_>, url: &str, allow_http: bool) -> Result<Vec<u8>> {
+    let mut handle = cargo::util::network::http::http_handle(workspace.gctx())?;
+    handle.url(url)?;
+    handle.follow_location(true)?;
+    let mut body = Vec::new();
+    {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|data| {
+            body.extend_from_slice(data);
+            Result::Ok(data.len())
+        })?;
+        transfer
+            .perform()
+            .map_err(|err| anyhow!("cannot download {}: {}", url, err))?;
+    }
+    if let Some(effective_url) = handle.effective_url()? {
+        if !effective_url.starts_with("https://") && !allow_http {
+            return Err(anyhow!(
+                "refusing to download the patch {}, redirected to {} without https, pass `--allow-http` to allow it",
+                url,
+                effective_url
+            ));
+        }
+    }
+    match handle.response_code()? {
+        200 => Ok(body),
+        code => Err(anyhow!("cannot download {}: HTTP status {}", url, code)),
+    }
+}
+
+/// Copies the patch files given to `apply` into `folder`, to apply them like
+/// the patches folder, and returns the names of their crates, parsed from the
+/// file names.
+pub fn collect_patch_files(patch_files: &[String], folder: &Path) -> Result<Vec<String>> {
+    if folder.exists() {
+        fs::remove_dir_all(folder)?;
+    }
+    fs::create_dir_all(folder)?;
+    let mut names = Vec::new();
+    for patch_file in patch_files.iter().map(Path::new) {
+        if patch_file.file_name().is_none() {
+            return Err(anyhow!("{:?} is not a patch file", patch_file));
+        }
+        let (stem, _) = split_patch_stem(&patch_stem(patch_file).unwrap_or_default());
+        let Some((name, _)) = stem.split_once('+') else {
+            return Err(anyhow!(
+                "patch {:?} is not named after its crate, like `<crate>+<version>.patch`",
+                patch_file
+            ));
+        };
+        // a patch in a crate folder keeps it, which names its crate
+        let target = folder.join(patch_file_key(patch_file));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(patch_file, target)
+            .map_err(|err| anyhow!("cannot read {:?}: {}", patch_file, err))?;
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Checks that every patch in the patches folder applies to a pristine copy of
+/// the crate it is for, without touching `target/patch`, and fails listing
+/// the broken ones.
+pub fn check_patches(
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+) -> Result<()> {
+    git::check_installed()?;
+    let patches_folder = workspace.patches_folder();
+    let check_folder = workspace.patch_target_tmp_folder().join("check");
+    let normalize_eol = workspace.root_patch_metadata_strings("normalize_eol")?;
+    let groups = if patches_folder.exists() {
+        patch_groups(&patches_folder)?
+    } else {
+        Vec::new()
+    };
+
+    let mut broken = Vec::new();
+    for patch_files in groups {
+        let patch_file = &patch_files[0];
+        let (stem, _) = split_patch_stem(&patch_stem(patch_file).unwrap_or_default());
+        let Some((pkg_name, version)) = stem.split_once('+') else {
+            continue;
+        };
+        let pkg_id = match version.strip_prefix(GIT_REV_PREFIX) {
+            Some(rev) => query_git_package(resolve, pkg_name, rev),
+            None => resolve
+                .query(&format!("{}@{}", pkg_name, version))
+                .or_else(|_| resolve.query(pkg_name)),
+        };
+        let pkg_id = match pkg_id {
+            Result::Ok(pkg_id) => pkg_id,
+            Err(err) => {
+                warn!(
+                    "crate: {}, patch {:?} is broken: {}",
+                    pkg_name, patch_file, err
+                );
+                broken.push(format!("{}+{}", pkg_name, version));
+                continue;
+            }
+        };
+        if find_patch_files(&patches_folder, pkg_id)? != patch_files {
+            if version_range(version).is_some_and(|req| req.matches(pkg_id.version())) {
+                crate_info!(
+                    "crate: {}, skip patch {:?}, there is a patch for the resolved version.",
+                    pkg_name,
+                    patch_file
+                );
+                continue;
+            }
+            warn!(
+                "crate: {}, patch {:?} is broken: it is for {} but {} is resolved.",
+                pkg_name, patch_file, version, pkg_id
+            );
+            broken.push(format!("{}+{}", pkg_name, version));
+            continue;
+        }
+        let pkg = pkg_set.get_one(pkg_id)?;
+        let path = copy_package(pkg, &workspace.config, &check_folder, true)?;
+        let eol = normalize_eol.iter().any(|n| matches_spec(n, pkg_id));
+        let failed = first_failing_patch(&path, &patch_files, eol);
+        fs::remove_dir_all(&path)?;
+        match failed? {
+            None => {
+                for patch_file in patch_files.iter() {
+                    crate_info!("crate: {}, patch {:?} applies.", pkg_name, patch_file);
+                }
+            }
+            Some(patch_file) => {
+                warn!(
+                    "crate: {}, patch {:?} does not apply to {}.",
+                    pkg_name, patch_file, pkg_id
+                );
+                broken.push(format!("{}+{}", pkg_name, version));
+            }
+        }
+    }
+    let _ = fs::remove_dir(&check_folder);
+    let _ = fs::remove_dir(workspace.patch_target_tmp_folder());
+    if !broken.is_empty() {
+        return Err(anyhow!(
+            "{} patch(es) don't apply to the resolved crates:\n{}",
+            broken.len(),
+            broken
+                .iter()
+                .map(|b| format!("  - {}", b))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Applies `patch_files` one after another to the crate at `path` and returns
+/// the first one that doesn't apply.
+pub fn first_failing_patch<'a>(
+    path: &Path,
+    patch_files: &'a [PathBuf],
+    normalize_eol: bool,
+) -> Result<Option<&'a PathBuf>> {
+    for patch_file in patch_files {
+        if patch::is_placeholder(patch_file)? {
+            continue;
+        }
+        if normalize_eol {
+            patch::normalize_line_endings(path, patch_file)?;
+        }
+        git::init(path)?;
+        if !git::check(path, patch_file)? {
+            return Ok(Some(patch_file));
+        }
+        git::apply(path, patch_file)?;
+        git::destroy(path)?;
+    }
+    Ok(None)
+}
+
+/// Undoes the patches of the crates in the patch metadata, or of `only`, in
+/// their copies in `target/patch`, leaving crates that are not copied alone.
+fn reverse_patches(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    patches_folder: &Path,
+    only: &[String],
+    target: Option<&str>,
+) -> Result<()> {
+    let patch_target_folder = match target {
+        Some(target) => workspace.patch_target_folder().join(target),
+        None => workspace.patch_target_folder(),
+    };
+    match target {
+        Some(target) => info!("reversing patch for {}", target),
+        None => info!("reversing patch"),
+    }
+    let mut crates = if only.is_empty() {
+        configured_crates(args, workspace, pkg_set, resolve)?
+            .into_iter()
+            .collect::<Vec<_>>()
+    } else {
+        only.iter()
+            .map(|n| query_dependency(resolve, n).and_then(|id| pkg_set.get_one(id)))
+            .collect::<Result<Vec<_>>>()?
+    };
+    crates.sort_by_key(|pkg| pkg.package_id());
+
+    let mut warnings = Warnings::default();
+    let mut summary = Summary::default();
+    for pkg in crates {
+        let patch_files = find_patch_files(patches_folder, pkg.package_id())?;
+        if patch_files.is_empty() {
+            crate_info!("crate: {}, skip, there is no patch.", pkg.name());
+            summary.push(pkg, "skipped, no patch");
+            continue;
+        };
+        let patch_target_path = patch_target_folder.join(pkg.slug()?);
+        if !patch_target_path.exists() {
+            crate_info!(
+                "crate: {}, skip, {:?} does not exist.",
+                pkg.name(),
+                patch_target_path
+            );
+            summary.push(pkg, "skipped, not copied");
+            continue;
+        }
+        if args.apply.dry_run {
+            for patch_file in patch_files.iter().rev() {
+                crate_info!(
+                    "crate: {}, would reverse patch {:?}.",
+                    pkg.name(),
+                    patch_file
+                );
+            }
+            summary.push(pkg, "would reverse");
+            continue;
+        }
+        // the last numbered patch was applied last
+        git::init(&patch_target_path)?;
+        let mut failed = None;
+        for patch_file in patch_files.iter().rev() {
+            let reversed = if patch::is_placeholder(patch_file)? {
+                Ok(())
+            } else if git::check_reverse(&patch_target_path, patch_file)? {
+                git::reverse(&patch_target_path, patch_file)
+            } else {
+                Err(anyhow!("its files don't match the patched ones"))
+            };
+            if let Err(err) = reversed {
+                failed = Some((patch_file, err));
+                break;
+            }
+            crate_info!(
+                "crate: {}, successfully reversed patch {:?}.",
+                pkg.name(),
+                patch_file
+            );
+        }
+        git::destroy(&patch_target_path)?;
+        match failed {
+            None => summary.push(pkg, "reversed"),
+            Some((patch_file, err)) => {
+                warnings.push(format!(
+                    "crate: {}, cannot reverse patch {:?} in {:?}, it was not applied or was edited since: {}",
+                    pkg.name(),
+                    patch_file,
+                    patch_target_path,
+                    err.to_string().trim_end()
+                ));
+                summary.push(pkg, "not reversed");
+            }
+        }
+    }
+    if args.apply.summary_only {
+        summary.print();
+    }
+    warnings.check(args.apply.deny_warnings)
+}
+
+/// The patch files of the newest version of crate `name` other than
+/// `version`, with that version. Patches of a version range or a git revision
+/// don't tell which sources they were made on and are left out.
+pub fn stale_patches(
+    patches_folder: &Path,
+    name: &str,
+    version: &semver::Version,
+) -> Result<Option<(semver::Version, Vec<PathBuf>)>> {
+    let mut stale = None;
+    if !patches_folder.exists() {
+        return Ok(stale);
+    }
+    for patch_files in patch_groups(patches_folder)? {
+        let (stem, _) = split_patch_stem(&patch_stem(&patch_files[0]).unwrap_or_default());
+        let Some(old) = stem
+            .split_once('+')
+            .filter(|(pkg_name, _)| *pkg_name == name)
+            .and_then(|(_, old)| semver::Version::parse(old).ok())
+        else {
+            continue;
+        };
+        let newer = match &stale {
+            Some((newest, _)) => old > *newest,
+            None => true,
+        };
+        if old != *version && newer {
+            stale = Some((old, patch_files));
+        }
+    }
+    Ok(stale)
+}
+
+/// Whether the changes of all `patch_files` are in the crate at `path`, found
+/// by undoing them one after another, last one first, in a copy of it.
+pub fn all_patches_present(
+    path: &Path,
+    tmp_folder: &Path,
+    patch_files: &[PathBuf],
+) -> Result<bool> {
+    let copy = tmp_folder.join("current");
+    if copy.exists() {
+        fs::remove_dir_all(&copy)?;
+    }
+    fs::create_dir_all(&copy)?;
+    copy_dir(path, &copy)?;
+    git::init(&copy)?;
+    let mut present = true;
+    for patch_file in patch_files.iter().rev() {
+        if patch::is_placeholder(patch_file)? {
+            continue;
+        }
+        if !git::reverse_loosely(&copy, patch_file)? {
+            present = false;
+            break;
+        }
+    }
+    fs::remove_dir_all(&copy)?;
+    Ok(present)
+}
+
+/// SHA-256 of the contents of every file under `dir`, keyed by absolute path.
+fn snapshot_files(dir: &Path) -> Result<HashMap<PathBuf, String>> {
+    use sha2::{Digest, Sha256};
+    let mut files = HashMap::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let dir = std::path::absolute(dir)?;
+    for file in list_files(&dir)? {
+        let path = dir.join(file);
+        let hash = format!("{:x}", Sha256::digest(fs::read(&path)?));
+        files.insert(path, hash);
+    }
+    Ok(files)
+}
+
+/// Prints the files that were created, modified or deleted between the two
+/// snapshots, as one path per line or as a JSON object of the three lists.
+fn print_changed_files(
+    format: ChangedFilesFormat,
+    before: &HashMap<PathBuf, String>,
+    after: &HashMap<PathBuf, String>,
+) -> Result<()> {
+    let sorted = |mut paths: Vec<&PathBuf>| {
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+    };
+    let created = sorted(after.keys().filter(|p| !before.contains_key(*p)).collect());
+    let modified = sorted(
+        after
+            .iter()
+            .filter(|(p, hash)| before.get(*p).is_some_and(|h| h != *hash))
+            .map(|(p, _)| p)
+            .collect(),
+    );
+    let deleted = sorted(before.keys().filter(|p| !after.contains_key(*p)).collect());
+    match format {
+        ChangedFilesFormat::Lines => {
+            let mut changed = [created, modified, deleted].concat();
+            changed.sort();
+            for path in changed {
+                println!("{}", path);
+            }
+        }
+        ChangedFilesFormat::Json => {
+            let changed = serde_json::json!({
+                "created": created,
+                "modified": modified,
+                "deleted": deleted,
+            });
+            println!("{}", serde_json::to_string_pretty(&changed)?);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the patches in `patches_folder` to the copies in `target/patch`, or
+/// in its folder of `target` with `--per-target-layout`, see [`ApplyRun`].
+fn apply_patches_to(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    patches_folder: &Path,
+    only: &[String],
+    target: Option<&str>,
+) -> Result<()> {
+    let started = Instant::now();
+
+    match target {
+        Some(target) => info!("applying patch for {}", target),
+        None => info!("applying patch"),
+    }
+    check_duplicate_patches(patches_folder)?;
+
+    let mut run = ApplyRun::new(args, workspace, pkg_set, resolve, patches_folder, target)?;
+    let malformed = run.check_malformed()?;
+    run.select(only)?;
+    let patched_crates = run.check_crates()?;
+    run.clean_copies()?;
+    run.prepare_copies()?;
+    if patches_folder.exists() {
+        for patch_files in patch_groups(patches_folder)? {
+            if patch_files
+                .iter()
+                .any(|patch_file| malformed.iter().any(|(file, _)| file == patch_file))
+            {
+                continue;
+            }
+            run.apply_group(&patch_files)?;
+        }
+    }
+    run.copy_unpatched()?;
+    run.finish(&patched_crates, started)
+}
+
+/// A run of [`apply_patches_to`], with the crates it patches and what it found
+/// so far, reported once it is over.
+struct ApplyRun<'a, 'ws, 'gctx> {
+    args: &'a Cli,
+    workspace: &'a PatchWorkspace<'ws>,
+    pkg_set: &'a PackageSet<'gctx>,
+    resolve: &'a Resolve,
+    patches_folder: &'a Path,
+    target: Option<&'a str>,
+    patch_target_folder: PathBuf,
+    patch_target_tmp_folder: PathBuf,
+    /// Where crates are copied and patched, and only moved to
+    /// `patch_target_folder` once that succeeded
+    stage_folder: PathBuf,
+    /// The crates in the patch metadata, less those handled so far
+    crates_to_patch: HashSet<&'a Package>,
+    configured: HashSet<&'a Package>,
+    excludes: Excludes,
+    target_packages: Option<HashSet<PackageId>>,
+    /// The entries of the groups given with `--group`
+    grouped: Option<Vec<CrateEntry<'a>>>,
+    /// The crates given on the command line, which are applied again
+    selected: Option<HashSet<&'a Package>>,
+    /// Whether `--force` removes every copy first
+    cleaned: bool,
+    /// The copies made up front, in parallel
+    prepared: HashMap<PackageId, PathBuf>,
+    hashes_file: PathBuf,
+    hashes: toml::Table,
+    normalize_eol: Vec<String>,
+    warnings: Warnings,
+    summary: Summary,
+    failures: Vec<String>,
+    applied: Vec<AppliedPatch<'a>>,
+}
+
+impl<'a, 'ws, 'gctx> ApplyRun<'a, 'ws, 'gctx> {
+    fn new(
+        args: &'a Cli,
+        workspace: &'a PatchWorkspace<'ws>,
+        pkg_set: &'a PackageSet<'gctx>,
+        resolve: &'a Resolve,
+        patches_folder: &'a Path,
+        target: Option<&'a str>,
+    ) -> Result<Self> {
+        let patch_target_folder = match target {
+            Some(target) => workspace.patch_target_folder().join(target),
+            None => workspace.patch_target_folder(),
+        };
+        let patch_target_tmp_folder = workspace.patch_target_tmp_folder();
+        let crates_to_patch = configured_crates(args, workspace, pkg_set, resolve)?;
+        let configured = crates_to_patch.clone();
+        let excludes = Excludes::new(args, workspace)?;
+        let options = ResolveOptions::new(args, workspace)?;
+        let targets = match target {
+            Some(target) => vec![target.to_string()],
+            None => options.targets.clone(),
+        };
+        let target_packages = options.target_packages(workspace, resolve, &targets)?;
+        let grouped = if args.apply.groups.is_empty() {
+            None
+        } else {
+            let mut grouped = Vec::new();
+            for entry in workspace.patch_crates()? {
+                if entry
+                    .setting("group")?
+                    .is_some_and(|group| args.apply.groups.iter().any(|g| g == group))
+                {
+                    grouped.push(entry);
+                }
+            }
+            Some(grouped)
+        };
+        let hashes_file = patches_folder.join(HASHES_FILE);
+        let hashes: toml::Table = if hashes_file.exists() {
+            fs::read_to_string(&hashes_file)?.parse()?
+        } else {
+            toml::Table::new()
+        };
+        let normalize_eol = workspace.root_patch_metadata_strings("normalize_eol")?;
+        Ok(ApplyRun {
+            args,
+            workspace,
+            pkg_set,
+            resolve,
+            patches_folder,
+            target,
+            stage_folder: patch_target_tmp_folder.join("stage"),
+            patch_target_folder,
+            patch_target_tmp_folder,
+            crates_to_patch,
+            configured,
+            excludes,
+            target_packages,
+            grouped,
+            selected: None,
+            cleaned: false,
+            prepared: HashMap::new(),
+            hashes_file,
+            hashes,
+            normalize_eol,
+            warnings: Warnings::default(),
+            summary: Summary::default(),
+            failures: Vec::new(),
+            applied: Vec::new(),
+        })
+    }
+
+    fn on_targets(&self, pkg: &Package) -> bool {
+        self.target_packages
+            .as_ref()
+            .is_none_or(|ids| ids.contains(&pkg.package_id()))
+    }
+
+    fn in_groups(&self, pkg: &Package) -> bool {
+        self.grouped
+            .as_ref()
+            .is_none_or(|entries| entries.iter().any(|entry| entry.matches(pkg.package_id())))
+    }
+
+    /// Whether the run patches `pkg`: given on the command line if any are,
+    /// a dependency on the targets and in the groups.
+    fn patches(&self, pkg: &Package) -> bool {
+        self.selected
+            .as_ref()
+            .is_none_or(|selected| selected.contains(pkg))
+            && self.on_targets(pkg)
+            && self.in_groups(pkg)
+    }
+
+    /// Whether the copy of `pkg` is current: a copy of another source, or
+    /// patched with patches that changed since, is copied and patched again.
+    /// With `--dry-run`, copies that `--force` would remove count as missing.
+    fn is_current(&self, pkg: &Package) -> Result<bool> {
+        let path = self.patch_target_folder.join(pkg.slug()?);
+        Ok(!(self.args.apply.dry_run && self.cleaned)
+            && path.exists()
+            && is_current_copy(&path, &source_stamp(pkg, &self.workspace.config)?)
+            && !patches_changed(
+                &path,
+                &find_patch_files(self.patches_folder, pkg.package_id())?,
+            )?)
+    }
+
+    fn prepared_path(&self, pkg: &Package) -> Option<PathBuf> {
+        self.prepared.get(&pkg.package_id()).cloned()
+    }
+
+    /// The malformed patch files, reported all at once before any crate is
+    /// copied.
+    fn check_malformed(&mut self) -> Result<Vec<(PathBuf, String)>> {
+        let args = self.args;
+        let patches_folder = self.patches_folder;
+        let malformed = malformed_patches(patches_folder)?;
+        if !malformed.is_empty() {
+            let list = malformed
+                .iter()
+                .map(|(file, problem)| format!("  - {:?}: {}", file, problem))
+                .collect::<Vec<_>>()
+                .join("\n");
+            if args.run.strict || args.patching.release {
+                return Err(anyhow!(
+                    "malformed patch files in {:?}:\n{}",
+                    patches_folder,
+                    list
+                ));
+            }
+            self.warnings.push(format!(
+                "malformed patch files in {:?} are skipped, `--strict` makes them an error:\n{}",
+                patches_folder, list
+            ));
+        }
+        Ok(malformed)
+    }
+
+    /// Takes the crates given on the command line as the only ones to apply
+    /// again, warning about those missing from the patch metadata.
+    fn select(&mut self, only: &[String]) -> Result<()> {
+        let args = self.args;
+        let pkg_set = self.pkg_set;
+        let resolve = self.resolve;
+        self.selected = if only.is_empty() {
+            None
+        } else {
+            let selected = only
+                .iter()
+                .map(|n| query_dependency(resolve, n).and_then(|id| pkg_set.get_one(id)))
+                .collect::<Result<HashSet<_>>>()?;
+            for pkg in selected.iter() {
+                if self.crates_to_patch.contains(pkg) {
+                    continue;
+                }
+                if self.excludes.matches(pkg.package_id()) {
+                    crate_info!("crate: {}, skip, it is excluded.", pkg.name());
+                    continue;
+                }
+                if args.unlisted {
+                    self.warnings.push(format!(
+                        "crate: {}, {} is not in the [package.metadata.patch] or [workspace.metadata.patch] section of Cargo.toml, patched anyway.",
+                        pkg.name(), pkg.name()
+                    ));
+                    self.crates_to_patch.insert(pkg);
+                } else {
+                    self.warnings.push(format!(
+                        "crate: {}, {} is not in the [package.metadata.patch] or [workspace.metadata.patch] section of Cargo.toml. Did you forget to add it?",
+                        pkg.name(), pkg.name()
+                    ));
+                }
+            }
+            Some(selected)
+        };
+        Ok(())
+    }
+
+    /// Checks the crates the run patches before any is copied: for orphan
+    /// patches, yanked versions, overrides to other sources and changed path
+    /// sources. Returns the crates with patches.
+    fn check_crates(&mut self) -> Result<Vec<&'a Package>> {
+        let args = self.args;
+        let workspace = self.workspace;
+        let pkg_set = self.pkg_set;
+        let patches_folder = self.patches_folder;
+        if args.apply.deny_orphans {
+            let orphans = orphan_patches(patches_folder, &self.crates_to_patch, &self.excludes)?;
+            if !orphans.is_empty() {
+                return Err(anyhow!(
+                    "{} patch(es) for crates missing from the [package.metadata.patch] or [workspace.metadata.patch] section of Cargo.toml, add the crates or remove the patches:\n{}",
+                    orphans.len(),
+                    orphans
+                        .iter()
+                        .map(|orphan| format!("  - {:?}", orphan))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ));
+            }
+        }
+
+        let patched: Vec<PackageId> = self
+            .crates_to_patch
+            .iter()
+            .filter(|pkg| self.patches(pkg))
+            .map(|pkg| pkg.package_id())
+            .collect();
+        match yanked_crates(workspace, pkg_set, &patched, args.patching.release) {
+            Result::Ok(yanked) if args.patching.release && !yanked.is_empty() => {
+                return Err(anyhow!(
+                    "{} patched crate version(s) yanked from their registry, update them with `cargo update` and create their patches again:\n{}",
+                    yanked.len(),
+                    yanked
+                        .iter()
+                        .map(|id| format!("  - {} {}", id.name(), id.version()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ));
+            }
+            Result::Ok(yanked) => {
+                for id in yanked {
+                    self.warnings.push(format!(
+                        "crate: {}, version {} is yanked from registry `{}`, the patch is still applied to the locked source.",
+                        id.name(),
+                        id.version(),
+                        id.source_id().display_registry_name()
+                    ));
+                }
+            }
+            Err(err) => self.warnings.push(format!(
+                "could not check whether the patched crates are yanked: {:#}",
+                err
+            )),
+        }
+        let patched_crates = self
+            .crates_to_patch
+            .iter()
+            .filter(|pkg| patched.contains(&pkg.package_id()))
+            .copied()
+            .collect::<Vec<_>>();
+        for (name, section, entry) in conflicting_overrides(workspace, &patched_crates)? {
+            self.warnings.push(format!(
+                "crate: {}, `{}` under [patch.{}] overrides it to another source, so its patched copy doesn't take effect. Remove that entry and override it with the copy in {:?} instead.",
+                name, entry, section, workspace.patch_target_folder()
+            ));
+        }
+        for pkg in self.crates_to_patch.iter() {
+            pkg.populate_pristine_dir(&workspace.config)?;
+            let path = self.patch_target_folder.join(pkg.slug()?);
+            if patched.contains(&pkg.package_id())
+                && is_changed_path_source(&path, pkg, &workspace.config)?
+            {
+                self.warnings.push(format!(
+                    "crate: {}, its path source {:?} changed since it was copied to {:?}, it is copied and patched again.",
+                    pkg.name(),
+                    pkg.root(),
+                    path
+                ));
+            }
+        }
+        Ok(patched_crates)
+    }
+
+    /// Asks before overwriting edited copies, then removes the copies of
+    /// `--clean`, or all of them with `--force`.
+    fn clean_copies(&mut self) -> Result<()> {
+        let args = self.args;
+        let reapply = self.selected.is_some();
+        self.cleaned = args.copy.force && !reapply;
+        let cleaned = self.cleaned;
+        let mut replaced = Vec::new();
+        if cleaned && self.patch_target_folder.exists() {
+            for entry in fs::read_dir(&self.patch_target_folder)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    replaced.push(path);
+                }
+            }
+        } else if !cleaned {
+            for pkg in self.crates_to_patch.iter() {
+                let path = self.patch_target_folder.join(pkg.slug()?);
+                if self.patches(pkg)
+                    && path.exists()
+                    && (args.copy.force || reapply || !self.is_current(pkg)?)
+                {
+                    replaced.push(path);
+                }
+            }
+        }
+        let mut edited = Vec::new();
+        for path in replaced {
+            if is_edited_copy(&path)? {
+                edited.push(path);
+            }
+        }
+        edited.sort();
+        if !args.apply.dry_run {
+            confirm_overwrite(args, &edited)?;
+        }
+
+        if !args.clean.is_empty() {
+            for pkg in self.selected.iter().flatten() {
+                let patch_target_path = self.patch_target_folder.join(pkg.slug()?);
+                if !patch_target_path.exists() {
+                    continue;
+                }
+                if args.apply.dry_run {
+                    crate_info!(
+                        "crate: {}, would remove {:?}.",
+                        pkg.name(),
+                        patch_target_path
+                    );
+                } else {
+                    discard_copy(&patch_target_path)?;
+                    crate_info!("crate: {}, removed {:?}.", pkg.name(), patch_target_path);
+                }
+            }
+        }
+
+        if cleaned && args.apply.dry_run {
+            info!("would clean up patch folder.");
+        } else if cleaned {
+            info!("Cleaning up patch folder.");
+            if self.patch_target_folder.exists() {
+                fs::remove_dir_all(&self.patch_target_folder)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the crates that are copied again to the stage folder up front,
+    /// in parallel. `--sparse-apply` only copies the crates it patches, and only
+    /// once the patch is known.
+    fn prepare_copies(&mut self) -> Result<()> {
+        let args = self.args;
+        let workspace = self.workspace;
+        let reapply = self.selected.is_some();
+        self.prepared = if args.apply.dry_run || args.apply.sparse_apply {
+            HashMap::new()
+        } else {
+            let mut pending = Vec::new();
+            for pkg in self.crates_to_patch.iter() {
+                if self.patches(pkg) && (args.copy.force || reapply || !self.is_current(pkg)?) {
+                    pending.push(*pkg);
+                }
+            }
+            pending.sort_by_key(|pkg| pkg.package_id());
+            let jobs = args
+                .run
+                .jobs
+                .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+            copy_packages(&pending, &workspace.config, &self.stage_folder, true, jobs)?
+        };
+        Ok(())
+    }
+
+    /// Applies the patches of one crate, `patch_files`, in the order they are
+    /// numbered.
+    fn apply_group(&mut self, patch_files: &[PathBuf]) -> Result<()> {
+        let pkg_set = self.pkg_set;
+        // the numbered patches of a crate are applied after the first
+        let patch_file = patch_files[0].clone();
+        let (filename, _) = split_patch_stem(
+            &patch_stem(&patch_file).ok_or(anyhow!("Patch file does not have a name"))?,
+        );
+        let Some((pkg_name, version)) = filename.split_once('+') else {
+            return Ok(());
+        };
+        if let Some(selected) = &self.selected {
+            if !selected.iter().any(|pkg| pkg.name() == pkg_name) {
+                return Ok(());
+            }
+        }
+        let Some(pkg_id) = self.patched_package(&patch_file, pkg_name, version)? else {
+            return Ok(());
+        };
+        let pkg = pkg_set.get_one(pkg_id)?;
+        if self
+            .selected
+            .as_ref()
+            .is_some_and(|selected| !selected.contains(pkg))
+        {
+            return Ok(());
+        }
+        if !self.on_targets(pkg) {
+            crate_info!(
+                "crate: {}, skip, not a dependency on the configured targets.",
+                pkg_name
+            );
+            self.summary.push(pkg, "skipped, not on targets");
+            self.crates_to_patch.remove(pkg);
+            return Ok(());
+        }
+        if self.crates_to_patch.contains(pkg) && !self.in_groups(pkg) {
+            crate_info!("crate: {}, skip, not in the selected groups.", pkg_name);
+            self.summary.push(pkg, "skipped, not in groups");
+            self.crates_to_patch.remove(pkg);
+            return Ok(());
+        }
+        if !self.crates_to_patch.contains(&pkg) && self.excludes.matches(pkg_id) {
+            crate_info!("crate: {}, skip, it is excluded.", pkg_name);
+            self.summary.push(pkg, "skipped, excluded");
+            return Ok(());
+        }
+        if !self.crates_to_patch.contains(&pkg) {
+            self.warnings.push(format!(
+                "crate: {}, {} is not in the [package.metadata.patch] or [workspace.metadata.patch] section of Cargo.toml. Did you forget to add it?",
+                pkg_name, pkg_name
+            ));
+            self.summary.push(pkg, "not in metadata");
+            return Ok(());
+        }
+
+        self.apply_crate(pkg, pkg_name, patch_files)?;
+        self.crates_to_patch.remove(pkg);
+        Ok(())
+    }
+
+    /// The resolved crate the patch `patch_file` of `pkg_name` and `version`
+    /// is applied to, if any.
+    fn patched_package(
+        &mut self,
+        patch_file: &Path,
+        pkg_name: &str,
+        version: &str,
+    ) -> Result<Option<PackageId>> {
+        let args = self.args;
+        let workspace = self.workspace;
+        let pkg_set = self.pkg_set;
+        let resolve = self.resolve;
+        let patches_folder = self.patches_folder;
+        // a patch that names its registry is for the crate from there,
+        // when the same version is resolved from several sources
+        let registry = patch_registry(patch_file)?;
+        let from_registry = registry.as_deref().and_then(|url| {
+            resolve.iter().find(|id| {
+                id.name() == pkg_name
+                    && id.version().to_string() == version
+                    && id.source_id().url().as_str() == url
+            })
+        });
+        let pkg_id = match (from_registry, version.strip_prefix(GIT_REV_PREFIX)) {
+            (Some(pkg_id), _) => pkg_id,
+            (None, Some(rev)) => {
+                let pkg_id = query_git_package(resolve, pkg_name, rev)?;
+                match pkg_id.source_id().precise_git_fragment() {
+                    Some(precise) if precise.starts_with(rev) => {}
+                    Some(precise) if args.patching.release => {
+                        return Err(anyhow!(
+                            "crate: {}, patch {:?} was made for revision {} but {} is pinned now",
+                            pkg_name, patch_file, rev, precise
+                        ))
+                    }
+                    Some(precise) => self.warnings.push(format!(
+                        "crate: {}, patch {:?} was made for revision {} but {} is pinned now, the patch may be stale.",
+                        pkg_name, patch_file, rev, &precise[..SHORT_REV_LEN.min(precise.len())]
+                    )),
+                    None => {
+                        return Err(anyhow!(
+                            "crate: {}, patch {:?} is for a git revision but the crate is resolved from {}",
+                            pkg_name, patch_file, pkg_id.source_id()
+                        ))
+                    }
+                }
+                pkg_id
+            }
+            (None, None) if version_range(version).is_some() => {
+                let pkg_id = resolve.query(pkg_name)?;
+                let exact = existing_patch_file(
+                    patches_folder,
+                    &format!("{}+{}", pkg_name, pkg_id.version()),
+                    workspace.config.nested,
+                );
+                if exact.exists() {
+                    crate_info!(
+                        "crate: {}, skip patch {:?}, {:?} is for the resolved version.",
+                        pkg_name,
+                        patch_file,
+                        exact
+                    );
+                    return Ok(None);
+                }
+                if !version_range(version)
+                    .is_some_and(|req| req.matches(pkg_id.version()))
+                {
+                    self.warnings.push(format!(
+                        "crate: {}, patch {:?} is for versions {} but {} is resolved, skipped.",
+                        pkg_name, patch_file, version, pkg_id.version()
+                    ));
+                    return Ok(None);
+                }
+                if args.patching.release {
+                    return Err(anyhow!(
+                        "crate: {}, patch {:?} is for versions {}, but `--release` needs a patch for exactly {}",
+                        pkg_name, patch_file, version, pkg_id.version()
+                    ));
+                }
+                let tested = tested_versions(patch_file)?;
+                if !tested.is_empty() && !tested.contains(pkg_id.version()) {
+                    self.warnings.push(format!(
+                        "crate: {}, patch {:?} was only tested against {}, not the resolved {}. Check the copy and create the patch again with `--range {}` to record it.",
+                        pkg_name,
+                        patch_file,
+                        tested.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", "),
+                        pkg_id.version(),
+                        version
+                    ));
+                }
+                pkg_id
+            }
+            (None, None) => match resolve.query(format!("{}@{}", pkg_name, version).as_str()) {
+                Result::Ok(pkg_id) => pkg_id,
+                Err(_) if args.apply.detect_upstreamed && !args.patching.release => {
+                    if detect_upstreamed(
+                        workspace,
+                        pkg_set,
+                        resolve,
+                        pkg_name,
+                        version,
+                        patch_file,
+                    )? {
+                        self.warnings.push(format!(
+                        "crate: {}, patch {:?} applies as a no-op — upstream may already include this fix; consider removing it.",
+                        pkg_name, patch_file
+                    ));
+                    }
+                    return Ok(None);
+                }
+                Err(err) => match resolve.query(pkg_name) {
+                    Result::Ok(pkg_id) if args.apply.strict_version || args.patching.release => {
+                        return Err(anyhow!(
+                            "crate: {}, patch {:?} is for version {} but {} is resolved, run `cargo patch-crate {}` to create it again",
+                            pkg_name, patch_file, version, pkg_id.version(), pkg_name
+                        ))
+                    }
+                    Result::Ok(pkg_id) => {
+                        let exact = existing_patch_file(
+                            patches_folder,
+                            &format!("{}+{}", pkg_name, pkg_id.version()),
+                            workspace.config.nested,
+                        );
+                        if exact.exists() {
+                            crate_info!(
+                                "crate: {}, skip patch {:?}, {:?} is for the resolved version.",
+                                pkg_name,
+                                patch_file,
+                                exact
+                            );
+                            return Ok(None);
+                        }
+                        self.warnings.push(format!(
+                            "crate: {}, patch {:?} is for version {} but {} is resolved, applying it anyway; run `cargo patch-crate {}` to create it again.",
+                            pkg_name, patch_file, version, pkg_id.version(), pkg_name
+                        ));
+                        pkg_id
+                    }
+                    // not resolved at all, or in several versions
+                    Err(_) => return Err(err),
+                },
+            },
+        };
+        let source_id = pkg_id.source_id();
+        match registry {
+            Some(url) if source_id.url().as_str() != url => self.warnings.push(format!(
+                "crate: {}, patch {:?} was created for the crate from registry {} but it is resolved from {}.",
+                pkg_name, patch_file, url, source_id
+            )),
+            None if source_id.is_registry() && !source_id.is_crates_io() => {
+                crate_info!(
+                    "crate: {}, patch {:?} names no registry, applied to the crate from {}.",
+                    pkg_name,
+                    patch_file,
+                    source_id
+                );
+            }
+            _ => {}
+        }
+        Ok(Some(pkg_id))
+    }
+
+    /// Applies `patch_files` to a fresh copy of `pkg`, or leaves a current
+    /// copy as it is.
+    fn apply_crate(
+        &mut self,
+        pkg: &'a Package,
+        pkg_name: &str,
+        patch_files: &[PathBuf],
+    ) -> Result<()> {
+        let args = self.args;
+        let workspace = self.workspace;
+        let resolve = self.resolve;
+        let reapply = self.selected.is_some();
+        let target = self.target;
+        let patch_file = patch_files[0].clone();
+        let patch_target_path = self.patch_target_folder.join(pkg.slug()?);
+        let patch_name = patch_file_key(&patch_file);
+        if !reapply
+            && patch_target_path.exists()
+            && patches_changed(&patch_target_path, patch_files)?
+        {
+            crate_info!(
+                "crate: {}, its patches changed since {:?} was patched, applying them again.",
+                pkg_name,
+                patch_target_path
+            );
+        }
+        if !(reapply || self.prepared_path(pkg).is_some() || !self.is_current(pkg)?) {
+            if args.patching.release
+                && !has_recorded_hash(&self.hashes, &patch_name, &patch_target_path)?
+            {
+                return Err(anyhow!(
+                    "crate: {}, {:?} does not match the hash recorded for {:?} in {:?}, run with `--force` to apply the patch again",
+                    pkg_name, patch_target_path, patch_file, self.hashes_file
+                ));
+            }
+            if args.apply.dry_run {
+                crate_info!(
+                    "crate: {}, would skip applying patch, {:?} already exists.",
+                    pkg_name,
+                    patch_target_path
+                );
+                self.summary.push(pkg, "would skip, already exists");
+            } else {
+                crate_info!("crate: {}, skip applying patch, {:?} already exists. Did you forget to add `--force`?", pkg_name, patch_target_path);
+                self.summary.push(pkg, "skipped, already exists");
+            }
+            return Ok(());
+        }
+        if let Err(err) = verify_checksum(pkg, resolve) {
+            if args.patching.release || args.resolving.locked || !args.apply.allow_checksum_mismatch
+            {
+                return Err(err.context(
+                    "the registry cache may be corrupted or tampered with; remove the crate from it to download it again, or pass `--allow-checksum-mismatch`",
+                ));
+            }
+            self.warnings.push(format!(
+                "{}, patched anyway with `--allow-checksum-mismatch`.",
+                err
+            ));
+        }
+        self.check_patch_paths(pkg, pkg_name, patch_files)?;
+        if args.apply.dry_run {
+            for patch_file in patch_files.iter() {
+                crate_info!("crate: {}, would apply patch {:?}.", pkg_name, patch_file);
+            }
+            self.summary
+                .record(pkg, "would apply", Some(&patch_file), None);
+            return Ok(());
+        }
+        let apply_path = if args.apply.sparse_apply {
+            sparse_copy_package(
+                pkg,
+                &workspace.config,
+                &self.patch_target_tmp_folder,
+                patch_files,
+            )?
+        } else if let Some(path) = self.prepared_path(pkg) {
+            path
+        } else {
+            copy_package(pkg, &workspace.config, &self.stage_folder, true)?
+        };
+        crate_info!("crate: {}, applying patch started.", pkg_name);
+        if let Some(path) = dependency_path(workspace, resolve, pkg.package_id()) {
+            crate_info!("crate: {}, a dependency pulled in by {}.", pkg_name, path);
+        }
+        let eol = args.patching.normalize_eol
+            || self
+                .normalize_eol
+                .iter()
+                .any(|n| matches_spec(n, pkg.package_id()));
+        let mut failed = None;
+        for patch_file in patch_files.iter() {
+            match apply_patch(args, pkg_name, &apply_path, patch_file, eol) {
+                Result::Ok(Some(command)) => self.warnings.push(format!(
+                    "crate: {}, patch {:?} only applied with `{}`, it is getting fragile; consider creating it again.",
+                    pkg_name, patch_file, command
+                )),
+                Result::Ok(None) => {}
+                Err(err) => {
+                    failed = Some((patch_file, err));
+                    break;
+                }
+            }
+        }
+        if let Some((patch_file, err)) = failed {
+            return self.fail_crate(
+                pkg,
+                pkg_name,
+                patch_file,
+                err,
+                &apply_path,
+                &patch_target_path,
+            );
+        }
+        let patched_path = if args.apply.sparse_apply {
+            fill_patched_package(
+                pkg,
+                &workspace.config,
+                &apply_path,
+                &patch_target_path,
+                patch_files,
+            )?;
+            fs::remove_dir_all(&self.patch_target_tmp_folder)?;
+            fs::write(
+                source_marker(&patch_target_path),
+                source_stamp(pkg, &workspace.config)?,
+            )?;
+            seal_copy(&patch_target_path)?;
+            patch_target_path.clone()
+        } else {
+            apply_path.clone()
+        };
+        if args.apply.record_hashes {
+            self.hashes
+                .insert(patch_name.clone(), tree_hash(&patched_path)?.into());
+        } else if args.patching.release
+            && !has_recorded_hash(&self.hashes, &patch_name, &patched_path)?
+        {
+            discard_copy(&patched_path)?;
+            return Err(anyhow!(
+                "crate: {}, patched crate does not match the hash recorded for {:?} in {:?}, run with `--record-hashes` if the patch changed on purpose",
+                pkg_name, patch_file, self.hashes_file
+            ));
+        }
+        if !args.apply.sparse_apply {
+            commit_copy(&apply_path, &patch_target_path)?;
+        }
+        record_patches(&patch_target_path, patch_files)?;
+        for patch_file in patch_files.iter() {
+            crate_info!(
+                "crate: {}, successfully applied patch {:?}.",
+                pkg_name,
+                patch_file
+            );
+        }
+        if !args.run.allow_manifest_changes {
+            let mut touched = false;
+            for patch_file in patch_files.iter() {
+                touched |= touches_manifest(&patch::read(patch_file)?);
+            }
+            if touched {
+                warn_manifest_changes(
+                    pkg_name,
+                    &pkg.pristine_root(&workspace.config)?,
+                    &patch_target_path,
+                );
+            }
+        }
+        print_override(args, workspace, pkg, &patch_target_path, target)?;
+        self.summary.record(pkg, "applied", Some(&patch_file), None);
+        self.applied.push(AppliedPatch {
+            pkg,
+            patch_files: patch_files.to_vec(),
+            path: patch_target_path.clone(),
+            normalize_eol: eol,
+        });
+        Ok(())
+    }
+
+    /// Checks the paths `patch_files` touch against the allowed paths, and
+    /// warns about case-only renames and paths colliding by case.
+    fn check_patch_paths(
+        &mut self,
+        pkg: &Package,
+        pkg_name: &str,
+        patch_files: &[PathBuf],
+    ) -> Result<()> {
+        let workspace = self.workspace;
+        let mut renames = Vec::new();
+        let mut paths = list_files(&pkg.pristine_root(&workspace.config)?)?;
+        for patch_file in patch_files.iter() {
+            patch::check_paths(patch_file)?;
+            let content = patch::read(patch_file)?;
+            for entry in workspace.patch_crates()? {
+                if entry.matches(pkg.package_id()) {
+                    check_allowed_paths(&entry, &content)?;
+                }
+            }
+            for (old, new) in patch::case_only_renames(&content) {
+                self.warnings.push(format!(
+                    "crate: {}, patch {:?} renames {} to {} by case only, which relies on git's rename handling on case-insensitive file systems.",
+                    pkg_name, patch_file, old, new
+                ));
+                renames.push((old, new));
+            }
+            for path in patch::touched_paths(&content) {
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        for (a, b) in case_collisions(&paths) {
+            let renamed = renames
+                .iter()
+                .any(|(old, new)| (old == a && new == b) || (old == b && new == a));
+            if !renamed {
+                self.warnings.push(format!(
+                    "crate: {}, {} and {} only differ in case and collide on case-insensitive file systems.",
+                    pkg_name, a, b
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles `patch_file` failing to apply to the copy of `pkg` at
+    /// `apply_path`: the crate is left unpatched with `--continue-on-error`, as
+    /// it was unless `--fail-fast`, else the run fails.
+    fn fail_crate(
+        &mut self,
+        pkg: &Package,
+        pkg_name: &str,
+        patch_file: &Path,
+        err: anyhow::Error,
+        apply_path: &Path,
+        patch_target_path: &Path,
+    ) -> Result<()> {
+        let args = self.args;
+        let workspace = self.workspace;
+        if args.apply.continue_on_error {
+            // leaves the crate copied but unpatched
+            if args.apply.sparse_apply {
+                fs::remove_dir_all(&self.patch_target_tmp_folder)?;
+            } else {
+                discard_copy(apply_path)?;
+            }
+            copy_package(pkg, &workspace.config, &self.patch_target_folder, true)?;
+            self.warnings.push(format!(
+                "crate: {}, patch {:?} failed to apply, {:?} is left unpatched: {}",
+                pkg_name,
+                patch_file,
+                patch_target_path,
+                err.to_string().trim_end()
+            ));
+            self.failures
+                .push(format!("  - {} {:?}", pkg_name, patch_file));
+            self.summary.record(
+                pkg,
+                "failed, left unpatched",
+                Some(patch_file),
+                Some(err.to_string().trim_end().to_string()),
+            );
+        } else if !args.apply.fail_fast {
+            // the previous copy, if any, is left as it was
+            if args.apply.sparse_apply {
+                fs::remove_dir_all(&self.patch_target_tmp_folder)?;
+            } else {
+                discard_copy(apply_path)?;
+            }
+            warn!(
+                "crate: {}, patch {:?} failed to apply, {:?} is left as it was: {}",
+                pkg_name,
+                patch_file,
+                patch_target_path,
+                err.to_string().trim_end()
+            );
+            self.failures
+                .push(format!("  - {} {:?}", pkg_name, patch_file));
+            self.summary.record(
+                pkg,
+                "failed",
+                Some(patch_file),
+                Some(err.to_string().trim_end().to_string()),
+            );
+        } else {
+            // the previous copy, if any, is left as it was
+            discard_copy(apply_path)?;
+            if self.stage_folder.exists() {
+                fs::remove_dir_all(&self.stage_folder)?;
+            }
+            let _ = fs::remove_dir(&self.patch_target_tmp_folder);
+            self.summary.record(
+                pkg,
+                "failed",
+                Some(patch_file),
+                Some(err.to_string().trim_end().to_string()),
+            );
+            return Err(anyhow!(
+                "crate: {}, patch {:?} failed to apply: {}",
+                pkg_name,
+                patch_file,
+                err
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copies the crates of the run that have no patch.
+    fn copy_unpatched(&mut self) -> Result<()> {
+        let args = self.args;
+        let workspace = self.workspace;
+        let resolve = self.resolve;
+        let reapply = self.selected.is_some();
+        let target = self.target;
+        let crates = std::mem::take(&mut self.crates_to_patch);
+        for pkg in crates {
+            if self
+                .selected
+                .as_ref()
+                .is_some_and(|selected| !selected.contains(pkg))
+            {
+                continue;
+            }
+            if !self.on_targets(pkg) {
+                crate_info!(
+                    "crate: {}, skip, not a dependency on the configured targets.",
+                    pkg.name()
+                );
+                self.summary.push(pkg, "skipped, not on targets");
+                continue;
+            }
+            if !self.in_groups(pkg) {
+                crate_info!("crate: {}, skip, not in the selected groups.", pkg.name());
+                self.summary.push(pkg, "skipped, not in groups");
+                continue;
+            }
+            let copied = args.copy.force
+                || reapply
+                || self.prepared_path(pkg).is_some()
+                || !self.is_current(pkg)?;
+            if args.apply.dry_run {
+                if copied {
+                    crate_info!("crate: {}, would copy, there is no patch.", pkg.name());
+                    self.summary.push(pkg, "would copy, no patch");
+                } else {
+                    crate_info!("crate: {}, would skip, already copied.", pkg.name());
+                    self.summary.push(pkg, "would skip, already exists");
+                }
+                continue;
+            }
+            let patch_target_path = match self.prepared_path(pkg) {
+                Some(staged) => {
+                    let patch_target_path = self.patch_target_folder.join(pkg.slug()?);
+                    commit_copy(&staged, &patch_target_path)?;
+                    patch_target_path
+                }
+                None => copy_package(pkg, &workspace.config, &self.patch_target_folder, copied)?,
+            };
+            if copied {
+                record_patches(&patch_target_path, &[])?;
+                if let Some(path) = dependency_path(workspace, resolve, pkg.package_id()) {
+                    crate_info!("crate: {}, a dependency pulled in by {}.", pkg.name(), path);
+                }
+                print_override(args, workspace, pkg, &patch_target_path, target)?;
+            }
+            self.summary.push(pkg, "copied, no patch");
+        }
+        Ok(())
+    }
+
+    /// Writes what the run recorded and reports it, failing on the crates
+    /// that failed to apply.
+    fn finish(mut self, patched_crates: &[&Package], started: Instant) -> Result<()> {
+        let args = self.args;
+        let workspace = self.workspace;
+        if args.apply.check_independence && !args.apply.dry_run {
+            check_independence(
+                args,
+                &workspace.config,
+                &self.applied,
+                &self.patch_target_tmp_folder,
+            )?;
+        }
+        if (args.manifest.write_manifest || args.apply.emit_patch_section) && !args.apply.dry_run {
+            let mut copied = Vec::new();
+            for pkg in self.configured.iter() {
+                if self.on_targets(pkg)
+                    && self.in_groups(pkg)
+                    && self.patch_target_folder.join(pkg.slug()?).exists()
+                {
+                    copied.push(*pkg);
+                }
+            }
+            copied.sort_by_key(|pkg| pkg.package_id());
+            if args.manifest.write_manifest {
+                write_manifest(
+                    args,
+                    workspace,
+                    &copied,
+                    &self.configured,
+                    &self.patch_target_folder,
+                )?;
+            }
+            if args.apply.emit_patch_section {
+                emit_patch_section(workspace, &copied, &self.patch_target_folder)?;
+            }
+        }
+        if self.grouped.is_some() {
+            for group in args.apply.groups.iter() {
+                let crates = workspace
+                    .patch_crates()?
+                    .into_iter()
+                    .filter(|entry| entry.setting("group").ok().flatten() == Some(group.as_str()))
+                    .map(|entry| entry.name)
+                    .collect::<Vec<_>>();
+                if crates.is_empty() {
+                    self.warnings.push(format!(
+                        "group {} has no crates in the patch metadata.",
+                        group
+                    ));
+                } else {
+                    info!("group {}: {}", group, crates.join(", "));
+                }
+            }
+        }
+        if self.selected.is_none() {
+            let stale = stale_copies(&self.patch_target_folder, &self.configured)?;
+            if !stale.is_empty() {
+                self.warnings.push(format!(
+                    "copies of crates that are no longer in the patch metadata or resolved, which a `[patch]` entry may still point at:\n{}\nremove them with `cargo patch-crate prune --keep-applied`.",
+                    stale
+                        .iter()
+                        .map(|path| format!("  - {:?}", path))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ));
+            }
+        }
+        if args.apply.record_hashes && !args.apply.dry_run {
+            fs::write(&self.hashes_file, toml::to_string(&self.hashes)?)?;
+            info!(
+                "recorded hashes of patched crates in {:?}",
+                self.hashes_file
+            );
+        }
+        self.update_lock(patched_crates)?;
+        // copies of crates that were skipped after all
+        if self.stage_folder.exists() {
+            fs::remove_dir_all(&self.stage_folder)?;
+        }
+        let _ = fs::remove_dir(&self.patch_target_tmp_folder);
+        if args.apply.summary_only {
+            self.summary.print();
+        }
+        if !args.apply.dry_run {
+            if !report::enabled() {
+                info!("{}", self.summary.totals(self.warnings.len()));
+            }
+            info!("applying patch finished in {:.2?}", started.elapsed());
+        }
+        self.warnings
+            .check(args.apply.deny_warnings || args.patching.release)?;
+        // unless `--fail-fast`, the other crates are patched first
+        if !self.failures.is_empty() {
+            return Err(anyhow!(
+                "{} crate(s) failed to apply their patches:\n{}",
+                self.failures.len(),
+                self.failures.join("\n")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Updates the [`LOCK_FILE`], which only a run over all patched crates
+    /// knows every entry of.
+    fn update_lock(&mut self, patched_crates: &[&Package]) -> Result<()> {
+        let args = self.args;
+        let patches_folder = self.patches_folder;
+        if !args.apply.dry_run
+            && self.selected.is_none()
+            && self.grouped.is_none()
+            && self.failures.is_empty()
+        {
+            let mut entries = Vec::new();
+            for pkg in patched_crates.iter() {
+                for patch_file in find_patch_files(patches_folder, pkg.package_id())? {
+                    entries.push(lock_entry(pkg.package_id(), &patch_file)?);
+                }
+            }
+            let lock_file = patches_folder.join(LOCK_FILE);
+            match read_patch_lock(patches_folder)? {
+                Some(locked) => {
+                    let drift = lock_drift(&locked, &entries);
+                    if !drift.is_empty() && (args.resolving.locked || args.patching.release) {
+                        return Err(anyhow!(
+                            "the patches differ from {:?}, which is only updated without `--locked` and `--release`:\n{}",
+                            lock_file,
+                            drift
+                                .iter()
+                                .map(|drift| format!("  - {}", drift))
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        ));
+                    }
+                    for drift in drift {
+                        self.warnings
+                            .push(format!("{}: {}, updated it.", LOCK_FILE, drift));
+                    }
+                }
+                None if !entries.is_empty() => {
+                    info!("recorded the applied patches in {:?}", lock_file)
+                }
+                None => {}
+            }
+            if !entries.is_empty() || lock_file.exists() {
+                write_patch_lock(patches_folder, entries)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies `patch_file` to the crate sources in `dir`, which don't have to be
+/// known to cargo, e.g. a vendored or manually extracted crate.
+pub fn apply_to(dir: &Path, patch_file: &Path, strip: Option<usize>) -> Result<()> {
+    git::check_installed()?;
+    if !dir.is_dir() {
+        return Err(anyhow!("{:?} is not a directory", dir));
+    }
+    if !patch_file.is_file() {
+        return Err(anyhow!("patch {:?} does not exist", patch_file));
+    }
+    // git runs inside `dir`
+    let patch_file = &std::path::absolute(patch_file)?;
+    patch::check_paths(patch_file)?;
+    // a repository of its own is applied to as is and must be kept
+    let own_repo = dir.join(".git").exists();
+    if !own_repo {
+        git::init(dir)?;
+    }
+    let detected = match strip {
+        Some(strip) => Result::Ok(Some(strip)),
+        None => git::detect_strip(dir, patch_file),
+    };
+    let result = match detected {
+        Result::Ok(Some(strip)) => git::apply_strip(dir, patch_file, strip).map(|_| strip),
+        // applying at git's level anyway tells why it doesn't apply
+        Result::Ok(None) => git::apply(dir, patch_file)
+            .map(|_| 1)
+            .map_err(|err| anyhow!("it applies at none of -p1, -p0 and -p2, at -p1: {}", err)),
+        Err(err) => Err(err),
+    };
+    if !own_repo {
+        git::destroy(dir)?;
+    }
+    let strip =
+        result.map_err(|err| anyhow!("failed to apply {:?} to {:?}: {}", patch_file, dir, err))?;
+    info!(
+        "successfully applied patch {:?} to {:?} with -p{}.",
+        patch_file, dir, strip
+    );
+    Ok(())
+}
+
+/// The patch files in `patches_folder` for crates that are neither in
+/// `configured` nor excluded, which applying skips with a warning.
+fn orphan_patches(
+    patches_folder: &Path,
+    configured: &HashSet<&Package>,
+    excludes: &Excludes,
+) -> Result<Vec<PathBuf>> {
+    let mut orphans = Vec::new();
+    if !patches_folder.exists() {
+        return Ok(orphans);
+    }
+    for patch_files in patch_groups(patches_folder)? {
+        let (stem, _) = split_patch_stem(&patch_stem(&patch_files[0]).unwrap_or_default());
+        let Some((pkg_name, _)) = stem.split_once('+') else {
+            continue;
+        };
+        if !configured.iter().any(|pkg| pkg.name() == pkg_name) && !excludes.matches_name(pkg_name)
+        {
+            orphans.extend(patch_files);
+        }
+    }
+    Ok(orphans)
+}
+
+/// Converts the line endings when asked to, then applies `patch_file` to the
+/// crate copied to `path`. With `--escalate`, returns the looser command the
+/// patch needed, if strict `git apply` was not enough.
+pub fn apply_patch(
+    args: &Cli,
+    pkg_name: &str,
+    path: &Path,
+    patch_file: &Path,
+    normalize_eol: bool,
+) -> Result<Option<String>> {
+    if patch::is_placeholder(patch_file)? {
+        return Ok(None);
+    }
+    if normalize_eol {
+        for file in patch::normalize_line_endings(path, patch_file)? {
+            crate_info!("crate: {}, normalized line endings of {}.", pkg_name, file);
+        }
+    }
+    git::init(path)?;
+    let apply = |strip: usize| {
+        if args.patching.release {
+            git::apply_exact(path, patch_file, strip).map(|_| None)
+        } else if args.patching.escalate {
+            git::apply_escalating(path, patch_file, strip, args.patching.fuzz)
+        } else {
+            git::apply_strip(path, patch_file, strip).map(|_| None)
+        }
+    };
+    let escalated = match (apply(args.patching.strip.unwrap_or(1)), args.patching.strip) {
+        (Result::Ok(escalated), _) => escalated,
+        // patches written by other tools may be rooted one level up or down
+        (Err(err), None) => match git::detect_strip(path, patch_file)? {
+            Some(strip) if strip != 1 => {
+                let escalated = apply(strip)?;
+                crate_info!(
+                    "crate: {}, patch {:?} applied with -p{}. Pin it with `--strip {}` when all patches are rooted alike, or create the patch again to root it like git.",
+                    pkg_name,
+                    patch_file,
+                    strip,
+                    strip
+                );
+                escalated
+            }
+            _ => return Err(err),
+        },
+        (Err(err), Some(_)) => return Err(err),
+    };
+    git::destroy(path)?;
+    Ok(escalated)
+}
+
+/// The patches of a crate applied in this run, and the crate they were
+/// applied to.
+struct AppliedPatch<'a> {
+    pkg: &'a Package,
+    patch_files: Vec<PathBuf>,
+    path: PathBuf,
+    normalize_eol: bool,
+}
+
+/// Applies the patches of every crate of the run on their own to a pristine
+/// copy of the crate, in reverse order, and fails if any result differs from
+/// the crate patched along with the others.
+fn check_independence(
+    args: &Cli,
+    config: &PatchConfig,
+    applied: &[AppliedPatch],
+    patch_target_tmp_folder: &Path,
+) -> Result<()> {
+    let mut coupled = Vec::new();
+    for patch in applied.iter().rev() {
+        let name = patch.pkg.name();
+        let isolated = copy_package(patch.pkg, config, patch_target_tmp_folder, true)?;
+        let result = patch
+            .patch_files
+            .iter()
+            .try_for_each(|patch_file| {
+                apply_patch(args, &name, &isolated, patch_file, patch.normalize_eol).map(|_| ())
+            })
+            .and_then(|_| Ok(tree_hash(&isolated)? == tree_hash(&patch.path)?));
+        fs::remove_dir_all(&isolated)?;
+        let (patches, gives, does, own) = match patch.patch_files.as_slice() {
+            [patch_file] => (format!("patch {:?}", patch_file), "gives", "does", "its"),
+            patch_files => (format!("patches {:?}", patch_files), "give", "do", "their"),
+        };
+        match result {
+            Result::Ok(true) => crate_info!(
+                "crate: {}, {} {} the same result on {} own.",
+                name,
+                patches,
+                gives,
+                own
+            ),
+            Result::Ok(false) => {
+                warn!(
+                    "crate: {}, {} {} a different result on {} own than along with the other patches.",
+                    name, patches, gives, own
+                );
+                coupled.push(name.to_string());
+            }
+            Err(err) => {
+                warn!(
+                    "crate: {}, {} {} not apply on {} own: {:#}",
+                    name, patches, does, own, err
+                );
+                coupled.push(name.to_string());
+            }
+        }
+    }
+    let _ = fs::remove_dir(patch_target_tmp_folder);
+    if !coupled.is_empty() {
+        return Err(anyhow!(
+            "patches of {} depend on the other patches",
+            coupled.join(", ")
+        ));
+    }
+    info!("all {} applied patches are independent.", applied.len());
+    Ok(())
+}
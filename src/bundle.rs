@@ -0,0 +1,95 @@
+//! A bundle packing every patch of the patches folder into a single file.
+
+use std::{
+    fs::{self, File},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use super::PATCH_EXT;
+
+/// Name of the entry recording which crate and version each patch targets.
+const MANIFEST: &str = "bundle.toml";
+
+/// Packs every patch file in `patches_folder` into a gzipped tarball,
+/// alongside a manifest mapping each file to its crate and version, and
+/// the index of a numbered patch.
+pub fn create(patches_folder: &Path, bundle_file: &Path) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    if patches_folder.exists() {
+        entries.extend(super::patch_files_in(patches_folder)?);
+    }
+    if entries.is_empty() {
+        bail!("no patch files found in {:?}", patches_folder);
+    }
+    entries.sort();
+
+    let mut manifest = toml::Table::new();
+    let mut patches = Vec::new();
+    for path in entries.iter() {
+        let file = super::patch_file_key(path);
+        let stem = super::patch_stem(path).unwrap_or_default();
+        let (stem, index) = super::split_patch_stem(&stem);
+        let (name, version) = stem.split_once('+').ok_or(anyhow!(
+            "patch file {:?} is not named `<crate>+<version>.{}`",
+            path,
+            PATCH_EXT
+        ))?;
+        let mut patch = toml::Table::new();
+        patch.insert("name".into(), name.into());
+        patch.insert("version".into(), version.into());
+        if let Some(index) = index {
+            patch.insert("index".into(), index.into());
+        }
+        patch.insert("file".into(), file.into());
+        patches.push(toml::Value::Table(patch));
+    }
+    manifest.insert("patch".into(), toml::Value::Array(patches));
+    let manifest = toml::to_string(&manifest)?;
+
+    let encoder = GzEncoder::new(File::create(bundle_file)?, Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, manifest.as_bytes())?;
+    for path in entries.iter() {
+        builder.append_path_with_name(path, super::patch_file_key(path))?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Unpacks a bundle made by [`create`] into `folder`, checking that every
+/// patch listed in its manifest is present.
+pub fn extract(bundle_file: &Path, folder: &Path) -> anyhow::Result<()> {
+    if folder.exists() {
+        fs::remove_dir_all(folder)?;
+    }
+    fs::create_dir_all(folder)?;
+    let file = File::open(bundle_file)
+        .with_context(|| format!("failed to open bundle {:?}", bundle_file))?;
+    tar::Archive::new(GzDecoder::new(file)).unpack(folder)?;
+
+    let manifest: toml::Table = fs::read_to_string(folder.join(MANIFEST))
+        .with_context(|| format!("{:?} is not a patch bundle", bundle_file))?
+        .parse()?;
+    for patch in manifest
+        .get("patch")
+        .and_then(|p| p.as_array())
+        .into_iter()
+        .flatten()
+    {
+        let file = patch
+            .get("file")
+            .and_then(|f| f.as_str())
+            .ok_or(anyhow!("invalid entry in bundle manifest: {}", patch))?;
+        if !folder.join(file).is_file() {
+            bail!("bundle {:?} is missing patch file {}", bundle_file, file);
+        }
+    }
+    Ok(())
+}
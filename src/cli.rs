@@ -0,0 +1,1117 @@
+//! The command line: the flags and subcommands, the default arguments of the
+//! patch metadata and `--print-config`.
+
+use std::{ffi::OsString, path::PathBuf};
+
+use anyhow::{anyhow, Ok, Result};
+use cargo::core::Workspace;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+use super::{
+    create::{cargo_files, junk_files},
+    PatchWorkspace, ResolveOptions, WorkspaceExt, CARGO_FILES, JUNK_FILES, PATCH_DIR_ENV,
+    PATCH_EXT,
+};
+
+#[derive(Parser, Debug, Clone)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    args_override_self = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Create the patches of these crates from their copies in
+    /// `target/patch`, same as `create <CRATE>...`; `apply <CRATE>...`
+    /// applies the patches of crates instead
+    #[arg(value_name = "CRATE")]
+    pub crates: Vec<String>,
+    #[command(flatten)]
+    pub resolving: ResolveArgs,
+    #[command(flatten)]
+    pub run: RunArgs,
+    #[command(flatten)]
+    pub diff: DiffArgs,
+    #[command(flatten)]
+    pub create: CreateArgs,
+    #[command(flatten)]
+    pub patching: PatchingArgs,
+    #[command(flatten)]
+    pub apply: ApplyArgs,
+    #[command(flatten)]
+    pub copy: CopyArgs,
+    #[command(flatten)]
+    pub manifest: ManifestArgs,
+    /// Overwrite copies in `target/patch` that were edited since they were
+    /// patched, and write previewed patches, without asking
+    #[arg(short, long, global = true, visible_alias = "no-confirm")]
+    pub yes: bool,
+    /// Only print warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Also print the git and patch commands that are run and their output,
+    /// `-vv` makes cargo verbose too
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Path to the `Cargo.toml` of the workspace to patch, or of one of its
+    /// members, instead of the one found from the current directory
+    #[arg(long, global = true, value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+    /// Read and write the patches in this folder instead of `patches/`,
+    /// relative to the workspace root
+    #[arg(long, global = true, value_name = "PATH")]
+    pub patch_dir: Option<PathBuf>,
+    /// Copy and patch the crates in this folder instead of `target/patch`,
+    /// relative to the workspace root, e.g. a `vendor` folder to commit
+    #[arg(long, global = true, value_name = "PATH")]
+    pub target_dir: Option<PathBuf>,
+    /// `json` prints a report of what happened to every crate on stdout when
+    /// applying or creating patches, and the log on stderr; `github` prints
+    /// only the warnings and errors, as GitHub Actions annotations
+    #[arg(long, value_enum, default_value = "lines")]
+    pub format: RunFormat,
+    /// Print the effective settings and where each one comes from, then exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// Print the disk space used by each crate in `target/patch` and exit
+    #[arg(long)]
+    pub report_size: bool,
+    /// Print the resolved version of a crate and exit
+    #[arg(long, value_name = "CRATE")]
+    pub dump_resolved_version: Option<String>,
+    /// Set by `apply <patch file>`: also patch crates missing from the patch
+    /// metadata, with a warning
+    #[arg(skip)]
+    pub unlisted: bool,
+    /// Remove the copy of this crate from `target/patch` and apply its patches
+    /// again, leaving the other crates untouched, may be repeated
+    #[arg(long, value_name = "CRATE", conflicts_with_all = ["crates", "check", "reverse"])]
+    pub clean: Vec<String>,
+}
+
+/// How the dependencies are resolved and where their sources are read from,
+/// for the commands that resolve the workspace.
+#[derive(Args, Debug, Clone)]
+pub struct ResolveArgs {
+    /// Resolve without accessing the network, like `net.offline` in cargo config
+    #[arg(long)]
+    pub offline: bool,
+    /// Fetch git dependencies with the `git` executable, like `net.git-fetch-with-cli`
+    #[arg(long)]
+    pub git_fetch_with_cli: bool,
+    /// Give up on a network request that transfers nothing for this many
+    /// seconds, like `http.timeout` in cargo config
+    #[arg(long, value_name = "SECS")]
+    pub network_timeout: Option<u64>,
+    /// Retry network requests that fail spuriously this many times with
+    /// backoff, like `net.retry` in cargo config
+    #[arg(long, value_name = "N")]
+    pub network_retries: Option<u32>,
+    /// Require the resolved crates and their sources to be the ones in `Cargo.lock`
+    #[arg(long)]
+    pub locked: bool,
+    /// Resolve from this lockfile instead of the `Cargo.lock` of the
+    /// workspace, relative to the workspace root, e.g. one generated in CI
+    #[arg(long, value_name = "PATH")]
+    pub lockfile: Option<PathBuf>,
+    /// Copy and diff registry and git crates from their extraction in this
+    /// folder, relative to the workspace root, instead of the cargo cache
+    #[arg(long, value_name = "PATH")]
+    pub pristine_dir: Option<PathBuf>,
+    /// Extract the crates missing from `--pristine-dir` from the cargo cache
+    #[arg(long, requires = "pristine_dir")]
+    pub populate_pristine_dir: bool,
+    /// Resolve the features of dev-dependencies and keep them with `--target`;
+    /// they are patched without it too
+    #[arg(long, visible_alias = "include-dev")]
+    pub dev: bool,
+    /// Resolve with all features of the workspace members enabled
+    #[arg(long)]
+    pub all_features: bool,
+    /// Resolve with only these features of the workspace members enabled
+    #[arg(long, value_delimiter = ',', value_name = "FEATURES")]
+    pub features: Vec<String>,
+    /// Resolve without the default features of the workspace members
+    #[arg(long)]
+    pub no_default_features: bool,
+    /// Only copy crates that are dependencies on this target, may be repeated
+    #[arg(long = "target", value_name = "TRIPLE")]
+    pub targets: Vec<String>,
+    /// Leave these crates of the patch metadata out, by name, spec or glob
+    /// pattern, after its patterns are expanded, like `exclude` in the patch
+    /// metadata
+    #[arg(long, value_delimiter = ',', value_name = "CRATE")]
+    pub exclude: Vec<String>,
+}
+
+/// Shared by the commands that apply and create patches.
+#[derive(Args, Debug, Clone)]
+pub struct RunArgs {
+    /// Fail on patch files that are not named `<crate>+<version>.patch` or
+    /// hold no diff, instead of skipping them with a warning, and on created
+    /// patches with changes that only touch whitespace
+    #[arg(long)]
+    pub strict: bool,
+    /// Create this many patches, or copy this many crates when applying, at
+    /// the same time, by default one per CPU
+    #[arg(short, long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: Option<usize>,
+    /// Don't warn about patches that change the `Cargo.toml` of their crate
+    #[arg(long)]
+    pub allow_manifest_changes: bool,
+}
+
+/// How the copies of crates are diffed into patches, for `create`, `resolve`
+/// and `rebase`.
+#[derive(Args, Debug, Clone)]
+pub struct DiffArgs {
+    /// Only diff files with these extensions when creating a patch, e.g. `rs,toml`
+    #[arg(long, value_delimiter = ',', value_name = "EXT")]
+    pub include_ext: Vec<String>,
+    /// Keep the absolute paths of this machine in created patches instead of
+    /// replacing them with placeholders
+    #[arg(long)]
+    pub no_sanitize: bool,
+    /// Lines of context around each change when creating a patch
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(0..=100))]
+    pub context_lines: u32,
+    /// Leave changes that only touch whitespace out of created patches, like
+    /// `git diff --ignore-all-space`
+    #[arg(long, conflicts_with = "diff_tool")]
+    pub ignore_whitespace: bool,
+    /// Create patches with this command instead of `git diff`, e.g. `diff -ruN`
+    #[arg(long, value_name = "COMMAND")]
+    pub diff_tool: Option<String>,
+    /// How patches are created
+    #[arg(long, value_enum, default_value = "git", conflicts_with = "diff_tool")]
+    pub backend: Backend,
+}
+
+/// The flags of `create`, and of running with crates.
+#[derive(Args, Debug, Clone)]
+pub struct CreateArgs {
+    /// Name the created patches after this version requirement, e.g. `^1.0.100`,
+    /// so that they apply to every matching version
+    #[arg(long, value_name = "REQ")]
+    pub range: Option<String>,
+    /// Create the changes on top of the patches of a crate as its next
+    /// numbered patch, `<crate>+<version>.<index>-<name>.patch`
+    #[arg(long, value_name = "NAME")]
+    pub name: Option<String>,
+    /// Keep the temporary folder a patch is created in, with its git
+    /// repository, to see why the diff came out as it did
+    #[arg(long)]
+    pub keep_tmp: bool,
+    /// Create the patch from an edited copy of the crate in this folder, e.g.
+    /// a clone of its repository, instead of its copy in `target/patch`
+    #[arg(long, value_name = "DIR")]
+    pub from: Option<PathBuf>,
+    /// With `--from`, only take the changes made since this commit of its
+    /// git repository, e.g. the tag of the resolved version
+    #[arg(long, value_name = "REV", requires = "from")]
+    pub from_rev: Option<String>,
+    /// Write the patch even when the copy has no changes, as an empty
+    /// placeholder that applying skips
+    #[arg(long)]
+    pub allow_empty: bool,
+    /// Choose the hunks that go into the created patches, like `git add -p`
+    #[arg(long, conflicts_with_all = ["diff_tool", "backend"])]
+    pub interactive: bool,
+    /// Apply each created patch to a pristine copy of its crate and fail with
+    /// the files that then differ from the edited copy
+    #[arg(long, conflicts_with = "interactive")]
+    pub verify: bool,
+    /// Write the created patches gzip compressed as `<crate>+<version>.patch.gz`
+    #[arg(long)]
+    pub compress: bool,
+    /// Write the created patches into crate folders, as
+    /// `<crate>/<version>/<crate>.patch`, instead of flat file names
+    #[arg(long)]
+    pub nested: bool,
+    /// Why the crate is patched, written above the diff of the created patch
+    /// in place of the `reason` in the patch metadata
+    #[arg(short, long, value_name = "WHY")]
+    pub message: Option<String>,
+    /// Keep running and create the patches again whenever their copies in
+    /// `target/patch` change, until stopped with Ctrl-C
+    #[arg(long, conflicts_with_all = ["check", "interactive"])]
+    pub watch: bool,
+    /// Print every created patch in color before it is written and, on a
+    /// terminal, ask whether to write it
+    #[arg(long, conflicts_with_all = ["check", "watch"])]
+    pub preview: bool,
+    /// Compare the created patches with the committed ones instead of writing
+    /// them; without crates, check that every patch applies to its resolved crate
+    #[arg(long)]
+    pub check: bool,
+}
+
+/// How a patch is applied, for `apply` and `rebase`.
+#[derive(Args, Debug, Clone)]
+pub struct PatchingArgs {
+    /// When a patch doesn't apply strictly, retry ignoring whitespace, then
+    /// with a three-way merge, and warn about the options it needed
+    #[arg(long, overrides_with = "no_escalate")]
+    pub escalate: bool,
+    /// Don't retry patches with looser options, overriding `--escalate`
+    #[arg(long, overrides_with = "escalate")]
+    pub no_escalate: bool,
+    /// With `--escalate`, finally retry with GNU `patch --fuzz <N>`, which
+    /// lets up to N lines of context around each hunk differ
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=10))]
+    pub fuzz: Option<u32>,
+    /// Remove N leading path components from the paths in patches, like
+    /// `git apply -p<N>`. Without it, a patch that fails at `-p1` is retried
+    /// at `-p0` and `-p2`
+    #[arg(long, value_name = "N")]
+    pub strip: Option<usize>,
+    /// Fail on any deviation: exact versions and revisions, verified sources,
+    /// no whitespace fuzz and patched crates matching their recorded hashes
+    #[arg(long)]
+    pub release: bool,
+    /// Convert the line endings of patched files to the ones each patch expects
+    #[arg(long)]
+    pub normalize_eol: bool,
+}
+
+/// The flags of `apply`, and of running without crates.
+#[derive(Args, Debug, Clone)]
+pub struct ApplyArgs {
+    /// Treat warnings raised while applying patches as errors
+    #[arg(long)]
+    pub deny_warnings: bool,
+    /// Fail before applying anything if a patch in `patches/` is for a crate
+    /// missing from the patch metadata, instead of warning and skipping it
+    #[arg(long)]
+    pub deny_orphans: bool,
+    /// Print a single table of the applied crates instead of a line per step
+    #[arg(long)]
+    pub summary_only: bool,
+    /// Patch a crate whose source doesn't match the checksum in `Cargo.lock`
+    /// with a warning, instead of failing; ignored with `--release` or `--locked`
+    #[arg(long)]
+    pub allow_checksum_mismatch: bool,
+    /// Record the hashes of the patched crates that `--release` checks
+    #[arg(long, conflicts_with = "release")]
+    pub record_hashes: bool,
+    /// After applying, print the files created, modified or deleted in `target/patch`
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "lines"
+    )]
+    pub changed_files: Option<ChangedFilesFormat>,
+    /// Report patches whose changes are already part of a newer resolved version
+    #[arg(long, conflicts_with = "release")]
+    pub detect_upstreamed: bool,
+    /// Fail when a patch is for another version than the resolved one, instead
+    /// of applying it anyway
+    #[arg(long)]
+    pub strict_version: bool,
+    /// Leave a crate unpatched when its patch fails to apply, instead of
+    /// keeping its previous copy
+    #[arg(long, conflicts_with = "release")]
+    pub continue_on_error: bool,
+    /// Stop at the first patch that fails to apply, instead of patching the
+    /// other crates before failing
+    #[arg(long, conflicts_with = "continue_on_error")]
+    pub fail_fast: bool,
+    /// Report which crates would be copied, patched and skipped, without
+    /// changing any file
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Undo the patches in the copies in `target/patch` instead of applying them
+    #[arg(long)]
+    pub reverse: bool,
+    /// Check that every applied patch gives the same result on its own as
+    /// along with the others
+    #[arg(long)]
+    pub check_independence: bool,
+    /// Apply each patch to a copy of only the files it touches, then fill in
+    /// the rest of the crate with copies that share their data with the
+    /// source on file systems that support it
+    #[arg(long, alias = "link")]
+    pub sparse_apply: bool,
+    /// Apply the patches in place to the crates in this `cargo vendor` output,
+    /// relative to the workspace root, updating their `.cargo-checksum.json`,
+    /// instead of to copies in `target/patch`
+    #[arg(long, value_name = "DIR", conflicts_with = "sparse_apply")]
+    pub apply_to_vendor: Option<PathBuf>,
+    /// Only apply the patches of crates in this group, may be repeated
+    #[arg(long = "group", value_name = "GROUP")]
+    pub groups: Vec<String>,
+    /// Apply into `target/patch/<triple>` for each of the configured targets
+    #[arg(long, conflicts_with_all = ["write_manifest", "emit_patch_section"])]
+    pub per_target_layout: bool,
+    /// Print the `[patch]` sections for the copied crates to paste into the
+    /// root `Cargo.toml`
+    #[arg(long)]
+    pub emit_patch_section: bool,
+}
+
+/// How crates are copied to `target/patch`, for `apply`, `extract` and `init`.
+#[derive(Args, Debug, Clone)]
+pub struct CopyArgs {
+    #[arg(short, long)]
+    pub force: bool,
+    /// Print the `[patch]` paths of copied crates relative to this directory
+    #[arg(long, value_name = "DIR", conflicts_with = "absolute")]
+    pub relative_to: Option<PathBuf>,
+    /// Print the `[patch]` paths of copied crates as absolute paths
+    #[arg(long)]
+    pub absolute: bool,
+}
+
+/// How the `[patch]` entries of the copies are written, for `apply` and `init`.
+#[derive(Args, Debug, Clone)]
+pub struct ManifestArgs {
+    /// Add or update the `[patch]` entries of the copied crates in the root
+    /// `Cargo.toml`
+    #[arg(long)]
+    pub write_manifest: bool,
+    /// With `--write-manifest`, remove the entries into `target/patch` of
+    /// crates that are no longer in the patch metadata
+    #[arg(long, requires = "write_manifest")]
+    pub prune_manifest: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum ChangedFilesFormat {
+    /// One absolute path per line
+    Lines,
+    /// A JSON object with `created`, `modified` and `deleted` lists
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunFormat {
+    /// The log, one line per message
+    Lines,
+    /// A JSON report of every crate on stdout, the log on stderr
+    Json,
+    /// `::warning` and `::error` workflow commands instead of the log
+    Github,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// `git diff` in a temporary repository
+    Git,
+    /// A diff computed in process, without running any program
+    Native,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// One line per item
+    Lines,
+    /// A JSON array of objects
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Apply the patches in `patches/`, same as running without any crate, or
+    /// only those of the given crates
+    Apply {
+        /// Only (re)apply the patches of these crates, leaving the others
+        /// untouched, or only these `.patch` files or `https://` URLs, as
+        /// `<crate>=<url>` when the file name of the URL doesn't name its crate
+        crates: Vec<String>,
+        /// Apply the patches packed in a bundle instead of `patches/`
+        #[arg(long)]
+        bundle: Option<PathBuf>,
+        /// Also download patches from `http://` URLs
+        #[arg(long)]
+        allow_http: bool,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+        #[command(flatten)]
+        run: RunArgs,
+        #[command(flatten)]
+        patching: PatchingArgs,
+        #[command(flatten)]
+        apply: ApplyArgs,
+        #[command(flatten)]
+        copy: CopyArgs,
+        #[command(flatten)]
+        manifest: ManifestArgs,
+    },
+    /// Create the patches of these crates from their copies in
+    /// `target/patch`, same as running with the crates
+    Create {
+        #[arg(value_name = "CRATE", required = true)]
+        crates: Vec<String>,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+        #[command(flatten)]
+        run: RunArgs,
+        #[command(flatten)]
+        diff: DiffArgs,
+        #[command(flatten)]
+        create: CreateArgs,
+    },
+    /// Pack every patch in `patches/` into a single bundle file
+    Bundle {
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Regenerate the patch of a crate after resolving the conflicts in its copy
+    Resolve {
+        #[arg(value_name = "CRATE")]
+        name: String,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+        #[command(flatten)]
+        run: RunArgs,
+        #[command(flatten)]
+        diff: DiffArgs,
+    },
+    /// Move the patch made for an older version of a crate onto the resolved
+    /// version, merging its changes into a fresh copy
+    Rebase {
+        #[arg(value_name = "CRATE")]
+        name: String,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+        #[command(flatten)]
+        run: RunArgs,
+        #[command(flatten)]
+        diff: DiffArgs,
+        #[command(flatten)]
+        patching: PatchingArgs,
+    },
+    /// List the patches with their status and the crates in the patch metadata
+    List {
+        #[arg(long, value_enum, default_value = "lines")]
+        format: OutputFormat,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+    },
+    /// List every resolved dependency that can be patched
+    Deps {
+        /// Only list crates whose name contains this
+        filter: Option<String>,
+        #[arg(long, value_enum, default_value = "lines")]
+        format: OutputFormat,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+    },
+    /// Print the committed patch of a crate, colored on a terminal
+    Show {
+        #[arg(value_name = "CRATE")]
+        name: String,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+    },
+    /// Copy crates to `target/patch` without applying any patch, to start
+    /// editing them
+    Extract {
+        #[arg(value_name = "CRATE", required = true)]
+        crates: Vec<String>,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+        #[command(flatten)]
+        copy: CopyArgs,
+    },
+    /// Add crates to the patch metadata of the root `Cargo.toml` and copy them
+    /// to `target/patch`, to start patching them
+    Init {
+        #[arg(value_name = "CRATE", required = true)]
+        crates: Vec<String>,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+        #[command(flatten)]
+        copy: CopyArgs,
+        #[command(flatten)]
+        manifest: ManifestArgs,
+    },
+    /// Tell for each patched crate whether its copy in `target/patch` matches
+    /// its patches
+    Status {
+        /// Only these crates instead of all crates in the patch metadata
+        crates: Vec<String>,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+    },
+    /// Print what applying would do for each crate without doing anything:
+    /// its resolved version, its patch and its copy in `target/patch`
+    Plan {
+        /// Only these crates instead of all crates in the patch metadata
+        crates: Vec<String>,
+        #[arg(long, value_enum, default_value = "lines")]
+        format: OutputFormat,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+    },
+    /// Apply a patch to a crate extracted anywhere, without cargo
+    ApplyTo {
+        dir: PathBuf,
+        #[arg(long, value_name = "FILE")]
+        patch: PathBuf,
+        /// Remove N leading path components from the paths in the patch, like
+        /// `git apply -p<N>`, instead of trying `-p1`, `-p0` and `-p2`
+        #[arg(long, value_name = "N")]
+        strip: Option<usize>,
+    },
+    /// Rewrite the patches in `patches/` into their canonical form
+    Normalize,
+    /// Write the patches in the layout of the npm `patch-package` tool
+    ExportNpm {
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Delete the patches of crates, their copies in `target/patch` and the
+    /// `[patch]` entries pointing at the copies
+    Remove {
+        #[arg(value_name = "CRATE", required = true)]
+        crates: Vec<String>,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+    },
+    /// Remove the copied crates from `target/patch`
+    Prune {
+        /// Keep the crates that are still listed in the patch metadata
+        #[arg(long)]
+        keep_applied: bool,
+        /// With `--keep-applied`, list the copies that would be removed
+        /// without removing them
+        #[arg(long, requires = "keep_applied")]
+        dry_run: bool,
+        #[command(flatten)]
+        resolving: ResolveArgs,
+    },
+    /// Print the script that completes the subcommands, flags and crate names
+    /// of cargo-patch-crate in this shell
+    Completions {
+        #[arg(value_enum, required_unless_present = "list_crates")]
+        shell: Option<Shell>,
+        /// Print the crates in the patch metadata, for the scripts to complete
+        #[arg(long = "crates", hide = true)]
+        list_crates: bool,
+    },
+}
+
+impl Cli {
+    /// Parses `argv`, taking the flags given after the subcommand as the
+    /// flags of the run, where the commands read them.
+    pub fn try_parse_args<I, T>(argv: I) -> Result<Cli, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let cli = Cli::try_parse_from(argv)?;
+        let Some(command) = cli.command.clone() else {
+            return Result::Ok(cli);
+        };
+        Result::Ok(match command {
+            Command::Apply {
+                resolving,
+                run,
+                patching,
+                apply,
+                copy,
+                manifest,
+                ..
+            } => Cli {
+                resolving,
+                run,
+                patching,
+                apply,
+                copy,
+                manifest,
+                ..cli
+            },
+            Command::Create {
+                resolving,
+                run,
+                diff,
+                create,
+                ..
+            } => Cli {
+                resolving,
+                run,
+                diff,
+                create,
+                ..cli
+            },
+            Command::Resolve {
+                resolving,
+                run,
+                diff,
+                ..
+            } => Cli {
+                resolving,
+                run,
+                diff,
+                ..cli
+            },
+            Command::Rebase {
+                resolving,
+                run,
+                diff,
+                patching,
+                ..
+            } => Cli {
+                resolving,
+                run,
+                diff,
+                patching,
+                ..cli
+            },
+            Command::Extract {
+                resolving, copy, ..
+            } => Cli {
+                resolving,
+                copy,
+                ..cli
+            },
+            Command::Init {
+                resolving,
+                copy,
+                manifest,
+                ..
+            } => Cli {
+                resolving,
+                copy,
+                manifest,
+                ..cli
+            },
+            Command::Prune {
+                dry_run, resolving, ..
+            } => {
+                let mut cli = Cli { resolving, ..cli };
+                cli.apply.dry_run = dry_run;
+                cli
+            }
+            Command::List { resolving, .. }
+            | Command::Deps { resolving, .. }
+            | Command::Show { resolving, .. }
+            | Command::Status { resolving, .. }
+            | Command::Plan { resolving, .. }
+            | Command::Remove { resolving, .. } => Cli { resolving, ..cli },
+            Command::Bundle { .. }
+            | Command::ApplyTo { .. }
+            | Command::Normalize
+            | Command::ExportNpm { .. }
+            | Command::Completions { .. } => cli,
+        })
+    }
+}
+
+pub fn cli_args() -> Vec<OsString> {
+    // cargo invokes us as `cargo-patch-crate patch-crate <args>`
+    std::env::args_os()
+        .enumerate()
+        .filter(|(i, arg)| !(*i == 1 && arg == "patch-crate"))
+        .map(|(_, arg)| arg)
+        .collect()
+}
+
+/// The flag of `cmd` that `token` gives, `--long[=value]` or `-s[value]`,
+/// and whether the token holds its value too.
+fn find_flag<'c>(cmd: &'c clap::Command, token: &str) -> Option<(&'c clap::Arg, bool)> {
+    if let Some(long) = token.strip_prefix("--") {
+        let (name, inline) = match long.split_once('=') {
+            Some((name, _)) => (name, true),
+            None => (long, false),
+        };
+        cmd.get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(name)
+                    || arg
+                        .get_all_aliases()
+                        .is_some_and(|aliases| aliases.contains(&name))
+            })
+            .map(|arg| (arg, inline))
+    } else {
+        let mut chars = token.strip_prefix('-')?.chars();
+        let short = chars.next()?;
+        cmd.get_arguments()
+            .find(|arg| {
+                arg.get_short() == Some(short)
+                    || arg
+                        .get_all_short_aliases()
+                        .is_some_and(|aliases| aliases.contains(&short))
+            })
+            .map(|arg| (arg, chars.next().is_some()))
+    }
+}
+
+/// Whether the flag `arg` takes the next argument as its value, unless
+/// given with it.
+fn takes_value(arg: &clap::Arg) -> bool {
+    arg.get_num_args()
+        .is_some_and(|range| range.min_values() > 0)
+}
+
+/// Where the arguments of the command `argv` runs start, after its
+/// subcommand if any, and that command. Only global flags can come before a
+/// subcommand.
+fn command_start<'c>(cli: &'c clap::Command, argv: &[OsString]) -> (usize, &'c clap::Command) {
+    let mut i = 1;
+    while let Some(token) = argv.get(i).and_then(|token| token.to_str()) {
+        if token == "--" {
+            break;
+        }
+        if token.starts_with('-') {
+            if let Some((arg, false)) = find_flag(cli, token) {
+                if takes_value(arg) {
+                    i += 1;
+                }
+            }
+            i += 1;
+            continue;
+        }
+        if let Some(sub) = cli.find_subcommand(token) {
+            return (i + 1, sub);
+        }
+        break;
+    }
+    (1, cli)
+}
+
+/// Re-parses the command line with the `default_args` of the root manifest's
+/// patch metadata inserted after the subcommand, so that arguments given
+/// explicitly win. The flags the command doesn't take are left out, with
+/// their values.
+pub fn with_default_args(args: &Cli, workspace: &Workspace<'_>) -> Result<Cli> {
+    let default_args = workspace.root_patch_metadata_strings("default_args")?;
+    if default_args.is_empty() {
+        return Ok(args.clone());
+    }
+    let mut cli = Cli::command();
+    // hands the global flags down to the subcommands
+    cli.build();
+    let argv = cli_args();
+    let (start, command) = command_start(&cli, &argv);
+    let mut taken = Vec::new();
+    let mut default_args = default_args.into_iter();
+    while let Some(token) = default_args.next() {
+        if !token.starts_with('-') || token == "-" {
+            taken.push(token);
+            continue;
+        }
+        let taken_by_command = find_flag(command, &token);
+        // a flag of no command at all is left for clap to report
+        let Some((arg, inline)) = taken_by_command
+            .or_else(|| find_flag(&cli, &token))
+            .or_else(|| cli.get_subcommands().find_map(|sub| find_flag(sub, &token)))
+        else {
+            taken.push(token);
+            continue;
+        };
+        let value = if !inline && takes_value(arg) {
+            default_args.next()
+        } else {
+            None
+        };
+        if taken_by_command.is_some() {
+            taken.push(token);
+            taken.extend(value);
+        }
+    }
+    let argv = argv[..start]
+        .iter()
+        .cloned()
+        .chain(taken.into_iter().map(OsString::from))
+        .chain(argv[start..].iter().cloned());
+    Cli::try_parse_args(argv)
+        .map_err(|err| anyhow!("invalid `default_args` in patch metadata: {}", err))
+}
+
+/// Fails when a flag that only applies to creating patches is given while
+/// running without crates, which applies the patches; clap can't tell the
+/// two apart, as both take the flags of `apply` and `create`.
+pub fn check_create_flags(args: &Cli) -> Result<()> {
+    let creating = !args.crates.is_empty() && !args.apply.reverse && args.clean.is_empty();
+    if args.command.is_some() || creating {
+        return Ok(());
+    }
+    let flags = [
+        ("--range", args.create.range.is_some()),
+        ("--name", args.create.name.is_some()),
+        ("--keep-tmp", args.create.keep_tmp),
+        ("--from", args.create.from.is_some()),
+        ("--allow-empty", args.create.allow_empty),
+        ("--interactive", args.create.interactive),
+        ("--verify", args.create.verify),
+        ("--compress", args.create.compress),
+        ("--nested", args.create.nested),
+        ("--message", args.create.message.is_some()),
+        ("--watch", args.create.watch),
+        ("--preview", args.create.preview),
+    ];
+    match flags.iter().find(|(_, given)| *given) {
+        Some((flag, _)) => Err(anyhow!(
+            "`{}` only applies to creating patches, as in `cargo patch-crate create <CRATE>... {}`",
+            flag,
+            flag
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Prints every setting along with where it came from. `cli` holds the
+/// arguments given on the command line only, `args` includes `default_args`.
+pub fn print_config(cli: &Cli, args: &Cli, workspace: &PatchWorkspace<'_>) -> Result<()> {
+    fn setting(name: &str, value: impl std::fmt::Display, source: &str) {
+        println!("{:<20} {:<40} ({})", name, value.to_string(), source);
+    }
+    let source = |given: bool, defaulted: bool| {
+        if given {
+            "flag"
+        } else if defaulted {
+            "metadata default_args"
+        } else {
+            "default"
+        }
+    };
+    let flag = |name: &str, given: bool, value: bool| {
+        setting(name, value, source(given, value && !given));
+    };
+
+    let default_args = workspace.root_patch_metadata_strings("default_args")?;
+    let metadata_include_ext = workspace.root_patch_metadata_strings("include_ext")?;
+
+    setting(
+        "manifest",
+        workspace.root_manifest().display(),
+        if cli.manifest_path.is_some() {
+            "flag"
+        } else {
+            "discovered"
+        },
+    );
+    setting(
+        "patches_dir",
+        workspace.patches_folder().display(),
+        if cli.patch_dir.is_none()
+            && std::env::var_os(PATCH_DIR_ENV).is_some_and(|dir| !dir.is_empty())
+        {
+            "environment PATCH_CRATE_DIR"
+        } else {
+            source(cli.patch_dir.is_some(), args.patch_dir.is_some())
+        },
+    );
+    setting(
+        "target_dir",
+        workspace.patch_target_folder().display(),
+        source(cli.target_dir.is_some(), args.target_dir.is_some()),
+    );
+    match &args.resolving.lockfile {
+        Some(lockfile) => setting(
+            "lockfile",
+            workspace.root().join(lockfile).display(),
+            source(cli.resolving.lockfile.is_some(), true),
+        ),
+        None => setting(
+            "lockfile",
+            workspace.root().join("Cargo.lock").display(),
+            "default",
+        ),
+    }
+    match &args.resolving.pristine_dir {
+        Some(pristine_dir) => setting(
+            "pristine_dir",
+            workspace.root().join(pristine_dir).display(),
+            source(cli.resolving.pristine_dir.is_some(), true),
+        ),
+        None => setting("pristine_dir", "cargo cache", "default"),
+    }
+    flag(
+        "populate_pristine_dir",
+        cli.resolving.populate_pristine_dir,
+        args.resolving.populate_pristine_dir,
+    );
+    setting("patch_ext", PATCH_EXT, "default");
+    if !args.diff.include_ext.is_empty() {
+        let given = !cli.diff.include_ext.is_empty();
+        setting(
+            "include_ext",
+            args.diff.include_ext.join(","),
+            source(given, !given),
+        );
+    } else if !metadata_include_ext.is_empty() {
+        setting(
+            "include_ext",
+            metadata_include_ext.join(","),
+            "metadata include_ext",
+        );
+    } else {
+        setting("include_ext", "*", "default");
+    }
+    let cargo_files = cargo_files(workspace)?;
+    setting(
+        "cargo_files",
+        cargo_files.join(","),
+        if cargo_files == CARGO_FILES {
+            "default"
+        } else {
+            "metadata cargo_files"
+        },
+    );
+    let junk_files = junk_files(workspace)?;
+    setting(
+        "junk_files",
+        junk_files.join(","),
+        if junk_files == JUNK_FILES {
+            "default"
+        } else {
+            "metadata junk_files"
+        },
+    );
+    match &args.diff.diff_tool {
+        Some(tool) => setting(
+            "diff_tool",
+            tool,
+            source(cli.diff.diff_tool.is_some(), true),
+        ),
+        None => setting("diff_tool", "git diff", "default"),
+    }
+    flag("force", cli.copy.force, args.copy.force);
+    flag("yes", cli.yes, args.yes);
+    flag(
+        "deny_warnings",
+        cli.apply.deny_warnings,
+        args.apply.deny_warnings,
+    );
+    flag(
+        "deny_orphans",
+        cli.apply.deny_orphans,
+        args.apply.deny_orphans,
+    );
+    flag(
+        "detect_upstreamed",
+        cli.apply.detect_upstreamed,
+        args.apply.detect_upstreamed,
+    );
+    flag(
+        "strict_version",
+        cli.apply.strict_version,
+        args.apply.strict_version,
+    );
+    flag("strict", cli.run.strict, args.run.strict);
+    flag(
+        "summary_only",
+        cli.apply.summary_only,
+        args.apply.summary_only,
+    );
+    flag("quiet", cli.quiet, args.quiet);
+    flag(
+        "normalize_eol",
+        cli.patching.normalize_eol,
+        args.patching.normalize_eol,
+    );
+    flag("no_sanitize", cli.diff.no_sanitize, args.diff.no_sanitize);
+    flag(
+        "sparse_apply",
+        cli.apply.sparse_apply,
+        args.apply.sparse_apply,
+    );
+    flag("escalate", cli.patching.escalate, args.patching.escalate);
+    if let Some(fuzz) = args.patching.fuzz {
+        setting("fuzz", fuzz, source(cli.patching.fuzz.is_some(), true));
+    }
+    if let Some(strip) = args.patching.strip {
+        setting("strip", strip, source(cli.patching.strip.is_some(), true));
+    }
+    flag(
+        "continue_on_error",
+        cli.apply.continue_on_error,
+        args.apply.continue_on_error,
+    );
+    flag("fail_fast", cli.apply.fail_fast, args.apply.fail_fast);
+    flag("offline", cli.resolving.offline, args.resolving.offline);
+    flag(
+        "allow_checksum_mismatch",
+        cli.apply.allow_checksum_mismatch,
+        args.apply.allow_checksum_mismatch,
+    );
+    flag(
+        "git_fetch_with_cli",
+        cli.resolving.git_fetch_with_cli,
+        args.resolving.git_fetch_with_cli,
+    );
+    flag(
+        "per_target_layout",
+        cli.apply.per_target_layout,
+        args.apply.per_target_layout,
+    );
+    let resolve = ResolveOptions::new(args, workspace)?;
+    let resolve_metadata = workspace
+        .root_patch_metadata()
+        .into_iter()
+        .find_map(|m| m.get("resolve"))
+        .and_then(|r| r.as_table());
+    let resolve_source = |given: bool, key: &str| {
+        if given {
+            "flag"
+        } else if resolve_metadata.is_some_and(|r| r.contains_key(key)) {
+            "metadata resolve"
+        } else {
+            "default"
+        }
+    };
+    setting(
+        "resolve.dev",
+        resolve.dev,
+        resolve_source(args.resolving.dev, "dev"),
+    );
+    setting(
+        "resolve.all_features",
+        resolve.all_features,
+        resolve_source(args.resolving.all_features, "all_features"),
+    );
+    setting(
+        "resolve.features",
+        resolve.features.join(","),
+        resolve_source(!args.resolving.features.is_empty(), "features"),
+    );
+    setting(
+        "resolve.default_features",
+        resolve.default_features,
+        resolve_source(args.resolving.no_default_features, "default_features"),
+    );
+    setting(
+        "resolve.targets",
+        resolve.targets.join(","),
+        resolve_source(!args.resolving.targets.is_empty(), "targets"),
+    );
+    match (&args.copy.relative_to, args.copy.absolute) {
+        (Some(dir), _) => setting(
+            "override_paths",
+            format!("relative to {}", dir.display()),
+            source(cli.copy.relative_to.is_some(), true),
+        ),
+        (None, true) => setting(
+            "override_paths",
+            "absolute",
+            source(cli.copy.absolute, true),
+        ),
+        (None, false) => setting("override_paths", "relative to workspace root", "default"),
+    }
+    setting(
+        "default_args",
+        format!("{:?}", default_args),
+        if default_args.is_empty() {
+            "default"
+        } else {
+            "metadata"
+        },
+    );
+    Ok(())
+}
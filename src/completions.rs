@@ -0,0 +1,203 @@
+//! The scripts that complete the subcommands, flags and crate names of
+//! cargo-patch-crate in a shell.
+
+use clap::CommandFactory;
+
+use super::cli::{Cli, Shell};
+
+const BIN: &str = "cargo-patch-crate";
+
+/// The command that prints the crates to complete.
+const LIST_CRATES: &str = "cargo patch-crate completions --crates";
+
+/// The flags of `cmd` as they are typed, `--long` and `-s`.
+fn flags(cmd: &clap::Command) -> Vec<String> {
+    let mut flags = Vec::new();
+    for arg in cmd.get_arguments() {
+        if arg.is_hide_set() || arg.is_positional() {
+            continue;
+        }
+        let longs = arg.get_long_and_visible_aliases().unwrap_or_default();
+        flags.extend(longs.into_iter().map(|long| format!("--{}", long)));
+        let shorts = arg.get_short_and_visible_aliases().unwrap_or_default();
+        flags.extend(shorts.into_iter().map(|short| format!("-{}", short)));
+    }
+    flags
+}
+
+/// The names of a subcommand and the flags it takes.
+type SubcommandWords = (Vec<String>, Vec<String>);
+
+/// The names of each subcommand with the flags it takes, global ones
+/// included, and the words that can follow `cargo patch-crate` itself.
+fn words() -> (Vec<SubcommandWords>, Vec<String>) {
+    let mut cli = Cli::command();
+    // adds `--help` and `--version` and hands the global flags down
+    cli.build();
+    let mut top = flags(&cli);
+    let mut subcommands = Vec::new();
+    for sub in cli.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let names = std::iter::once(sub.get_name())
+            .chain(sub.get_visible_aliases())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        top.extend(names.iter().cloned());
+        subcommands.push((names, flags(sub)));
+    }
+    (subcommands, top)
+}
+
+/// The completion script for `shell`. It completes the subcommands, the
+/// flags of the subcommand typed and the crates in the patch metadata of
+/// the workspace, which it asks `cargo patch-crate` for, both for
+/// `cargo patch-crate` and `cargo-patch-crate`.
+pub fn script(shell: Shell) -> String {
+    let (subcommands, top) = words();
+    let function = BIN.replace('-', "_");
+    match shell {
+        Shell::Bash => {
+            let cases = subcommands
+                .iter()
+                .map(|(names, flags)| {
+                    format!(
+                        "        {}) words=\"{}\" ;;\n",
+                        names.join("|"),
+                        flags.join(" ")
+                    )
+                })
+                .collect::<String>();
+            format!(
+                r#"_{function}() {{
+local cur="${{COMP_WORDS[COMP_CWORD]}}" sub="" words i
+for ((i = 1; i < COMP_CWORD; i++)); do
+    case "${{COMP_WORDS[i]}}" in
+        patch-crate | -*) ;;
+        *) sub="${{COMP_WORDS[i]}}"; break ;;
+    esac
+done
+case "$sub" in
+{cases}        *) words="{top}" ;;
+esac
+if [[ $cur != -* ]]; then
+    words="$words $({LIST_CRATES} 2>/dev/null)"
+fi
+COMPREPLY=($(compgen -W "$words" -- "$cur"))
+}}
+complete -F _{function} {BIN}
+"#,
+                top = top.join(" ")
+            )
+        }
+        Shell::Zsh => {
+            let cases = subcommands
+                .iter()
+                .map(|(names, flags)| {
+                    format!(
+                        "        {}) candidates=({}) ;;\n",
+                        names.join("|"),
+                        flags.join(" ")
+                    )
+                })
+                .collect::<String>();
+            format!(
+                r#"#compdef {BIN}
+_{function}() {{
+local sub="" word
+local -a candidates
+for word in ${{words[2,CURRENT-1]}}; do
+    [[ $word == -* || $word == patch-crate ]] && continue
+    sub=$word
+    break
+done
+case $sub in
+{cases}        *) candidates=({top}) ;;
+esac
+if [[ $PREFIX != -* ]]; then
+    candidates+=(${{(f)"$({LIST_CRATES} 2>/dev/null)"}})
+fi
+compadd -a candidates
+}}
+compdef _{function} {BIN}
+"#,
+                top = top.join(" ")
+            )
+        }
+        Shell::Fish => {
+            let all = subcommands
+                .iter()
+                .flat_map(|(names, _)| names.iter().cloned())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut script = format!("complete -c {BIN} -f\n");
+            for word in top.iter() {
+                let option = match word.strip_prefix("--") {
+                    Some(long) => format!("-l {}", long),
+                    None => match word.strip_prefix('-') {
+                        Some(short) => format!("-s {}", short),
+                        None => format!("-a {}", word),
+                    },
+                };
+                script.push_str(&format!(
+                    "complete -c {BIN} -n \"not __fish_seen_subcommand_from {all}\" {option}\n"
+                ));
+            }
+            for (names, flags) in subcommands.iter() {
+                for flag in flags.iter() {
+                    let option = match flag.strip_prefix("--") {
+                        Some(long) => format!("-l {}", long),
+                        None => format!("-s {}", flag.trim_start_matches('-')),
+                    };
+                    script.push_str(&format!(
+                        "complete -c {BIN} -n \"__fish_seen_subcommand_from {}\" {option}\n",
+                        names.join(" ")
+                    ));
+                }
+            }
+            script.push_str(&format!(
+                "complete -c {BIN} -a \"({LIST_CRATES} 2>/dev/null)\"\n"
+            ));
+            script
+        }
+        Shell::Powershell => {
+            let quote = |words: &[String]| {
+                words
+                    .iter()
+                    .map(|word| format!("'{}'", word))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let cases = subcommands
+                .iter()
+                .map(|(names, flags)| {
+                    format!(
+                        "        {{ $_ -in @({}) }} {{ @({}) }}\n",
+                        quote(names),
+                        quote(flags)
+                    )
+                })
+                .collect::<String>();
+            format!(
+                r#"Register-ArgumentCompleter -Native -CommandName {BIN} -ScriptBlock {{
+param($wordToComplete, $commandAst, $cursorPosition)
+$sub = ''
+foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {{
+    $word = $element.ToString()
+    if ($word -eq $wordToComplete) {{ break }}
+    if (-not $word.StartsWith('-') -and $word -ne 'patch-crate') {{ $sub = $word; break }}
+}}
+$candidates = switch ($sub) {{
+{cases}        default {{ @({top}) }}
+}}
+if (-not $wordToComplete.StartsWith('-')) {{
+    $candidates += @({LIST_CRATES} 2>$null)
+}}
+$candidates | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+    [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+}}
+}}
+"#,
+                top = quote(&top)
+            )
+        }
+    }
+}
@@ -0,0 +1,980 @@
+//! Creating the patches of crates from their edited copies in `target/patch`.
+
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use anyhow::{anyhow, Ok, Result};
+use cargo::core::{
+    package::{Package, PackageSet},
+    PackageId, Resolve, Workspace,
+};
+
+use super::{
+    case_collisions, case_only_renames, check_allowed_paths, clear_work_tree,
+    cli::{Backend, Cli},
+    copy_dir, dependency_path, diff_with_tool, existing_patch_file, find_patch_files, git,
+    is_changed_path_source, is_range_patch, list_files,
+    lock::{tree_hash, update_patch_lock},
+    native, nested_layout, nested_patch_file, numbered_patch_files, patch, patch_file_stem,
+    patch_header, patch_header_field, patch_stem, preview_patch, query_dependency, range_file_stem,
+    record_patches, reformatted_files, report, seal_copy, split_patch_stem, tested_versions,
+    touches_manifest, warn_manifest_changes, CrateEntry, PackageExt, PatchWorkspace, WorkspaceExt,
+    CARGO_FILES, DEFAULT_REFORMAT_THRESHOLD, GZ_EXT, JUNK_FILES, PATCHIGNORE_FILE, PATCH_EXT,
+    REASON_HEADER, WATCH_INTERVAL,
+};
+
+/// Stages the edited copy of `pkg` in `from` for `--from` in the temporary
+/// folder, where its patch is created from like from a copy in
+/// `target/patch`. With `--from-rev`, the pristine crate with the changes
+/// `from` made since that commit applied, otherwise `from` as it is, without
+/// its git repository and `target` folder.
+fn stage_from(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg: &Package,
+    from: &Path,
+) -> Result<PathBuf> {
+    let from = std::path::absolute(from)?;
+    let name = pkg.name();
+    let manifest = fs::read_to_string(from.join("Cargo.toml"))
+        .map_err(|err| anyhow!("{:?} is not a crate, it has no Cargo.toml: {}", from, err))?;
+    let package = manifest
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|t| t.get("package").and_then(|p| p.as_table()).cloned())
+        .unwrap_or_default();
+    let from_name = package.get("name").and_then(|v| v.as_str());
+    // the version of a workspace member may be `version.workspace = true`
+    let from_version = package.get("version").and_then(|v| v.as_str());
+    if from_name != Some(name.as_str()) {
+        warn!(
+            "crate: {}, {:?} holds crate {}, not {}.",
+            name,
+            from,
+            from_name.unwrap_or("?"),
+            name
+        );
+    } else if from_version.is_some_and(|version| version != pkg.version().to_string()) {
+        warn!(
+            "crate: {}, {:?} holds version {}, the patch is created against the resolved {}.",
+            name,
+            from,
+            from_version.unwrap_or_default(),
+            pkg.version()
+        );
+    }
+    let pristine = pkg.pristine_root(&workspace.config)?;
+    let staged = workspace
+        .patch_target_tmp_folder()
+        .join("from")
+        .join(pkg.slug()?);
+    if staged.exists() {
+        fs::remove_dir_all(&staged)?;
+    }
+    match &args.create.from_rev {
+        Some(rev) => {
+            copy_dir(&pristine, &staged)?;
+            let changes = staged.with_extension(PATCH_EXT);
+            git::diff_since(&from, rev, &changes)?;
+            if fs::metadata(&changes)?.len() == 0 {
+                fs::remove_file(changes)?;
+                return Ok(staged);
+            }
+            git::init(&staged)?;
+            git::apply(&staged, &changes).map_err(|err| {
+                anyhow!(
+                    "crate: {}, the changes in {:?} since {} don't apply to the resolved crate: {}",
+                    name,
+                    from,
+                    rev,
+                    err
+                )
+            })?;
+            git::destroy(&staged)?;
+            fs::remove_file(changes)?;
+        }
+        None => {
+            fs::create_dir_all(&staged)?;
+            for entry in fs::read_dir(&from)? {
+                let entry = entry?;
+                if entry.file_name() == ".git" || entry.file_name() == "target" {
+                    continue;
+                }
+                if entry.file_type()?.is_dir() {
+                    copy_dir(&entry.path(), &staged.join(entry.file_name()))?;
+                } else {
+                    fs::copy(entry.path(), staged.join(entry.file_name()))?;
+                }
+            }
+            // a clone has the manifest cargo normalized when publishing
+            if fs::read(pristine.join("Cargo.toml.orig")).ok() == Some(manifest.into_bytes()) {
+                fs::copy(pristine.join("Cargo.toml"), staged.join("Cargo.toml"))?;
+            }
+            let published = list_files(&pristine)?.into_iter().collect::<HashSet<_>>();
+            let added = list_files(&staged)?
+                .into_iter()
+                .filter(|file| !published.contains(file))
+                .count();
+            if added > 0 {
+                crate_info!(
+                    "crate: {}, {} file(s) of {:?} are not in the published crate and are added by the patch, pass `--from-rev` to only take the changes since a commit.",
+                    name,
+                    added,
+                    from
+                );
+            }
+        }
+    }
+    Ok(staged)
+}
+
+/// A crate to create the patch of, with everything the worker threads need
+/// gathered up front, as `Package` can't be shared between threads.
+struct CreateJob<'a> {
+    name: &'a str,
+    pkg_id: PackageId,
+    pkg_root: PathBuf,
+    slug: String,
+    patch_target_path: PathBuf,
+    patch_file: PathBuf,
+    /// Patches `patch_file` is created on top of
+    base: Vec<PathBuf>,
+    output: PathBuf,
+    entry: Option<CrateEntry<'a>>,
+}
+
+impl CreateJob<'_> {
+    fn report(&self, action: &str, status: &str, error: Option<String>) {
+        report::push(
+            &self.pkg_id.name(),
+            &self.pkg_id.version().to_string(),
+            action,
+            status,
+            Some(&self.patch_file),
+            error,
+        );
+    }
+}
+
+/// Settings shared by all crates of one create run.
+struct CreateContext<'a> {
+    args: &'a Cli,
+    diff_options: git::DiffOptions,
+    reformat_threshold: usize,
+    patches_folder: PathBuf,
+    patch_target_tmp_folder: PathBuf,
+    machine_paths: Vec<(String, &'static str)>,
+    /// The patterns of the `.patchignore` of the workspace root
+    patchignore: Vec<String>,
+    /// Paths relative to the crate root that are never part of a patch
+    cargo_files: Vec<String>,
+    /// Gitignore patterns of files that are never part of a patch
+    junk_files: Vec<String>,
+}
+
+/// The files cargo injects into crates, [`CARGO_FILES`] unless the patch
+/// metadata has its own `cargo_files`, which may be empty to keep them all.
+pub fn cargo_files(workspace: &Workspace<'_>) -> Result<Vec<String>> {
+    if workspace
+        .root_patch_metadata()
+        .iter()
+        .any(|metadata| metadata.contains_key("cargo_files"))
+    {
+        workspace.root_patch_metadata_strings("cargo_files")
+    } else {
+        Ok(CARGO_FILES.iter().map(|file| file.to_string()).collect())
+    }
+}
+
+/// The patterns of the files tools leave in copies, [`JUNK_FILES`] unless the
+/// patch metadata has its own `junk_files`, which may be empty to keep them
+/// all.
+pub fn junk_files(workspace: &Workspace<'_>) -> Result<Vec<String>> {
+    if workspace
+        .root_patch_metadata()
+        .iter()
+        .any(|metadata| metadata.contains_key("junk_files"))
+    {
+        workspace.root_patch_metadata_strings("junk_files")
+    } else {
+        Ok(JUNK_FILES.iter().map(|file| file.to_string()).collect())
+    }
+}
+
+/// Whether `path`, relative to the crate root with `/` separators, matches
+/// one of the gitignore `patterns`: one ending in `/` matches a folder and
+/// everything in it, one with another `/` is anchored to the crate root, and
+/// any other is matched against each file and folder name.
+pub fn is_ignored(path: &str, patterns: &[String]) -> bool {
+    let components = path.split('/').collect::<Vec<_>>();
+    patterns.iter().any(|pattern| {
+        let (pattern, dir) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern.as_str(), false),
+        };
+        let Result::Ok(glob) = glob::Pattern::new(pattern.trim_start_matches('/')) else {
+            return false;
+        };
+        // a folder is never the last component, which is the file
+        let candidates = if dir {
+            &components[..components.len().saturating_sub(1)]
+        } else {
+            &components[..]
+        };
+        if pattern.contains('/') {
+            (1..=candidates.len()).any(|n| glob.matches(&candidates[..n].join("/")))
+        } else {
+            candidates.iter().any(|name| glob.matches(name))
+        }
+    })
+}
+
+/// The patterns of the `.patchignore` in `dir`, if there is one, followed by
+/// one for the file itself, which is never part of a patch.
+fn patchignore_patterns(dir: &Path) -> Result<Vec<String>> {
+    let file = dir.join(PATCHIGNORE_FILE);
+    if !file.is_file() {
+        return Ok(Vec::new());
+    }
+    let mut patterns = fs::read_to_string(&file)?
+        .lines()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    patterns.push(format!("/{}", PATCHIGNORE_FILE));
+    Ok(patterns)
+}
+
+/// The absolute paths of this machine that created patches must not contain,
+/// with the placeholders [`patch::sanitize`] replaces them with.
+fn machine_paths(workspace: &Workspace<'_>) -> Vec<(String, &'static str)> {
+    let mut paths = vec![
+        (workspace.root().to_path_buf(), "$WORKSPACE"),
+        (
+            workspace.gctx().home().as_path_unlocked().to_path_buf(),
+            "$CARGO_HOME",
+        ),
+    ];
+    if let Some(home) = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        paths.push((PathBuf::from(home), "~"));
+    }
+    let mut machine_paths = Vec::new();
+    for (path, placeholder) in paths {
+        let path = path.to_string_lossy();
+        let path = path.trim_end_matches(['/', '\\']);
+        // not `/` or `C:`, which are in every path
+        if path.len() < 3 {
+            continue;
+        }
+        machine_paths.push((path.to_string(), placeholder));
+        if path.contains('\\') {
+            machine_paths.push((path.replace('\\', "/"), placeholder));
+        }
+    }
+    machine_paths
+}
+
+/// Creates the patches of `crates`, then keeps creating the patch of each
+/// crate again once its copy changed and stayed the same for a
+/// [`WATCH_INTERVAL`], so that a save of several files is one update. A patch
+/// that can't be created is reported and watched on. Stopping in the middle
+/// of an update is harmless, the next run creates the patch again.
+pub fn watch_patches(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    crates: &[String],
+) -> Result<()> {
+    let mut watched = Vec::new();
+    for n in crates {
+        let pkg = pkg_set.get_one(query_dependency(resolve, n)?)?;
+        watched.push((n.clone(), pkg.patch_target_path(workspace)?));
+    }
+    let hashes = |watched: &[(String, PathBuf)]| -> Vec<Option<String>> {
+        watched
+            .iter()
+            .map(|(_, path)| tree_hash(path).ok())
+            .collect()
+    };
+    if let Err(err) = create_patches(args, workspace, pkg_set, resolve, crates) {
+        warn!("{:#}", err);
+    }
+    let mut created = hashes(&watched);
+    info!(
+        "watching {} for changes, press Ctrl-C to stop.",
+        watched
+            .iter()
+            .map(|(_, path)| format!("{:?}", path))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    loop {
+        thread::sleep(WATCH_INTERVAL);
+        let mut current = hashes(&watched);
+        if current == created {
+            continue;
+        }
+        // wait for the editor to finish writing
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let settled = hashes(&watched);
+            if settled == current {
+                break;
+            }
+            current = settled;
+        }
+        let changed = watched
+            .iter()
+            .zip(current.iter().zip(created.iter()))
+            .filter(|(_, (now, before))| now != before)
+            .map(|((n, _), _)| n.clone())
+            .collect::<Vec<_>>();
+        info!("changed: {}", changed.join(", "));
+        if let Err(err) = create_patches(args, workspace, pkg_set, resolve, &changed) {
+            warn!("{:#}", err);
+        }
+        created = hashes(&watched);
+    }
+}
+
+pub fn create_patches(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    crates: &[String],
+) -> Result<()> {
+    let patches_folder = workspace.patches_folder();
+
+    let diff_options = git::DiffOptions {
+        include_ext: if args.diff.include_ext.is_empty() {
+            workspace.root_patch_metadata_strings("include_ext")?
+        } else {
+            args.diff.include_ext.clone()
+        },
+        interactive: args.create.interactive,
+        context_lines: args.diff.context_lines,
+        ignore_whitespace: args.diff.ignore_whitespace,
+    };
+    if args.diff.ignore_whitespace && args.diff.backend == Backend::Native {
+        return Err(anyhow!(
+            "`--ignore-whitespace` is only supported by the git backend"
+        ));
+    }
+
+    let reformat_threshold = match workspace
+        .root_patch_metadata()
+        .into_iter()
+        .find_map(|m| m.get("reformat_threshold"))
+    {
+        Some(value) => value
+            .as_integer()
+            .filter(|percent| (1..=100).contains(percent))
+            .ok_or(anyhow!(
+                "`reformat_threshold` in patch metadata must be a percentage from 1 to 100"
+            ))? as usize,
+        None => DEFAULT_REFORMAT_THRESHOLD,
+    };
+
+    if args.diff.diff_tool.is_none() && args.diff.backend == Backend::Git {
+        git::check_installed().map_err(|err| {
+            anyhow!(
+                "git is required for patch-crate's default backend; install git or use `--backend native`: {}",
+                err
+            )
+        })?;
+    }
+    info!("starting patch creation.");
+    if !patches_folder.exists() {
+        fs::create_dir_all(&patches_folder)?;
+    }
+    // a checkout with `core.autocrlf` would otherwise give patches CRLF endings
+    let attributes = patches_folder.join(".gitattributes");
+    if !attributes.exists() && !args.create.check {
+        fs::write(&attributes, format!("*.{} -text\n", PATCH_EXT))?;
+        info!(
+            "wrote {:?}, so that git checks out patches byte for byte.",
+            attributes
+        );
+    }
+    if args.create.from.is_some() && crates.len() > 1 {
+        return Err(anyhow!("`--from` creates the patch of a single crate"));
+    }
+    let entries = workspace.patch_crates()?;
+    let mut jobs: Vec<CreateJob> = Vec::new();
+    for n in crates.iter() {
+        let pkg_id = query_dependency(resolve, n)?;
+        let pkg = pkg_set.get_one(pkg_id)?;
+        let slug = pkg.slug()?.to_string();
+        // each crate is diffed in its own folder, so it may only be listed once
+        if jobs.iter().any(|job| job.slug == slug) {
+            continue;
+        }
+        if let Some(path) = dependency_path(workspace, resolve, pkg_id) {
+            crate_info!("crate: {}, a dependency pulled in by {}.", n, path);
+        }
+        // the patch is the difference to the current source, which would
+        // revert whatever changed in it since the copy was made
+        if args.create.from.is_none()
+            && is_changed_path_source(&pkg.patch_target_path(workspace)?, pkg, &workspace.config)?
+        {
+            return Err(anyhow!(
+                "crate: {}, its path source {:?} changed since it was copied to {:?}, so the patch would undo those changes. Save your changes, copy it again with `cargo patch-crate --clean {}` and redo them.",
+                n,
+                pkg.root(),
+                pkg.patch_target_path(workspace)?,
+                n
+            ));
+        }
+        let stem = match &args.create.range {
+            Some(range) => range_file_stem(pkg_id, range)?,
+            None => patch_file_stem(pkg_id),
+        };
+        let (mut patch_file, base) = if args.create.name.is_none()
+            && numbered_patch_files(&patches_folder, &stem)?.is_empty()
+        {
+            (
+                existing_patch_file(&patches_folder, &stem, workspace.config.nested),
+                Vec::new(),
+            )
+        } else {
+            numbered_patch_file(
+                &patches_folder,
+                &stem,
+                args.create.name.as_deref(),
+                args.create.check,
+                workspace.config.nested,
+            )?
+        };
+        if args.create.compress && patch_file.extension() != Some(OsStr::new(GZ_EXT)) {
+            patch_file = PathBuf::from(format!("{}.{}", patch_file.display(), GZ_EXT));
+        }
+        let stem = patch_stem(&patch_file).unwrap_or_default().to_string();
+        pkg.populate_pristine_dir(&workspace.config)?;
+        jobs.push(CreateJob {
+            name: n,
+            pkg_id,
+            pkg_root: pkg.pristine_root(&workspace.config)?,
+            patch_target_path: match &args.create.from {
+                Some(from) => stage_from(args, workspace, pkg, from)?,
+                None => pkg.patch_target_path(workspace)?,
+            },
+            patch_file,
+            base,
+            // only replaces `patch_file` once all checks passed
+            output: workspace
+                .patch_target_folder()
+                .join(format!("{}.new.{}", stem, PATCH_EXT)),
+            entry: entries.iter().find(|entry| entry.matches(pkg_id)).cloned(),
+            slug,
+        });
+    }
+
+    // `git add -p` and the preview read from the terminal, one crate at a time
+    let workers = if args.create.interactive || args.create.preview {
+        1
+    } else {
+        args.run
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+    };
+    let context = CreateContext {
+        args,
+        diff_options,
+        reformat_threshold,
+        patches_folder: workspace.patches_folder(),
+        patch_target_tmp_folder: workspace.patch_target_tmp_folder(),
+        machine_paths: machine_paths(workspace),
+        patchignore: patchignore_patterns(workspace.root())?,
+        cargo_files: cargo_files(workspace)?,
+        junk_files: junk_files(workspace)?,
+    };
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<bool>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers.min(jobs.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(job) = jobs.get(i) else {
+                            break results;
+                        };
+                        results.push((i, create_patch(job, &context)));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("patch creation panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+
+    let mut differing = Vec::new();
+    let mut errors = Vec::new();
+    let mut created = Vec::new();
+    for (i, result) in results {
+        match result {
+            Result::Ok(true) => created.push(jobs[i].pkg_id),
+            Result::Ok(false) => differing.push(jobs[i].name),
+            Err(err) => {
+                // what went wrong can be inspected there
+                let tmp = context.patch_target_tmp_folder.join(&jobs[i].slug);
+                if tmp.exists() {
+                    warn!(
+                        "crate: {}, creating the patch failed, kept {:?} for inspection.",
+                        jobs[i].name, tmp
+                    );
+                }
+                jobs[i].report("failed", "failed", Some(format!("{:#}", err)));
+                errors.push((jobs[i].name, err));
+            }
+        }
+    }
+    // only removed once empty, another run may be using it
+    let _ = fs::remove_dir(&context.patch_target_tmp_folder);
+    if !args.create.check {
+        update_patch_lock(&patches_folder, &created)?;
+    }
+    if errors.len() == 1 {
+        return Err(errors.remove(0).1);
+    }
+    if !errors.is_empty() {
+        for (name, err) in &errors {
+            warn!("crate: {}, {:#}", name, err);
+        }
+        return Err(anyhow!(
+            "failed to create the patches of {}",
+            errors
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if !differing.is_empty() {
+        return Err(anyhow!(
+            "patches of {} differ from the committed files",
+            differing.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// The numbered patch to create for `stem` and the patches it is created on top
+/// of. The patch named `name`, or with `--check` the last one, is created
+/// again, otherwise one with the next index.
+fn numbered_patch_file(
+    patches_folder: &Path,
+    stem: &str,
+    name: Option<&str>,
+    check: bool,
+    nested: bool,
+) -> Result<(PathBuf, Vec<PathBuf>)> {
+    if let Some(name) = name {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!(
+                "`--name {}` may only contain ASCII letters, digits, `-` and `_`",
+                name
+            ));
+        }
+    }
+    let index_of = |patch_file: &Path| {
+        let stem = patch_stem(patch_file)?;
+        split_patch_stem(&stem).1.map(str::to_string)
+    };
+    let plain = existing_patch_file(patches_folder, stem, nested);
+    let mut base = Vec::new();
+    if plain.exists() {
+        base.push(plain);
+    }
+    let mut numbered = numbered_patch_files(patches_folder, stem)?;
+    let again = match name {
+        Some(name) => numbered.iter().position(|patch_file| {
+            index_of(patch_file)
+                .and_then(|index| index.split_once('-').map(|(_, n)| n == name))
+                .unwrap_or_default()
+        }),
+        None if check => numbered.len().checked_sub(1),
+        None => None,
+    };
+    if let Some(i) = again {
+        // the later patches would have to be created again on top of it
+        if i + 1 != numbered.len() {
+            return Err(anyhow!(
+                "only the last numbered patch {:?} can be created again, not {:?}",
+                numbered[numbered.len() - 1],
+                numbered[i]
+            ));
+        }
+        let patch_file = numbered.remove(i);
+        base.extend(numbered);
+        return Ok((patch_file, base));
+    }
+    let next = numbered
+        .iter()
+        .filter_map(|patch_file| {
+            let index = index_of(patch_file)?;
+            let number = index.split_once('-').map_or(index.as_str(), |(n, _)| n);
+            number.parse::<usize>().ok()
+        })
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let index = match name {
+        Some(name) => format!("{:02}-{}", next, name),
+        None => format!("{:02}", next),
+    };
+    base.extend(numbered);
+    let patch_file = if nested_layout(patches_folder, nested)? {
+        nested_patch_file(patches_folder, &format!("{}.{}", stem, index))
+    } else {
+        patches_folder.join(format!("{}.{}.{}", stem, index, PATCH_EXT))
+    };
+    Ok((patch_file, base))
+}
+
+/// Creates the patch of one crate in `target/patch-tmp/<slug>`, returning
+/// whether it matches the committed one with `--check`.
+fn create_patch(job: &CreateJob, context: &CreateContext) -> Result<bool> {
+    let args = context.args;
+    let n = job.name;
+    let output = &job.output;
+    let patch_file = &job.patch_file;
+    info!("crate: {}, starting patch creation.", n);
+    let patch_target_tmp_folder = context.patch_target_tmp_folder.join(&job.slug);
+    if patch_target_tmp_folder.exists() {
+        fs::remove_dir_all(&patch_target_tmp_folder)?;
+    }
+    // the pristine crate with the patches applied that `patch_file` follows
+    let pkg_root = if job.base.is_empty() {
+        job.pkg_root.clone()
+    } else {
+        let base_root = patch_target_tmp_folder.join("base").join(&job.slug);
+        fs::create_dir_all(&base_root)?;
+        copy_dir(&job.pkg_root, &base_root)?;
+        git::init(&base_root)?;
+        for base in job.base.iter() {
+            git::apply(&base_root, base).map_err(|err| {
+                anyhow!("crate: {}, patch {:?} failed to apply: {}", n, base, err)
+            })?;
+        }
+        git::destroy(&base_root)?;
+        base_root
+    };
+    let mut patchignore = context.patchignore.clone();
+    patchignore.extend(patchignore_patterns(&job.patch_target_path)?);
+    if !patchignore.is_empty()
+        && (args.diff.diff_tool.is_some() || args.diff.backend == Backend::Native)
+    {
+        warn!(
+            "crate: {}, `{}` is only honored by the git backend, its files may end up in the patch.",
+            n, PATCHIGNORE_FILE
+        );
+    }
+    // anchored to the crate root, before `.patchignore` so it can re-include them
+    let excluded = context
+        .cargo_files
+        .iter()
+        .map(|file| format!("/{}", file))
+        .chain(context.junk_files.iter().cloned())
+        .chain(patchignore)
+        .collect::<Vec<_>>();
+    if let Some(diff_tool) = &args.diff.diff_tool {
+        diff_with_tool(
+            diff_tool,
+            &pkg_root,
+            &job.patch_target_path,
+            &patch_target_tmp_folder,
+            output,
+            &context.cargo_files,
+            &context.junk_files,
+        )?;
+    } else if args.diff.backend == Backend::Native {
+        let content = native::diff(
+            &pkg_root,
+            &job.patch_target_path,
+            &context.diff_options.include_ext,
+            &context.cargo_files,
+            &context.junk_files,
+            context.diff_options.context_lines,
+        )?;
+        fs::write(output, content)?;
+    } else {
+        let patch_target_tmp_path = patch_target_tmp_folder.join(&job.slug);
+        fs::create_dir_all(&patch_target_tmp_path)?;
+        copy_dir(&pkg_root, &patch_target_tmp_path)?;
+        git::init_excluding(&patch_target_tmp_path, &excluded)?;
+        git::destroy(&job.patch_target_path)?;
+        let modified = list_files(&job.patch_target_path)?;
+        for (a, b) in case_collisions(&modified) {
+            warn!(
+                "crate: {}, {} and {} only differ in case and collide on case-insensitive file systems.",
+                n, a, b
+            );
+        }
+        // recorded with git before copying the changes over, since on a
+        // case-insensitive file system the copy would keep the old name
+        for (old, new) in case_only_renames(&list_files(&pkg_root)?, &modified) {
+            warn!("crate: {}, {} is renamed to {} by case only.", n, old, new);
+            git::rename(&patch_target_tmp_path, &old, &new)?;
+        }
+        // only the edited files are left, so that `git add` also records deletions
+        clear_work_tree(&patch_target_tmp_path)?;
+        copy_dir(&job.patch_target_path, &patch_target_tmp_path)?;
+        git::create_patch(&patch_target_tmp_path, output, &context.diff_options)?;
+    }
+    let remove_tmp = || -> Result<()> {
+        if args.create.keep_tmp {
+            info!(
+                "crate: {}, kept {:?} with its git repository for inspection.",
+                n, patch_target_tmp_folder
+            );
+        } else if patch_target_tmp_folder.exists() {
+            fs::remove_dir_all(&patch_target_tmp_folder)?;
+        }
+        Ok(())
+    };
+    if output.exists() {
+        let mut content = fs::read_to_string(output)?;
+        if !args.diff.no_sanitize {
+            let (sanitized, changed) = patch::sanitize(
+                &content,
+                &patch_target_tmp_folder.to_string_lossy(),
+                &context.machine_paths,
+            );
+            if changed > 0 {
+                warn!(
+                    "crate: {}, replaced absolute paths of this machine on {} line(s) of the patch, pass `--no-sanitize` to keep them.",
+                    n, changed
+                );
+            }
+            content = sanitized;
+        }
+        // an empty patch would silently do nothing
+        if patch::normalize(&content).is_empty() && !args.create.check && !args.create.allow_empty {
+            fs::remove_file(output)?;
+            remove_tmp()?;
+            if job.base.is_empty() {
+                warn!(
+                    "crate: {}, no changes detected in {:?}, {:?} is not written.",
+                    n, job.patch_target_path, patch_file
+                );
+            } else {
+                info!(
+                    "crate: {}, no changes on top of its other patches, {:?} is not created.",
+                    n, patch_file
+                );
+            }
+            job.report("skipped", "skipped, no changes", None);
+            return Ok(true);
+        }
+        let whitespace = patch::whitespace_changes(&content);
+        if !whitespace.is_empty() {
+            let list = whitespace
+                .iter()
+                .map(|(path, count)| format!("  - {}: {} change(s)", path, count))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let message = format!(
+                "crate: {}, changes that only touch whitespace are in the patch, `--ignore-whitespace` leaves them out:\n{}",
+                n, list
+            );
+            if args.run.strict {
+                fs::remove_file(output)?;
+                remove_tmp()?;
+                return Err(anyhow!(message));
+            }
+            warn!("{}", message);
+        }
+        if !args.run.allow_manifest_changes && touches_manifest(&content) {
+            warn_manifest_changes(n, &pkg_root, &job.patch_target_path);
+        }
+        for path in reformatted_files(&pkg_root, &content, context.reformat_threshold) {
+            warn!(
+                "crate: {}, the changes to {} look like a reformat, not a targeted fix — did rustfmt run inside target/patch?",
+                n, path
+            );
+        }
+        let mut tested = Vec::new();
+        if is_range_patch(&job.patch_file) {
+            tested = tested_versions(&job.patch_file)?;
+            if !tested.contains(job.pkg_id.version()) {
+                tested.push(job.pkg_id.version().clone());
+                tested.sort();
+            }
+        }
+        // without a `reason` in the metadata, a message given when the patch
+        // was created before is kept
+        let has_reason = match &job.entry {
+            Some(entry) => entry.setting("reason")?.is_some(),
+            None => false,
+        };
+        let reason = match &args.create.message {
+            Some(message) => Some(message.clone()),
+            None if !has_reason && job.patch_file.exists() => {
+                patch_header_field(&job.patch_file, REASON_HEADER)?
+            }
+            None => None,
+        };
+        let header = patch_header(job.entry.as_ref(), job.pkg_id, reason.as_deref(), &tested)?;
+        if let Some(entry) = &job.entry {
+            if let Err(err) = check_allowed_paths(entry, &content) {
+                fs::remove_file(output)?;
+                return Err(err);
+            }
+        }
+        fs::write(output, header + &patch::normalize(&content))?;
+        if args.create.verify {
+            if let Err(err) = verify_patch(job, context, &pkg_root, &excluded) {
+                fs::remove_file(output)?;
+                remove_tmp()?;
+                return Err(err);
+            }
+        }
+    }
+    remove_tmp()?;
+    if !args.create.check {
+        if output.exists()
+            && args.create.preview
+            && !preview_patch(args, n, &fs::read_to_string(output)?)?
+        {
+            fs::remove_file(output)?;
+            info!("crate: {}, {:?} is not written.", n, patch_file);
+            job.report("skipped", "skipped, not confirmed", None);
+            return Ok(true);
+        }
+        if output.exists() {
+            if let Some(folder) = patch_file.parent() {
+                fs::create_dir_all(folder)?;
+            }
+            patch::write(patch_file, &fs::read_to_string(output)?)?;
+            fs::remove_file(output)?;
+            // the patch in the other format would be applied as well
+            let other = match patch_file
+                .to_str()
+                .and_then(|p| p.strip_suffix(&format!(".{}", GZ_EXT)))
+            {
+                Some(plain) => PathBuf::from(plain),
+                None => PathBuf::from(format!("{}.{}", patch_file.display(), GZ_EXT)),
+            };
+            if other.exists() {
+                fs::remove_file(&other)?;
+                info!(
+                    "crate: {}, removed {:?} in favor of {:?}.",
+                    n, other, patch_file
+                );
+            }
+        }
+        // its edits are in the patch now
+        seal_copy(&job.patch_target_path)?;
+        record_patches(
+            &job.patch_target_path,
+            &find_patch_files(&context.patches_folder, job.pkg_id)?,
+        )?;
+        // a single message, so that the lines of crates created in parallel don't interleave
+        let mut message = format!("crate: {}, create patch successfully, {:?}", n, patch_file);
+        if patch_file.exists() {
+            for line in patch::stat(&patch::read(patch_file)?) {
+                message.push('\n');
+                message.push_str(&line);
+            }
+        }
+        info!("{}", message);
+        job.report("created", "created", None);
+        return Ok(true);
+    }
+    let created = fs::read_to_string(output)?;
+    fs::remove_file(output)?;
+    let committed = patch::read(patch_file)
+        .map_err(|err| anyhow!("crate: {}, cannot read {:?}: {}", n, patch_file, err))?;
+    let differences = patch::differences(&committed, &created);
+    if differences.is_empty() {
+        info!("crate: {}, patch {:?} is up to date.", n, patch_file);
+        job.report("skipped", "up to date", None);
+        return Ok(true);
+    }
+    // a single warning, so that the lines of crates created in parallel don't interleave
+    let mut message = format!("crate: {}, patch {:?} would change:", n, patch_file);
+    for difference in differences {
+        message.push_str(&format!("\n  - {}", difference));
+    }
+    warn!("{}", message);
+    job.report("failed", "would change", Some(message));
+    Ok(false)
+}
+
+/// Applies the patch just created for `job` to a copy of `pkg_root` and fails
+/// with the files that then differ from the edited copy, e.g. a deletion or
+/// a binary file the patch missed. Files left out of the patch on purpose,
+/// through `--include-ext`, `.patchignore` or `cargo_files`, are not
+/// compared.
+fn verify_patch(
+    job: &CreateJob,
+    context: &CreateContext,
+    pkg_root: &Path,
+    excluded: &[String],
+) -> Result<()> {
+    let n = job.name;
+    let verify = context
+        .patch_target_tmp_folder
+        .join(&job.slug)
+        .join("verify")
+        .join(&job.slug);
+    fs::create_dir_all(&verify)?;
+    copy_dir(pkg_root, &verify)?;
+    git::init_excluding(&verify, excluded)?;
+    git::apply(&verify, &job.output).map_err(|err| {
+        anyhow!(
+            "crate: {}, the created patch doesn't apply to the pristine crate: {}",
+            n,
+            err
+        )
+    })?;
+    let mut paths = list_files(&verify)?;
+    paths.extend(list_files(&job.patch_target_path)?);
+    paths.sort();
+    paths.dedup();
+    let include_ext = &context.diff_options.include_ext;
+    let mut mismatches = paths
+        .into_iter()
+        .filter(|path| {
+            include_ext.is_empty()
+                || include_ext
+                    .iter()
+                    .any(|ext| path.ends_with(&format!(".{}", ext.trim_start_matches('.'))))
+        })
+        .filter(|path| {
+            fs::read(verify.join(path)).ok() != fs::read(job.patch_target_path.join(path)).ok()
+        })
+        .collect::<Vec<_>>();
+    let ignored = git::ignored(&verify, &mismatches)?;
+    mismatches.retain(|path| !ignored.contains(path));
+    git::destroy(&verify)?;
+    if !mismatches.is_empty() {
+        return Err(anyhow!(
+            "crate: {}, the created patch doesn't reproduce {:?}, these files differ once it is applied to the pristine crate:\n{}",
+            n,
+            job.patch_target_path,
+            mismatches
+                .iter()
+                .map(|path| format!("  - {}", path))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    }
+    crate_info!("crate: {}, verified that the patch reproduces the copy.", n);
+    Ok(())
+}
@@ -0,0 +1,587 @@
+//! Running git, to diff copies into patches and apply patches to them.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::log::{self, Traced};
+
+/// Turns the error of starting git into one that says what is missing,
+/// rather than a bare "No such file or directory".
+fn spawn_error(err: std::io::Error) -> anyhow::Error {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        anyhow::anyhow!("git executable not found on PATH; install git")
+    } else {
+        anyhow::anyhow!("failed to run git: {}", err)
+    }
+}
+
+/// A git command that takes files byte for byte: no line ending
+/// conversion by the user's `core.autocrlf`, so that patches changing
+/// line endings on purpose are created and applied exactly.
+fn git() -> Command {
+    let mut command = Command::new("git");
+    command.args(["-c", "core.autocrlf=false", "-c", "core.safecrlf=false"]);
+    command
+}
+
+/// Fails early when git is missing, before any crate is half copied.
+pub fn check_installed() -> anyhow::Result<()> {
+    let status = git()
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .traced()
+        .status()
+        .map_err(spawn_error)?;
+    if !status.success() {
+        anyhow::bail!("`git --version` failed with {}, is git installed?", status);
+    }
+    Ok(())
+}
+
+/// Runs git without capturing its output, for commands whose output we
+/// never read. Large crates otherwise buffer the whole `git add` listing.
+fn run<I, S>(repo_dir: &Path, args: I) -> anyhow::Result<ExitStatus>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let status = git()
+        .current_dir(repo_dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log::stderr())
+        .traced()
+        .status()
+        .map_err(spawn_error)?;
+    Ok(status)
+}
+
+pub fn init(repo_dir: &Path) -> anyhow::Result<()> {
+    init_excluding(repo_dir, &[])
+}
+
+/// Like [`init`], with the files matching the gitignore `exclude` patterns
+/// left out of the repository, also once they are changed.
+pub fn init_excluding(repo_dir: &Path, exclude: &[String]) -> anyhow::Result<()> {
+    if !run(repo_dir, ["init"])?.success() {
+        anyhow::bail!("`git init` failed in {:?}", repo_dir);
+    }
+    // overrides a `text` attribute in the crate's own `.gitattributes`
+    let info = repo_dir.join(".git").join("info");
+    fs::create_dir_all(&info)?;
+    fs::write(info.join("attributes"), "* -text\n")?;
+    if !exclude.is_empty() {
+        fs::write(info.join("exclude"), exclude.join("\n") + "\n")?;
+    }
+    if !run(repo_dir, ["add", "."])?.success() {
+        anyhow::bail!("`git add` of the pristine crate failed in {:?}", repo_dir);
+    }
+    // without a commit of the pristine crate every file would end up in
+    // the patch, so neither a missing identity nor signing may fail it
+    let status = run(
+        repo_dir,
+        [
+            "-c",
+            "user.name=cargo-patch-crate",
+            "-c",
+            "user.email=cargo-patch-crate@localhost",
+            "-c",
+            "commit.gpgsign=false",
+            "commit",
+            "--allow-empty",
+            "--no-verify",
+            "-m",
+            "zero",
+        ],
+    )?;
+    if !status.success() {
+        anyhow::bail!(
+            "`git commit` of the pristine crate failed in {:?}",
+            repo_dir
+        );
+    }
+    Ok(())
+}
+
+/// The patch file given to git: `patch_file` itself, or a copy of what
+/// [`super::patch::read`] makes of it when that differs, e.g. LF line
+/// endings for a patch a checkout gave CRLF ones, removed once dropped.
+struct PatchInput {
+    path: PathBuf,
+    copy: bool,
+}
+
+impl PatchInput {
+    fn new(patch_file: &Path) -> anyhow::Result<Self> {
+        static COPIES: AtomicUsize = AtomicUsize::new(0);
+        let translated = match (fs::read(patch_file), super::patch::read(patch_file)) {
+            (Ok(raw), Ok(content)) if raw != content.as_bytes() => Some(content),
+            _ => None,
+        };
+        let Some(translated) = translated else {
+            return Ok(Self {
+                path: patch_file.to_path_buf(),
+                copy: false,
+            });
+        };
+        let path = std::env::temp_dir().join(format!(
+            "cargo-patch-crate-{}-{}.patch",
+            std::process::id(),
+            COPIES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, translated)?;
+        Ok(Self { path, copy: true })
+    }
+}
+
+impl Drop for PatchInput {
+    fn drop(&mut self) {
+        if self.copy {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn patch_file_arg(patch_file: &Path) -> String {
+    #[cfg(target_os = "windows")]
+    let patch_file = patch_file
+        .to_string_lossy()
+        .to_string()
+        .trim_start_matches(r#"\\?\"#)
+        .to_string();
+    #[cfg(not(target_os = "windows"))]
+    let patch_file = patch_file.to_string_lossy().to_string();
+    patch_file
+}
+
+/// `--unidiff-zero` for a patch created with `--context-lines 0`, whose
+/// hunks `git apply` refuses otherwise.
+fn context_args(patch_file: &Path) -> &'static [&'static str] {
+    match fs::read_to_string(patch_file) {
+        Ok(content) if super::patch::has_zero_context(&content) => &["--unidiff-zero"],
+        _ => &[],
+    }
+}
+
+fn apply_command(repo_dir: &Path, patch_file: &Path, extra_args: &[&str]) -> Command {
+    let mut command = git();
+    command
+        .current_dir(repo_dir)
+        .args([
+            "apply",
+            "--ignore-space-change",
+            "--ignore-whitespace",
+            "--whitespace=nowarn",
+        ])
+        .args(context_args(patch_file))
+        .args(extra_args)
+        .arg(patch_file_arg(patch_file));
+    command
+}
+
+pub fn apply(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<()> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    run_apply(apply_command(repo_dir, patch_file, &[]))
+}
+
+/// Applies a patch with `-p<strip>` leading path components removed.
+pub fn apply_strip(repo_dir: &Path, patch_file: &Path, strip: usize) -> anyhow::Result<()> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    run_apply(apply_command(
+        repo_dir,
+        patch_file,
+        &[&format!("-p{}", strip)],
+    ))
+}
+
+/// The first of the usual strip levels, `-p1` as git writes patches,
+/// `-p0` and `-p2`, at which the patch applies to `repo_dir`.
+pub fn detect_strip(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<Option<usize>> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    for strip in [1, 0, 2] {
+        let status = apply_command(repo_dir, patch_file, &["--check", &format!("-p{}", strip)])
+            .stdout(Stdio::null())
+            .stderr(log::stderr())
+            .traced()
+            .status()
+            .map_err(spawn_error)?;
+        if status.success() {
+            return Ok(Some(strip));
+        }
+    }
+    Ok(None)
+}
+
+/// Applies a patch whose context has to match exactly, whitespace
+/// included.
+pub fn apply_exact(repo_dir: &Path, patch_file: &Path, strip: usize) -> anyhow::Result<()> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    let mut command = git();
+    command
+        .current_dir(repo_dir)
+        .args(["apply", "--whitespace=nowarn", &format!("-p{}", strip)])
+        .args(context_args(patch_file))
+        .arg(patch_file_arg(patch_file));
+    run_apply(command)
+}
+
+fn run_apply(mut command: Command) -> anyhow::Result<()> {
+    let out = command.traced().output().map_err(spawn_error)?;
+
+    if !out.status.success() {
+        anyhow::bail!(String::from_utf8(out.stderr)?)
+    }
+    log::trace_stderr(&out.stderr);
+    Ok(())
+}
+
+/// Applies strictly, then ignoring whitespace, then with a three-way merge
+/// against the pristine crate committed by [`init`], then with `fuzz`
+/// through GNU `patch`, stopping at the first that succeeds, each with
+/// `strip` leading path components removed. Returns the command that was
+/// needed beyond strict.
+pub fn apply_escalating(
+    repo_dir: &Path,
+    patch_file: &Path,
+    strip: usize,
+    fuzz: Option<u32>,
+) -> anyhow::Result<Option<String>> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    let ladder: [(Option<&'static str>, &[&str]); 3] = [
+        (None, &[]),
+        (
+            Some("git apply --ignore-space-change --ignore-whitespace"),
+            &["--ignore-space-change", "--ignore-whitespace"],
+        ),
+        (Some("git apply --3way"), &["--3way"]),
+    ];
+    let mut last_err = None;
+    for (options, extra_args) in ladder {
+        let mut command = git();
+        command
+            .current_dir(repo_dir)
+            .args(["apply", "--whitespace=nowarn", &format!("-p{}", strip)])
+            .args(context_args(patch_file))
+            .args(extra_args)
+            .arg(patch_file_arg(patch_file));
+        match run_apply(command) {
+            Ok(()) => return Ok(options.map(str::to_string)),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    if let Some(fuzz) = fuzz {
+        match apply_fuzzy(repo_dir, patch_file, strip, fuzz) {
+            Ok(()) => return Ok(Some(format!("patch --fuzz {}", fuzz))),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("patch did not apply")))
+}
+
+/// Applies with GNU `patch`, letting up to `fuzz` lines of context differ.
+/// A dry run goes first, as `patch` keeps the hunks that applied when
+/// others fail.
+fn apply_fuzzy(repo_dir: &Path, patch_file: &Path, strip: usize, fuzz: u32) -> anyhow::Result<()> {
+    for dry_run in [true, false] {
+        let mut command = Command::new("patch");
+        command
+            .current_dir(repo_dir)
+            .args([
+                &format!("-p{}", strip),
+                "--batch",
+                "--forward",
+                "--no-backup-if-mismatch",
+            ])
+            .args(["--reject-file=-", &format!("--fuzz={}", fuzz)]);
+        if dry_run {
+            command.arg("--dry-run");
+        }
+        let out = command
+            .arg("--input")
+            .arg(patch_file_arg(patch_file))
+            .traced()
+            .output()
+            .map_err(|err| {
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    anyhow::anyhow!(
+                        "patch executable not found on PATH; install GNU patch to use `--fuzz`"
+                    )
+                } else {
+                    anyhow::anyhow!("failed to run patch: {}", err)
+                }
+            })?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "`patch --fuzz {}` failed: {}",
+                fuzz,
+                String::from_utf8_lossy(&out.stdout)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the patch applies to `repo_dir`, without applying it.
+pub fn check(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<bool> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    let status = apply_command(repo_dir, patch_file, &["--check"])
+        .stdout(Stdio::null())
+        .stderr(log::stderr())
+        .traced()
+        .status()
+        .map_err(spawn_error)?;
+    Ok(status.success())
+}
+
+/// Undoes a patch applied with [`apply`].
+pub fn reverse(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<()> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    run_apply(apply_command(repo_dir, patch_file, &["--reverse"]))
+}
+
+/// Returns whether the patch could be reverted, i.e. whether its changes
+/// are already present in `repo_dir`.
+pub fn check_reverse(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<bool> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    let status = apply_command(repo_dir, patch_file, &["--check", "--reverse"])
+        .stdout(Stdio::null())
+        .stderr(log::stderr())
+        .traced()
+        .status()
+        .map_err(spawn_error)?;
+    Ok(status.success())
+}
+
+/// Undoes a patch with a single line of context that has to match, so that
+/// its changes are still found next to other edits. Returns whether it
+/// could be undone.
+pub fn reverse_loosely(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<bool> {
+    let input = PatchInput::new(patch_file)?;
+    let patch_file = input.path.as_path();
+    let status = apply_command(repo_dir, patch_file, &["--reverse", "-C1"])
+        .stdout(Stdio::null())
+        .stderr(log::stderr())
+        .traced()
+        .status()
+        .map_err(spawn_error)?;
+    Ok(status.success())
+}
+
+/// Renames a file in the index and the work tree, also when only the case
+/// of its name changes on a case-insensitive file system.
+pub fn rename(repo_dir: &Path, from: &str, to: &str) -> anyhow::Result<()> {
+    let status = run(
+        repo_dir,
+        [OsStr::new("mv"), "-f".as_ref(), from.as_ref(), to.as_ref()],
+    )?;
+    if !status.success() {
+        anyhow::bail!("failed to rename {} to {}", from, to);
+    }
+    Ok(())
+}
+
+/// Merges the changes from `base` to `theirs` into the file `ours` in
+/// place, returns false when some conflict and are left between markers.
+/// Binary files that differ count as a conflict and keep `ours`.
+pub fn merge_file(
+    ours: &Path,
+    base: &Path,
+    theirs: &Path,
+    labels: &[String; 3],
+) -> anyhow::Result<bool> {
+    let out = git()
+        .args([
+            "merge-file",
+            "-L",
+            &labels[0],
+            "-L",
+            &labels[1],
+            "-L",
+            &labels[2],
+        ])
+        .args([ours, base, theirs])
+        .stdin(Stdio::null())
+        .traced()
+        .output()
+        .map_err(spawn_error)?;
+    log::trace_stderr(&out.stderr);
+    Ok(out.status.success())
+}
+
+/// The `paths` that the ignore rules of the repository at `repo_dir` leave
+/// out, whether they are tracked or not.
+pub fn ignored(repo_dir: &Path, paths: &[String]) -> anyhow::Result<Vec<String>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut child = git()
+        .current_dir(repo_dir)
+        .args(["check-ignore", "--no-index", "--stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(log::stderr())
+        .traced()
+        .spawn()
+        .map_err(spawn_error)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all((paths.join("\n") + "\n").as_bytes())?;
+    }
+    let out = child.wait_with_output()?;
+    // 1 when nothing is ignored
+    if !out.status.success() && out.status.code() != Some(1) {
+        anyhow::bail!("`git check-ignore` failed in {:?}", repo_dir);
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Writes the changes of the work tree at `dir`, a folder of a git
+/// repository, since the commit `rev` to `patch_file`, with the paths
+/// relative to `dir`.
+pub fn diff_since(dir: &Path, rev: &str, patch_file: &Path) -> anyhow::Result<()> {
+    let out = git()
+        .current_dir(dir)
+        .args([
+            "diff",
+            "--relative",
+            "--binary",
+            "--no-ext-diff",
+            "--no-color",
+            "--no-textconv",
+            "--src-prefix=a/",
+            "--dst-prefix=b/",
+            rev,
+            "--",
+        ])
+        .traced()
+        .output()
+        .map_err(spawn_error)?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "`git diff {}` failed in {:?}: {}",
+            rev,
+            dir,
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        );
+    }
+    fs::write(patch_file, out.stdout)?;
+    Ok(())
+}
+
+pub fn destroy(repo_dir: &Path) -> anyhow::Result<()> {
+    let git_dir = repo_dir.join(".git");
+    if git_dir.exists() {
+        fs::remove_dir_all(git_dir)?;
+    }
+    Ok(())
+}
+
+/// Options controlling what ends up in a patch created by [`create_patch`].
+#[derive(Debug, Default)]
+pub struct DiffOptions {
+    /// Only diff files with one of these extensions, everything if empty.
+    pub include_ext: Vec<String>,
+    /// Pick the hunks to include with `git add --patch`.
+    pub interactive: bool,
+    /// Lines of context around each change.
+    pub context_lines: u32,
+    /// Leave out changes that only touch whitespace.
+    pub ignore_whitespace: bool,
+}
+
+pub fn create_patch(
+    repo_dir: &Path,
+    patch_file: &Path,
+    options: &DiffOptions,
+) -> anyhow::Result<()> {
+    let unified = format!("--unified={}", options.context_lines);
+    let pathspecs = options
+        .include_ext
+        .iter()
+        .map(|ext| format!("*.{}", ext.trim_start_matches('.')))
+        .collect::<Vec<_>>();
+
+    if options.interactive {
+        // new files are only offered once git knows about them
+        if !run(repo_dir, ["add", "--intent-to-add", "."])?.success() {
+            anyhow::bail!("`git add --intent-to-add` failed in {:?}", repo_dir);
+        }
+        let status = git()
+            .current_dir(repo_dir)
+            .args(["add", "--patch", "--"])
+            .args(&pathspecs)
+            .traced()
+            .status()
+            .map_err(spawn_error)?;
+        if !status.success() {
+            anyhow::bail!("`git add --patch` was aborted");
+        }
+    } else if !run(repo_dir, ["add", "."])?.success() {
+        anyhow::bail!("`git add` of the edited crate failed in {:?}", repo_dir);
+    }
+
+    let mut diff = git();
+    diff.current_dir(repo_dir)
+        // the same output whatever the config of the machine
+        .args([
+            "-c",
+            "core.quotePath=true",
+            "-c",
+            "diff.relative=false",
+            "-c",
+            "diff.suppressBlankEmpty=false",
+            "diff",
+            "--staged",
+            "--find-renames",
+            // embeds binary files instead of `Binary files differ`
+            "--binary",
+            "--no-ext-diff",
+            "--no-color",
+            "--no-textconv",
+            "--src-prefix=a/",
+            "--dst-prefix=b/",
+            "--diff-algorithm=myers",
+            "--indent-heuristic",
+            &unified,
+            "--inter-hunk-context=0",
+        ]);
+    if options.ignore_whitespace {
+        diff.arg("--ignore-all-space");
+    }
+    let out = diff
+        .arg("--")
+        .args(&pathspecs)
+        .traced()
+        .output()
+        .map_err(spawn_error)?;
+
+    // an empty patch must only mean that nothing changed
+    if !out.status.success() {
+        anyhow::bail!(
+            "`git diff` failed in {:?}: {}",
+            repo_dir,
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        );
+    }
+    log::trace_stderr(&out.stderr);
+    fs::write(patch_file, out.stdout)?;
+    Ok(())
+}
@@ -20,7 +20,10 @@
 //! ```
 //!
 //! It specifies which dependency to patch (in this case
-//! serde). Running:
+//! serde). In a virtual workspace, where there is no `[package]`,
+//! the same table can be put under `[workspace.metadata.patch]`;
+//! the tables of the workspace and of all its members are merged.
+//! Running:
 //!
 //! ```sh
 //! cargo patch-crate
@@ -51,335 +54,4711 @@
 //! ```
 
 use anyhow::{anyhow, Ok, Result};
+use apply::{
+    all_patches_present, apply_patch, apply_patches, apply_to, check_patches, collect_patch_files,
+    download_patch, first_failing_patch, is_patch_path, is_patch_url, stale_patches,
+};
 use cargo::{
     core::{
+        compiler::{CompileKind, CompileTarget, RustcTargetData},
+        dependency::DepKind,
         package::{Package, PackageSet},
         registry::PackageRegistry,
         resolver::{features::CliFeatures, HasDevUnits},
-        Resolve, Workspace,
+        MaybePackage, PackageId, PackageIdSpec, PackageIdSpecQuery, Resolve, Workspace,
     },
     ops::{get_resolved_packages, load_pkg_lockfile, resolve_with_previous},
     sources::SourceConfigMap,
-    util::{cache_lock::CacheLockMode, important_paths::find_root_manifest_for_wd, GlobalContext},
+    util::{
+        cache_lock::CacheLockMode,
+        important_paths::find_root_manifest_for_wd,
+        interning::InternedString,
+        network::retry::{Retry, RetryResult},
+        FileLock, Filesystem, GlobalContext,
+    },
 };
-use clap::Parser;
-use fs_extra::dir::{copy, CopyOptions};
+use cli::{
+    check_create_flags, cli_args, print_config, with_default_args, Cli, Command, OutputFormat,
+    RunFormat,
+};
+use create::{create_patches, is_ignored, watch_patches};
+use lock::{tree_hash, update_patch_lock, verify_checksum};
 use log::*;
 use std::{
-    collections::HashSet,
-    ffi::OsStr,
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    ffi::{OsStr, OsString},
     fs,
+    io::IsTerminal,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Poll,
+    thread,
+    time::Duration,
 };
+use vendor::apply_to_vendor;
 
 const PATCH_EXT: &str = "patch";
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    crates: Vec<String>,
-    #[arg(short, long)]
-    force: bool,
+/// The extension after [`PATCH_EXT`] of gzip compressed patches.
+const GZ_EXT: &str = "gz";
+
+/// Marks the version part of a patch file name as a git revision.
+const GIT_REV_PREFIX: &str = "git-";
+const SHORT_REV_LEN: usize = 7;
+
+/// Starts the line above the diff of a patch that names the registry of a
+/// crate not from crates.io.
+const REGISTRY_HEADER: &str = "Registry: ";
+
+/// Start of the line above the diff of a range patch that lists the versions
+/// it was created from.
+const TESTED_HEADER: &str = "Tested-Versions: ";
+
+/// Start of the line above the diff of a patch that tells why it exists.
+const REASON_HEADER: &str = "Reason: ";
+
+/// How often `--watch` looks for changes to the copies.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// File in the patches folder with the hashes `--release` checks patched
+/// crates against, keyed by patch file name.
+const HASHES_FILE: &str = "patch-hashes.toml";
+
+/// File that a run with `--keep-tmp`, or one that failed, leaves in
+/// `target/patch-tmp`, so that the runs after it keep the folder for
+/// inspection instead of cleaning it up.
+const KEEP_TMP_MARKER: &str = ".patch-crate-keep";
+
+/// File in the patches folder that records which crate versions are patched
+/// with which patch files and their SHA-256, see [`lock_entry`].
+const LOCK_FILE: &str = "patch-crate.lock";
+
+/// Extension of the file next to each copy in `target/patch` that records the
+/// source it was copied from, see [`source_stamp`].
+const SOURCE_MARKER_EXT: &str = "patch-crate-source";
+
+/// Starts the line of a source marker with the fingerprint of the patches the
+/// copy was patched with, see [`record_patches`].
+const PATCHES_LINE: &str = "patches: ";
+
+/// File of gitignore patterns, in the workspace root or the root of a copy,
+/// for files created patches leave out.
+const PATCHIGNORE_FILE: &str = ".patchignore";
+
+/// Files cargo adds to a crate when packaging and extracting it, which
+/// created patches leave out unless `cargo_files` in the patch metadata
+/// lists others.
+const CARGO_FILES: [&str; 3] = [".cargo-ok", ".cargo_vcs_info.json", "Cargo.toml.orig"];
+
+/// Gitignore patterns for files that tools and builds leave in a copy, which
+/// created patches leave out unless `junk_files` in the patch metadata lists
+/// others: a `target` folder of building the copy by itself, and files of
+/// file managers and editors.
+const JUNK_FILES: [&str; 5] = ["/target/", ".DS_Store", "Thumbs.db", "*.swp", "*~"];
+
+/// Percentage of the lines of a file a patch has to replace for its changes
+/// to be reported as a likely reformat.
+const DEFAULT_REFORMAT_THRESHOLD: usize = 50;
+/// Files this short are often rewritten on purpose.
+const REFORMAT_MIN_LINES: usize = 20;
+
+/// Logs an info line, unless `--quiet` was given. Shadows the macro of
+/// `paris`, so that warnings and errors are all that is left when quiet.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::log::quiet() {
+            $crate::log::line("<cyan><info></>", format!($($arg)*));
+        }
+    };
 }
 
-trait PackageExt {
-    fn slug(&self) -> Result<&str>;
-    fn patch_target_path(&self, workspace: &Workspace<'_>) -> Result<PathBuf>;
+/// Logs a warning, which the JSON report also attaches to its crate.
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        $crate::report::warning(&message);
+        $crate::log::warning(message);
+    }};
 }
 
-impl PackageExt for Package {
-    fn slug(&self) -> Result<&str> {
-        if let Some(name) = self.root().file_name().and_then(|s| s.to_str()) {
-            Ok(name)
-        } else {
-            Err(anyhow!("Dependency Folder does not have a name"))
+/// Logs a per-crate progress line, unless `--summary-only` or `--quiet` was
+/// given.
+macro_rules! crate_info {
+    ($($arg:tt)*) => {
+        if $crate::log::per_crate() {
+            info!($($arg)*);
         }
+    };
+}
+
+mod apply;
+mod bundle;
+mod cli;
+mod completions;
+mod create;
+mod git;
+mod lock;
+mod log;
+mod native;
+mod patch;
+mod progress;
+mod report;
+mod vendor;
+
+/// Outcome for each crate handled while applying patches, printed as a table
+/// at the end with `--summary-only`.
+#[derive(Default)]
+struct Summary(Vec<(String, String, &'static str)>);
+
+impl Summary {
+    fn push(&mut self, pkg: &Package, status: &'static str) {
+        self.record(pkg, status, None, None);
     }
 
-    fn patch_target_path(&self, workspace: &Workspace<'_>) -> Result<PathBuf> {
-        let slug = self.slug()?;
-        let patch_target_path = workspace.patch_target_folder().join(slug);
-        Ok(patch_target_path)
+    /// Records the outcome of a crate, along with its patch and error for the
+    /// JSON report.
+    fn record(
+        &mut self,
+        pkg: &Package,
+        status: &'static str,
+        patch_file: Option<&Path>,
+        error: Option<String>,
+    ) {
+        report::push(
+            &pkg.name(),
+            &pkg.version().to_string(),
+            Self::action(status),
+            status,
+            patch_file,
+            error,
+        );
+        self.0
+            .push((pkg.name().to_string(), pkg.version().to_string(), status));
+    }
+
+    /// What `status` counts as: `applied`, `reversed`, `copied`, `skipped` or
+    /// `failed`.
+    fn action(status: &'static str) -> &'static str {
+        match status {
+            "applied" | "reversed" => status,
+            "copied, no patch" => "copied",
+            _ if status.starts_with("failed") => "failed",
+            _ => "skipped",
+        }
+    }
+
+    /// How many crates ended up as `action`.
+    fn count(&self, action: &str) -> usize {
+        self.0
+            .iter()
+            .filter(|(_, _, status)| Self::action(status) == action)
+            .count()
+    }
+
+    /// The one line account of a run, printed at its end.
+    fn totals(&self, warnings: usize) -> String {
+        format!(
+            "{} applied, {} copied without a patch, {} skipped, {} warning(s), {} failed",
+            self.count("applied"),
+            self.count("copied"),
+            self.count("skipped"),
+            warnings,
+            self.count("failed")
+        )
+    }
+
+    fn print(&self) {
+        let name_width = self.0.iter().map(|r| r.0.len()).max().unwrap_or(0).max(5);
+        let version_width = self.0.iter().map(|r| r.1.len()).max().unwrap_or(0).max(7);
+        info!(
+            "{:<name_width$}  {:<version_width$}  status",
+            "crate", "version"
+        );
+        for (name, version, status) in self.0.iter() {
+            info!(
+                "{:<name_width$}  {:<version_width$}  {}",
+                name, version, status
+            );
+        }
     }
 }
 
-trait WorkspaceExt {
-    fn patches_folder(&self) -> PathBuf;
-    fn patch_target_folder(&self) -> PathBuf;
-    fn patch_target_tmp_folder(&self) -> PathBuf;
-    fn clean_patch_folder(&self) -> Result<()>;
+/// Warnings raised while applying patches, collected so that all of them can
+/// be reported at once when `--deny-warnings` is set.
+#[derive(Default)]
+struct Warnings(Vec<String>);
+
+impl Warnings {
+    fn push(&mut self, message: String) {
+        warn!("{}", message);
+        self.0.push(message);
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn check(self, deny: bool) -> Result<()> {
+        if deny && !self.0.is_empty() {
+            let summary = self
+                .0
+                .iter()
+                .map(|w| format!("  - {}", w))
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(anyhow!(
+                "{} warning(s) encountered with `--deny-warnings`:\n{}",
+                self.0.len(),
+                summary
+            ));
+        }
+        Ok(())
+    }
 }
 
-impl WorkspaceExt for Workspace<'_> {
-    fn patches_folder(&self) -> PathBuf {
-        self.root().join("patches/")
+trait PackageExt {
+    fn slug(&self) -> Result<String>;
+    fn patch_target_path(&self, workspace: &PatchWorkspace<'_>) -> Result<PathBuf>;
+    fn pristine_root(&self, config: &PatchConfig) -> Result<PathBuf>;
+    fn populate_pristine_dir(&self, config: &PatchConfig) -> Result<()>;
+}
+
+impl PackageExt for Package {
+    /// The folder name of the copy of the crate in `target/patch`. A registry
+    /// crate is extracted to `<name>-<version>` and keeps that name. Other
+    /// sources are named the same way, whatever their folder is called, like a
+    /// git checkout named after the revision. Many revisions of a git crate
+    /// may share a version, so its short revision is added, as in its patch
+    /// file name.
+    fn slug(&self) -> Result<String> {
+        let source_id = self.package_id().source_id();
+        if source_id.is_registry() {
+            return match self.root().file_name().and_then(|s| s.to_str()) {
+                Some(name) => Ok(name.to_string()),
+                None => Err(anyhow!("Dependency Folder does not have a name")),
+            };
+        }
+        let slug = format!("{}-{}", self.name(), self.version());
+        Ok(match source_id.precise_git_fragment() {
+            Some(precise) => format!(
+                "{}-{}{}",
+                slug,
+                GIT_REV_PREFIX,
+                &precise[..SHORT_REV_LEN.min(precise.len())]
+            ),
+            None => slug,
+        })
     }
-    fn patch_target_folder(&self) -> PathBuf {
-        self.root().join("target/patch/")
+
+    fn patch_target_path(&self, workspace: &PatchWorkspace<'_>) -> Result<PathBuf> {
+        let slug = self.slug()?;
+        let patch_target_path = workspace.patch_target_folder().join(slug);
+        Ok(patch_target_path)
     }
-    fn patch_target_tmp_folder(&self) -> PathBuf {
-        self.root().join("target/patch-tmp/")
+
+    /// The source the crate is copied and diffed from: its extraction named
+    /// after its [`slug`](Self::slug) in `--pristine-dir`, which must be the
+    /// resolved crate, or else its source in the cargo cache. A path crate
+    /// always is its own source.
+    fn pristine_root(&self, config: &PatchConfig) -> Result<PathBuf> {
+        use anyhow::Context as _;
+        let Some(pristine_dir) = &config.pristine_dir else {
+            return Ok(self.root().to_path_buf());
+        };
+        if self.package_id().source_id().is_path() {
+            return Ok(self.root().to_path_buf());
+        }
+        let path = pristine_dir.join(self.slug()?);
+        if !path.exists() {
+            return Ok(self.root().to_path_buf());
+        }
+        let manifest = fs::read_to_string(path.join("Cargo.toml"))
+            .with_context(|| format!("{:?} in `--pristine-dir` has no Cargo.toml", path))?;
+        let package = manifest
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|t| t.get("package").and_then(|p| p.as_table()).cloned())
+            .unwrap_or_default();
+        let name = package.get("name").and_then(|v| v.as_str());
+        let version = package.get("version").and_then(|v| v.as_str());
+        if name != Some(self.name().as_str()) || version != Some(&self.version().to_string()) {
+            return Err(anyhow!(
+                "{:?} in `--pristine-dir` is {} {}, not the resolved {} {}",
+                path,
+                name.unwrap_or("?"),
+                version.unwrap_or("?"),
+                self.name(),
+                self.version()
+            ));
+        }
+        Ok(path)
     }
 
-    fn clean_patch_folder(&self) -> Result<()> {
-        let path = self.patch_target_folder();
+    /// Extracts the crate into `--pristine-dir` from the cargo cache when it
+    /// is missing there and [`PatchConfig::populate_pristine_dir`] is set,
+    /// before [`pristine_root`](Self::pristine_root) looks it up.
+    fn populate_pristine_dir(&self, config: &PatchConfig) -> Result<()> {
+        let Some(pristine_dir) = &config.pristine_dir else {
+            return Ok(());
+        };
+        if !config.populate_pristine_dir || self.package_id().source_id().is_path() {
+            return Ok(());
+        }
+        let path = pristine_dir.join(self.slug()?);
         if path.exists() {
-            fs::remove_dir_all(self.patch_target_folder())?;
+            return Ok(());
+        }
+        fs::create_dir_all(pristine_dir)?;
+        // copied aside first, so that an interrupted copy is not taken for an
+        // extraction
+        let tmp_path = pristine_dir.join(format!(".{}.tmp", self.slug()?));
+        if tmp_path.exists() {
+            fs::remove_dir_all(&tmp_path)?;
         }
+        copy_dir(self.root(), &tmp_path)?;
+        fs::rename(&tmp_path, &path)?;
+        crate_info!(
+            "crate: {}, extracted to {:?} with `--populate-pristine-dir`.",
+            self.name(),
+            path
+        );
         Ok(())
     }
 }
 
-fn resolve_ws<'a>(ws: &Workspace<'a>) -> Result<(PackageSet<'a>, Resolve)> {
-    let mut registry =
-        PackageRegistry::new_with_source_config(ws.gctx(), SourceConfigMap::new(ws.gctx())?)?;
-    registry.lock_patches();
-    let resolve = {
-        let prev = load_pkg_lockfile(ws)?;
-        let resolve: Resolve = resolve_with_previous(
-            &mut registry,
-            ws,
-            &CliFeatures::new_all(true),
-            HasDevUnits::No,
-            prev.as_ref(),
-            None,
-            &[],
-            false,
-        )?;
-        resolve
-    };
-    let packages = get_resolved_packages(&resolve, registry)?;
-    Ok((packages, resolve))
+/// The spec for the crate of a renamed dependency when `spec` names it by its
+/// alias, see [`WorkspaceExt::dependency_aliases`]: `serde@1` for `serde_real@1`
+/// of `serde_real = { package = "serde", version = "1" }`.
+fn unalias(spec: &str, aliases: &[(InternedString, InternedString)]) -> Option<String> {
+    let (name, version) = spec.split_at(spec.find('@').unwrap_or(spec.len()));
+    let (_, package) = aliases.iter().find(|(alias, _)| alias.as_str() == name)?;
+    Some(format!("{}{}", package, version))
 }
 
-fn copy_package(pkg: &Package, patch_target_folder: &Path, overwrite: bool) -> Result<PathBuf> {
-    fs::create_dir_all(patch_target_folder)?;
-    let options = CopyOptions::new();
-    let patch_target_path = patch_target_folder.join(pkg.slug()?);
-    if patch_target_path.exists() {
-        if overwrite {
-            info!("crate: {}, copy to {:?}", pkg.name(), &patch_target_folder);
-            fs::remove_dir_all(&patch_target_path)?;
-        } else {
+/// Replaces the crates given on the command line by the alias of a renamed
+/// dependency with the spec of their crate, with a note for each.
+fn unalias_args(args: &mut Cli, workspace: &Workspace<'_>) {
+    let aliases = workspace.dependency_aliases();
+    let mut specs = args
+        .crates
+        .iter_mut()
+        .chain(args.clean.iter_mut())
+        .chain(args.dump_resolved_version.iter_mut())
+        .collect::<Vec<_>>();
+    match &mut args.command {
+        Some(
+            Command::Resolve { name, .. }
+            | Command::Rebase { name, .. }
+            | Command::Show { name, .. },
+        ) => specs.push(name),
+        Some(
+            Command::Apply { crates, .. }
+            | Command::Extract { crates, .. }
+            | Command::Init { crates, .. }
+            | Command::Status { crates, .. }
+            | Command::Remove { crates, .. },
+        ) => specs.extend(
+            crates
+                .iter_mut()
+                .filter(|spec| !is_patch_path(spec) && !is_patch_url(spec)),
+        ),
+        _ => {}
+    }
+    for spec in specs {
+        if let Some(package) = unalias(spec, &aliases) {
             info!(
-                "crate: {}, skip, {:?} already exists.",
-                pkg.name(),
-                &patch_target_path
+                "`{}` is the alias of a renamed dependency, taken as crate `{}`.",
+                spec, package
             );
-            return Ok(patch_target_path);
+            *spec = package;
         }
     }
-    let _ = copy(pkg.root(), patch_target_folder, &options)?;
-    Ok(patch_target_path)
 }
 
-fn find_cargo_toml(path: &Path) -> Result<PathBuf> {
-    let path = fs::canonicalize(path)?;
-    find_root_manifest_for_wd(&path)
+/// Whether `spec`, a crate name or a `name@version` spec as given on the
+/// command line and in `crates`, is for `pkg_id`.
+fn matches_spec(spec: &str, pkg_id: PackageId) -> bool {
+    PackageIdSpec::parse(spec).is_ok_and(|spec| spec.matches(pkg_id))
 }
 
-pub fn run() -> anyhow::Result<()> {
-    let args = {
-        let mut args = Cli::parse();
-        if let Some(idx) = args.crates.iter().position(|c| c == "patch-crate") {
-            args.crates.remove(idx);
-        }
-        args
-    };
-
-    let gctx = GlobalContext::default()?;
-    let _lock = gctx.acquire_package_cache_lock(CacheLockMode::Shared)?;
+/// The keys of a table in `crates` of the patch metadata.
+const CRATE_KEYS: [&str; 8] = [
+    "name",
+    "version",
+    "features",
+    "optional",
+    "group",
+    "allowed_paths",
+    "reason",
+    "upstream_pr",
+];
 
-    let cargo_toml_path = find_cargo_toml(&PathBuf::from("."))?;
+/// An entry of `crates` in the patch metadata: a crate name, or a table with
+/// the `name` of the crate and its own settings. The name may be a glob
+/// pattern such as `tokio*`, for all resolved crates it matches.
+#[derive(Clone)]
+struct CrateEntry<'a> {
+    name: Cow<'a, str>,
+    /// The name as listed, when it is the alias of a renamed dependency and
+    /// `name` the crate it stands for
+    alias: Option<Cow<'a, str>>,
+    settings: Option<&'a toml::Table>,
+}
 
-    let workspace = Workspace::new(&cargo_toml_path, &gctx)?;
+impl<'a> CrateEntry<'a> {
+    /// Whether the entry is for `pkg_id`, see [`matches_spec`], in the
+    /// `version` the entry asks for.
+    fn matches(&self, pkg_id: PackageId) -> bool {
+        let version = match self.setting("version") {
+            Result::Ok(version) => version.map(semver::VersionReq::parse),
+            Err(_) => return false,
+        };
+        let name = if self.is_pattern() {
+            self.matches_name(&pkg_id.name())
+        } else {
+            matches_spec(&self.name, pkg_id)
+        };
+        name && match version {
+            Some(Result::Ok(req)) => req.matches(pkg_id.version()),
+            Some(Err(_)) => false,
+            None => true,
+        }
+    }
 
-    let patches_folder = workspace.patches_folder();
+    /// Whether the entry is for crates named `name`, in any version.
+    fn matches_name(&self, name: &str) -> bool {
+        if self.is_pattern() {
+            glob::Pattern::new(&self.name).is_ok_and(|pattern| pattern.matches(name))
+        } else {
+            PackageIdSpec::parse(&self.name).is_ok_and(|spec| spec.name() == name)
+        }
+    }
 
-    let patch_target_folder = workspace.patch_target_folder();
-    let patch_target_tmp_folder = workspace.patch_target_tmp_folder();
+    fn is_pattern(&self) -> bool {
+        self.name.contains(['*', '?', '['])
+    }
 
-    let (pkg_set, resolve) = resolve_ws(&workspace)?;
-
-    if !args.crates.is_empty() {
-        info!("starting patch creation.");
-        if !patches_folder.exists() {
-            fs::create_dir_all(&patches_folder)?;
-        }
-        for n in args.crates.iter() {
-            // make patch
-            info!("crate: {}, starting patch creation.", n);
-            let pkg_id = resolve.query(n)?;
-            let pkg = pkg_set.get_one(pkg_id)?;
-            let patch_target_path = pkg.patch_target_path(&workspace)?;
-            let patch_target_tmp_path = copy_package(pkg, &patch_target_tmp_folder, true)?;
-            git::init(&patch_target_tmp_path)?;
-            git::destroy(&patch_target_path)?;
-            copy(
-                &patch_target_path,
-                &patch_target_tmp_folder,
-                &CopyOptions::new().overwrite(true).copy_inside(true),
-            )?;
-            let patch_file = patches_folder.join(format!(
-                "{}+{}.{}",
-                pkg_id.name(),
-                pkg_id.version(),
-                PATCH_EXT
+    /// The resolved crates of the entry: the dependencies whose names match
+    /// for a pattern, in the `version` and with the `features` the entry asks
+    /// for. It must match at least one, unless it is `optional`.
+    fn package_ids(&self, workspace: &Workspace<'_>, resolve: &Resolve) -> Result<Vec<PackageId>> {
+        let optional = self.setting_bool("optional")?.unwrap_or(false);
+        let version = self.setting("version")?;
+        if let Some(version) = version {
+            semver::VersionReq::parse(version).map_err(|err| {
+                anyhow!(
+                    "`version` of crate {} in patch metadata is not a version requirement: {}",
+                    self.name,
+                    err
+                )
+            })?;
+        }
+        let features = self.setting_strings("features")?.unwrap_or_default();
+        if !self.is_pattern() && version.is_none() && features.is_empty() {
+            return match resolve.query(&self.name) {
+                Result::Ok(id) => Ok(vec![id]),
+                // only a crate that isn't resolved at all, not an ambiguous name
+                Err(_) if optional && !resolve.iter().any(|id| self.matches(id)) => Ok(Vec::new()),
+                Err(err) => Err(err),
+            };
+        }
+        let members = workspace
+            .members()
+            .map(|m| m.package_id())
+            .collect::<HashSet<_>>();
+        let mut ids = resolve
+            .iter()
+            .filter(|id| !members.contains(id) && self.matches(*id))
+            .filter(|id| {
+                let enabled = resolve.features(*id);
+                features
+                    .iter()
+                    .all(|f| enabled.iter().any(|e| e.as_str() == *f))
+            })
+            .collect::<Vec<_>>();
+        if ids.is_empty() && !optional {
+            let mut wanted = String::new();
+            if let Some(version) = version {
+                wanted.push_str(&format!(" in version {}", version));
+            }
+            if !features.is_empty() {
+                wanted.push_str(&format!(" with the features {}", features.join(", ")));
+            }
+            return Err(anyhow!(
+                "`{}` in `crates` of patch metadata matches no resolved crate{}, `cargo patch-crate deps` lists the crates that can be patched",
+                self.name,
+                wanted
             ));
-            git::create_patch(&patch_target_tmp_path, &patch_file)?;
-            fs::remove_dir_all(&patch_target_tmp_folder)?;
-            info!("crate: {}, create patch successfully, {:?}", n, &patch_file);
         }
-    } else {
-        // apply patch
-        info!("applying patch");
+        ids.sort();
+        Ok(ids)
+    }
 
-        let custom_metadata = workspace.custom_metadata().into_iter().chain(
-            workspace
-                .members()
-                .flat_map(|member| member.manifest().custom_metadata()),
-        );
+    /// The keys of the table of this crate that mean nothing.
+    fn unknown_keys(&self) -> Vec<&'a str> {
+        self.settings
+            .into_iter()
+            .flat_map(|settings| settings.keys())
+            .map(String::as_str)
+            .filter(|key| !CRATE_KEYS.contains(key))
+            .collect()
+    }
+
+    fn parse(value: &'a toml::Value) -> Result<Self> {
+        let entry = Self::parse_value(value)?;
+        if entry.is_pattern() {
+            glob::Pattern::new(&entry.name).map_err(|err| {
+                anyhow!(
+                    "invalid pattern `{}` in `crates` of patch metadata: {}",
+                    entry.name,
+                    err
+                )
+            })?;
+        }
+        Ok(entry)
+    }
+
+    fn parse_value(value: &'a toml::Value) -> Result<Self> {
+        if let Some(name) = value.as_str() {
+            return Ok(CrateEntry {
+                name: Cow::Borrowed(name),
+                alias: None,
+                settings: None,
+            });
+        }
+        let settings = value.as_table().ok_or(anyhow!(
+            "`crates` in patch metadata must only contain crate names or tables"
+        ))?;
+        let name = settings
+            .get("name")
+            .and_then(|name| name.as_str())
+            .ok_or(anyhow!(
+                "crate table {} in patch metadata has no `name`",
+                value
+            ))?;
+        Ok(CrateEntry {
+            name: Cow::Borrowed(name),
+            alias: None,
+            settings: Some(settings),
+        })
+    }
 
-        let mut crates_to_patch = custom_metadata
-            .flat_map(|m| {
-                m.as_table()
-                    .and_then(|table| table.get("patch"))
-                    .into_iter()
-                    .flat_map(|patch| patch.as_table())
-                    .flat_map(|patch| patch.get("crates"))
-                    .filter_map(|crates| crates.as_array())
+    /// The string array setting `key` of this crate.
+    fn setting_strings(&self, key: &str) -> Result<Option<Vec<&'a str>>> {
+        let Some(value) = self.settings.and_then(|settings| settings.get(key)) else {
+            return Ok(None);
+        };
+        value
+            .as_array()
+            .and_then(|values| {
+                values
+                    .iter()
+                    .map(|v| v.as_str())
+                    .collect::<Option<Vec<_>>>()
             })
-            .flatten()
-            .flat_map(|s| s.as_str())
-            .map(|n| resolve.query(n).and_then(|id| pkg_set.get_one(id)))
-            .collect::<Result<HashSet<_>>>()?;
+            .map(Some)
+            .ok_or(anyhow!(
+                "`{}` of crate {} in patch metadata must be an array of strings",
+                key,
+                self.name
+            ))
+    }
 
-        if args.force {
-            info!("Cleaning up patch folder.");
-            workspace.clean_patch_folder()?;
+    /// The boolean setting `key` of this crate.
+    fn setting_bool(&self, key: &str) -> Result<Option<bool>> {
+        match self.settings.and_then(|settings| settings.get(key)) {
+            Some(value) => value.as_bool().map(Some).ok_or(anyhow!(
+                "`{}` of crate {} in patch metadata must be a boolean",
+                key,
+                self.name
+            )),
+            None => Ok(None),
         }
+    }
 
-        if patches_folder.exists() {
-            for entry in fs::read_dir(patches_folder)? {
-                let entry = entry?;
-                if entry.metadata()?.is_file()
-                    && entry.path().extension() == Some(OsStr::new(PATCH_EXT))
-                {
-                    let patch_file = entry.path();
-                    let filename = patch_file
-                        .file_stem()
-                        .and_then(|s| s.to_str())
-                        .ok_or(anyhow!("Patch file does not have a name"))?;
-
-                    if let Some((pkg_name, version)) = filename.split_once('+') {
-                        let pkg_id = resolve.query(format!("{}@{}", pkg_name, version).as_str())?;
-                        let pkg = pkg_set.get_one(pkg_id)?;
-                        if !crates_to_patch.contains(&pkg) {
-                            warn!(
-                                "crate: {}, {} is not in the [package.metadata.patch] section of Cargo.toml. Did you forget to add it?",
-                                pkg_name, pkg_name
-                            );
-                            continue;
-                        }
-
-                        let patch_target_path = pkg.patch_target_path(&workspace)?;
-                        if !patch_target_path.exists() {
-                            copy_package(pkg, &patch_target_folder, args.force)?;
-                            info!("crate: {}, applying patch started.", pkg_name);
-                            git::init(&patch_target_path)?;
-                            git::apply(&patch_target_path, &patch_file)?;
-                            git::destroy(&patch_target_path)?;
-                            info!(
-                                "crate: {}, successfully applied patch {:?}.",
-                                pkg_name, patch_file
-                            );
-                        } else {
-                            info!("crate: {}, skip applying patch, {:?} already exists. Did you forget to add `--force`?", pkg_name, patch_target_path);
-                        }
-                        crates_to_patch.remove(pkg);
-                    }
+    /// The string setting `key` of this crate.
+    fn setting(&self, key: &str) -> Result<Option<&'a str>> {
+        match self.settings.and_then(|settings| settings.get(key)) {
+            Some(value) => value.as_str().map(Some).ok_or(anyhow!(
+                "`{}` of crate {} in patch metadata must be a string",
+                key,
+                self.name
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+trait WorkspaceExt {
+    fn patch_bundle_folder(&self) -> PathBuf;
+    fn patch_download_folder(&self) -> PathBuf;
+    fn patch_metadata(&self) -> Vec<(&str, &toml::Table)>;
+    fn root_patch_metadata(&self) -> Vec<&toml::Table>;
+    fn root_patch_metadata_strings(&self, key: &str) -> Result<Vec<String>>;
+    fn patch_crates(&self) -> Result<Vec<CrateEntry<'_>>>;
+    fn dependency_aliases(&self) -> Vec<(InternedString, InternedString)>;
+    fn lock_patch_folder(&self) -> Result<FileLock>;
+}
+
+/// Where a run keeps its patches and copies, from the command line and the
+/// `default_args` of the workspace.
+#[derive(Clone, Debug, Default)]
+struct PatchConfig {
+    /// The patches folder given with `--patch-dir` or `PATCH_CRATE_DIR`
+    patch_dir: Option<PathBuf>,
+    /// The folder given with `--target-dir` that replaces `target/patch`
+    target_dir: Option<PathBuf>,
+    /// The folder given with `--pristine-dir`, joined to the workspace root
+    pristine_dir: Option<PathBuf>,
+    /// Whether to extract the crates missing from `--pristine-dir` into it:
+    /// `--populate-pristine-dir` was given to a command that copies crates,
+    /// and not with `--dry-run`
+    populate_pristine_dir: bool,
+    /// Whether `--nested` was given
+    nested: bool,
+}
+
+impl PatchConfig {
+    fn new(cli: &Cli, args: &Cli, workspace: &Workspace<'_>) -> Self {
+        PatchConfig {
+            patch_dir: patch_dir(cli, args),
+            target_dir: args.target_dir.clone(),
+            pristine_dir: args
+                .resolving
+                .pristine_dir
+                .as_ref()
+                .map(|pristine_dir| workspace.root().join(pristine_dir)),
+            populate_pristine_dir: args.resolving.populate_pristine_dir
+                && populates_pristine_dir(args),
+            nested: args.create.nested,
+        }
+    }
+}
+
+/// A workspace along with the [`PatchConfig`] of the run.
+struct PatchWorkspace<'gctx> {
+    workspace: Workspace<'gctx>,
+    config: PatchConfig,
+}
+
+impl<'gctx> std::ops::Deref for PatchWorkspace<'gctx> {
+    type Target = Workspace<'gctx>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.workspace
+    }
+}
+
+/// The environment variable that sets the patches folder.
+const PATCH_DIR_ENV: &str = "PATCH_CRATE_DIR";
+
+/// The patches folder to use instead of `patches/`: `--patch-dir` on the
+/// command line wins over `PATCH_CRATE_DIR`, which wins over `--patch-dir` in
+/// `default_args`.
+fn patch_dir(cli: &Cli, args: &Cli) -> Option<PathBuf> {
+    cli.patch_dir
+        .clone()
+        .or_else(|| {
+            std::env::var_os(PATCH_DIR_ENV)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+        })
+        .or_else(|| args.patch_dir.clone())
+}
+
+impl WorkspaceExt for Workspace<'_> {
+    fn patch_bundle_folder(&self) -> PathBuf {
+        self.root().join("target/patch-bundle/")
+    }
+    fn patch_download_folder(&self) -> PathBuf {
+        self.root().join("target/patch-downloads/")
+    }
+
+    /// The `[workspace.metadata.patch]` table followed by the
+    /// `[package.metadata.patch]` tables of all members, each with where it
+    /// comes from for error messages.
+    fn patch_metadata(&self) -> Vec<(&str, &toml::Table)> {
+        self.custom_metadata()
+            .map(|m| ("the workspace", m))
+            .into_iter()
+            .chain(self.members().filter_map(|member| {
+                Some((member.name().as_str(), member.manifest().custom_metadata()?))
+            }))
+            .filter_map(|(origin, m)| Some((origin, m.get("patch")?.as_table()?)))
+            .collect()
+    }
+
+    /// The `[workspace.metadata.patch]` and root `[package.metadata.patch]` tables.
+    fn root_patch_metadata(&self) -> Vec<&toml::Table> {
+        let root_package = match self.root_maybe() {
+            MaybePackage::Package(pkg) => pkg.manifest().custom_metadata(),
+            MaybePackage::Virtual(_) => None,
+        };
+        self.custom_metadata()
+            .into_iter()
+            .chain(root_package)
+            .filter_map(|m| m.get("patch"))
+            .filter_map(|patch| patch.as_table())
+            .collect()
+    }
+
+    /// Collects the string array `key` of the root patch metadata tables.
+    fn root_patch_metadata_strings(&self, key: &str) -> Result<Vec<String>> {
+        let mut strings = Vec::new();
+        for metadata in self.root_patch_metadata() {
+            if let Some(value) = metadata.get(key) {
+                let values = value
+                    .as_array()
+                    .ok_or(anyhow!("`{}` in patch metadata must be an array", key))?;
+                for value in values {
+                    let value = value.as_str().ok_or(anyhow!(
+                        "`{}` in patch metadata must only contain strings",
+                        key
+                    ))?;
+                    strings.push(value.to_string());
                 }
             }
         }
-        for pkg in crates_to_patch {
-            copy_package(pkg, &patch_target_folder, args.force)?;
+        Ok(strings)
+    }
+
+    /// The `crates` entries of all patch metadata tables, one per crate. A
+    /// crate listed by several members takes the settings of the member that
+    /// has some, and fails if two members give it different settings, as
+    /// they share one patch and one override.
+    fn patch_crates(&self) -> Result<Vec<CrateEntry<'_>>> {
+        let mut entries: Vec<(&str, CrateEntry<'_>)> = Vec::new();
+        let aliases = self.dependency_aliases();
+        for (origin, patch) in self.patch_metadata() {
+            let Some(crates) = patch.get("crates") else {
+                continue;
+            };
+            let crates = crates
+                .as_array()
+                .ok_or(anyhow!("`crates` in patch metadata must be an array"))?;
+            for entry in crates.iter().map(CrateEntry::parse) {
+                let mut entry = entry?;
+                if let Some(name) = unalias(&entry.name, &aliases) {
+                    entry.alias = Some(std::mem::replace(&mut entry.name, Cow::Owned(name)));
+                }
+                let Some(listed) = entries.iter_mut().find(|(_, e)| e.name == entry.name) else {
+                    entries.push((origin, entry));
+                    continue;
+                };
+                match (listed.1.settings, entry.settings) {
+                    (None, Some(_)) => *listed = (origin, entry),
+                    (Some(a), Some(b)) if a != b => {
+                        return Err(anyhow!(
+                            "crate {} is listed with different settings in the patch metadata of {} and {}, but they share one patch and one override; give it the same settings in both",
+                            entry.name,
+                            listed.0,
+                            origin
+                        ));
+                    }
+                    _ => {}
+                }
+            }
         }
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
     }
 
-    info!("Done");
-    Ok(())
+    /// The dependencies of the members renamed with `package = "..."`, as
+    /// pairs of the name they are given and the name of their crate. A name
+    /// that is also the name of a dependency is left to that crate.
+    fn dependency_aliases(&self) -> Vec<(InternedString, InternedString)> {
+        let deps = self
+            .members()
+            .flat_map(|member| member.dependencies())
+            .collect::<Vec<_>>();
+        let mut aliases = deps
+            .iter()
+            .filter_map(|dep| Some((dep.explicit_name_in_toml()?, dep.package_name())))
+            .filter(|(alias, _)| !deps.iter().any(|dep| dep.package_name() == *alias))
+            .collect::<Vec<_>>();
+        aliases.sort();
+        aliases.dedup();
+        aliases
+    }
+
+    /// Locks `target/patch.lock` for as long as the lock is held, waiting for
+    /// another run holding it, so that two runs don't both write to
+    /// `target/patch`. It sits next to the folder, which may be removed.
+    fn lock_patch_folder(&self) -> Result<FileLock> {
+        let target = Filesystem::new(self.root().join("target"));
+        Ok(target.open_rw_exclusive_create("patch.lock", self.gctx(), "the patch folder")?)
+    }
+}
+
+impl PatchWorkspace<'_> {
+    fn patches_folder(&self) -> PathBuf {
+        match &self.config.patch_dir {
+            // relative to the workspace root, an absolute path replaces it
+            Some(patch_dir) => self.root().join(patch_dir),
+            None => self.root().join("patches/"),
+        }
+    }
+    fn patch_target_folder(&self) -> PathBuf {
+        match &self.config.target_dir {
+            Some(target_dir) => self.root().join(target_dir),
+            None => self.root().join("target/patch/"),
+        }
+    }
+    /// Next to the patch folder, `<folder>-tmp`, so that the staged crates
+    /// are moved on the same file system.
+    fn patch_target_tmp_folder(&self) -> PathBuf {
+        let folder = self.patch_target_folder();
+        match (&self.config.target_dir, folder.file_name()) {
+            (Some(_), Some(name)) => {
+                folder.with_file_name(format!("{}-tmp", name.to_string_lossy()))
+            }
+            _ => self.root().join("target/patch-tmp/"),
+        }
+    }
+    fn clean_patch_folder(&self) -> Result<()> {
+        let path = self.patch_target_folder();
+        if path.exists() {
+            fs::remove_dir_all(self.patch_target_folder())?;
+        }
+        Ok(())
+    }
 }
 
-mod log {
-    pub use paris::*;
+/// How the dependency graph is resolved, from the `resolve` table of the root
+/// patch metadata with the command line flags taking precedence.
+struct ResolveOptions {
+    dev: bool,
+    all_features: bool,
+    features: Vec<String>,
+    default_features: bool,
+    targets: Vec<String>,
 }
 
-mod git {
-    use std::{ffi::OsStr, fs, path::Path, process::Command};
+impl ResolveOptions {
+    fn new(args: &Cli, workspace: &Workspace<'_>) -> Result<Self> {
+        let mut options = ResolveOptions {
+            dev: false,
+            all_features: true,
+            features: Vec::new(),
+            default_features: true,
+            targets: Vec::new(),
+        };
+        let metadata = workspace
+            .root_patch_metadata()
+            .into_iter()
+            .find_map(|m| m.get("resolve"));
+        if let Some(metadata) = metadata {
+            let metadata = metadata
+                .as_table()
+                .ok_or(anyhow!("`resolve` in patch metadata must be a table"))?;
+            let strings = |key: &str, value: &toml::Value| {
+                value
+                    .as_array()
+                    .and_then(|values| {
+                        values
+                            .iter()
+                            .map(|v| v.as_str().map(str::to_string))
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .ok_or(anyhow!(
+                        "`resolve.{}` in patch metadata must be an array of strings",
+                        key
+                    ))
+            };
+            let boolean = |key: &str, value: &toml::Value| {
+                value.as_bool().ok_or(anyhow!(
+                    "`resolve.{}` in patch metadata must be a boolean",
+                    key
+                ))
+            };
+            if let Some(features) = metadata.get("features") {
+                options.features = strings("features", features)?;
+                options.all_features = false;
+            }
+            if let Some(default_features) = metadata.get("default_features") {
+                options.default_features = boolean("default_features", default_features)?;
+                options.all_features = false;
+            }
+            for (key, value) in metadata {
+                match key.as_str() {
+                    "dev" => options.dev = boolean(key, value)?,
+                    "all_features" => options.all_features = boolean(key, value)?,
+                    "features" | "default_features" => {}
+                    "targets" => options.targets = strings(key, value)?,
+                    _ => {
+                        return Err(anyhow!(
+                            "unknown key `{}` in `resolve` of patch metadata, expected `dev`, `all_features`, `features`, `default_features` or `targets`",
+                            key
+                        ))
+                    }
+                }
+            }
+        }
+
+        options.dev |= args.resolving.dev;
+        if !args.resolving.features.is_empty() {
+            options.features = args.resolving.features.clone();
+            options.all_features = false;
+        }
+        if args.resolving.no_default_features {
+            options.default_features = false;
+            options.all_features = false;
+        }
+        options.all_features |= args.resolving.all_features;
+        if !args.resolving.targets.is_empty() {
+            options.targets = args.resolving.targets.clone();
+        }
 
-    pub fn init(repo_dir: &Path) -> anyhow::Result<()> {
-        Command::new("git")
-            .current_dir(repo_dir)
-            .args(["init"])
-            .output()?;
-        Command::new("git")
-            .current_dir(repo_dir)
-            .args(["add", "."])
-            .output()?;
-        Command::new("git")
-            .current_dir(repo_dir)
-            .args(["commit", "-m", "zero"])
-            .output()?;
-        Ok(())
+        for feature in options.features.iter() {
+            // `dep/feature` is checked by the resolver
+            if feature.contains('/') {
+                continue;
+            }
+            let known = workspace
+                .members()
+                .any(|member| member.summary().features().contains_key(feature.as_str()));
+            if !known {
+                return Err(anyhow!(
+                    "feature `{}` is not defined by any workspace member",
+                    feature
+                ));
+            }
+        }
+        Ok(options)
     }
 
-    pub fn apply(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<()> {
-        #[cfg(target_os = "windows")]
-        let patch_file = patch_file
-            .to_string_lossy()
-            .to_string()
-            .trim_start_matches(r#"\\?\"#)
-            .to_string();
-        #[cfg(not(target_os = "windows"))]
-        let patch_file = patch_file.to_string_lossy().to_string();
-
-        let out = Command::new("git")
-            .current_dir(repo_dir)
-            .args([
-                "apply",
-                "--ignore-space-change",
-                "--ignore-whitespace",
-                "--whitespace=nowarn",
-                &patch_file,
-            ])
-            .output()?;
-
-        if !out.status.success() {
-            anyhow::bail!(String::from_utf8(out.stderr)?)
+    fn cli_features(&self) -> Result<CliFeatures> {
+        CliFeatures::from_command_line(&self.features, self.all_features, self.default_features)
+    }
+
+    fn has_dev_units(&self) -> HasDevUnits {
+        if self.dev {
+            HasDevUnits::Yes
+        } else {
+            HasDevUnits::No
         }
-        Ok(())
     }
-    pub fn destroy(repo_dir: &Path) -> anyhow::Result<()> {
-        let git_dir = repo_dir.join(".git");
-        if git_dir.exists() {
-            fs::remove_dir_all(git_dir)?;
+
+    /// The packages that are dependencies on one of `targets`, `None` when
+    /// there is no target. Unknown triples are reported by rustc.
+    fn target_packages(
+        &self,
+        workspace: &Workspace<'_>,
+        resolve: &Resolve,
+        targets: &[String],
+    ) -> Result<Option<HashSet<PackageId>>> {
+        if targets.is_empty() {
+            return Ok(None);
         }
-        Ok(())
+        let kinds = targets
+            .iter()
+            .map(|target| CompileTarget::new(target).map(CompileKind::Target))
+            .collect::<Result<Vec<_>>>()?;
+        let target_data = RustcTargetData::new(workspace, &kinds)
+            .map_err(|err| anyhow!("invalid `targets` {:?}: {:#}", targets, err))?;
+
+        let mut packages = HashSet::new();
+        let mut pending = workspace
+            .members()
+            .map(|member| member.package_id())
+            .collect::<Vec<_>>();
+        while let Some(pkg_id) = pending.pop() {
+            if !packages.insert(pkg_id) {
+                continue;
+            }
+            for (dep_id, deps) in resolve.deps(pkg_id) {
+                let activated = deps.iter().any(|dep| match dep.kind() {
+                    DepKind::Development if !self.dev => false,
+                    DepKind::Build => target_data.dep_platform_activated(dep, CompileKind::Host),
+                    _ => kinds
+                        .iter()
+                        .any(|kind| target_data.dep_platform_activated(dep, *kind)),
+                });
+                if activated {
+                    pending.push(dep_id);
+                }
+            }
+        }
+        Ok(Some(packages))
+    }
+}
+
+fn resolve_ws<'a>(ws: &Workspace<'a>, args: &Cli) -> Result<(PackageSet<'a>, Resolve)> {
+    let options = ResolveOptions::new(args, ws)?;
+    let prev = match &args.resolving.lockfile {
+        Some(lockfile) => Some(load_lockfile(ws, &ws.root().join(lockfile))?),
+        None => load_pkg_lockfile(ws)?,
+    };
+    if args.resolving.locked && prev.is_none() {
+        return Err(anyhow!(
+            "`--locked` needs a lockfile, but {:?} does not exist",
+            ws.root().join("Cargo.lock")
+        ));
+    }
+    // cargo only resolves the whole workspace with all features, so fewer
+    // features are requested for each member
+    let specs = if options.all_features {
+        Vec::new()
+    } else {
+        ws.members()
+            .map(|member| member.package_id().to_spec())
+            .collect()
+    };
+    let cli_features = options.cli_features()?;
+    // cargo retries each request that fails spuriously, the whole resolution
+    // is tried again when one still fails, e.g. a registry that stays down
+    // for a while; with `--offline` nothing is requested to fail
+    let mut retry = Retry::new(ws.gctx())?;
+    let mut attempts = 1;
+    let (packages, resolve) = loop {
+        let result = retry.r#try(|| {
+            // the source config carries `[source]` replacements such as
+            // `replace-with` mirrors, so resolving and downloading go through
+            // them. Resolving only reads manifests, no build script of any
+            // crate is run.
+            let mut registry = PackageRegistry::new_with_source_config(
+                ws.gctx(),
+                SourceConfigMap::new(ws.gctx())?,
+            )?;
+            registry.lock_patches();
+            let resolve: Resolve = resolve_with_previous(
+                &mut registry,
+                ws,
+                &cli_features,
+                options.has_dev_units(),
+                prev.as_ref(),
+                None,
+                &specs,
+                false,
+            )?;
+            let packages = get_resolved_packages(&resolve, registry)?;
+            Result::Ok((packages, resolve))
+        });
+        match result {
+            RetryResult::Success(resolved) => break resolved,
+            RetryResult::Err(err) if attempts > 1 => {
+                return Err(err.context(format!(
+                    "resolving failed after {} attempts on network errors, retry with more `--network-retries` or a longer `--network-timeout`",
+                    attempts
+                )));
+            }
+            RetryResult::Err(err) => return Err(err),
+            RetryResult::Retry(sleep) => {
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(sleep));
+            }
+        }
+    };
+    if let (true, Some(prev)) = (args.resolving.locked, &prev) {
+        check_locked(&resolve, prev)?;
     }
-    pub fn create_patch(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<()> {
-        Command::new("git")
-            .current_dir(repo_dir)
-            .args(["add", "."])
-            .output()?;
+    Ok((packages, resolve))
+}
 
-        let out = Command::new("git")
-            .current_dir(repo_dir)
-            .args([OsStr::new("diff"), OsStr::new("--staged")])
-            .output()?;
+/// Reads the lockfile at `path` like cargo reads the `Cargo.lock` of `ws`.
+fn load_lockfile(ws: &Workspace<'_>, path: &Path) -> Result<Resolve> {
+    use anyhow::Context as _;
+    let content = fs::read_to_string(path)
+        .map_err(|err| anyhow!("cannot read the lockfile {:?}: {}", path, err))?;
+    let encoded: cargo::core::resolver::EncodableResolve = toml::from_str(&content)
+        .with_context(|| format!("failed to parse lock file at: {}", path.display()))?;
+    encoded
+        .into_resolve(&content, ws)
+        .with_context(|| format!("failed to parse lock file at: {}", path.display()))
+}
 
-        if out.status.success() {
-            fs::write(patch_file, out.stdout)?;
+/// Checks that every package of `resolve` is the one `Cargo.lock` pins, with
+/// the same source and checksum. As crates are resolved without `[patch]`, a
+/// crate that is overridden by its copy in `target/patch` is locked as a
+/// path package of the same version instead.
+fn check_locked(resolve: &Resolve, locked: &Resolve) -> Result<()> {
+    let mut mismatches = Vec::new();
+    for pkg_id in resolve.iter() {
+        if pkg_id.source_id().is_path() {
+            continue;
         }
-        Ok(())
+        let pinned = locked.iter().any(|id| id == pkg_id)
+            || locked.iter().any(|id| {
+                id.source_id().is_path()
+                    && id.name() == pkg_id.name()
+                    && id.version() == pkg_id.version()
+            });
+        let checksum = resolve.checksums().get(&pkg_id).cloned().flatten();
+        let locked_checksum = locked.checksums().get(&pkg_id).cloned().flatten();
+        if !pinned {
+            mismatches.push(format!("  - {} is not in the lockfile", pkg_id));
+        } else if locked_checksum.is_some() && checksum != locked_checksum {
+            mismatches.push(format!(
+                "  - {} has a different checksum than in the lockfile",
+                pkg_id
+            ));
+        }
+    }
+    if !mismatches.is_empty() {
+        return Err(anyhow!(
+            "the lockfile needs to be updated but `--locked` was passed:\n{}",
+            mismatches.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// The stem of a patch file, `foo+1.0.0` of `foo+1.0.0.patch` and of the
+/// compressed `foo+1.0.0.patch.gz`, or `None` for any other file. A patch in
+/// a crate folder like `foo/1.0.0/fix.patch` has the stem of the flat name it
+/// stands for, `foo+1.0.0`, or `foo+1.0.0.01-fix-a` for `01-fix-a.patch`.
+fn patch_stem(patch_file: &Path) -> Option<String> {
+    let name = patch_file.file_name()?.to_str()?;
+    let name = name.strip_suffix(&format!(".{}", GZ_EXT)).unwrap_or(name);
+    let stem = name.strip_suffix(&format!(".{}", PATCH_EXT))?;
+    match nested_patch(patch_file) {
+        Some((crate_name, version, stem)) if is_patch_index(stem) => {
+            Some(format!("{}+{}.{}", crate_name, version, stem))
+        }
+        Some((crate_name, version, _)) => Some(format!("{}+{}", crate_name, version)),
+        None => Some(stem.to_string()),
+    }
+}
+
+/// The crate, version and file stem of a patch in a crate folder
+/// `<crate>/<version>/<stem>.patch`, whose file name has no `+`.
+fn nested_patch(patch_file: &Path) -> Option<(&str, &str, &str)> {
+    let name = patch_file.file_name()?.to_str()?;
+    if name.contains('+') {
+        return None;
+    }
+    let stem = name.split_once(&format!(".{}", PATCH_EXT))?.0;
+    let version_folder = patch_file.parent()?;
+    let version = version_folder.file_name()?.to_str()?;
+    let crate_name = version_folder.parent()?.file_name()?.to_str()?;
+    is_patch_version(version).then_some((crate_name, version, stem))
+}
+
+/// Whether `index` is the index of a numbered patch, `01` or `01-fix-a`.
+fn is_patch_index(index: &str) -> bool {
+    let number = index.split_once('-').map_or(index, |(number, _)| number);
+    !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The name a patch file is recorded under: its path below the patches
+/// folder, which is the file name unless it is in a crate folder.
+fn patch_file_key(patch_file: &Path) -> String {
+    let file_name = patch_file
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    match nested_patch(patch_file) {
+        Some((crate_name, version, _)) => format!("{}/{}/{}", crate_name, version, file_name),
+        None => file_name,
+    }
+}
+
+/// The patch files in `patches_folder`, both the flat ones and those in crate
+/// folders like `foo/1.0.0/`, sorted by path.
+fn patch_files_in(patches_folder: &Path) -> Result<Vec<PathBuf>> {
+    let mut patch_files = Vec::new();
+    if !patches_folder.exists() {
+        return Ok(patch_files);
+    }
+    for entry in fs::read_dir(patches_folder)? {
+        let path = entry?.path();
+        if path.is_file() {
+            if patch_stem(&path).is_some() {
+                patch_files.push(path);
+            }
+            continue;
+        }
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if !path.is_dir() || hidden {
+            continue;
+        }
+        for version in fs::read_dir(&path)? {
+            let version = version?.path();
+            if !version.is_dir() {
+                continue;
+            }
+            for patch_file in fs::read_dir(&version)? {
+                let patch_file = patch_file?.path();
+                if patch_file.is_file()
+                    && patch_stem(&patch_file).is_some()
+                    && nested_patch(&patch_file).is_some()
+                {
+                    patch_files.push(patch_file);
+                }
+            }
+        }
+    }
+    patch_files.sort();
+    Ok(patch_files)
+}
+
+/// Whether new patches in `patches_folder` are written into crate folders:
+/// with `--nested`, or when some patch already is in one.
+fn nested_layout(patches_folder: &Path, nested: bool) -> Result<bool> {
+    if nested {
+        return Ok(true);
+    }
+    Ok(patch_files_in(patches_folder)?
+        .iter()
+        .any(|patch_file| nested_patch(patch_file).is_some()))
+}
+
+/// The patch file `<stem>.patch` in `patches_folder`, or `<stem>.patch.gz`
+/// when only that one exists. A patch in the crate folder of `stem` is used
+/// when there is no flat one, and a new patch goes into the crate folder in
+/// the [`nested_layout`].
+fn existing_patch_file(patches_folder: &Path, stem: &str, nested: bool) -> PathBuf {
+    let plain = patches_folder.join(format!("{}.{}", stem, PATCH_EXT));
+    let compressed = patches_folder.join(format!("{}.{}.{}", stem, PATCH_EXT, GZ_EXT));
+    if plain.exists() {
+        return plain;
+    }
+    if compressed.exists() {
+        return compressed;
+    }
+    let patch_files = patch_files_in(patches_folder).unwrap_or_default();
+    if let Some(patch_file) = patch_files.into_iter().find(|patch_file| {
+        nested_patch(patch_file).is_some() && patch_stem(patch_file).as_deref() == Some(stem)
+    }) {
+        return patch_file;
+    }
+    if nested_layout(patches_folder, nested).unwrap_or_default() {
+        nested_patch_file(patches_folder, stem)
+    } else {
+        plain
+    }
+}
+
+/// Where the patch of `stem` goes in a crate folder: `foo/1.0.0/foo.patch`
+/// for `foo+1.0.0`, and `foo/1.0.0/01-fix-a.patch` for `foo+1.0.0.01-fix-a`.
+fn nested_patch_file(patches_folder: &Path, stem: &str) -> PathBuf {
+    let (base, index) = split_patch_stem(stem);
+    match base.split_once('+') {
+        Some((crate_name, version)) => patches_folder.join(crate_name).join(version).join(format!(
+            "{}.{}",
+            index.unwrap_or(crate_name),
+            PATCH_EXT
+        )),
+        None => patches_folder.join(format!("{}.{}", stem, PATCH_EXT)),
+    }
+}
+
+/// The patch file in `patches_folder` for the crate `pkg_id`: the one named
+/// after its version or revision, or else one whose version range matches.
+fn find_patch_file(patches_folder: &Path, pkg_id: PackageId) -> Result<Option<PathBuf>> {
+    let exact = existing_patch_file(patches_folder, &patch_file_stem(pkg_id), false);
+    if exact.exists() {
+        return Ok(Some(exact));
+    }
+    if !patches_folder.exists() {
+        return Ok(None);
+    }
+    for patch_file in patch_files_in(patches_folder)? {
+        let stem = patch_stem(&patch_file).unwrap_or_default();
+        let matches = stem
+            .split_once('+')
+            .filter(|(name, _)| *name == pkg_id.name().as_str())
+            .and_then(|(_, version)| version_range(version))
+            .is_some_and(|req| req.matches(pkg_id.version()));
+        if matches {
+            return Ok(Some(patch_file));
+        }
+    }
+    Ok(None)
+}
+
+/// The patch files of the crate `pkg_id` in the order they are applied: the
+/// one `find_patch_file` finds, followed by the numbered ones of the same name.
+fn find_patch_files(patches_folder: &Path, pkg_id: PackageId) -> Result<Vec<PathBuf>> {
+    let found = find_patch_file(patches_folder, pkg_id)?;
+    let stem = match &found {
+        Some(patch_file) => patch_stem(patch_file).unwrap_or_default(),
+        None => patch_file_stem(pkg_id),
+    };
+    let mut patch_files = found.into_iter().collect::<Vec<_>>();
+    patch_files.extend(numbered_patch_files(patches_folder, &stem)?);
+    Ok(patch_files)
+}
+
+/// Splits the index off the version part of a numbered patch file name like
+/// `<name>+<version>.01-fix-a`, one of several patches of a crate applied in
+/// the order of their index.
+fn patch_index(version: &str) -> (&str, Option<&str>) {
+    if is_patch_version(version) {
+        return (version, None);
+    }
+    for (i, _) in version.rmatch_indices('.') {
+        let (base, index) = (&version[..i], &version[i + 1..]);
+        if is_patch_index(index) && is_patch_version(base) {
+            return (base, Some(index));
+        }
+    }
+    (version, None)
+}
+
+/// Whether the part of a patch file name after the `+` is one a patch is
+/// made for: a version, a version range or a git revision.
+fn is_patch_version(version: &str) -> bool {
+    semver::Version::parse(version).is_ok()
+        || version_range(version).is_some()
+        || version
+            .strip_prefix(GIT_REV_PREFIX)
+            .is_some_and(|rev| !rev.is_empty() && rev.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// The file stem of a patch without the index of a numbered patch, and that
+/// index.
+fn split_patch_stem(stem: &str) -> (String, Option<&str>) {
+    match stem.split_once('+') {
+        Some((name, version)) => {
+            let (version, index) = patch_index(version);
+            (format!("{}+{}", name, version), index)
+        }
+        None => (stem.to_string(), None),
+    }
+}
+
+/// The numbered patches `<stem>.<index>.patch` in `patches_folder`, sorted by
+/// index.
+fn numbered_patch_files(patches_folder: &Path, stem: &str) -> Result<Vec<PathBuf>> {
+    let mut patch_files = Vec::new();
+    if !patches_folder.exists() {
+        return Ok(patch_files);
+    }
+    for patch_file in patch_files_in(patches_folder)? {
+        let numbered = patch_stem(&patch_file).is_some_and(|patch_stem| {
+            let (base, index) = split_patch_stem(&patch_stem);
+            base == stem && index.is_some()
+        });
+        if numbered {
+            patch_files.push(patch_file);
+        }
+    }
+    patch_files.sort_by_key(|patch_file| patch_stem(patch_file));
+    Ok(patch_files)
+}
+
+/// The patch files in `patches_folder` grouped by crate, each group in the
+/// order it is applied: `foo+1.0.0.patch`, `foo+1.0.0.01-fix-a.patch` and
+/// `foo+1.0.0.02.patch` make up one group.
+fn patch_groups(patches_folder: &Path) -> Result<Vec<Vec<PathBuf>>> {
+    let mut patch_files = Vec::new();
+    for patch_file in patch_files_in(patches_folder)? {
+        let stem = patch_stem(&patch_file).unwrap_or_default();
+        let (base, index) = split_patch_stem(&stem);
+        let index = index.map(str::to_string);
+        patch_files.push((base, index, patch_file));
+    }
+    patch_files.sort();
+    let mut groups: Vec<(String, Vec<PathBuf>)> = Vec::new();
+    for (base, _, patch_file) in patch_files {
+        match groups.last_mut() {
+            Some((stem, group)) if *stem == base => group.push(patch_file),
+            _ => groups.push((base, vec![patch_file])),
+        }
+    }
+    Ok(groups.into_iter().map(|(_, group)| group).collect())
+}
+
+/// The patch files in `patches_folder` that applying can't make sense of,
+/// with what is wrong with them: a name that is not
+/// `<crate>+<version>.patch`, or content with no diff in it. Placeholders,
+/// which hold no more than a header, are fine.
+fn malformed_patches(patches_folder: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut malformed = Vec::new();
+    if !patches_folder.exists() {
+        return Ok(malformed);
+    }
+    // `Reason:` and the other fields written above the diff
+    let is_header = |line: &str| {
+        line.split_once(": ").is_some_and(|(field, _)| {
+            !field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+    };
+    for patch_file in patch_files_in(patches_folder)? {
+        let stem = patch_stem(&patch_file).unwrap_or_default();
+        let (base, _) = split_patch_stem(&stem);
+        let named = base
+            .split_once('+')
+            .is_some_and(|(name, version)| !name.is_empty() && is_patch_version(version));
+        if !named {
+            malformed.push((
+                patch_file,
+                format!("not named `<crate>+<version>.{}`", PATCH_EXT),
+            ));
+            continue;
+        }
+        let content = match patch::read(&patch_file) {
+            Result::Ok(content) => content,
+            Err(err) => {
+                malformed.push((patch_file, format!("cannot be read: {}", err)));
+                continue;
+            }
+        };
+        let files = patch::parse(&content);
+        let problem = if files.is_empty() {
+            content
+                .lines()
+                .any(|line| !line.trim().is_empty() && !is_header(line))
+                .then_some("holds no diff")
+        } else if files
+            .iter()
+            .any(|file| file.old_path.is_none() && file.new_path.is_none())
+        {
+            Some("has a hunk that names no file")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            malformed.push((patch_file, problem.to_string()));
+        }
+    }
+    malformed.sort();
+    Ok(malformed)
+}
+
+/// Fails when several patch files in `patches_folder` are for the same crate
+/// version, like `foo+1.0.0.patch` next to `foo+1.0.0.patch.gz` or a
+/// `foo+1.0.0 (copy).patch` left by a merge. Numbered patches of a crate
+/// differ in their index and don't count.
+fn check_duplicate_patches(patches_folder: &Path) -> Result<()> {
+    if !patches_folder.exists() {
+        return Ok(());
+    }
+    let mut patch_files: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for patch_file in patch_files_in(patches_folder)? {
+        let stem = patch_stem(&patch_file).unwrap_or_default();
+        let key = match stem.split_once('+') {
+            Some((name, version)) => {
+                let version = version
+                    .split(|c: char| c.is_whitespace() || c == '(')
+                    .next()
+                    .unwrap_or(version);
+                format!("{}+{}", name, version)
+            }
+            None => stem,
+        };
+        patch_files.entry(key).or_default().push(patch_file);
+    }
+    let mut duplicates = patch_files
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|(key, mut files)| {
+            files.sort();
+            format!("{}: {:?}", key, files)
+        })
+        .collect::<Vec<_>>();
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    duplicates.sort();
+    Err(anyhow!(
+        "several patch files are for the same crate version, keep one of each:\n  {}",
+        duplicates.join("\n  ")
+    ))
+}
+
+/// Patch file name without extension for `--range`: `<name>+<range>`.
+fn range_file_stem(pkg_id: PackageId, range: &str) -> Result<String> {
+    let range = range.replace(' ', "");
+    if range.contains(['<', '>', ':', '"', '/', '\\', '|', '?', '*']) {
+        return Err(anyhow!(
+            "`--range {}` can't be part of a file name on every platform, use a `^` or `~` requirement",
+            range
+        ));
+    }
+    let req =
+        version_range(&range).ok_or(anyhow!("`--range {}` is not a version requirement", range))?;
+    if !req.matches(pkg_id.version()) {
+        return Err(anyhow!(
+            "crate: {}, `--range {}` does not match the resolved version {}",
+            pkg_id.name(),
+            range,
+            pkg_id.version()
+        ));
+    }
+    Ok(format!("{}+{}", pkg_id.name(), range))
+}
+
+/// The version requirement of a patch file named like `<name>+^1.0.100`, `None`
+/// for a plain version.
+fn version_range(version: &str) -> Option<semver::VersionReq> {
+    if semver::Version::parse(version).is_ok() {
+        return None;
+    }
+    semver::VersionReq::parse(version).ok()
+}
+
+/// Patch file name without extension: `<name>+<version>`, or for crates from
+/// a git repository `<name>+git-<short rev>`, since many revisions may share a
+/// version.
+fn patch_file_stem(pkg_id: PackageId) -> String {
+    match pkg_id.source_id().precise_git_fragment() {
+        Some(precise) => format!(
+            "{}+{}{}",
+            pkg_id.name(),
+            GIT_REV_PREFIX,
+            &precise[..SHORT_REV_LEN.min(precise.len())]
+        ),
+        None => format!("{}+{}", pkg_id.name(), pkg_id.version()),
+    }
+}
+
+/// Resolves a crate given on the command line, explaining a name that is not in
+/// the dependency tree, with the closest names that are, on top of the error of
+/// cargo.
+fn query_dependency(resolve: &Resolve, spec: &str) -> Result<PackageId> {
+    use anyhow::Context as _;
+    let name = spec.split_once('@').map_or(spec, |(name, _)| name);
+    let mut names = resolve
+        .iter()
+        .map(|id| id.name().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    if names.iter().any(|n| n == name) {
+        return resolve.query(spec);
+    }
+    resolve.query(spec).with_context(|| {
+        let mut similar = names
+            .iter()
+            .map(|n| (strsim::jaro_winkler(name, n), n))
+            .filter(|(score, _)| *score > 0.8)
+            .collect::<Vec<_>>();
+        similar.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(b.1)));
+        let similar = similar.into_iter().take(3).map(|(_, n)| format!("`{}`", n));
+        let suggestions = similar.collect::<Vec<_>>();
+        if suggestions.is_empty() {
+            format!(
+                "crate `{}` was not found in the resolved dependency tree, `cargo patch-crate deps` lists the crates that can be patched",
+                name
+            )
+        } else {
+            format!(
+                "crate `{}` was not found in the resolved dependency tree, did you mean {}?",
+                name,
+                suggestions.join(", ")
+            )
+        }
+    })
+}
+
+/// How the workspace pulls in `pkg_id` when it is not a dependency of a
+/// member itself: the shortest chain from a member to it, like
+/// `app -> flate2 v1.1.10 -> miniz_oxide v0.8.9 -> adler2 v2.0.1`.
+fn dependency_path(
+    workspace: &Workspace<'_>,
+    resolve: &Resolve,
+    pkg_id: PackageId,
+) -> Option<String> {
+    let members = workspace
+        .members()
+        .map(|member| member.package_id())
+        .collect::<Vec<_>>();
+    // breadth first, so the first chain found is a shortest one
+    let mut parents: HashMap<PackageId, Option<PackageId>> =
+        members.iter().map(|member| (*member, None)).collect();
+    let mut pending = members.iter().copied().collect::<VecDeque<_>>();
+    while let Some(id) = pending.pop_front() {
+        if id == pkg_id {
+            break;
+        }
+        for (dep_id, _) in resolve.deps(id) {
+            if let Entry::Vacant(entry) = parents.entry(dep_id) {
+                entry.insert(Some(id));
+                pending.push_back(dep_id);
+            }
+        }
+    }
+    let mut chain = vec![pkg_id];
+    while let Some(Some(parent)) = parents.get(chain.last()?) {
+        chain.push(*parent);
+    }
+    // a member or a direct dependency of one needs no explanation
+    if chain.len() <= 2 {
+        return None;
+    }
+    chain.reverse();
+    Some(
+        chain
+            .iter()
+            .enumerate()
+            .map(|(i, id)| match i {
+                0 => id.name().to_string(),
+                _ => format!("{} v{}", id.name(), id.version()),
+            })
+            .collect::<Vec<_>>()
+            .join(" -> "),
+    )
+}
+
+/// The package a `name+git-<rev>` patch is for: the git package named `name`
+/// pinned to `rev`, else the only git package of that name, so that a package
+/// of the same name from another source is never taken for it.
+fn query_git_package(resolve: &Resolve, name: &str, rev: &str) -> Result<PackageId> {
+    let git = resolve
+        .iter()
+        .filter(|id| id.name() == name && id.source_id().is_git())
+        .collect::<Vec<_>>();
+    let pinned = git.iter().find(|id| {
+        id.source_id()
+            .precise_git_fragment()
+            .is_some_and(|precise| precise.starts_with(rev))
+    });
+    match (pinned, git.as_slice()) {
+        (Some(pkg_id), _) | (None, [pkg_id]) => Ok(*pkg_id),
+        // reported by the caller as resolved from another source
+        (None, []) => resolve.query(name),
+        (None, _) => Err(anyhow!(
+            "crate: {}, none of the git packages {} is pinned to revision {}",
+            name,
+            git.iter()
+                .map(|id| id.source_id().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            rev
+        )),
+    }
+}
+
+fn copy_package(
+    pkg: &Package,
+    config: &PatchConfig,
+    patch_target_folder: &Path,
+    overwrite: bool,
+) -> Result<PathBuf> {
+    pkg.populate_pristine_dir(config)?;
+    copy_crate(
+        &pkg.name(),
+        &pkg.pristine_root(config)?,
+        &pkg.slug()?,
+        &source_stamp(pkg, config)?,
+        patch_target_folder,
+        overwrite,
+    )
+}
+
+/// What a copy of `pkg` is made from: its package id, which includes the
+/// pinned revision of a git crate, and the checksum of the lockfile. A path
+/// dependency has no checksum and its source may change at any time, so the
+/// hash of its current source tree stands in for it. A copy made from
+/// `--pristine-dir` records the folder it was made from after the checksum.
+fn source_stamp(pkg: &Package, config: &PatchConfig) -> Result<String> {
+    let checksum = if pkg.package_id().source_id().is_path() {
+        tree_hash(pkg.root()).unwrap_or_else(|_| "-".to_string())
+    } else {
+        pkg.summary().checksum().unwrap_or("-").to_string()
+    };
+    let root = pkg.pristine_root(config)?;
+    Ok(if root != pkg.root() {
+        format!("{}\n{} {}\n", pkg.package_id(), checksum, root.display())
+    } else {
+        format!("{}\n{}\n", pkg.package_id(), checksum)
+    })
+}
+
+/// Whether the copy at `patch_target_path` was made from the path dependency
+/// `pkg`, whose source has changed since.
+fn is_changed_path_source(
+    patch_target_path: &Path,
+    pkg: &Package,
+    config: &PatchConfig,
+) -> Result<bool> {
+    let pkg_id = pkg.package_id();
+    Ok(pkg_id.source_id().is_path()
+        && fs::read_to_string(source_marker(patch_target_path))
+            .is_ok_and(|s| s.lines().next() == Some(pkg_id.to_string().as_str()))
+        && !is_current_copy(patch_target_path, &source_stamp(pkg, config)?))
+}
+
+/// The file next to the copy at `patch_target_path` that records its source.
+fn source_marker(patch_target_path: &Path) -> PathBuf {
+    let mut marker = patch_target_path.as_os_str().to_owned();
+    marker.push(".");
+    marker.push(SOURCE_MARKER_EXT);
+    PathBuf::from(marker)
+}
+
+/// Whether the copy at `patch_target_path` is complete and was made from the
+/// source `stamp` describes. Copies made before markers were written count as
+/// out of date.
+fn is_current_copy(patch_target_path: &Path, stamp: &str) -> bool {
+    is_complete_copy(patch_target_path)
+        && fs::read_to_string(source_marker(patch_target_path)).is_ok_and(|s| s.starts_with(stamp))
+}
+
+/// Whether the copy at `patch_target_path` was copied to the end, which the
+/// marker written last tells, and still has the `Cargo.toml` of its crate. A
+/// copy cut short by an interrupted run, or one whose files were removed
+/// since, is not.
+fn is_complete_copy(patch_target_path: &Path) -> bool {
+    source_marker(patch_target_path).is_file() && patch_target_path.join("Cargo.toml").is_file()
+}
+
+/// Records the tree hash of the copy at `patch_target_path` below the source
+/// in its marker, once it is copied, patched or its patch is created.
+fn seal_copy(patch_target_path: &Path) -> Result<()> {
+    let marker = source_marker(patch_target_path);
+    let Result::Ok(content) = fs::read_to_string(&marker) else {
+        return Ok(());
+    };
+    let mut sealed = content
+        .lines()
+        .take(2)
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+    sealed.push_str(&tree_hash(patch_target_path)?);
+    sealed.push('\n');
+    for line in content
+        .lines()
+        .filter(|line| line.starts_with(PATCHES_LINE))
+    {
+        sealed.push_str(line);
+        sealed.push('\n');
+    }
+    fs::write(marker, sealed)?;
+    Ok(())
+}
+
+/// The SHA-256 of the contents of `patch_files`, in the order they are
+/// applied, whether compressed or not.
+fn patches_fingerprint(patch_files: &[PathBuf]) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    for patch_file in patch_files {
+        hasher.update(patch::read(patch_file)?.as_bytes());
+        // so that moving a line from one patch to the next changes it
+        hasher.update([0]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Records the fingerprint of `patch_files` in the marker of the copy at
+/// `patch_target_path`, once it is patched with them or they are created
+/// from it. No patches are recorded too, for a crate copied without one.
+fn record_patches(patch_target_path: &Path, patch_files: &[PathBuf]) -> Result<()> {
+    let marker = source_marker(patch_target_path);
+    let Result::Ok(content) = fs::read_to_string(&marker) else {
+        return Ok(());
+    };
+    let mut recorded = content
+        .lines()
+        .filter(|line| !line.starts_with(PATCHES_LINE))
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+    recorded.push_str(&format!(
+        "{}{}\n",
+        PATCHES_LINE,
+        patches_fingerprint(patch_files)?
+    ));
+    fs::write(marker, recorded)?;
+    Ok(())
+}
+
+/// Whether the copy at `patch_target_path` was patched with other patches
+/// than `patch_files`, e.g. before one of them was edited. Copies that
+/// recorded no patches count as unchanged.
+fn patches_changed(patch_target_path: &Path, patch_files: &[PathBuf]) -> Result<bool> {
+    let Result::Ok(content) = fs::read_to_string(source_marker(patch_target_path)) else {
+        return Ok(false);
+    };
+    match content
+        .lines()
+        .find_map(|line| line.strip_prefix(PATCHES_LINE))
+    {
+        Some(recorded) => Ok(recorded != patches_fingerprint(patch_files)?),
+        None => Ok(false),
+    }
+}
+
+/// Whether the copy at `patch_target_path` was edited since it was sealed,
+/// see [`seal_copy`]. Copies without a recorded hash count as unedited.
+fn is_edited_copy(patch_target_path: &Path) -> Result<bool> {
+    let Result::Ok(content) = fs::read_to_string(source_marker(patch_target_path)) else {
+        return Ok(false);
+    };
+    match content
+        .lines()
+        .nth(2)
+        .filter(|line| !line.starts_with(PATCHES_LINE))
+    {
+        Some(hash) if patch_target_path.exists() => Ok(tree_hash(patch_target_path)? != hash),
+        _ => Ok(false),
+    }
+}
+
+/// Copies the crate sources at `root` into the folder `slug` of
+/// `patch_target_folder`, unless a copy of the same source is already there.
+/// Unlike `Package`, the arguments can be sent to another thread.
+fn copy_crate(
+    name: &str,
+    root: &Path,
+    slug: &str,
+    stamp: &str,
+    patch_target_folder: &Path,
+    overwrite: bool,
+) -> Result<PathBuf> {
+    fs::create_dir_all(patch_target_folder)?;
+    let patch_target_path = patch_target_folder.join(slug);
+    if patch_target_path.exists() {
+        if overwrite {
+            crate_info!("crate: {}, copy to {:?}", name, &patch_target_folder);
+        } else if is_current_copy(&patch_target_path, stamp) {
+            crate_info!(
+                "crate: {}, skip, {:?} already exists.",
+                name,
+                &patch_target_path
+            );
+            return Ok(patch_target_path);
+        } else if !is_complete_copy(&patch_target_path) {
+            crate_info!(
+                "crate: {}, {:?} is incomplete, e.g. left by an interrupted run, copy again.",
+                name,
+                &patch_target_path
+            );
+        } else {
+            crate_info!(
+                "crate: {}, {:?} is not a copy of the resolved source, copy again.",
+                name,
+                &patch_target_path
+            );
+        }
+        fs::remove_dir_all(&patch_target_path)?;
+    }
+    // written last, so that a copy cut short is never taken for complete
+    let marker = source_marker(&patch_target_path);
+    if marker.exists() {
+        fs::remove_file(&marker)?;
+    }
+    copy_dir(root, &patch_target_path)?;
+    fs::write(marker, stamp)?;
+    seal_copy(&patch_target_path)?;
+    Ok(patch_target_path)
+}
+
+/// Asks `question` on a terminal and returns whether it was answered with
+/// yes. Without a terminal to ask, the answer is no.
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Prints the patch `content` of crate `pkg_name` before it is written, in
+/// color on a terminal, and asks whether to write it unless `--yes` is given
+/// or there is no terminal to ask.
+fn preview_patch(args: &Cli, pkg_name: &str, content: &str) -> Result<bool> {
+    if std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none() {
+        print!("{}", patch::colorize(content));
+    } else {
+        print!("{}", content);
+    }
+    if args.yes || !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    confirm(&format!("crate: {}, write this patch?", pkg_name))
+}
+
+/// Asks before the copies in `edited`, which were edited since they were
+/// sealed, are overwritten, unless `--yes` is given, and fails when the answer
+/// is no.
+fn confirm_overwrite(args: &Cli, edited: &[PathBuf]) -> Result<()> {
+    if edited.is_empty() || args.yes {
+        return Ok(());
+    }
+    let list = edited
+        .iter()
+        .map(|path| format!("  - {:?}", path))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !confirm(&format!(
+        "these copies were edited since they were patched and would be overwritten:\n{}\noverwrite them?",
+        list
+    ))? {
+        return Err(anyhow!(
+            "copies edited since they were patched would be overwritten, create their patches first or pass `--yes` to overwrite them:\n{}",
+            list
+        ));
+    }
+    Ok(())
+}
+
+/// Moves a crate copied and patched in the staging folder, along with its
+/// source marker, to `patch_target_path`, replacing the copy there.
+fn commit_copy(staged: &Path, patch_target_path: &Path) -> Result<()> {
+    if source_marker(patch_target_path).exists() {
+        fs::remove_file(source_marker(patch_target_path))?;
+    }
+    if patch_target_path.exists() {
+        fs::remove_dir_all(patch_target_path)?;
+    }
+    if let Some(parent) = patch_target_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(staged, patch_target_path).is_err() {
+        // e.g. `target/patch` on another file system
+        copy_dir(staged, patch_target_path)?;
+        fs::remove_dir_all(staged)?;
+    }
+    let marker = source_marker(staged);
+    if marker.exists() {
+        fs::copy(&marker, source_marker(patch_target_path))?;
+        fs::remove_file(marker)?;
+    }
+    seal_copy(patch_target_path)
+}
+
+/// Removes a copy and its source marker.
+fn discard_copy(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    let marker = source_marker(path);
+    if marker.exists() {
+        fs::remove_file(marker)?;
+    }
+    Ok(())
+}
+
+/// Copies the contents of `source` into `target`, replacing the files that are
+/// already there. Files keep their permissions and, on Unix, symlinks are
+/// recreated instead of followed, since patches created by git record both.
+fn copy_dir(source: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let from = entry.path();
+        let to = target.join(entry.file_name());
+        // never write through a symlink that is already there
+        if to
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_symlink())
+        {
+            fs::remove_file(&to)?;
+        }
+        #[cfg(unix)]
+        if entry.file_type()?.is_symlink() {
+            if to.is_dir() {
+                fs::remove_dir_all(&to)?;
+            } else if to.exists() {
+                fs::remove_file(&to)?;
+            }
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+            continue;
+        }
+        if from.is_dir() {
+            copy_dir(&from, &to)?;
+        } else {
+            fs::copy(&from, &to)?;
+            progress::inc();
+        }
+    }
+    Ok(())
+}
+
+/// Copies `pkgs` into `patch_target_folder` on up to `jobs` threads, the
+/// copies of different crates being independent.
+fn copy_packages(
+    pkgs: &[&Package],
+    config: &PatchConfig,
+    patch_target_folder: &Path,
+    overwrite: bool,
+    jobs: usize,
+) -> Result<HashMap<PackageId, PathBuf>> {
+    let mut crates = Vec::new();
+    for pkg in pkgs {
+        pkg.populate_pristine_dir(config)?;
+        crates.push((
+            pkg.name().to_string(),
+            pkg.pristine_root(config)?,
+            pkg.slug()?,
+            source_stamp(pkg, config)?,
+        ));
+    }
+    let mut files = 0;
+    for (_, root, _, _) in crates.iter() {
+        files += list_files(root)?.len();
+    }
+    progress::start(files as u64, &format!("copying {} crate(s)", crates.len()));
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<PathBuf>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..jobs.min(crates.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((name, root, slug, stamp)) = crates.get(i) else {
+                            break results;
+                        };
+                        progress::message(name);
+                        results.push((
+                            i,
+                            copy_crate(name, root, slug, stamp, patch_target_folder, overwrite),
+                        ));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("copying crates panicked"))
+            .collect()
+    });
+    progress::finish();
+    results.sort_by_key(|(i, _)| *i);
+    let mut copied = HashMap::new();
+    for (i, result) in results {
+        copied.insert(pkgs[i].package_id(), result?);
+    }
+    Ok(copied)
+}
+
+/// Removes everything in the repository at `dir` but its `.git` folder.
+fn clear_work_tree(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths of all files under `dir` relative to it, with `/` separators.
+fn list_files(dir: &Path) -> Result<Vec<String>> {
+    fn walk(dir: &Path, prefix: &str, files: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &format!("{}/", path), files)?;
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    walk(dir, "", &mut files)?;
+    Ok(files)
+}
+
+/// Pairs of paths in `paths` that only differ in case, which would end up as
+/// the same file on a case-insensitive file system.
+fn case_collisions(paths: &[String]) -> Vec<(&str, &str)> {
+    let mut collisions = Vec::new();
+    for (i, a) in paths.iter().enumerate() {
+        for b in paths[i + 1..].iter() {
+            if a != b && a.eq_ignore_ascii_case(b) {
+                collisions.push((a.as_str(), b.as_str()));
+            }
+        }
+    }
+    collisions
+}
+
+/// Files of the pristine crate that were renamed in its copy by changing the
+/// case of their path only, as `(old, new)` pairs.
+fn case_only_renames(pristine: &[String], modified: &[String]) -> Vec<(String, String)> {
+    pristine
+        .iter()
+        .filter(|old| !modified.contains(old))
+        .filter_map(|old| {
+            modified
+                .iter()
+                .find(|new| new.eq_ignore_ascii_case(old) && !pristine.contains(new))
+                .map(|new| (old.clone(), new.clone()))
+        })
+        .collect()
+}
+
+/// Logs the `[patch]` entry that makes cargo build the copy of `pkg` at
+/// `patch_target_path`. The path is relative to the workspace root unless
+/// `--relative-to` or `--absolute` say otherwise.
+fn print_override(
+    args: &Cli,
+    workspace: &Workspace<'_>,
+    pkg: &Package,
+    patch_target_path: &Path,
+    target: Option<&str>,
+) -> Result<()> {
+    let patch_target_path = std::path::absolute(patch_target_path)?;
+    let path = if args.copy.absolute {
+        patch_target_path
+    } else {
+        let base = match &args.copy.relative_to {
+            Some(dir) => std::path::absolute(dir)?,
+            None => std::path::absolute(workspace.root())?,
+        };
+        relative_path(&patch_target_path, &base)
+    };
+    let mut section = patch_section(pkg);
+    if !pkg.package_id().source_id().is_crates_io() {
+        section = format!("{:?}", section);
+    }
+    let path = path.to_string_lossy().replace('\\', "/");
+    match target {
+        // `[patch]` cannot be scoped to a target, so it is passed per build
+        Some(target) => crate_info!(
+            "crate: {}, build for {} with `--config 'patch.{}.{}.path={:?}'`.",
+            pkg.name(),
+            target,
+            section,
+            pkg.name(),
+            path
+        ),
+        None => crate_info!(
+            "crate: {}, override it with `{} = {{ path = {:?} }}` under [patch.{}].",
+            pkg.name(),
+            pkg.name(),
+            path,
+            section
+        ),
+    }
+    Ok(())
+}
+
+/// The `[patch]` table overriding the source of `pkg`: `crates-io` or the
+/// URL of its registry or repository.
+fn patch_section(pkg: &Package) -> String {
+    let source_id = pkg.package_id().source_id();
+    if source_id.is_crates_io() {
+        "crates-io".to_string()
+    } else {
+        source_id.url().to_string()
+    }
+}
+
+/// Points the `[patch]` entries of the root manifest at the copies of
+/// `crates` in `patch_target_folder`, keeping its formatting. With
+/// `--prune-manifest`, also removes the entries into `patch_target_folder` of
+/// crates that are no longer in the patch metadata.
+fn write_manifest(
+    args: &Cli,
+    workspace: &Workspace<'_>,
+    crates: &[&Package],
+    configured: &HashSet<&Package>,
+    patch_target_folder: &Path,
+) -> Result<()> {
+    let manifest = workspace.root_manifest();
+    let root = std::path::absolute(workspace.root())?;
+    let original = fs::read_to_string(manifest)?;
+    let mut doc: toml_edit::DocumentMut = original.parse()?;
+    let patch = doc
+        .entry("patch")
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or(anyhow!("`patch` in {:?} must be a table", manifest))?;
+    patch.set_implicit(true);
+
+    insert_patch_entries(patch, crates, patch_target_folder, &root, manifest)?;
+
+    if args.manifest.prune_manifest {
+        let target = std::path::absolute(patch_target_folder)?;
+        for (section, table) in patch.iter_mut() {
+            let Some(table) = table.as_table_like_mut() else {
+                continue;
+            };
+            let stale = table
+                .iter()
+                .filter(|(key, entry)| {
+                    let Some(entry) = entry.as_table_like() else {
+                        return false;
+                    };
+                    let into_target = entry
+                        .get("path")
+                        .and_then(|path| path.as_str())
+                        .is_some_and(|path| root.join(path).starts_with(&target));
+                    let name = entry
+                        .get("package")
+                        .and_then(|name| name.as_str())
+                        .unwrap_or(key);
+                    into_target && !configured.iter().any(|pkg| pkg.name() == name)
+                })
+                .map(|(key, _)| key.to_string())
+                .collect::<Vec<_>>();
+            for name in stale {
+                crate_info!(
+                    "crate: {}, removed its stale entry under [patch.{}].",
+                    name,
+                    section
+                );
+                table.remove(&name);
+            }
+        }
+    }
+
+    let updated = doc.to_string();
+    if updated != original {
+        fs::write(manifest, updated)?;
+        info!("updated the [patch] entries in {:?}", manifest);
+    }
+    Ok(())
+}
+
+/// Prints the `[patch]` sections that point at the copies of `crates` in
+/// `patch_target_folder`, ready to be pasted into the root `Cargo.toml`.
+fn emit_patch_section(
+    workspace: &Workspace<'_>,
+    crates: &[&Package],
+    patch_target_folder: &Path,
+) -> Result<()> {
+    let root = std::path::absolute(workspace.root())?;
+    let mut patch = toml_edit::Table::new();
+    patch.set_implicit(true);
+    insert_patch_entries(
+        &mut patch,
+        crates,
+        patch_target_folder,
+        &root,
+        workspace.root_manifest(),
+    )?;
+    let mut doc = toml_edit::DocumentMut::new();
+    doc.insert("patch", toml_edit::Item::Table(patch));
+    print!("{}", doc);
+    Ok(())
+}
+
+/// Adds the entries of `crates` to the `[patch]` table of `manifest`, or
+/// updates their paths, which point at the copies relative to `root`.
+fn insert_patch_entries(
+    patch: &mut toml_edit::Table,
+    crates: &[&Package],
+    patch_target_folder: &Path,
+    root: &Path,
+    manifest: &Path,
+) -> Result<()> {
+    for pkg in crates {
+        let section = patch_section(pkg);
+        let path = relative_path(
+            &std::path::absolute(patch_target_folder.join(pkg.slug()?))?,
+            root,
+        );
+        let path = path.to_string_lossy().replace('\\', "/");
+        // two versions of a crate need keys of their own, like in `[dependencies]`
+        let versions = crates
+            .iter()
+            .filter(|other| other.name() == pkg.name() && patch_section(other) == section)
+            .count();
+        let key = if versions > 1 {
+            format!(
+                "{}-{}",
+                pkg.name(),
+                pkg.version().to_string().replace(['.', '+'], "_")
+            )
+        } else {
+            pkg.name().to_string()
+        };
+        let table = patch
+            .entry(&section)
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .ok_or(anyhow!(
+                "`patch.{}` in {:?} must be a table",
+                section,
+                manifest
+            ))?;
+        match table
+            .get_mut(&key)
+            .and_then(|entry| entry.as_table_like_mut())
+        {
+            Some(entry) => {
+                if entry.get("path").and_then(|p| p.as_str()) != Some(path.as_str()) {
+                    entry.insert("path", toml_edit::value(path));
+                }
+            }
+            None => {
+                let mut entry = toml_edit::InlineTable::new();
+                entry.insert("path", path.into());
+                if versions > 1 {
+                    entry.insert("package", pkg.name().as_str().into());
+                }
+                table.insert(&key, toml_edit::value(entry));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `path` relative to `base`, both of which must be absolute.
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    while let (Some(p), Some(b)) = (path_components.peek(), base_components.peek()) {
+        if p != b {
+            break;
+        }
+        path_components.next();
+        base_components.next();
+    }
+    base_components
+        .map(|_| std::path::Component::ParentDir)
+        .chain(path_components)
+        .collect()
+}
+
+/// Fails when the patch `content` touches files outside of the
+/// `allowed_paths` of the crate, which are file paths or, ending in `/`,
+/// folders.
+fn check_allowed_paths(entry: &CrateEntry<'_>, content: &str) -> Result<()> {
+    let Some(allowed) = entry.setting_strings("allowed_paths")? else {
+        return Ok(());
+    };
+    let disallowed = patch::touched_paths(content)
+        .into_iter()
+        .filter(|path| {
+            !allowed
+                .iter()
+                .any(|allowed| match allowed.strip_suffix('/') {
+                    Some(folder) => path.starts_with(&format!("{}/", folder)),
+                    None => path == allowed,
+                })
+        })
+        .map(|path| format!("  - {}", path))
+        .collect::<Vec<_>>();
+    if !disallowed.is_empty() {
+        return Err(anyhow!(
+            "crate: {}, the patch modifies paths outside of its `allowed_paths`:\n{}",
+            entry.name,
+            disallowed.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Lines naming the `reason` and `upstream_pr` of a crate, the registry of a
+/// crate that is not from crates.io and the `tested` versions of a range
+/// patch, written above the diff of its patch, where `git apply` ignores them.
+/// A `reason` given here takes the place of the one in the metadata.
+fn patch_header(
+    entry: Option<&CrateEntry<'_>>,
+    pkg_id: PackageId,
+    reason: Option<&str>,
+    tested: &[semver::Version],
+) -> Result<String> {
+    let mut header = String::new();
+    let reason = match (reason, entry) {
+        (Some(reason), _) => Some(reason.to_string()),
+        (None, Some(entry)) => entry.setting("reason")?.map(|reason| reason.to_string()),
+        (None, None) => None,
+    };
+    if let Some(reason) = reason {
+        header.push_str(&format!("{}{}\n", REASON_HEADER, reason));
+    }
+    if let Some(entry) = entry {
+        if let Some(upstream_pr) = entry.setting("upstream_pr")? {
+            header.push_str(&format!("Upstream-PR: {}\n", upstream_pr));
+        }
+    }
+    let source_id = pkg_id.source_id();
+    if source_id.is_registry() && !source_id.is_crates_io() {
+        header.push_str(&format!("{}{}\n", REGISTRY_HEADER, source_id.url()));
+    }
+    if !tested.is_empty() {
+        let tested = tested.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        header.push_str(&format!("{}{}\n", TESTED_HEADER, tested.join(", ")));
+    }
+    if !header.is_empty() {
+        header.push('\n');
+    }
+    Ok(header)
+}
+
+/// The rest of the line starting with `field` above the diff of
+/// `patch_file`, see [`patch_header`].
+fn patch_header_field(patch_file: &Path, field: &str) -> Result<Option<String>> {
+    let content = patch::read(patch_file)?;
+    Ok(content
+        .lines()
+        .take_while(|line| !line.starts_with("diff ") && !line.starts_with("--- "))
+        .find_map(|line| line.strip_prefix(field))
+        .map(|value| value.trim().to_string()))
+}
+
+/// The registry URL the `Registry:` line above the diff of `patch_file` names.
+fn patch_registry(patch_file: &Path) -> Result<Option<String>> {
+    patch_header_field(patch_file, REGISTRY_HEADER)
+}
+
+/// The versions the range patch `patch_file` was created from, sorted, empty
+/// for a patch that doesn't exist yet or predates the `Tested-Versions:` line.
+fn tested_versions(patch_file: &Path) -> Result<Vec<semver::Version>> {
+    use anyhow::Context as _;
+    if !patch_file.exists() {
+        return Ok(Vec::new());
+    }
+    let mut versions = Vec::new();
+    for version in patch_header_field(patch_file, TESTED_HEADER)?
+        .iter()
+        .flat_map(|value| value.split(','))
+    {
+        versions.push(semver::Version::parse(version.trim()).with_context(|| {
+            format!(
+                "`{}` in {:?} lists an invalid version",
+                TESTED_HEADER.trim_end(),
+                patch_file
+            )
+        })?);
+    }
+    versions.sort();
+    Ok(versions)
+}
+
+/// Whether `patch_file` is named after a version requirement, like
+/// `<name>+^1.0.100.patch`, rather than a version or revision.
+fn is_range_patch(patch_file: &Path) -> bool {
+    patch_stem(patch_file).is_some_and(|stem| {
+        split_patch_stem(&stem)
+            .0
+            .split_once('+')
+            .is_some_and(|(_, version)| version_range(version).is_some())
+    })
+}
+
+/// The paths `patch_files` touch, relative to the crate root.
+fn touched_paths(patch_files: &[PathBuf]) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for patch_file in patch_files {
+        for path in patch::touched_paths(&patch::read(patch_file)?) {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Copies only the files `patch_files` touch from the pristine crate into its
+/// folder in `patch_target_tmp_folder`, which is enough for `git apply`.
+fn sparse_copy_package(
+    pkg: &Package,
+    config: &PatchConfig,
+    patch_target_tmp_folder: &Path,
+    patch_files: &[PathBuf],
+) -> Result<PathBuf> {
+    if patch_target_tmp_folder.exists() {
+        fs::remove_dir_all(patch_target_tmp_folder)?;
+    }
+    let patch_target_tmp_path = patch_target_tmp_folder.join(pkg.slug()?);
+    fs::create_dir_all(&patch_target_tmp_path)?;
+    pkg.populate_pristine_dir(config)?;
+    let root = pkg.pristine_root(config)?;
+    for path in touched_paths(patch_files)? {
+        let source = root.join(&path);
+        if source.is_file() {
+            let target = patch_target_tmp_path.join(&path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&source, &target)?;
+        }
+    }
+    Ok(patch_target_tmp_path)
+}
+
+/// Fills `patch_target_path` with a copy of the pristine crate, whose files
+/// `patch_files` touch are then taken from `patched_path`, where the patches
+/// were applied by themselves. [`copy_dir`] clones the other files on file
+/// systems that support it, like btrfs, XFS or APFS, and copies them
+/// elsewhere, never linking them: the copy may be edited, and a linked file
+/// would change cargo's source cache or `--pristine-dir` along with it.
+fn fill_patched_package(
+    pkg: &Package,
+    config: &PatchConfig,
+    patched_path: &Path,
+    patch_target_path: &Path,
+    patch_files: &[PathBuf],
+) -> Result<()> {
+    discard_copy(patch_target_path)?;
+    copy_dir(&pkg.pristine_root(config)?, patch_target_path)?;
+    for path in touched_paths(patch_files)? {
+        let patched = patched_path.join(&path);
+        let target = patch_target_path.join(&path);
+        // deleted by the patches, or replaced without writing through a symlink
+        if target.symlink_metadata().is_ok() {
+            fs::remove_file(&target)?;
+        }
+        if patched.is_file() {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&patched, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates a patch with a user supplied diff command. It runs inside
+/// `patch_target_tmp_folder` with the pristine (`a`) and modified (`b`) trees
+/// appended to its arguments and must print a unified diff to stdout. The
+/// `exclude` paths and the files matching the `junk` patterns are removed
+/// from both trees first, as the tool can't be told to skip them.
+fn diff_with_tool(
+    diff_tool: &str,
+    pkg_root: &Path,
+    patch_target_path: &Path,
+    patch_target_tmp_folder: &Path,
+    patch_file: &Path,
+    exclude: &[String],
+    junk: &[String],
+) -> Result<()> {
+    let mut words = diff_tool.split_whitespace();
+    let program = words
+        .next()
+        .ok_or(anyhow!("`--diff-tool` must not be empty"))?;
+
+    let pristine = patch_target_tmp_folder.join("a");
+    let modified = patch_target_tmp_folder.join("b");
+    for tree in [&pristine, &modified] {
+        if tree.exists() {
+            fs::remove_dir_all(tree)?;
+        }
+    }
+    fs::create_dir_all(&pristine)?;
+    fs::create_dir_all(&modified)?;
+    copy_dir(pkg_root, &pristine)?;
+    copy_dir(patch_target_path, &modified)?;
+    git::destroy(&modified)?;
+    for tree in [&pristine, &modified] {
+        for path in list_files(tree)? {
+            if exclude.contains(&path) || is_ignored(&path, junk) {
+                fs::remove_file(tree.join(path))?;
+            }
+        }
+    }
+
+    let out = std::process::Command::new(program)
+        .current_dir(patch_target_tmp_folder)
+        .args(words)
+        .args(["a", "b"])
+        .traced()
+        .output()
+        .map_err(|err| anyhow!("failed to run diff tool `{}`: {}", diff_tool, err))?;
+
+    // like diff(1), the tool may exit with 1 when the trees differ
+    match out.status.code() {
+        Some(0) | Some(1) => fs::write(patch_file, out.stdout)?,
+        _ => {
+            return Err(anyhow!(
+                "diff tool `{}` failed: {}",
+                diff_tool,
+                String::from_utf8_lossy(&out.stderr)
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites every patch in `patches_folder` into the form [`patch::normalize`]
+/// produces, which is also the form new patches are created in.
+fn normalize_patches(patches_folder: &Path) -> Result<()> {
+    for patch_file in patch_files_in(patches_folder)? {
+        let content = patch::read(&patch_file)?;
+        let normalized = patch::normalize(&content);
+        if normalized != content {
+            patch::write(&patch_file, &normalized)?;
+            info!("normalized {:?}", patch_file);
+        }
+    }
+    Ok(())
+}
+
+/// Writes a copy of every patch into `output` in the layout of the npm
+/// `patch-package` tool, named by [`npm_patch_stem`], with the paths inside
+/// rooted at `node_modules/<name>/`. Patches of git revisions and version
+/// ranges, which patch-package has no names for, are skipped.
+fn export_npm(patches_folder: &Path, output: &Path) -> Result<()> {
+    fs::create_dir_all(output)?;
+    for patch_file in patch_files_in(patches_folder)? {
+        let stem = patch_stem(&patch_file).unwrap_or_default();
+        let Some((name, _)) = stem.split_once('+') else {
+            warn!(
+                "skip {:?}, not named `<crate>+<version>.{}`",
+                patch_file, PATCH_EXT
+            );
+            continue;
+        };
+        let Some(npm_stem) = npm_patch_stem(&stem) else {
+            warn!(
+                "skip {:?}, patch-package only names patches after a version, not a git revision or a version range",
+                patch_file
+            );
+            continue;
+        };
+        let content = patch::read(&patch_file)?;
+        let exported = reroot_patch(&content, &format!("node_modules/{}/", name));
+        // patch-package only reads uncompressed patches
+        let target = output.join(format!("{}.{}", npm_stem, PATCH_EXT));
+        fs::write(&target, exported)?;
+        info!("exported {:?}", target);
+    }
+    Ok(())
+}
+
+/// The file stem patch-package gives the patch of `stem`: `<name>+<version>`
+/// as it is, and the numbered patch `<name>+<version>.<index>-<desc>` in its
+/// sequence form `<name>+<version>+<index>+<desc>`, with the index padded to
+/// three digits like patch-package pads it. `None` for a patch of a git
+/// revision or a version range.
+fn npm_patch_stem(stem: &str) -> Option<String> {
+    let (name, version) = stem.split_once('+')?;
+    let (version, index) = patch_index(version);
+    semver::Version::parse(version).ok()?;
+    let Some(index) = index else {
+        return Some(format!("{}+{}", name, version));
+    };
+    Some(match index.split_once('-') {
+        Some((number, desc)) => format!("{}+{}+{:0>3}+{}", name, version, number, desc),
+        None => format!("{}+{}+{:0>3}", name, version, index),
+    })
+}
+
+/// `content` with the paths of all its headers rooted at `root`, e.g.
+/// `node_modules/<name>/`: the `diff --git`, `---` and `+++` lines, renames
+/// and copies, and the `Binary files` line of a binary change without data.
+fn reroot_patch(content: &str, root: &str) -> String {
+    let reroot = |path: &str| match path.split_at_checked(2) {
+        Some((side @ ("a/" | "b/"), path)) => format!("{}{}{}", side, root, path),
+        _ => path.to_string(),
+    };
+    patch::map_headers(content, |line| {
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            if let Some((old, new)) = paths.split_once(" b/") {
+                return format!(
+                    "diff --git {} {}",
+                    reroot(old),
+                    reroot(&format!("b/{}", new))
+                );
+            }
+        }
+        for prefix in ["--- ", "+++ "] {
+            if let Some(path) = line.strip_prefix(prefix) {
+                return format!("{}{}", prefix, reroot(path));
+            }
+        }
+        for prefix in ["rename from ", "rename to ", "copy from ", "copy to "] {
+            if let Some(path) = line.strip_prefix(prefix) {
+                return format!("{}{}{}", prefix, root, path);
+            }
+        }
+        let binary = line
+            .strip_prefix("Binary files ")
+            .and_then(|paths| paths.strip_suffix(" differ"))
+            .and_then(|paths| paths.split_once(" and "));
+        if let Some((old, new)) = binary {
+            return format!("Binary files {} and {} differ", reroot(old), reroot(new));
+        }
+        line.to_string()
+    })
+}
+
+/// Files whose changes in `content` look like a wholesale reformat: they
+/// remove at least `threshold` percent of the lines of the pristine file and
+/// add back about as many.
+fn reformatted_files(pkg_root: &Path, content: &str, threshold: usize) -> Vec<String> {
+    let mut reformatted = Vec::new();
+    for file in patch::parse(content) {
+        let Some(path) = &file.old_path else {
+            continue;
+        };
+        let Result::Ok(pristine) = fs::read_to_string(pkg_root.join(path)) else {
+            continue;
+        };
+        let total = pristine.lines().count();
+        let removed = file
+            .hunk_lines
+            .iter()
+            .filter(|l| l.starts_with('-'))
+            .count();
+        let added = file
+            .hunk_lines
+            .iter()
+            .filter(|l| l.starts_with('+'))
+            .count();
+        // balanced within 20%
+        let balanced = added.min(removed) * 5 >= added.max(removed) * 4;
+        if total >= REFORMAT_MIN_LINES && removed * 100 >= total * threshold && balanced {
+            reformatted.push(path.clone());
+        }
+    }
+    reformatted
+}
+
+/// Whether a patch changes the `Cargo.toml` of its crate.
+fn touches_manifest(content: &str) -> bool {
+    patch::parse(content)
+        .iter()
+        .any(|file| file.paths().any(|path| path == "Cargo.toml"))
+}
+
+/// Warns that the patch of the crate `pkg_name` changes its `Cargo.toml`,
+/// naming the keys that differ between the crate at `pristine` and the one at
+/// `patched`.
+fn warn_manifest_changes(pkg_name: &str, pristine: &Path, patched: &Path) {
+    fn diff(
+        prefix: &str,
+        old: Option<&toml::Value>,
+        new: Option<&toml::Value>,
+        keys: &mut Vec<String>,
+    ) {
+        match (old, new) {
+            (Some(toml::Value::Table(old)), Some(toml::Value::Table(new))) => {
+                let mut names = old.keys().chain(new.keys()).collect::<Vec<_>>();
+                names.sort();
+                names.dedup();
+                for name in names {
+                    let key = if prefix.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", prefix, name)
+                    };
+                    diff(&key, old.get(name), new.get(name), keys);
+                }
+            }
+            (old, new) if old != new => keys.push(format!("`{}`", prefix)),
+            _ => {}
+        }
+    }
+    let read = |root: &Path| -> Option<toml::Value> {
+        fs::read_to_string(root.join("Cargo.toml"))
+            .ok()?
+            .parse::<toml::Table>()
+            .ok()
+            .map(toml::Value::Table)
+    };
+    let changed = match (read(pristine), read(patched)) {
+        (Some(old), Some(new)) => {
+            let mut keys = Vec::new();
+            diff("", Some(&old), Some(&new), &mut keys);
+            if keys.is_empty() {
+                return;
+            }
+            format!("changes {} in its Cargo.toml", keys.join(", "))
+        }
+        _ => "changes its Cargo.toml".to_string(),
+    };
+    warn!(
+        "crate: {}, the patch {}. cargo resolves the patched crate with this manifest, so the workspace may lock other versions of its dependencies, build a dependency twice or no longer match Cargo.lock. Pass `--allow-manifest-changes` if that is intended.",
+        pkg_name, changed
+    );
+}
+
+/// Lists the files under `dir` that still contain git conflict markers.
+fn conflicted_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut conflicted = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            conflicted.extend(conflicted_files(&path)?);
+        } else if let Result::Ok(content) = fs::read_to_string(&path) {
+            let has_markers = content.lines().any(|line| {
+                line.starts_with("<<<<<<< ") || line.starts_with(">>>>>>> ") || line == "======="
+            });
+            if has_markers {
+                conflicted.push(path);
+            }
+        }
+    }
+    Ok(conflicted)
+}
+
+/// Checks whether the changes of a patch made for `version` are already part of
+/// the newer version of the crate that is currently resolved.
+fn detect_upstreamed(
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    pkg_name: &str,
+    version: &str,
+    patch_file: &Path,
+) -> Result<bool> {
+    let pkg_id = resolve.query(pkg_name)?;
+    let patched_version = semver::Version::parse(version)?;
+    if pkg_id.version() <= &patched_version {
+        return Err(anyhow!(
+            "crate: {}, version {} of patch {:?} is not resolved",
+            pkg_name,
+            version,
+            patch_file
+        ));
+    }
+    info!(
+        "crate: {}, checking whether {} already includes patch {:?}.",
+        pkg_name,
+        pkg_id.version(),
+        patch_file
+    );
+    let pkg = pkg_set.get_one(pkg_id)?;
+    let patch_target_tmp_folder = workspace.patch_target_tmp_folder();
+    let patch_target_tmp_path =
+        copy_package(pkg, &workspace.config, &patch_target_tmp_folder, true)?;
+    git::init(&patch_target_tmp_path)?;
+    let upstreamed = git::check_reverse(&patch_target_tmp_path, patch_file)?;
+    fs::remove_dir_all(&patch_target_tmp_folder)?;
+    Ok(upstreamed)
+}
+
+/// Unlike `fs::canonicalize`, this keeps symlinks (and on Windows the plain
+/// drive prefix) as they are, so the workspace is found at the path it is
+/// mounted at, e.g. inside a sandbox, rather than at its location on the host.
+fn find_cargo_toml(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    find_root_manifest_for_wd(&path)
+}
+
+/// Fails when the folder given with `--target-dir` holds the workspace or the
+/// patches, which removing the copied crates would remove too.
+fn check_target_dir(workspace: &PatchWorkspace<'_>) -> Result<()> {
+    let folder = std::path::absolute(workspace.patch_target_folder())?;
+    let root = std::path::absolute(workspace.root())?;
+    let patches_folder = std::path::absolute(workspace.patches_folder())?;
+    if root.starts_with(&folder)
+        || patches_folder.starts_with(&folder)
+        || folder.starts_with(&patches_folder)
+    {
+        return Err(anyhow!(
+            "`--target-dir {:?}` must be a folder of its own: not the workspace root or above it, and not holding or inside the patches folder {:?}",
+            folder,
+            patches_folder
+        ));
+    }
+    Ok(())
+}
+
+/// The manifest given with `--manifest-path`, which like for cargo must be an
+/// existing `Cargo.toml`.
+fn given_cargo_toml(path: &Path) -> Result<PathBuf> {
+    if path.file_name() != Some(OsStr::new("Cargo.toml")) {
+        return Err(anyhow!(
+            "the manifest-path must be a path to a Cargo.toml file, got {:?}",
+            path
+        ));
+    }
+    if !path.is_file() {
+        return Err(anyhow!("manifest path {:?} does not exist", path));
+    }
+    Ok(std::path::absolute(path)?)
+}
+
+pub fn run() -> anyhow::Result<()> {
+    let result = run_command().map_err(offline_error);
+    if report::enabled() {
+        report::print()?;
+    }
+    if let Err(err) = &result {
+        if log::github() {
+            let causes = err.chain().map(|c| c.to_string()).collect::<Vec<_>>();
+            log::annotation("error", &causes.join("\nbecause: "));
+        }
+    }
+    result
+}
+
+/// Tells which crate is missing from the local caches when cargo refused to
+/// download it with `--offline` or `net.offline`, as cargo only tells that a
+/// request was attempted.
+fn offline_error(err: anyhow::Error) -> anyhow::Error {
+    let causes = err
+        .chain()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>();
+    if !causes
+        .iter()
+        .any(|cause| cause.contains("--offline was specified"))
+    {
+        return err;
+    }
+    let pkg = causes.iter().find_map(|cause| {
+        cause
+            .strip_prefix("failed to download `")?
+            .strip_suffix('`')
+            .map(str::to_string)
+    });
+    match pkg {
+        Some(pkg) => err.context(format!(
+            "offline: crate {} is not available locally, download it with `cargo fetch` while online",
+            pkg
+        )),
+        None => err.context(
+            "offline: a crate is not available locally, download it with `cargo fetch` while online",
+        ),
+    }
+}
+
+fn run_command() -> anyhow::Result<()> {
+    let cli = Cli::try_parse_args(cli_args()).unwrap_or_else(|err| err.exit());
+
+    if let Some(Command::ApplyTo { dir, patch, strip }) = &cli.command {
+        return apply_to(dir, patch, *strip);
+    }
+    if let Some(Command::Completions {
+        shell: Some(shell),
+        list_crates: false,
+    }) = &cli.command
+    {
+        print!("{}", completions::script(*shell));
+        return Ok(());
+    }
+
+    let mut gctx = GlobalContext::default()?;
+    let cargo_toml_path = match &cli.manifest_path {
+        Some(path) => given_cargo_toml(path)?,
+        None => find_cargo_toml(&PathBuf::from("."))?,
+    };
+    // called by the completion scripts, which need nothing but the names
+    if let Some(Command::Completions {
+        list_crates: true, ..
+    }) = &cli.command
+    {
+        for entry in Workspace::new(&cargo_toml_path, &gctx)?.patch_crates()? {
+            println!("{}", entry.name);
+        }
+        return Ok(());
+    }
+
+    let mut args = with_default_args(&cli, &Workspace::new(&cargo_toml_path, &gctx)?)?;
+    // `create <crates>` is the explicit form of running with the crates
+    if let Some(Command::Create { crates, .. }) = &args.command {
+        args.crates = crates.clone();
+        args.command = None;
+    }
+    check_create_flags(&args)?;
+    match args.format {
+        RunFormat::Lines => {}
+        RunFormat::Json => report::enable(),
+        RunFormat::Github => log::set_github(),
+    }
+    // like cargo, only applies `net.offline` from the config files once
+    // configured; the rest of `[net]`, proxies and timeouts are read on use
+    let mut cli_config = Vec::new();
+    if args.resolving.git_fetch_with_cli {
+        cli_config.push("net.git-fetch-with-cli=true".to_string());
+    }
+    if let Some(timeout) = args.resolving.network_timeout {
+        cli_config.push(format!("http.timeout={}", timeout));
+    }
+    if let Some(retries) = args.resolving.network_retries {
+        cli_config.push(format!("net.retry={}", retries));
+    }
+    log::set_level(args.quiet, args.verbose)?;
+    gctx.configure(
+        u32::from(args.verbose.saturating_sub(1)),
+        log::quiet() || log::github(),
+        None,
+        false,
+        false,
+        args.resolving.offline,
+        &None,
+        &[],
+        &cli_config,
+    )?;
+    let _lock = gctx.acquire_package_cache_lock(CacheLockMode::Shared)?;
+
+    let workspace = Workspace::new(&cargo_toml_path, &gctx)?;
+    let config = PatchConfig::new(&cli, &args, &workspace);
+    let workspace = PatchWorkspace { workspace, config };
+    if args.target_dir.is_some() {
+        check_target_dir(&workspace)?;
+    }
+    let _patch_lock = workspace.lock_patch_folder()?;
+    let patch_target_tmp_folder = workspace.patch_target_tmp_folder();
+    let uses_tmp = uses_tmp_folder(&args);
+    let kept = patch_target_tmp_folder.join(KEEP_TMP_MARKER).exists();
+    let keep_tmp = args.create.keep_tmp || kept;
+    if uses_tmp && patch_target_tmp_folder.exists() {
+        if keep_tmp {
+            info!(
+                "kept {:?}, left behind by an earlier run; remove it once inspected to have runs clean it up again.",
+                patch_target_tmp_folder
+            );
+        } else {
+            // no other run holds the lock, so it is from one that was interrupted
+            fs::remove_dir_all(&patch_target_tmp_folder)?;
+            info!(
+                "removed {:?}, left behind by an earlier run.",
+                patch_target_tmp_folder
+            );
+        }
+    }
+    let result = run_in_workspace(&cli, args, &workspace);
+    if uses_tmp && patch_target_tmp_folder.exists() {
+        if result.is_err() || keep_tmp {
+            // kept for inspection; a failure to mark it must not hide the result
+            let _ = fs::write(patch_target_tmp_folder.join(KEEP_TMP_MARKER), "");
+        } else {
+            fs::remove_dir_all(&patch_target_tmp_folder)?;
+        }
+    }
+    result
+}
+
+/// Whether the command of `args` works in `target/patch-tmp`, and so takes
+/// care of what an earlier run left there. Commands that only read, and
+/// `--dry-run`, leave it as it is.
+/// Whether `args` run a command that copies crates, and so extracts them into
+/// `--pristine-dir` with `--populate-pristine-dir`.
+fn populates_pristine_dir(args: &Cli) -> bool {
+    let command = matches!(
+        args.command,
+        None | Some(
+            Command::Apply { .. }
+                | Command::Resolve { .. }
+                | Command::Rebase { .. }
+                | Command::Extract { .. }
+                | Command::Init { .. }
+        )
+    );
+    command
+        && !args.apply.dry_run
+        && !args.print_config
+        && !args.report_size
+        && args.dump_resolved_version.is_none()
+}
+
+fn uses_tmp_folder(args: &Cli) -> bool {
+    let command = matches!(
+        args.command,
+        None | Some(
+            Command::Apply { .. }
+                | Command::Resolve { .. }
+                | Command::Rebase { .. }
+                | Command::Status { .. }
+        )
+    );
+    command
+        && !args.apply.dry_run
+        && !args.print_config
+        && !args.report_size
+        && args.dump_resolved_version.is_none()
+}
+
+/// Runs the command of `args` in `workspace`, with the patch folder locked.
+fn run_in_workspace(cli: &Cli, mut args: Cli, workspace: &PatchWorkspace<'_>) -> Result<()> {
+    log::set_per_crate(!args.apply.summary_only);
+    unalias_args(&mut args, workspace);
+    // an invalid `crates` fails where it is used
+    for entry in workspace.patch_crates().unwrap_or_default() {
+        if let Some(alias) = &entry.alias {
+            info!(
+                "`{}` in `crates` of patch metadata is the alias of a renamed dependency, taken as crate `{}`.",
+                alias, entry.name
+            );
+        }
+        for key in entry.unknown_keys() {
+            warn!(
+                "crate: {}, unknown key `{}` in its table in `crates` of patch metadata, ignored.",
+                entry.name, key
+            );
+        }
+    }
+
+    if args.print_config {
+        return print_config(cli, &args, workspace);
+    }
+
+    if args.report_size {
+        return report_size(workspace);
+    }
+
+    if let Some(name) = &args.dump_resolved_version {
+        let (_, resolve) = resolve_ws(workspace, &args)?;
+        println!("{}", query_dependency(&resolve, name)?.version());
+        return Ok(());
+    }
+
+    // running with crates takes `--dry-run` along with the flags of `apply`
+    let creating = args.command.is_none() && !args.crates.is_empty() && !args.apply.reverse;
+    if args.apply.dry_run && creating {
+        return Err(anyhow!(
+            "`--dry-run` only previews applying and reversing patches, see `--check` to compare created patches"
+        ));
+    }
+
+    if let Some(vendor_dir) = &args.apply.apply_to_vendor {
+        let only = match &args.command {
+            None if args.crates.is_empty()
+                && args.clean.is_empty()
+                && !args.apply.reverse
+                && !args.create.check =>
+            {
+                &args.crates
+            }
+            Some(Command::Apply {
+                crates,
+                bundle: None,
+                ..
+            }) if !crates.iter().any(|c| is_patch_path(c) || is_patch_url(c)) => crates,
+            _ => {
+                return Err(anyhow!(
+                    "`--apply-to-vendor` only applies the patches in the patches folder"
+                ))
+            }
+        };
+        if args.apply.dry_run {
+            return Err(anyhow!(
+                "`--apply-to-vendor` can't be previewed with `--dry-run`, see `plan`"
+            ));
+        }
+        let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+        apply_to_vendor(
+            &args,
+            workspace,
+            &pkg_set,
+            &resolve,
+            &workspace.root().join(vendor_dir),
+            only,
+        )?;
+        info!("Done");
+        return Ok(());
+    }
+
+    match &args.command {
+        // usually run before the workspace is loaded, or `create` taken as
+        // the crates
+        Some(Command::ApplyTo { dir, patch, strip }) => {
+            return apply_to(dir, patch, *strip);
+        }
+        Some(Command::Completions { shell, .. }) => {
+            match shell {
+                Some(shell) => print!("{}", completions::script(*shell)),
+                None => {
+                    for entry in workspace.patch_crates()? {
+                        println!("{}", entry.name);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Create { crates, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            if args.create.watch {
+                watch_patches(&args, workspace, &pkg_set, &resolve, crates)?;
+            } else {
+                create_patches(&args, workspace, &pkg_set, &resolve, crates)?;
+            }
+        }
+        Some(Command::Bundle { output, .. }) => {
+            bundle::create(&workspace.patches_folder(), output)?;
+            info!("bundle created, {:?}", output);
+        }
+        Some(Command::ExportNpm { output, .. }) => {
+            export_npm(&workspace.patches_folder(), output)?;
+        }
+        Some(Command::List { format, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            list_crates(&args, workspace, &pkg_set, &resolve, *format)?;
+        }
+        Some(Command::Deps { filter, format, .. }) => {
+            let (_, resolve) = resolve_ws(workspace, &args)?;
+            list_deps(workspace, &resolve, filter.as_deref(), *format)?;
+            return Ok(());
+        }
+        Some(Command::Show { name, .. }) => {
+            let (_, resolve) = resolve_ws(workspace, &args)?;
+            let pkg_id = query_dependency(&resolve, name)?;
+            let patch_files = find_patch_files(&workspace.patches_folder(), pkg_id)?;
+            if patch_files.is_empty() {
+                return Err(anyhow!("crate: {}, there is no patch for {}", name, pkg_id));
+            }
+            let mut content = String::new();
+            for patch_file in patch_files {
+                content.push_str(&patch::read(&patch_file)?);
+            }
+            if std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none() {
+                print!("{}", patch::colorize(&content));
+            } else {
+                print!("{}", content);
+            }
+            return Ok(());
+        }
+        Some(Command::Extract { crates, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            extract_crates(&args, workspace, &pkg_set, &resolve, crates)?;
+        }
+        Some(Command::Init { crates, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            init_crates(&args, workspace, &pkg_set, &resolve, crates)?;
+        }
+        Some(Command::Status { crates, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            patch_status(&args, workspace, &pkg_set, &resolve, crates)?;
+        }
+        Some(Command::Plan { crates, format, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            let crates = plan_crates(&args, workspace, &pkg_set, &resolve, crates)?;
+            print_plan(workspace, &crates, *format)?;
+            return Ok(());
+        }
+        Some(Command::Normalize) => {
+            normalize_patches(&workspace.patches_folder())?;
+        }
+        Some(Command::Prune {
+            keep_applied: true, ..
+        }) => {
+            if workspace.patch_target_folder().exists() {
+                let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+                prune_patch_folder(&args, workspace, &pkg_set, &resolve)?;
+            }
+        }
+        Some(Command::Remove { crates, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            remove_patches(&args, workspace, &pkg_set, &resolve, crates)?;
+        }
+        Some(Command::Prune {
+            keep_applied: false,
+            ..
+        }) => {
+            info!("Cleaning up patch folder.");
+            workspace.clean_patch_folder()?;
+        }
+        Some(Command::Apply {
+            crates,
+            bundle: Some(bundle_file),
+            ..
+        }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            let bundle_folder = workspace.patch_bundle_folder();
+            bundle::extract(bundle_file, &bundle_folder)?;
+            let result =
+                apply_patches(&args, workspace, &pkg_set, &resolve, &bundle_folder, crates);
+            fs::remove_dir_all(&bundle_folder)?;
+            result?;
+        }
+        Some(Command::Apply {
+            crates,
+            bundle: None,
+            allow_http,
+            ..
+        }) if crates.iter().any(|c| is_patch_path(c) || is_patch_url(c)) => {
+            if !crates.iter().all(|c| is_patch_path(c) || is_patch_url(c)) {
+                return Err(anyhow!(
+                    "`apply` takes either crate names or patch files, not both"
+                ));
+            }
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            let crates = crates
+                .iter()
+                .map(|c| match is_patch_url(c) {
+                    true => download_patch(&args, workspace, &resolve, c, *allow_http)
+                        .map(|patch_file| patch_file.display().to_string()),
+                    false => Ok(c.clone()),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let crates = &crates;
+            let single_folder = workspace.patch_bundle_folder();
+            let args = Cli {
+                unlisted: true,
+                ..args.clone()
+            };
+            let result = collect_patch_files(crates, &single_folder).and_then(|only| {
+                apply_patches(&args, workspace, &pkg_set, &resolve, &single_folder, &only)
+            });
+            fs::remove_dir_all(&single_folder)?;
+            result?;
+        }
+        Some(Command::Apply {
+            crates,
+            bundle: None,
+            ..
+        }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            apply_patches(
+                &args,
+                workspace,
+                &pkg_set,
+                &resolve,
+                &workspace.patches_folder(),
+                crates,
+            )?;
+        }
+        Some(Command::Rebase { name, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            rebase_patch(&args, workspace, &pkg_set, &resolve, name)?;
+        }
+        Some(Command::Resolve { name, .. }) => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            let pkg = pkg_set.get_one(query_dependency(&resolve, name)?)?;
+            let patch_target_path = pkg.patch_target_path(workspace)?;
+            let conflicted = conflicted_files(&patch_target_path)?;
+            if !conflicted.is_empty() {
+                return Err(anyhow!(
+                    "crate: {}, unresolved conflict markers remain in:\n{}",
+                    name,
+                    conflicted
+                        .iter()
+                        .map(|f| format!("  - {:?}", f))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ));
+            }
+            create_patches(
+                &args,
+                workspace,
+                &pkg_set,
+                &resolve,
+                std::slice::from_ref(name),
+            )?;
+        }
+        None if !args.clean.is_empty() => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            apply_patches(
+                &args,
+                workspace,
+                &pkg_set,
+                &resolve,
+                &workspace.patches_folder(),
+                &args.clean,
+            )?;
+        }
+        None if !args.crates.is_empty() && !args.apply.reverse => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            if args.create.watch {
+                watch_patches(&args, workspace, &pkg_set, &resolve, &args.crates)?;
+            } else {
+                create_patches(&args, workspace, &pkg_set, &resolve, &args.crates)?;
+            }
+        }
+        None if args.create.check => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            check_patches(workspace, &pkg_set, &resolve)?;
+        }
+        None => {
+            let (pkg_set, resolve) = resolve_ws(workspace, &args)?;
+            // `--reverse <crates>` only undoes the patches of these crates
+            apply_patches(
+                &args,
+                workspace,
+                &pkg_set,
+                &resolve,
+                &workspace.patches_folder(),
+                &args.crates,
+            )?;
+        }
+    }
+
+    info!("Done");
+    Ok(())
+}
+
+/// Lists every patch in `patches/` with whether it is for the resolved version
+/// of its crate, whether the crate is copied to `target/patch` and its size,
+/// followed by the crates in the patch metadata that have no patch yet.
+fn list_crates(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    format: OutputFormat,
+) -> Result<()> {
+    let patches_folder = workspace.patches_folder();
+    let mut folders = vec![workspace.patch_target_folder()];
+    // the copies `--per-target-layout` made for the configured targets
+    for target in ResolveOptions::new(args, workspace)?.targets {
+        let folder = workspace.patch_target_folder().join(target);
+        if folder.is_dir() {
+            folders.push(folder);
+        }
+    }
+    let entries = workspace.patch_crates()?;
+    let groups = if patches_folder.exists() {
+        patch_groups(&patches_folder)?
+    } else {
+        Vec::new()
+    };
+
+    let mut listed = Vec::new();
+    let mut patched = HashSet::new();
+    for patch_files in groups {
+        let (stem, _) = split_patch_stem(&patch_stem(&patch_files[0]).unwrap_or_default());
+        let Some((pkg_name, version)) = stem.split_once('+') else {
+            continue;
+        };
+        patched.insert(pkg_name.to_string());
+        let resolved = match version.strip_prefix(GIT_REV_PREFIX) {
+            Some(rev) => query_git_package(resolve, pkg_name, rev).ok(),
+            None => resolve
+                .query(&format!("{}@{}", pkg_name, version))
+                .or_else(|_| resolve.query(pkg_name))
+                .ok(),
+        };
+        let current = resolved.is_some_and(|pkg_id| match version.strip_prefix(GIT_REV_PREFIX) {
+            Some(rev) => pkg_id
+                .source_id()
+                .precise_git_fragment()
+                .is_some_and(|precise| precise.starts_with(rev)),
+            None => match version_range(version) {
+                Some(req) => req.matches(pkg_id.version()),
+                None => pkg_id.version().to_string() == version,
+            },
+        });
+        let copied = match resolved {
+            Some(pkg_id) => {
+                let slug = pkg_set.get_one(pkg_id)?.slug()?.to_string();
+                folders.iter().any(|folder| folder.join(&slug).exists())
+            }
+            None => false,
+        };
+        let mut size = 0;
+        for patch_file in &patch_files {
+            size += fs::metadata(patch_file)?.len();
+        }
+        let mut settings = match entries.iter().find(|entry| entry.matches_name(pkg_name)) {
+            Some(entry) => patch_settings(entry)?,
+            None => Vec::new(),
+        };
+        // e.g. given with `--message` when the patch was created
+        if !settings.iter().any(|(key, _)| key == "reason") {
+            if let Some(reason) = patch_header_field(&patch_files[0], REASON_HEADER)? {
+                settings.push(("reason".to_string(), reason.into()));
+            }
+        }
+        listed.push(serde_json::json!({
+            "name": pkg_name,
+            "version": version,
+            "resolved": resolved.map(|pkg_id| pkg_id.version().to_string()),
+            "current": current,
+            "copied": copied,
+            "size": size,
+            "patches": patch_files
+                .iter()
+                .map(|patch_file| patch_file_key(patch_file))
+                .collect::<Vec<_>>(),
+            "settings": settings.into_iter().collect::<serde_json::Map<_, _>>(),
+        }));
+    }
+    for entry in &entries {
+        for resolved in entry.package_ids(workspace, resolve)? {
+            if !patched.insert(resolved.name().to_string()) {
+                continue;
+            }
+            listed.push(serde_json::json!({
+                "name": resolved.name().as_str(),
+                "version": serde_json::Value::Null,
+                "resolved": resolved.version().to_string(),
+                "current": false,
+                "copied": false,
+                "size": 0,
+                "patches": [],
+                "settings": patch_settings(entry)?.into_iter().collect::<serde_json::Map<_, _>>(),
+            }));
+        }
+    }
+
+    match format {
+        OutputFormat::Lines => {
+            for item in &listed {
+                let name = item["name"].as_str().unwrap_or_default();
+                let resolved = item["resolved"].as_str();
+                match item["version"].as_str() {
+                    None => info!("{} {}: no patch", name, resolved.unwrap_or_default()),
+                    Some(version) => {
+                        let status = match resolved {
+                            Some(_) if item["current"] == true => "current".to_string(),
+                            Some(resolved) => format!("stale, {} is resolved", resolved),
+                            None => "not a dependency".to_string(),
+                        };
+                        let patches = item["patches"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|patch| patch.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        info!(
+                            "{} {}: {} ({}), {}, {}",
+                            name,
+                            version,
+                            patches,
+                            human_size(item["size"].as_u64().unwrap_or_default()),
+                            status,
+                            if item["copied"] == true {
+                                "copied"
+                            } else {
+                                "not copied"
+                            }
+                        );
+                    }
+                }
+                for (key, value) in item["settings"].as_object().into_iter().flatten() {
+                    match value {
+                        serde_json::Value::String(value) => info!("  {}: {}", key, value),
+                        value => info!("  {}: {}", key, value),
+                    }
+                }
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&listed)?),
+    }
+    Ok(())
+}
+
+/// The settings of a metadata entry that `list` shows.
+fn patch_settings(entry: &CrateEntry<'_>) -> Result<Vec<(String, serde_json::Value)>> {
+    let mut settings = Vec::new();
+    for key in ["version", "group", "reason", "upstream_pr"] {
+        if let Some(value) = entry.setting(key)? {
+            settings.push((key.to_string(), value.to_string().into()));
+        }
+    }
+    if let Some(features) = entry.setting_strings("features")? {
+        settings.push(("features".to_string(), features.into()));
+    }
+    if let Some(optional) = entry.setting_bool("optional")? {
+        settings.push(("optional".to_string(), optional.into()));
+    }
+    Ok(settings)
+}
+
+/// Prints the resolved packages other than the workspace members, sorted by
+/// name and version.
+fn list_deps(
+    workspace: &Workspace<'_>,
+    resolve: &Resolve,
+    filter: Option<&str>,
+    format: OutputFormat,
+) -> Result<()> {
+    let members = workspace
+        .members()
+        .map(|m| m.package_id())
+        .collect::<HashSet<_>>();
+    let mut deps = resolve
+        .iter()
+        .filter(|id| !members.contains(id))
+        .filter(|id| filter.is_none_or(|f| id.name().contains(f)))
+        .collect::<Vec<_>>();
+    deps.sort_by(|a, b| (a.name(), a.version()).cmp(&(b.name(), b.version())));
+    match format {
+        OutputFormat::Lines => {
+            for id in deps {
+                println!("{} {}", id.name(), id.version());
+            }
+        }
+        OutputFormat::Json => {
+            let deps = deps
+                .into_iter()
+                .map(|id| {
+                    serde_json::json!({
+                        "name": id.name().as_str(),
+                        "version": id.version().to_string(),
+                        "source": id.source_id().as_url().to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&deps)?);
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the crates listed in the patch metadata of the workspace and all of
+/// its members. `Workspace::custom_metadata` is `[workspace.metadata]`, which is
+/// the only place available in a virtual manifest, while every member,
+/// default or not, contributes its `[package.metadata]`. Crates that are not
+/// in the cache yet are downloaded at the same time.
+fn configured_crates<'a>(
+    args: &Cli,
+    workspace: &Workspace<'_>,
+    pkg_set: &'a PackageSet<'_>,
+    resolve: &Resolve,
+) -> Result<HashSet<&'a Package>> {
+    let all_features = ResolveOptions::new(args, workspace)?.all_features;
+    let mut ids = HashSet::new();
+    for entry in workspace.patch_crates()? {
+        // fewer features than a build may need leave out crates
+        if !all_features && !resolve.iter().any(|id| entry.matches(id)) {
+            warn!(
+                "crate: {}, skip, it is not a dependency with the selected features, so it is neither copied nor patched.",
+                entry.name
+            );
+            continue;
+        }
+        let entry_ids = entry.package_ids(workspace, resolve)?;
+        if entry_ids.is_empty() {
+            crate_info!(
+                "crate: {}, skip, it is optional and not resolved.",
+                entry.name
+            );
+        }
+        ids.extend(entry_ids);
+    }
+    let excludes = Excludes::new(args, workspace)?;
+    for exclude in excludes.0.iter() {
+        if !ids.iter().any(|id| Excludes::entry(exclude).matches(*id)) {
+            warn!(
+                "`{}` in `exclude` matches none of the crates to patch, ignored.",
+                exclude
+            );
+        }
+    }
+    ids.retain(|id| !excludes.matches(*id));
+    Ok(pkg_set.get_many(ids)?.into_iter().collect())
+}
+
+/// The crates taken out of `crates` of the patch metadata by `--exclude` and
+/// `exclude` in the patch metadata, as names, specs or glob patterns like the
+/// entries of `crates`.
+struct Excludes(Vec<String>);
+
+impl Excludes {
+    fn new(args: &Cli, workspace: &Workspace<'_>) -> Result<Self> {
+        let mut excludes = args.resolving.exclude.clone();
+        excludes.extend(workspace.root_patch_metadata_strings("exclude")?);
+        for exclude in excludes.iter() {
+            let entry = Self::entry(exclude);
+            if entry.is_pattern() {
+                glob::Pattern::new(&entry.name).map_err(|err| {
+                    anyhow!("invalid pattern `{}` in `exclude`: {}", exclude, err)
+                })?;
+            }
+        }
+        Ok(Self(excludes))
+    }
+
+    fn entry(exclude: &str) -> CrateEntry<'_> {
+        CrateEntry {
+            name: Cow::Borrowed(exclude),
+            alias: None,
+            settings: None,
+        }
+    }
+
+    fn matches(&self, pkg_id: PackageId) -> bool {
+        self.0
+            .iter()
+            .any(|exclude| Self::entry(exclude).matches(pkg_id))
+    }
+
+    fn matches_name(&self, name: &str) -> bool {
+        self.0
+            .iter()
+            .any(|exclude| Self::entry(exclude).matches_name(name))
+    }
+}
+
+/// The entries of the `[patch]` sections of the root manifest that override
+/// one of `crates` to a source other than its copy in `target/patch`, as
+/// the crate, its section and the entry. Cargo then builds that source, so
+/// the patch doesn't take effect.
+fn conflicting_overrides(
+    workspace: &PatchWorkspace<'_>,
+    crates: &[&Package],
+) -> Result<Vec<(String, String, String)>> {
+    let manifest = workspace.root_manifest();
+    let doc: toml_edit::DocumentMut = fs::read_to_string(manifest)?.parse()?;
+    let Some(patch) = doc.get("patch").and_then(|p| p.as_table_like()) else {
+        return Ok(Vec::new());
+    };
+    let root = std::path::absolute(workspace.root())?;
+    let target = std::path::absolute(workspace.patch_target_folder())?;
+    let mut conflicts = Vec::new();
+    for (section, table) in patch.iter() {
+        let Some(table) = table.as_table_like() else {
+            continue;
+        };
+        for (key, entry) in table.iter() {
+            let name = entry
+                .get("package")
+                .and_then(|name| name.as_str())
+                .unwrap_or(key);
+            let into_target = entry
+                .get("path")
+                .and_then(|path| path.as_str())
+                .is_some_and(|path| root.join(path).starts_with(&target));
+            if !into_target && crates.iter().any(|pkg| pkg.name() == name) {
+                conflicts.push((
+                    name.to_string(),
+                    section.to_string(),
+                    format!("{} = {}", key, entry.to_string().trim()),
+                ));
+            }
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Returns the registry crates among `pkg_ids` whose version has been yanked,
+/// the same way `cargo package` checks the `Cargo.lock` it ships. With
+/// `refresh` the index is fetched again first, otherwise, as offline, the
+/// cached index is asked, which may not know about recent yanks.
+fn yanked_crates(
+    workspace: &Workspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    pkg_ids: &[PackageId],
+    refresh: bool,
+) -> Result<Vec<PackageId>> {
+    let gctx = workspace.gctx();
+    let _lock = gctx.acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
+    let mut sources = pkg_set.sources_mut();
+    let mut pending: Vec<PackageId> = pkg_ids
+        .iter()
+        .filter(|id| id.source_id().is_registry())
+        .copied()
+        .collect();
+    if refresh && !gctx.offline() {
+        for (_id, source) in sources.sources_mut() {
+            source.invalidate_cache();
+        }
+    }
+    let mut results = Vec::new();
+    while !pending.is_empty() {
+        pending.retain(|pkg_id| {
+            if let Some(source) = sources.get_mut(pkg_id.source_id()) {
+                match source.is_yanked(*pkg_id) {
+                    Poll::Ready(result) => results.push((*pkg_id, result)),
+                    Poll::Pending => return true,
+                }
+            }
+            false
+        });
+        for (_id, source) in sources.sources_mut() {
+            source.block_until_ready()?;
+        }
+    }
+    let mut yanked = Vec::new();
+    for (pkg_id, is_yanked) in results {
+        if is_yanked? {
+            yanked.push(pkg_id);
+        }
+    }
+    yanked.sort();
+    Ok(yanked)
+}
+
+/// Deletes the patches of `crates`, their copies in `target/patch`, also the
+/// ones of `--per-target-layout`, their recorded hashes and the `[patch]`
+/// entries of the root manifest pointing at the copies.
+fn remove_patches(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    crates: &[String],
+) -> Result<()> {
+    let patches_folder = workspace.patches_folder();
+    let hashes_file = patches_folder.join(HASHES_FILE);
+    let mut hashes: toml::Table = if hashes_file.exists() {
+        fs::read_to_string(&hashes_file)?.parse()?
+    } else {
+        toml::Table::new()
+    };
+    let recorded = hashes.len();
+    let mut folders = vec![workspace.patch_target_folder()];
+    // the copies `--per-target-layout` made for the configured targets
+    for target in ResolveOptions::new(args, workspace)?.targets {
+        let folder = workspace.patch_target_folder().join(target);
+        if folder.is_dir() {
+            folders.push(folder);
+        }
+    }
+    let entries = workspace.patch_crates()?;
+    let mut copies = Vec::new();
+    let mut removed_ids = Vec::new();
+    for name in crates {
+        let pkg = pkg_set.get_one(query_dependency(resolve, name)?)?;
+        removed_ids.push(pkg.package_id());
+        let mut removed = false;
+        for patch_file in find_patch_files(&patches_folder, pkg.package_id())? {
+            fs::remove_file(&patch_file)?;
+            hashes.remove(&patch_file_key(&patch_file));
+            // removing a crate folder fails unless its last patch is gone
+            if let (Some(_), Some(version_folder)) =
+                (nested_patch(&patch_file), patch_file.parent())
+            {
+                if fs::remove_dir(version_folder).is_ok() {
+                    let _ = version_folder.parent().map(fs::remove_dir);
+                }
+            }
+            crate_info!("crate: {}, removed patch {:?}.", name, patch_file);
+            removed = true;
+        }
+        for folder in folders.iter() {
+            let patch_target_path = std::path::absolute(folder.join(pkg.slug()?))?;
+            if patch_target_path.exists() {
+                fs::remove_dir_all(&patch_target_path)?;
+                crate_info!("crate: {}, removed {:?}.", name, patch_target_path);
+                removed = true;
+            }
+            let marker = source_marker(&patch_target_path);
+            if marker.exists() {
+                fs::remove_file(marker)?;
+            }
+            copies.push(patch_target_path);
+        }
+        if !removed {
+            crate_info!(
+                "crate: {}, nothing to remove, it has no patch and is not copied.",
+                name
+            );
+        }
+        if entries.iter().any(|entry| entry.matches(pkg.package_id())) {
+            crate_info!(
+                "crate: {}, it is still listed in the patch metadata, remove it there to stop copying it.",
+                name
+            );
+        }
+    }
+    if hashes.len() != recorded {
+        fs::write(&hashes_file, toml::to_string(&hashes)?)?;
+    }
+    update_patch_lock(&patches_folder, &removed_ids)?;
+    remove_manifest_entries(workspace, &copies)
+}
+
+/// Removes the `[patch]` entries of the root manifest whose path is one of
+/// `copies`, keeping its formatting.
+fn remove_manifest_entries(workspace: &Workspace<'_>, copies: &[PathBuf]) -> Result<()> {
+    let manifest = workspace.root_manifest();
+    let root = std::path::absolute(workspace.root())?;
+    let original = fs::read_to_string(manifest)?;
+    let mut doc: toml_edit::DocumentMut = original.parse()?;
+    let Some(patch) = doc.get_mut("patch").and_then(|p| p.as_table_like_mut()) else {
+        return Ok(());
+    };
+    for (section, table) in patch.iter_mut() {
+        let Some(table) = table.as_table_like_mut() else {
+            continue;
+        };
+        let removed = table
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .as_table_like()
+                    .and_then(|entry| entry.get("path"))
+                    .and_then(|path| path.as_str())
+                    .is_some_and(|path| copies.contains(&root.join(path)))
+            })
+            .map(|(key, _)| key.to_string())
+            .collect::<Vec<_>>();
+        for key in removed {
+            crate_info!(
+                "crate: {}, removed its entry under [patch.{}].",
+                key,
+                section
+            );
+            table.remove(&key);
+        }
+    }
+    // sections that only held removed entries would be left empty
+    let empty = patch
+        .iter()
+        .filter(|(_, table)| table.as_table_like().is_some_and(|t| t.is_empty()))
+        .map(|(section, _)| section.to_string())
+        .collect::<Vec<_>>();
+    for section in empty {
+        patch.remove(&section);
+    }
+    if patch.is_empty() {
+        doc.remove("patch");
+    }
+
+    let updated = doc.to_string();
+    if updated != original {
+        fs::write(manifest, updated)?;
+        info!("updated the [patch] entries in {:?}", manifest);
+    }
+    Ok(())
+}
+
+fn prune_patch_folder(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+) -> Result<()> {
+    let configured = configured_crates(args, workspace, pkg_set, resolve)?;
+    for path in stale_copies(&workspace.patch_target_folder(), &configured)? {
+        if is_edited_copy(&path)? && !args.yes {
+            warn!(
+                "{:?} was edited since it was patched, kept; create its patch first or pass `--yes` to prune it.",
+                path
+            );
+        } else if args.apply.dry_run {
+            info!("would prune {:?}", path);
+        } else {
+            info!("pruning {:?}", path);
+            discard_copy(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The copies in `patch_target_folder` of crates that are not in
+/// `configured`, e.g. of a version that is no longer resolved. Folders without
+/// a source marker were not copied by patch-crate and are never stale.
+fn stale_copies(
+    patch_target_folder: &Path,
+    configured: &HashSet<&Package>,
+) -> Result<Vec<PathBuf>> {
+    let configured = configured
+        .iter()
+        .map(|pkg| pkg.slug().map(OsString::from))
+        .collect::<Result<HashSet<_>>>()?;
+    let mut stale = Vec::new();
+    if !patch_target_folder.exists() {
+        return Ok(stale);
+    }
+    for entry in fs::read_dir(patch_target_folder)? {
+        let entry = entry?;
+        if entry.metadata()?.is_dir()
+            && !configured.contains(&entry.file_name())
+            && source_marker(&entry.path()).exists()
+        {
+            stale.push(entry.path());
+        }
+    }
+    stale.sort();
+    Ok(stale)
+}
+
+fn report_size(workspace: &PatchWorkspace<'_>) -> Result<()> {
+    let patch_target_folder = workspace.patch_target_folder();
+    let mut total = 0;
+    if patch_target_folder.exists() {
+        let mut entries = fs::read_dir(&patch_target_folder)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            if entry
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == SOURCE_MARKER_EXT)
+            {
+                continue;
+            }
+            let size = fs_extra::dir::get_size(entry.path())?;
+            total += size;
+            info!(
+                "{:>10}  {}",
+                human_size(size),
+                entry.file_name().to_string_lossy()
+            );
+        }
+    }
+    info!(
+        "{:>10}  total in {:?}",
+        human_size(total),
+        patch_target_folder
+    );
+    Ok(())
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Rebases the patch of an older version of crate `name` onto its resolved
+/// version: the changes the old patches make to the old version are merged
+/// into a fresh copy of the new one in `target/patch`, its patch is created
+/// and the old patches are deleted. Conflicts are left in the copy with
+/// markers, to be fixed and finished with `resolve`.
+fn rebase_patch(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    name: &str,
+) -> Result<()> {
+    git::check_installed()?;
+    let pkg_id = query_dependency(resolve, name)?;
+    let pkg_name = pkg_id.name();
+    if !pkg_id.source_id().is_registry() {
+        return Err(anyhow!(
+            "crate: {}, only crates from a registry can be rebased, {} is not",
+            pkg_name,
+            pkg_id
+        ));
+    }
+    let patches_folder = workspace.patches_folder();
+    if let Some(patch_file) = find_patch_file(&patches_folder, pkg_id)? {
+        return Err(anyhow!(
+            "crate: {}, patch {:?} is already for {}, nothing to rebase",
+            pkg_name,
+            patch_file,
+            pkg_id.version()
+        ));
+    }
+    let Some((old_version, old_patches)) =
+        stale_patches(&patches_folder, &pkg_name, pkg_id.version())?
+    else {
+        return Err(anyhow!(
+            "crate: {}, there is no patch for another version in {:?}",
+            pkg_name,
+            patches_folder
+        ));
+    };
+    info!(
+        "crate: {}, rebasing the patch of {} onto {}.",
+        pkg_name,
+        old_version,
+        pkg_id.version()
+    );
+
+    // the old version is no longer resolved, download it the way cargo would
+    let old_id = PackageId::new(pkg_name, old_version.clone(), pkg_id.source_id());
+    let mut registry = PackageRegistry::new_with_source_config(
+        workspace.gctx(),
+        SourceConfigMap::new(workspace.gctx())?,
+    )?;
+    registry.add_sources([pkg_id.source_id()])?;
+    let old_set = registry.get(&[old_id])?;
+    let old_pkg = old_set.get_one(old_id)?;
+
+    let pkg = pkg_set.get_one(pkg_id)?;
+    let patch_target_path = pkg.patch_target_path(workspace)?;
+    if is_edited_copy(&patch_target_path)? {
+        confirm_overwrite(args, std::slice::from_ref(&patch_target_path))?;
+    }
+    let rebase_folder = workspace.patch_target_tmp_folder().join("rebase");
+    let base = copy_package(
+        old_pkg,
+        &workspace.config,
+        &rebase_folder.join("base"),
+        true,
+    )?;
+    let patched = copy_package(
+        old_pkg,
+        &workspace.config,
+        &rebase_folder.join("patched"),
+        true,
+    )?;
+    let eol = args.patching.normalize_eol
+        || workspace
+            .root_patch_metadata_strings("normalize_eol")?
+            .iter()
+            .any(|n| matches_spec(n, old_id));
+    for patch_file in &old_patches {
+        apply_patch(args, &pkg_name, &patched, patch_file, eol).map_err(|err| {
+            anyhow!(
+                "crate: {}, patch {:?} does not apply to {} it was made for: {}",
+                pkg_name,
+                patch_file,
+                old_id,
+                err
+            )
+        })?;
+    }
+    let copy = copy_package(
+        pkg,
+        &workspace.config,
+        &workspace.patch_target_folder(),
+        true,
+    )?;
+    let labels = [
+        format!("{} {}", pkg_name, pkg_id.version()),
+        format!("{} {}", pkg_name, old_version),
+        "patch".to_string(),
+    ];
+    let conflicts = merge_trees(&base, &patched, &copy, &rebase_folder, &labels);
+    fs::remove_dir_all(&rebase_folder)?;
+    let _ = fs::remove_dir(workspace.patch_target_tmp_folder());
+    let conflicts = conflicts?;
+
+    let old_list = old_patches
+        .iter()
+        .map(|f| format!("  - {:?}", f))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "crate: {}, the patch of {} conflicts with {} in {:?}:\n{}\nfix them, run `cargo patch-crate resolve {}` and delete the old patches:\n{}",
+            pkg_name,
+            old_version,
+            pkg_id.version(),
+            copy,
+            conflicts
+                .iter()
+                .map(|c| format!("  - {}", c))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            pkg_name,
+            old_list
+        ));
+    }
+    create_patches(args, workspace, pkg_set, resolve, &[pkg_name.to_string()])?;
+    for patch_file in &old_patches {
+        fs::remove_file(patch_file)?;
+    }
+    info!(
+        "crate: {}, rebased onto {}, deleted the old patches:\n{}",
+        pkg_name,
+        pkg_id.version(),
+        old_list
+    );
+    Ok(())
+}
+
+/// Merges the changes from `base` to `patched` into `copy`, file by file with
+/// `git merge-file`, and returns the files that conflict. Conflicting text is
+/// left in `copy` between markers labeled with the versions of `copy`, `base`
+/// and the patch.
+fn merge_trees(
+    base: &Path,
+    patched: &Path,
+    copy: &Path,
+    tmp_folder: &Path,
+    labels: &[String; 3],
+) -> Result<Vec<String>> {
+    let mut files = list_files(base)?;
+    files.extend(list_files(patched)?);
+    files.sort();
+    files.dedup();
+    let read = |dir: &Path, file: &str| fs::read(dir.join(file)).ok();
+    let empty = tmp_folder.join("empty");
+    fs::write(&empty, "")?;
+    let mut conflicts = Vec::new();
+    for file in files {
+        let (before, after) = (read(base, &file), read(patched, &file));
+        if before == after {
+            continue;
+        }
+        let target = copy.join(&file);
+        match (before, after, read(copy, &file)) {
+            // removed by the patch
+            (_, None, None) => {}
+            (before, None, current) if current == before => fs::remove_file(&target)?,
+            (_, None, Some(_)) => conflicts.push(format!(
+                "{}, removed by the patch but changed upstream",
+                file
+            )),
+            (_, Some(_), None) => {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(patched.join(&file), &target)?;
+                if base.join(&file).exists() {
+                    conflicts.push(format!(
+                        "{}, changed by the patch but removed upstream",
+                        file
+                    ));
+                }
+            }
+            (_, Some(after), Some(current)) if current == after => {}
+            (before, Some(_), Some(_)) => {
+                let base_file = match before {
+                    Some(_) => base.join(&file),
+                    None => empty.clone(),
+                };
+                if !git::merge_file(&target, &base_file, &patched.join(&file), labels)? {
+                    conflicts.push(file);
+                }
+            }
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Copies `crates` to `target/patch` as they are, without their patches, and
+/// prints where. Existing copies are kept unless `--force` is given.
+fn extract_crates(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    crates: &[String],
+) -> Result<()> {
+    let mut pkgs = Vec::new();
+    for name in crates {
+        pkgs.push(pkg_set.get_one(query_dependency(resolve, name)?)?);
+    }
+    let patch_target_folder = workspace.patch_target_folder();
+    if args.copy.force {
+        let mut edited = Vec::new();
+        for pkg in pkgs.iter() {
+            let patch_target_path = patch_target_folder.join(pkg.slug()?);
+            if is_edited_copy(&patch_target_path)? {
+                edited.push(patch_target_path);
+            }
+        }
+        confirm_overwrite(args, &edited)?;
+    }
+    for pkg in pkgs {
+        verify_checksum(pkg, resolve)?;
+        let patch_target_path = copy_package(
+            pkg,
+            &workspace.config,
+            &patch_target_folder,
+            args.copy.force,
+        )?;
+        info!(
+            "crate: {}, extracted to {:?}.",
+            pkg.name(),
+            std::path::absolute(&patch_target_path)?
+        );
+        print_override(args, workspace, pkg, &patch_target_path, None)?;
+    }
+    Ok(())
+}
+
+/// Adds `crates` to `crates` of the root patch metadata, keeping the
+/// formatting of the manifest, copies them to `target/patch` and tells what to
+/// do next. Crates already in the metadata are left as they are, so running it
+/// again changes nothing.
+fn init_crates(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    crates: &[String],
+) -> Result<()> {
+    let entries = workspace.patch_crates()?;
+    let manifest = workspace.root_manifest();
+    let original = fs::read_to_string(manifest)?;
+    let mut doc: toml_edit::DocumentMut = original.parse()?;
+    // `[workspace.metadata.patch]` when it is there or the root has no package
+    let section = if doc
+        .get("workspace")
+        .and_then(|w| w.get("metadata"))
+        .and_then(|m| m.get("patch"))
+        .is_some()
+        || matches!(workspace.root_maybe(), MaybePackage::Virtual(_))
+    {
+        "workspace"
+    } else {
+        "package"
+    };
+    let mut pkgs = Vec::new();
+    for name in crates {
+        let pkg_id = query_dependency(resolve, name)?;
+        pkgs.push(pkg_set.get_one(pkg_id)?);
+        if entries.iter().any(|entry| entry.matches(pkg_id)) {
+            crate_info!("crate: {}, already in the patch metadata.", name);
+            continue;
+        }
+        let mut table = doc.as_table_mut() as &mut dyn toml_edit::TableLike;
+        for key in [section, "metadata", "patch"] {
+            table = table
+                .entry(key)
+                .or_insert_with(|| {
+                    // only `[<section>.metadata.patch]` gets a header
+                    let mut table = toml_edit::Table::new();
+                    table.set_implicit(key != "patch");
+                    toml_edit::Item::Table(table)
+                })
+                .as_table_like_mut()
+                .ok_or(anyhow!("`{}` in {:?} must be a table", key, manifest))?;
+        }
+        let list = table
+            .entry("crates")
+            .or_insert(toml_edit::value(toml_edit::Array::new()))
+            .as_array_mut()
+            .ok_or(anyhow!(
+                "`crates` in [{}.metadata.patch] of {:?} must be an array",
+                section,
+                manifest
+            ))?;
+        // only a crate resolved in several versions needs its version pinned
+        if resolve
+            .iter()
+            .filter(|id| id.name() == pkg_id.name())
+            .count()
+            > 1
+        {
+            let mut entry = toml_edit::InlineTable::new();
+            entry.insert("name", pkg_id.name().as_str().into());
+            entry.insert("version", format!("={}", pkg_id.version()).into());
+            list.push(entry);
+        } else {
+            list.push(pkg_id.name().as_str());
+        }
+        crate_info!(
+            "crate: {}, added to `crates` in [{}.metadata.patch].",
+            name,
+            section
+        );
+    }
+    let updated = doc.to_string();
+    if updated != original {
+        fs::write(manifest, updated)?;
+        info!("updated the patch metadata in {:?}", manifest);
+    }
+
+    extract_crates(args, workspace, pkg_set, resolve, crates)?;
+    if args.manifest.write_manifest {
+        let mut configured = configured_crates(args, workspace, pkg_set, resolve)?;
+        configured.extend(pkgs.iter().copied());
+        write_manifest(
+            args,
+            workspace,
+            &pkgs,
+            &configured,
+            &workspace.patch_target_folder(),
+        )?;
+    }
+    for pkg in pkgs {
+        info!(
+            "next: edit {:?} and run `cargo patch-crate {}` to save your changes as a patch in {:?}{}, then commit the patch along with Cargo.toml.",
+            pkg.patch_target_path(workspace)?,
+            pkg.name(),
+            workspace.patches_folder(),
+            if args.manifest.write_manifest {
+                ""
+            } else {
+                "; add the override above to Cargo.toml, or pass `--write-manifest` to add it"
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Compares the copies in `target/patch` of the crates in the patch metadata,
+/// or of `only`, with a pristine copy that the patches are applied to, and
+/// prints one line per crate.
+fn patch_status(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    only: &[String],
+) -> Result<()> {
+    git::check_installed()?;
+    let patches_folder = workspace.patches_folder();
+    let status_folder = workspace.patch_target_tmp_folder().join("status");
+    let normalize_eol = workspace.root_patch_metadata_strings("normalize_eol")?;
+    let mut crates = if only.is_empty() {
+        configured_crates(args, workspace, pkg_set, resolve)?
+            .into_iter()
+            .collect::<Vec<_>>()
+    } else {
+        only.iter()
+            .map(|n| query_dependency(resolve, n).and_then(|id| pkg_set.get_one(id)))
+            .collect::<Result<Vec<_>>>()?
+    };
+    crates.sort_by_key(|pkg| pkg.package_id());
+
+    for pkg in crates {
+        let patch_files = find_patch_files(&patches_folder, pkg.package_id())?;
+        if patch_files.is_empty() {
+            continue;
+        }
+        let patch_target_path = pkg.patch_target_path(workspace)?;
+        let status = if !patch_target_path.exists() {
+            "not copied".to_string()
+        } else if !is_complete_copy(&patch_target_path) {
+            "incomplete copy, apply again".to_string()
+        } else if !is_current_copy(&patch_target_path, &source_stamp(pkg, &workspace.config)?) {
+            format!("not a copy of {}, apply again", pkg.package_id())
+        } else {
+            let eol = normalize_eol
+                .iter()
+                .any(|n| matches_spec(n, pkg.package_id()));
+            let expected = copy_package(pkg, &workspace.config, &status_folder, true)?;
+            let failed = first_failing_patch(&expected, &patch_files, eol);
+            let in_sync = tree_hash(&expected)? == tree_hash(&patch_target_path)?;
+            discard_copy(&expected)?;
+            if let Some(patch_file) = failed? {
+                format!("patch {:?} does not apply", patch_file)
+            } else if in_sync {
+                "in sync".to_string()
+            } else if all_patches_present(&patch_target_path, &status_folder, &patch_files)? {
+                "local changes not in patch".to_string()
+            } else {
+                "patch not fully applied".to_string()
+            }
+        };
+        info!("crate: {}, {}.", pkg.name(), status);
+    }
+    if status_folder.exists() {
+        fs::remove_dir_all(&status_folder)?;
+    }
+    let _ = fs::remove_dir(workspace.patch_target_tmp_folder());
+    Ok(())
+}
+
+/// What applying does to one crate, see [`plan_crates`].
+struct PlannedCrate {
+    pkg_id: PackageId,
+    /// The patch files of the crate, or the one a new patch is written to
+    patch_files: Vec<PathBuf>,
+    patch_target_path: PathBuf,
+}
+
+/// The crates applying would handle, all crates in the patch metadata or
+/// `only`, with their patches and copies, sorted by name and version. Nothing
+/// is copied or written.
+fn plan_crates(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    only: &[String],
+) -> Result<Vec<PlannedCrate>> {
+    let patches_folder = workspace.patches_folder();
+    let mut crates = if only.is_empty() {
+        configured_crates(args, workspace, pkg_set, resolve)?
+            .into_iter()
+            .collect::<Vec<_>>()
+    } else {
+        only.iter()
+            .map(|n| query_dependency(resolve, n).and_then(|id| pkg_set.get_one(id)))
+            .collect::<Result<Vec<_>>>()?
+    };
+    crates.sort_by_key(|pkg| pkg.package_id());
+    let mut planned = Vec::new();
+    for pkg in crates {
+        let mut patch_files = find_patch_files(&patches_folder, pkg.package_id())?;
+        if patch_files.is_empty() {
+            patch_files.push(existing_patch_file(
+                &patches_folder,
+                &patch_file_stem(pkg.package_id()),
+                workspace.config.nested,
+            ));
+        }
+        planned.push(PlannedCrate {
+            pkg_id: pkg.package_id(),
+            patch_files,
+            patch_target_path: pkg.patch_target_path(workspace)?,
+        });
+    }
+    Ok(planned)
+}
+
+/// Prints the crates of [`plan_crates`], one line each or as a JSON array.
+fn print_plan(
+    workspace: &Workspace<'_>,
+    crates: &[PlannedCrate],
+    format: OutputFormat,
+) -> Result<()> {
+    let relative = |path: &Path| {
+        path.strip_prefix(workspace.root())
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    };
+    match format {
+        OutputFormat::Lines => {
+            for planned in crates {
+                let patched = planned.patch_files.iter().all(|file| file.exists());
+                let patches = planned
+                    .patch_files
+                    .iter()
+                    .map(|file| relative(file))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let target = relative(&planned.patch_target_path);
+                let action = if patched {
+                    format!("apply {} to {}", patches, target)
+                } else {
+                    format!("copy to {}, no patch at {}", target, patches)
+                };
+                println!(
+                    "{} {}: {}",
+                    planned.pkg_id.name(),
+                    planned.pkg_id.version(),
+                    action
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let crates = crates
+                .iter()
+                .map(|planned| {
+                    let patched = planned.patch_files.iter().all(|file| file.exists());
+                    serde_json::json!({
+                        "name": planned.pkg_id.name().as_str(),
+                        "version": planned.pkg_id.version().to_string(),
+                        "source": planned.pkg_id.source_id().as_url().to_string(),
+                        "action": if patched { "apply" } else { "copy" },
+                        "patches": planned.patch_files,
+                        "patch_exists": patched,
+                        "target": planned.patch_target_path,
+                        "copied": planned.patch_target_path.exists(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&crates)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty folder for the test `name` in the temporary folder.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("patch-crate-{}-{}", name, std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn strings(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_paths_colliding_by_case() {
+        let paths = strings(&["src/Foo.rs", "src/lib.rs", "src/foo.rs", "SRC/lib.rs"]);
+        assert_eq!(
+            case_collisions(&paths),
+            [("src/Foo.rs", "src/foo.rs"), ("src/lib.rs", "SRC/lib.rs")]
+        );
+        assert!(case_collisions(&strings(&["a.rs", "b.rs"])).is_empty());
+    }
+
+    #[test]
+    fn finds_files_renamed_by_case_only() {
+        let pristine = strings(&["src/Foo.rs", "src/lib.rs", "README.md"]);
+        let modified = strings(&["src/foo.rs", "src/lib.rs", "readme.md", "README.md"]);
+        assert_eq!(
+            case_only_renames(&pristine, &modified),
+            [("src/Foo.rs".to_string(), "src/foo.rs".to_string())]
+        );
+    }
+
+    #[test]
+    fn takes_a_copy_without_its_marker_or_cargo_toml_for_incomplete() {
+        let dir = temp_dir("complete-copy");
+        let copy = dir.join("demo-0.1.0");
+        fs::create_dir_all(copy.join("src")).unwrap();
+        fs::write(copy.join("src/lib.rs"), "").unwrap();
+        fs::write(source_marker(&copy), "demo 0.1.0\n").unwrap();
+        assert!(!is_complete_copy(&copy));
+        assert!(!is_current_copy(&copy, "demo 0.1.0\n"));
+
+        fs::write(copy.join("Cargo.toml"), "[package]\n").unwrap();
+        assert!(is_complete_copy(&copy));
+        assert!(is_current_copy(&copy, "demo 0.1.0\n"));
+
+        fs::remove_file(source_marker(&copy)).unwrap();
+        assert!(!is_complete_copy(&copy));
+    }
+
+    #[test]
+    fn ignores_the_junk_files_tools_leave() {
+        let junk = JUNK_FILES.map(String::from);
+        for path in [
+            ".DS_Store",
+            "src/.DS_Store",
+            "src/.lib.rs.swp",
+            "src/lib.rs~",
+            "target/debug/build",
+        ] {
+            assert!(is_ignored(path, &junk), "{}", path);
+        }
+        for path in ["src/lib.rs", "src/target/mod.rs", "target", "DS_Store.rs"] {
+            assert!(!is_ignored(path, &junk), "{}", path);
+        }
+        assert!(!is_ignored(".DS_Store", &[]));
+    }
+
+    #[test]
+    fn matches_crate_names_with_a_glob() {
+        let entry = CrateEntry {
+            name: Cow::Borrowed("tok*"),
+            alias: None,
+            settings: None,
+        };
+        assert!(entry.is_pattern());
+        let names = ["tokio", "tokio-util", "serde"]
+            .into_iter()
+            .filter(|name| entry.matches_name(name))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["tokio", "tokio-util"]);
+
+        let entry = CrateEntry {
+            name: Cow::Borrowed("tokio@1.0.0"),
+            ..entry
+        };
+        assert!(!entry.is_pattern());
+        assert!(entry.matches_name("tokio"));
+        assert!(!entry.matches_name("tokio-util"));
+    }
+
+    #[test]
+    fn exports_patches_in_the_layout_of_patch_package() {
+        let dir = temp_dir("export-npm");
+        let patches = dir.join("patches");
+        let rename = "diff --git a/src/Foo.rs b/src/foo.rs\nsimilarity index 100%\nrename from src/Foo.rs\nrename to src/foo.rs\n";
+        let binary = "diff --git a/logo.png b/logo.png\nnew file mode 100644\nindex 0000000..1234567\nBinary files /dev/null and b/logo.png differ\n";
+        fs::create_dir_all(&patches).unwrap();
+        fs::write(patches.join("demo+0.1.0.patch"), rename).unwrap();
+        fs::write(patches.join("demo+0.1.0.02-logo.patch"), binary).unwrap();
+        fs::write(patches.join("demo+git-1234abc.patch"), rename).unwrap();
+        fs::write(patches.join("demo+^0.1.patch"), rename).unwrap();
+
+        export_npm(&patches, &dir.join("npm")).unwrap();
+        let mut names = fs::read_dir(dir.join("npm"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["demo+0.1.0+002+logo.patch", "demo+0.1.0.patch"]);
+        assert_eq!(
+            fs::read_to_string(dir.join("npm/demo+0.1.0.patch")).unwrap(),
+            "diff --git a/node_modules/demo/src/Foo.rs b/node_modules/demo/src/foo.rs\nsimilarity index 100%\nrename from node_modules/demo/src/Foo.rs\nrename to node_modules/demo/src/foo.rs\n"
+        );
+        assert!(
+            fs::read_to_string(dir.join("npm/demo+0.1.0+002+logo.patch"))
+                .unwrap()
+                .contains("Binary files /dev/null and b/node_modules/demo/logo.png differ\n")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copies_keep_the_executable_bit_and_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("executable");
+        let source = dir.join("registry/demo-0.1.0");
+        fs::create_dir_all(source.join("scripts")).unwrap();
+        fs::write(source.join("Cargo.toml"), "[package]\n").unwrap();
+        let script = source.join("scripts/gen.sh");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("gen.sh", source.join("scripts/link.sh")).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        copy_dir(&source, &dir.join("copy")).unwrap();
+        assert_eq!(mode(&dir.join("copy/scripts/gen.sh")), 0o755);
+
+        let copy = copy_crate(
+            "demo",
+            &source,
+            "demo-0.1.0",
+            "stamp\n",
+            &dir.join("patch"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(mode(&copy.join("scripts/gen.sh")), 0o755);
+        assert_eq!(mode(&copy.join("Cargo.toml")) & 0o111, 0);
+        assert_eq!(
+            fs::read_link(copy.join("scripts/link.sh")).unwrap(),
+            Path::new("gen.sh")
+        );
     }
 }
@@ -0,0 +1,211 @@
+//! The lock file and the hashes file of the patches folder, and the checksums
+//! that `--release` and `--locked` check the patched crates against.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Ok, Result};
+use cargo::core::{package::Package, PackageId, Resolve};
+
+use super::{find_patch_files, list_files, patch_file_key, LOCK_FILE};
+
+/// SHA-256 of the paths and contents of all files under `dir`.
+pub fn tree_hash(dir: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut files = list_files(dir)?;
+    files.sort();
+    let mut hasher = Sha256::new();
+    for file in files {
+        let content = fs::read(dir.join(&file))?;
+        hasher.update(file.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(content);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Whether the crate at `patch_target_path` hashes to what is recorded for
+/// the patch `patch_name`.
+pub fn has_recorded_hash(
+    hashes: &toml::Table,
+    patch_name: &str,
+    patch_target_path: &Path,
+) -> Result<bool> {
+    let recorded = hashes.get(patch_name).and_then(|hash| hash.as_str());
+    Ok(recorded == Some(tree_hash(patch_target_path)?.as_str()))
+}
+
+/// One `[[patch]]` of [`LOCK_FILE`]: the crate `pkg_id` is patched with
+/// `patch_file`, whose bytes hash to `sha256`.
+pub fn lock_entry(pkg_id: PackageId, patch_file: &Path) -> Result<toml::Table> {
+    use sha2::{Digest, Sha256};
+    let mut entry = toml::Table::new();
+    entry.insert("name".into(), pkg_id.name().to_string().into());
+    entry.insert("version".into(), pkg_id.version().to_string().into());
+    entry.insert(
+        "source".into(),
+        pkg_id.source_id().as_url().to_string().into(),
+    );
+    entry.insert("file".into(), patch_file_key(patch_file).into());
+    entry.insert(
+        "sha256".into(),
+        format!("{:x}", Sha256::digest(fs::read(patch_file)?)).into(),
+    );
+    Ok(entry)
+}
+
+/// The `[[patch]]` entries of the [`LOCK_FILE`] in `patches_folder`, `None`
+/// when it doesn't exist yet.
+pub fn read_patch_lock(patches_folder: &Path) -> Result<Option<Vec<toml::Table>>> {
+    use anyhow::Context as _;
+    let lock_file = patches_folder.join(LOCK_FILE);
+    if !lock_file.exists() {
+        return Ok(None);
+    }
+    let lock: toml::Table = fs::read_to_string(&lock_file)?
+        .parse()
+        .with_context(|| format!("{:?} is not valid", lock_file))?;
+    Ok(Some(
+        lock.get("patch")
+            .and_then(|patches| patches.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.as_table().cloned())
+            .collect(),
+    ))
+}
+
+/// Writes `entries` as the [`LOCK_FILE`] in `patches_folder`, sorted by patch
+/// file name, unless it already has exactly these.
+pub fn write_patch_lock(patches_folder: &Path, mut entries: Vec<toml::Table>) -> Result<()> {
+    let file = |entry: &toml::Table| {
+        entry
+            .get("file")
+            .and_then(|file| file.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    entries.sort_by_key(file);
+    let mut lock = toml::Table::new();
+    lock.insert(
+        "patch".into(),
+        toml::Value::Array(entries.into_iter().map(toml::Value::Table).collect()),
+    );
+    let content = format!(
+        "# Written by cargo patch-crate, commit it along with the patches.\n\n{}",
+        toml::to_string(&lock)?
+    );
+    let lock_file = patches_folder.join(LOCK_FILE);
+    if fs::read_to_string(&lock_file).ok().as_deref() != Some(content.as_str()) {
+        fs::write(&lock_file, content)?;
+    }
+    Ok(())
+}
+
+/// How the patches applied now, `current`, differ from the `locked` ones.
+pub fn lock_drift(locked: &[toml::Table], current: &[toml::Table]) -> Vec<String> {
+    let get = |entry: &toml::Table, key: &str| {
+        entry
+            .get(key)
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let mut drift = Vec::new();
+    for entry in current {
+        let file = get(entry, "file");
+        match locked.iter().find(|locked| get(locked, "file") == file) {
+            None => drift.push(format!("{} is not locked", file)),
+            Some(locked) if get(locked, "sha256") != get(entry, "sha256") => {
+                drift.push(format!("{} changed since it was locked", file))
+            }
+            Some(locked)
+                if (get(locked, "version"), get(locked, "source"))
+                    != (get(entry, "version"), get(entry, "source")) =>
+            {
+                drift.push(format!(
+                    "{} was locked for {} {} but patches {} {} now",
+                    file,
+                    get(locked, "name"),
+                    get(locked, "version"),
+                    get(entry, "name"),
+                    get(entry, "version")
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    for entry in locked {
+        let file = get(entry, "file");
+        if !current.iter().any(|current| get(current, "file") == file) {
+            drift.push(format!("{} is locked but no longer applied", file));
+        }
+    }
+    drift
+}
+
+/// Replaces the entries of the crates `pkg_ids` in the [`LOCK_FILE`] of
+/// `patches_folder` with their current patches, if there is a lock file.
+pub fn update_patch_lock(patches_folder: &Path, pkg_ids: &[PackageId]) -> Result<()> {
+    let Some(mut entries) = read_patch_lock(patches_folder)? else {
+        return Ok(());
+    };
+    for pkg_id in pkg_ids {
+        entries.retain(|entry| {
+            entry.get("name").and_then(|name| name.as_str()) != Some(pkg_id.name().as_str())
+                || entry.get("version").and_then(|version| version.as_str())
+                    != Some(pkg_id.version().to_string().as_str())
+        });
+        for patch_file in find_patch_files(patches_folder, *pkg_id)? {
+            entries.push(lock_entry(*pkg_id, &patch_file)?);
+        }
+    }
+    write_patch_lock(patches_folder, entries)
+}
+
+/// Checks that the pristine source of a registry crate is the one recorded in
+/// the lockfile: the downloaded `.crate` file, or for a vendored crate the
+/// package checksum of its `.cargo-checksum.json`, must match.
+pub fn verify_checksum(pkg: &Package, resolve: &Resolve) -> Result<()> {
+    use sha2::{Digest, Sha256};
+    let Some(Some(expected)) = resolve.checksums().get(&pkg.package_id()) else {
+        return Ok(());
+    };
+    let vendored = pkg.root().join(".cargo-checksum.json");
+    let actual = if vendored.exists() {
+        let checksums: serde_json::Value = serde_json::from_str(&fs::read_to_string(&vendored)?)?;
+        checksums["package"]
+            .as_str()
+            .ok_or(anyhow!("{:?} has no package checksum", vendored))?
+            .to_string()
+    } else {
+        // `<cargo home>/registry/src/<index>/<slug>` is unpacked from
+        // `<cargo home>/registry/cache/<index>/<slug>.crate`
+        let crate_file = pkg
+            .root()
+            .parent()
+            .and_then(|index| Some((index.file_name()?, index.parent()?.parent()?)))
+            .map(|(index, registry)| {
+                registry.join("cache").join(index).join(format!(
+                    "{}-{}.crate",
+                    pkg.name(),
+                    pkg.version()
+                ))
+            })
+            .filter(|crate_file| crate_file.is_file())
+            .ok_or(anyhow!(
+                "crate: {}, cannot find its downloaded .crate file to verify",
+                pkg.name()
+            ))?;
+        format!("{:x}", Sha256::digest(fs::read(crate_file)?))
+    };
+    if &actual != expected {
+        return Err(anyhow!(
+            "crate: {}, source checksum does not match the lockfile: expected {}, found {}",
+            pkg.name(),
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! The lines printed while running, as plain text or as GitHub Actions
+//! annotations, at the level of `--quiet` and `--verbose`.
+
+use std::{
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+static PER_CRATE: AtomicBool = AtomicBool::new(true);
+
+static GITHUB: AtomicBool = AtomicBool::new(false);
+
+/// 0 when quiet, 1 by default, more when verbose.
+static LEVEL: AtomicU8 = AtomicU8::new(1);
+
+/// Sets the level from `--quiet` and `--verbose`, else from the
+/// `PATCH_CRATE_LOG` environment variable: `quiet`, `normal` or
+/// `verbose`.
+pub fn set_level(quiet: bool, verbose: u8) -> anyhow::Result<()> {
+    let level = if quiet {
+        0
+    } else if verbose > 0 {
+        verbose.saturating_add(1)
+    } else {
+        match std::env::var("PATCH_CRATE_LOG").as_deref() {
+            Err(_) | Ok("") | Ok("normal") => 1,
+            Ok("quiet") => 0,
+            Ok("verbose") => 2,
+            Ok(other) => anyhow::bail!(
+                "`PATCH_CRATE_LOG` must be `quiet`, `normal` or `verbose`, not `{}`",
+                other
+            ),
+        }
+    };
+    LEVEL.store(level, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn quiet() -> bool {
+    LEVEL.load(Ordering::Relaxed) == 0
+}
+
+pub fn verbose() -> bool {
+    LEVEL.load(Ordering::Relaxed) > 1
+}
+
+pub trait Traced {
+    /// Prints the command about to be run, when verbose.
+    fn traced(&mut self) -> &mut Self;
+}
+
+impl Traced for Command {
+    fn traced(&mut self) -> &mut Self {
+        if verbose() {
+            line("<cyan><info></>", format!("running {:?}", self));
+        }
+        self
+    }
+}
+
+/// Prints a line with an icon, to stderr when stdout holds the JSON
+/// report, and nothing with `--format github`.
+pub fn line(icon: &str, message: String) {
+    if github() {
+        return;
+    }
+    let message = format!("{} {}", icon, message);
+    super::progress::suspend(|| {
+        if super::report::enabled() {
+            ::paris::output::format_stderr(message, "\n");
+        } else {
+            ::paris::output::format_stdout(message, "\n");
+        }
+    });
+}
+
+pub fn warning(message: String) {
+    if github() {
+        annotation("warning", &message);
+    } else {
+        line("<yellow><warn></>", message);
+    }
+}
+
+/// Replaces the log with GitHub Actions workflow commands.
+pub fn set_github() {
+    GITHUB.store(true, Ordering::Relaxed);
+}
+
+pub fn github() -> bool {
+    GITHUB.load(Ordering::Relaxed)
+}
+
+/// Prints `message` as a GitHub Actions `::warning` or `::error` workflow
+/// command, which annotates the first patch file the message names.
+pub fn annotation(level: &str, message: &str) {
+    fn escape(value: &str) -> String {
+        value
+            .replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    }
+    // paths are quoted in messages, from the workspace root on in CI
+    let file = message
+        .split('"')
+        .skip(1)
+        .step_by(2)
+        .find(|quoted| quoted.ends_with(".patch") || quoted.ends_with(".patch.gz"))
+        .map(|quoted| {
+            let path = std::path::PathBuf::from(quoted.replace("\\\\", "\\"));
+            match std::env::current_dir() {
+                Ok(dir) => path
+                    .strip_prefix(&dir)
+                    .map_or(path.clone(), |p| p.to_path_buf()),
+                Err(_) => path,
+            }
+        });
+    super::progress::suspend(|| match file {
+        Some(file) => println!(
+            "::{} file={}::{}",
+            level,
+            escape(&file.to_string_lossy())
+                .replace(':', "%3A")
+                .replace(',', "%2C"),
+            escape(message)
+        ),
+        None => println!("::{}::{}", level, escape(message)),
+    });
+}
+
+/// Prints the error output of a command that was captured, when verbose.
+pub fn trace_stderr(stderr: &[u8]) {
+    if verbose() && !stderr.is_empty() {
+        eprint!("{}", String::from_utf8_lossy(stderr));
+    }
+}
+
+/// Where the error output of a command that is not captured goes: shown
+/// when verbose.
+pub fn stderr() -> Stdio {
+    if verbose() {
+        Stdio::inherit()
+    } else {
+        Stdio::null()
+    }
+}
+
+/// Whether per-crate progress lines are logged.
+pub fn per_crate() -> bool {
+    PER_CRATE.load(Ordering::Relaxed) && !quiet()
+}
+
+pub fn set_per_crate(enabled: bool) {
+    PER_CRATE.store(enabled, Ordering::Relaxed);
+}
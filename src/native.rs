@@ -0,0 +1,123 @@
+//! Diffing without git, with `--backend native`.
+
+use std::{collections::BTreeSet, fs, path::Path};
+
+use anyhow::bail;
+use similar::TextDiff;
+
+/// Diffs the `modified` tree against the `pristine` one, in the format of
+/// `git diff`: a `diff --git` section per changed file, with the headers
+/// of created and deleted files. With `include_ext`, only files with one
+/// of these extensions are diffed, with `context_lines` around each change.
+/// The paths in `exclude`, and those matching the gitignore patterns in
+/// `junk`, are never diffed.
+pub fn diff(
+    pristine: &Path,
+    modified: &Path,
+    include_ext: &[String],
+    exclude: &[String],
+    junk: &[String],
+    context_lines: u32,
+) -> anyhow::Result<String> {
+    let mut paths = BTreeSet::new();
+    paths.extend(super::list_files(pristine)?);
+    paths.extend(super::list_files(modified)?);
+
+    let mut patch = String::new();
+    for path in paths {
+        if exclude.contains(&path) || super::is_ignored(&path, junk) {
+            continue;
+        }
+        let included = include_ext.is_empty()
+            || include_ext.iter().any(|ext| {
+                Path::new(&path).extension().and_then(|e| e.to_str())
+                    == Some(ext.trim_start_matches('.'))
+            });
+        if !included {
+            continue;
+        }
+        let old_file = pristine.join(&path);
+        let new_file = modified.join(&path);
+        let old = read(&old_file)?;
+        let new = read(&new_file)?;
+        let old_mode = mode(&old_file)?;
+        let new_mode = mode(&new_file)?;
+        if old == new && old_mode == new_mode {
+            continue;
+        }
+
+        patch.push_str(&format!("diff --git a/{0} b/{0}\n", path));
+        let (old_name, new_name) = match (&old, &new) {
+            (None, _) => {
+                patch.push_str(&format!("new file mode {}\n", new_mode));
+                ("/dev/null".to_string(), format!("b/{}", path))
+            }
+            (_, None) => {
+                patch.push_str(&format!("deleted file mode {}\n", old_mode));
+                (format!("a/{}", path), "/dev/null".to_string())
+            }
+            _ => {
+                if old_mode != new_mode {
+                    patch.push_str(&format!("old mode {}\nnew mode {}\n", old_mode, new_mode));
+                }
+                (format!("a/{}", path), format!("b/{}", path))
+            }
+        };
+        let old = text(&path, old)?;
+        let new = text(&path, new)?;
+        if old == new {
+            // an empty file, or only the mode changed
+            continue;
+        }
+        patch.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+        let diff = TextDiff::from_lines(&old, &new);
+        for hunk in diff
+            .unified_diff()
+            .context_radius(context_lines as usize)
+            .missing_newline_hint(true)
+            .iter_hunks()
+        {
+            let hunk = hunk.to_string();
+            patch.push_str(&hunk);
+            if !hunk.ends_with('\n') {
+                patch.push('\n');
+            }
+        }
+    }
+    Ok(patch)
+}
+
+fn read(file: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    if file.is_file() {
+        Ok(Some(fs::read(file)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The contents of a file, which must be text: a git binary patch needs
+/// the blob ids only git can compute.
+fn text(path: &str, content: Option<Vec<u8>>) -> anyhow::Result<String> {
+    let content = content.unwrap_or_default();
+    match String::from_utf8(content) {
+        Ok(text) if !text.contains('\0') => Ok(text),
+        _ => bail!(
+            "{} is a binary file, which only the git backend can create patches for",
+            path
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn mode(file: &Path) -> anyhow::Result<&'static str> {
+    use std::os::unix::fs::PermissionsExt;
+    match fs::metadata(file) {
+        Ok(metadata) if metadata.permissions().mode() & 0o111 != 0 => Ok("100755"),
+        _ => Ok("100644"),
+    }
+}
+
+#[cfg(not(unix))]
+fn mode(_file: &Path) -> anyhow::Result<&'static str> {
+    Ok("100644")
+}
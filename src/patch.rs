@@ -0,0 +1,846 @@
+//! Reading, writing and parsing patch files.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{Read, Write},
+    path::{Component, Path},
+};
+
+use anyhow::{bail, Context};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+/// The changes a patch makes to a single file.
+#[derive(Debug, Default)]
+pub struct FileDiff {
+    /// Path before the change relative to the crate root, `None` for a
+    /// created file.
+    pub old_path: Option<String>,
+    /// Path after the change relative to the crate root, `None` for a
+    /// deleted file.
+    pub new_path: Option<String>,
+    /// Lines of all hunks including their ` `, `-` or `+` prefix, with
+    /// line endings (other than the final `\n`) preserved.
+    pub hunk_lines: Vec<String>,
+    seen_old_header: bool,
+}
+
+impl FileDiff {
+    pub fn paths(&self) -> impl Iterator<Item = &String> {
+        self.old_path.iter().chain(
+            self.new_path
+                .iter()
+                .filter(|p| Some(*p) != self.old_path.as_ref()),
+        )
+    }
+}
+
+/// Whether a changed file of the patch has hunks without any context
+/// line, as `git diff --unified=0` writes them.
+pub fn has_zero_context(content: &str) -> bool {
+    parse(content).iter().any(|file| {
+        file.old_path.is_some()
+            && file.new_path.is_some()
+            && !file.hunk_lines.is_empty()
+            && !file.hunk_lines.iter().any(|line| line.starts_with(' '))
+    })
+}
+
+/// Splits a patch in git or unified diff format into its files. Hunk
+/// bodies are consumed by their line counts, so removed lines that happen
+/// to start with `--` are not mistaken for file headers.
+pub fn parse(content: &str) -> Vec<FileDiff> {
+    let mut files: Vec<FileDiff> = Vec::new();
+    let mut lines = content.split('\n');
+    while let Some(raw_line) = lines.next() {
+        let line = raw_line.trim_end_matches('\r');
+        if let Some(range) = line.strip_prefix("@@ ") {
+            if files.is_empty() {
+                files.push(FileDiff::default());
+            }
+            let file = files.last_mut().unwrap();
+            read_hunk(range, &mut lines, |line| {
+                file.hunk_lines.push(line.to_string())
+            });
+        } else if let Some(paths) = line.strip_prefix("diff --git ") {
+            // only a fallback for mode-only or binary changes, which
+            // have no `---`/`+++` headers, as paths may contain spaces
+            let (old_path, new_path) = match paths.split_once(" b/") {
+                Some((old, new)) => (old.strip_prefix("a/").map(unquote), Some(unquote(new))),
+                None => (None, None),
+            };
+            files.push(FileDiff {
+                old_path,
+                new_path,
+                ..Default::default()
+            });
+        } else if let Some(path) = line.strip_prefix("--- ") {
+            let start_new = files
+                .last()
+                .is_none_or(|f| f.seen_old_header || !f.hunk_lines.is_empty());
+            if start_new {
+                files.push(FileDiff::default());
+            }
+            let file = files.last_mut().unwrap();
+            file.old_path = header_path(path);
+            file.seen_old_header = true;
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            if let Some(file) = files.last_mut() {
+                file.new_path = header_path(path);
+            }
+        } else if let Some(path) = line
+            .strip_prefix("rename from ")
+            .or_else(|| line.strip_prefix("copy from "))
+        {
+            if let Some(file) = files.last_mut() {
+                file.old_path = Some(unquote(path));
+            }
+        } else if let Some(path) = line
+            .strip_prefix("rename to ")
+            .or_else(|| line.strip_prefix("copy to "))
+        {
+            if let Some(file) = files.last_mut() {
+                file.new_path = Some(unquote(path));
+            }
+        }
+    }
+    files
+}
+
+/// The files of a patch with changes that only touch whitespace, like
+/// indentation or trailing spaces, and how many: runs of removed and
+/// added lines between context lines that are the same once whitespace is
+/// dropped.
+pub fn whitespace_changes(content: &str) -> Vec<(String, usize)> {
+    let squash = |line: &str| line[1..].split_whitespace().collect::<String>();
+    let mut changes = Vec::new();
+    for file in parse(content) {
+        let (Some(_), Some(path)) = (&file.old_path, &file.new_path) else {
+            continue;
+        };
+        let mut count = 0;
+        let (mut removed, mut added) = (Vec::new(), Vec::new());
+        for line in file.hunk_lines.iter().map(String::as_str).chain([" "]) {
+            match line.chars().next() {
+                Some('-') => removed.push(squash(line)),
+                Some('+') => added.push(squash(line)),
+                // a `\ No newline at end of file`
+                Some('\\') => {}
+                _ => {
+                    if !removed.is_empty() && removed == added {
+                        count += 1;
+                    }
+                    removed.clear();
+                    added.clear();
+                }
+            }
+        }
+        if count > 0 {
+            changes.push((path.clone(), count));
+        }
+    }
+    changes
+}
+
+/// Whether a patch changes no file at all, like the placeholders written
+/// with `--allow-empty`, which `git apply` would reject.
+pub fn is_placeholder(patch_file: &Path) -> anyhow::Result<bool> {
+    Ok(parse(&read(patch_file)?).is_empty())
+}
+
+/// The lines `git diff --stat` prints for a patch: each file with a bar of
+/// its added and removed lines, then the totals.
+pub fn stat(content: &str) -> Vec<String> {
+    const BAR_WIDTH: usize = 40;
+    let files = parse(content)
+        .iter()
+        .map(|file| {
+            let count = |prefix| {
+                file.hunk_lines
+                    .iter()
+                    .filter(|line| line.starts_with(prefix))
+                    .count()
+            };
+            let path = file.paths().cloned().collect::<Vec<_>>().join(" => ");
+            (path, count('+'), count('-'))
+        })
+        .collect::<Vec<_>>();
+    let path_width = files.iter().map(|f| f.0.len()).max().unwrap_or(0);
+    let most = files.iter().map(|f| f.1 + f.2).max().unwrap_or(0);
+    let count_width = most.to_string().len();
+    let bar = |n: usize| match most {
+        most if most > BAR_WIDTH && n > 0 => (n * BAR_WIDTH / most).max(1),
+        _ => n,
+    };
+    let mut lines = files
+        .iter()
+        .map(|(path, added, removed)| {
+            format!(
+                " {:<path_width$} | {:>count_width$} {}{}",
+                path,
+                added + removed,
+                "+".repeat(bar(*added)),
+                "-".repeat(bar(*removed))
+            )
+            .trim_end()
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+    let plural =
+        |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+    let added = files.iter().map(|f| f.1).sum::<usize>();
+    let removed = files.iter().map(|f| f.2).sum::<usize>();
+    let mut summary = format!(" {}", plural(files.len(), "file changed", "files changed"));
+    if added > 0 {
+        summary.push_str(&format!(
+            ", {}",
+            plural(added, "insertion(+)", "insertions(+)")
+        ));
+    }
+    if removed > 0 {
+        summary.push_str(&format!(
+            ", {}",
+            plural(removed, "deletion(-)", "deletions(-)")
+        ));
+    }
+    lines.push(summary);
+    lines
+}
+
+/// Path of a `---`/`+++` header without its `a/`-style prefix.
+fn header_path(path: &str) -> Option<String> {
+    // `diff -u` appends a tab and a timestamp
+    let path = unquote(path.split('\t').next().unwrap_or_default());
+    if path == "/dev/null" {
+        return None;
+    }
+    match path.split_once('/') {
+        Some((_, path)) => Some(path.to_string()),
+        None => Some(path),
+    }
+}
+
+/// Rewrites every line of a patch outside of its hunks with `f`, leaving
+/// hunk bodies untouched.
+pub fn map_headers(content: &str, mut f: impl FnMut(&str) -> String) -> String {
+    let mut mapped = Vec::new();
+    let mut lines = content.split('\n');
+    while let Some(line) = lines.next() {
+        let Some(range) = line.strip_prefix("@@ ") else {
+            mapped.push(f(line));
+            continue;
+        };
+        mapped.push(line.to_string());
+        read_hunk(range, &mut lines, |line| mapped.push(line.to_string()));
+    }
+    mapped.join("\n")
+}
+
+/// Rewrites the absolute paths of this machine out of a created patch:
+/// `diff_folder`, where a diff tool compared the `a` and `b` trees, is
+/// dropped from the file names of the headers, and each path of `roots`
+/// is replaced with its placeholder, longest first, in the headers and
+/// added lines. Context and removed lines must match the pristine crate,
+/// so they are kept. Returns the patch and the number of lines changed.
+pub fn sanitize(content: &str, diff_folder: &str, roots: &[(String, &str)]) -> (String, usize) {
+    let mut roots = roots.iter().collect::<Vec<_>>();
+    roots.sort_by_key(|(root, _)| std::cmp::Reverse(root.len()));
+    let diff_folder = format!("{}/", diff_folder.trim_end_matches(['/', '\\']));
+
+    let mut lines: Vec<(&str, bool)> = Vec::new();
+    let mut raw_lines = content.split('\n');
+    while let Some(line) = raw_lines.next() {
+        lines.push((line, false));
+        if let Some(range) = line.strip_prefix("@@ ") {
+            read_hunk(range, &mut raw_lines, |line| lines.push((line, true)));
+        }
+    }
+
+    let mut changed = 0;
+    let mut sanitized = Vec::new();
+    for (line, in_hunk) in lines {
+        if in_hunk && !line.starts_with('+') {
+            sanitized.push(line.to_string());
+            continue;
+        }
+        let mut new = if in_hunk || diff_folder.len() < 2 {
+            line.to_string()
+        } else {
+            line.replace(&diff_folder, "")
+        };
+        for (root, placeholder) in roots.iter() {
+            new = replace_path(&new, root, placeholder);
+        }
+        if new != line {
+            changed += 1;
+        }
+        sanitized.push(new);
+    }
+    (sanitized.join("\n"), changed)
+}
+
+/// Replaces `path` in `line` where it is a whole path or a prefix of one,
+/// not `/home/al` in `/home/alice`.
+fn replace_path(line: &str, path: &str, placeholder: &str) -> String {
+    let mut replaced = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(path) {
+        let end = start + path.len();
+        let whole =
+            !rest[end..].starts_with(|c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        replaced.push_str(&rest[..start]);
+        replaced.push_str(if whole { placeholder } else { path });
+        rest = &rest[end..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// Rewrites a patch into a canonical form that does not depend on the git
+/// version or machine it was made with: files are sorted by path, `index`
+/// lines are dropped unless a file is binary, timestamps after `---`/`+++`
+/// paths and the function names after hunk ranges are removed, header
+/// lines lose trailing whitespace and the patch ends with a single newline.
+/// Hunk bodies are left untouched.
+pub fn normalize(content: &str) -> String {
+    let mut lines: Vec<(&str, bool)> = Vec::new();
+    let mut raw_lines = content.split('\n');
+    while let Some(line) = raw_lines.next() {
+        lines.push((line, false));
+        if let Some(range) = line.strip_prefix("@@ ") {
+            read_hunk(range, &mut raw_lines, |line| lines.push((line, true)));
+        }
+    }
+
+    let mut normalized: Vec<(String, bool)> = Vec::new();
+    for (i, (line, in_hunk)) in lines.iter().enumerate() {
+        if *in_hunk {
+            normalized.push((line.to_string(), true));
+            continue;
+        }
+        let line = line.trim_end();
+        if line.starts_with("index ") {
+            let binary = lines[i + 1..]
+                .iter()
+                .filter(|(_, in_hunk)| !in_hunk)
+                .take_while(|(l, _)| !l.starts_with("diff --git "))
+                .any(|(l, _)| l.starts_with("GIT binary patch") || l.starts_with("Binary files "));
+            if !binary {
+                continue;
+            }
+        }
+        if line.starts_with("--- ") || line.starts_with("+++ ") {
+            let path = line.split('\t').next().unwrap_or_default();
+            normalized.push((path.to_string(), false));
+        } else if let Some(end) = line.strip_prefix("@@ ").and_then(|r| r.find(" @@")) {
+            // the function name depends on the diff drivers configured
+            normalized.push((line[.."@@ ".len() + end + " @@".len()].to_string(), false));
+        } else {
+            normalized.push((line.to_string(), false));
+        }
+    }
+
+    // whatever order `diff.orderFile` gives, after any leading comments
+    let mut files: Vec<Vec<(String, bool)>> = vec![Vec::new()];
+    for (line, in_hunk) in normalized {
+        if !in_hunk && line.starts_with("diff --git ") {
+            files.push(Vec::new());
+        }
+        if let Some(file) = files.last_mut() {
+            file.push((line, in_hunk));
+        }
+    }
+    let has_files = files.len() > 1;
+    for file in files[1..].iter_mut() {
+        // an empty last line of a hunk is context, not a trailing newline
+        while file
+            .last()
+            .is_some_and(|(l, in_hunk)| l.is_empty() && !in_hunk)
+        {
+            file.pop();
+        }
+        // but each chunk of a binary patch ends with one
+        if file
+            .iter()
+            .any(|(l, in_hunk)| !in_hunk && l == "GIT binary patch")
+        {
+            file.push((String::new(), false));
+        }
+    }
+    files[1..].sort_by(|a, b| a[0].0.cmp(&b[0].0));
+    let mut normalized = files.into_iter().flatten().collect::<Vec<_>>();
+    // a patch without `diff --git` lines is all in the first one
+    if !has_files {
+        while normalized
+            .last()
+            .is_some_and(|(l, in_hunk)| l.is_empty() && !in_hunk)
+        {
+            normalized.pop();
+        }
+    }
+    if normalized.is_empty() {
+        return String::new();
+    }
+    normalized
+        .into_iter()
+        .map(|(line, _)| line)
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
+
+/// Adds terminal colors to a patch: bold file headers, cyan hunk headers,
+/// red removed and green added lines.
+pub fn colorize(content: &str) -> String {
+    const BOLD: &str = "\x1b[1m";
+    const CYAN: &str = "\x1b[36m";
+    const RED: &str = "\x1b[31m";
+    const GREEN: &str = "\x1b[32m";
+    const RESET: &str = "\x1b[0m";
+
+    let mut colored = Vec::new();
+    let mut lines = content.split('\n');
+    while let Some(line) = lines.next() {
+        let Some(range) = line.strip_prefix("@@ ") else {
+            if line.is_empty() {
+                colored.push(String::new());
+            } else {
+                colored.push(format!("{}{}{}", BOLD, line, RESET));
+            }
+            continue;
+        };
+        colored.push(format!("{}@@ {}{}", CYAN, range, RESET));
+        read_hunk(range, &mut lines, |line| {
+            colored.push(match line.chars().next() {
+                Some('-') => format!("{}{}{}", RED, line, RESET),
+                Some('+') => format!("{}{}{}", GREEN, line, RESET),
+                _ => line.to_string(),
+            })
+        });
+    }
+    colored.join("\n")
+}
+
+/// Paths of the files a patch touches, relative to the crate root, in the
+/// order they appear.
+pub fn touched_paths(content: &str) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    for file in parse(content) {
+        for path in file.paths() {
+            if !paths.contains(path) {
+                paths.push(path.clone());
+            }
+        }
+    }
+    paths
+}
+
+/// Files a patch renames by changing the case of their path only, as
+/// `(old, new)` pairs.
+pub fn case_only_renames(content: &str) -> Vec<(String, String)> {
+    parse(content)
+        .into_iter()
+        .filter_map(|file| match (file.old_path, file.new_path) {
+            (Some(old), Some(new)) if old != new && old.eq_ignore_ascii_case(&new) => {
+                Some((old, new))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Describes how the patch `new` differs from `old` file by file, empty
+/// when both make the same changes.
+pub fn differences(old: &str, new: &str) -> Vec<String> {
+    fn path(file: &FileDiff) -> &str {
+        file.new_path
+            .as_deref()
+            .or(file.old_path.as_deref())
+            .unwrap_or_default()
+    }
+    fn squash(file: &FileDiff) -> Vec<String> {
+        file.hunk_lines
+            .iter()
+            .map(|line| line.split_whitespace().collect())
+            .collect()
+    }
+
+    let old_files = parse(old);
+    let new_files = parse(new);
+    let mut differences = Vec::new();
+    for file in new_files.iter() {
+        match old_files.iter().find(|f| path(f) == path(file)) {
+            None => differences.push(format!("{}: newly changed", path(file))),
+            Some(old) if old.hunk_lines == file.hunk_lines => {}
+            Some(old) if squash(old) == squash(file) => {
+                differences.push(format!("{}: whitespace changes only", path(file)))
+            }
+            Some(_) => differences.push(format!("{}: changes differ", path(file))),
+        }
+    }
+    for file in old_files.iter() {
+        if !new_files.iter().any(|f| path(f) == path(file)) {
+            differences.push(format!("{}: no longer changed", path(file)));
+        }
+    }
+    if differences.is_empty() && old != new {
+        differences.push("file headers differ".to_string());
+    }
+    differences
+}
+
+/// The content of a patch whose every line got a CRLF ending from a
+/// checkout with `core.autocrlf`, with LF endings again, or `None` when
+/// its file headers end in LF as they were created. Git leaves files that
+/// already contain CRLF alone, so before that checkout the patch had none.
+pub fn undo_crlf_checkout(content: &[u8]) -> Option<Vec<u8>> {
+    let header = content
+        .split(|b| *b == b'\n')
+        .find(|line| line.starts_with(b"diff ") || line.starts_with(b"--- "))?;
+    if !header.ends_with(b"\r") {
+        return None;
+    }
+    let mut lf = Vec::with_capacity(content.len());
+    for (i, b) in content.iter().enumerate() {
+        if !(*b == b'\r' && content.get(i + 1) == Some(&b'\n')) {
+            lf.push(*b);
+        }
+    }
+    Some(lf)
+}
+
+/// The content of a patch made by the npm `patch-package` tool, whose
+/// paths are rooted at `node_modules/<package>/`, with the paths relative
+/// to the package again, or `None` for any other patch.
+pub fn strip_npm_root(content: &str) -> Option<String> {
+    let name = content
+        .lines()
+        .find_map(|line| line.strip_prefix("diff --git a/node_modules/"))?
+        .split('/')
+        .next()?;
+    let root = format!("node_modules/{}/", name);
+    let mut rooted = true;
+    let stripped = map_headers(content, |line| {
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            let a = format!("a/{}", root);
+            let b = format!(" b/{}", root);
+            rooted &= paths.starts_with(&a) && paths.contains(&b);
+            return format!(
+                "diff --git {}",
+                paths.replacen(&a, "a/", 1).replacen(&b, " b/", 1)
+            );
+        }
+        for prefix in [
+            "--- a/",
+            "+++ b/",
+            "rename from ",
+            "rename to ",
+            "copy from ",
+            "copy to ",
+        ] {
+            if let Some(path) = line.strip_prefix(prefix) {
+                let Some(path) = path.strip_prefix(&root) else {
+                    rooted = false;
+                    return line.to_string();
+                };
+                return format!("{}{}", prefix, path);
+            }
+        }
+        line.to_string()
+    });
+    rooted.then_some(stripped)
+}
+
+/// The bytes every gzip file starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Writes `content` to `patch_file`, gzip compressed for a `.patch.gz`.
+/// The gzip header has no timestamp, so the same patch compresses to the
+/// same bytes.
+pub fn write(patch_file: &Path, content: &str) -> anyhow::Result<()> {
+    if patch_file.extension() != Some(OsStr::new(super::GZ_EXT)) {
+        fs::write(patch_file, content)?;
+        return Ok(());
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(content.as_bytes())?;
+    fs::write(patch_file, encoder.finish()?)?;
+    Ok(())
+}
+
+/// The content of `patch_file` as git is given it: decompressed, with LF
+/// line endings again after a CRLF checkout, see [`undo_crlf_checkout`], and the paths
+/// of a `patch-package` patch relative to the package, see
+/// [`strip_npm_root`].
+pub fn read(patch_file: &Path) -> anyhow::Result<String> {
+    let mut content = fs::read(patch_file)?;
+    if content.starts_with(&GZIP_MAGIC) {
+        let mut plain = Vec::new();
+        GzDecoder::new(content.as_slice())
+            .read_to_end(&mut plain)
+            .with_context(|| format!("cannot decompress {:?}", patch_file))?;
+        content = plain;
+    }
+    if let Some(lf) = undo_crlf_checkout(&content) {
+        content = lf;
+    }
+    let content = String::from_utf8(content)?;
+    Ok(strip_npm_root(&content).unwrap_or(content))
+}
+
+/// Converts the line endings of every file the patch modifies to the ones
+/// its context lines expect, returning the converted paths.
+pub fn normalize_line_endings(repo_dir: &Path, patch_file: &Path) -> anyhow::Result<Vec<String>> {
+    let content = read(patch_file)?;
+    let mut converted = Vec::new();
+    for file in parse(&content) {
+        let Some(path) = &file.old_path else {
+            continue;
+        };
+        let mut expected = file
+            .hunk_lines
+            .iter()
+            .filter(|l| l.starts_with([' ', '-']))
+            .map(|l| l.ends_with('\r'));
+        let Some(crlf) = expected.next() else {
+            continue;
+        };
+        let target = repo_dir.join(path);
+        let Result::Ok(bytes) = fs::read(&target) else {
+            continue;
+        };
+        let has_crlf = bytes.windows(2).any(|w| w == b"\r\n");
+        let has_lf = bytes
+            .iter()
+            .enumerate()
+            .any(|(i, b)| *b == b'\n' && (i == 0 || bytes[i - 1] != b'\r'));
+        if (crlf && !has_lf) || (!crlf && !has_crlf) {
+            continue;
+        }
+        let mut lf = Vec::with_capacity(bytes.len());
+        for (i, b) in bytes.iter().enumerate() {
+            if !(*b == b'\r' && bytes.get(i + 1) == Some(&b'\n')) {
+                lf.push(*b);
+            }
+        }
+        let normalized = if crlf {
+            let mut crlf = Vec::with_capacity(lf.len() * 2);
+            for b in lf {
+                if b == b'\n' {
+                    crlf.push(b'\r');
+                }
+                crlf.push(b);
+            }
+            crlf
+        } else {
+            lf
+        };
+        fs::write(&target, normalized)?;
+        converted.push(path.clone());
+    }
+    Ok(converted)
+}
+
+/// Passes the body lines of the hunk whose header ends in `range` to
+/// `on_line`, taking as many lines from `lines` as the header counts.
+fn read_hunk<'a>(
+    range: &str,
+    lines: &mut impl Iterator<Item = &'a str>,
+    mut on_line: impl FnMut(&'a str),
+) {
+    let (mut old, mut new) = hunk_lengths(range);
+    while old > 0 || new > 0 {
+        let Some(line) = lines.next() else {
+            break;
+        };
+        match line.chars().next() {
+            Some('-') => old = old.saturating_sub(1),
+            Some('+') => new = new.saturating_sub(1),
+            Some('\\') => {}
+            _ => {
+                old = old.saturating_sub(1);
+                new = new.saturating_sub(1);
+            }
+        }
+        on_line(line);
+    }
+}
+
+/// Parses the line counts of a hunk header such as `-1,7 +1,8 @@`.
+fn hunk_lengths(range: &str) -> (usize, usize) {
+    let mut lengths = range.split_whitespace().take(2).map(|r| {
+        let r = r.trim_start_matches(['-', '+']);
+        match r.split_once(',') {
+            Some((_, len)) => len.parse().unwrap_or(0),
+            None => 1,
+        }
+    });
+    (lengths.next().unwrap_or(0), lengths.next().unwrap_or(0))
+}
+
+/// Undoes the C-style quoting git uses for paths with special characters.
+fn unquote(path: &str) -> String {
+    let Some(path) = path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) else {
+        return path.to_string();
+    };
+    let mut unquoted = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unquoted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unquoted.push('\t'),
+            Some('n') => unquoted.push('\n'),
+            Some(c) => unquoted.push(c),
+            None => {}
+        }
+    }
+    unquoted
+}
+
+/// Rejects patches that would write outside of the crate root, through
+/// absolute paths or `..` components.
+pub fn check_paths(patch_file: &Path) -> anyhow::Result<()> {
+    let content = read(patch_file)?;
+    for path in touched_paths(&content) {
+        let escapes = Path::new(&path).components().any(|c| {
+            matches!(
+                c,
+                Component::ParentDir | Component::RootDir | Component::Prefix(_)
+            )
+        });
+        if escapes {
+            bail!(
+                "patch {:?} modifies {:?}, which is outside of the crate root",
+                patch_file,
+                path
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A patch changing the first line of each of `paths`, made from
+    /// files with LF line endings.
+    fn lf_patch(paths: &[&str]) -> String {
+        paths
+            .iter()
+            .map(|path| {
+                format!(
+                    "diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n@@ -1,2 +1,2 @@\n-one\n+uno\n two\n",
+                    path
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_only_the_files_with_other_line_endings() {
+        let dir = crate::tests::temp_dir("normalize-line-endings");
+        fs::write(dir.join("crlf.txt"), "one\r\ntwo\r\n").unwrap();
+        fs::write(dir.join("mixed.txt"), "one\r\ntwo\n").unwrap();
+        fs::write(dir.join("lf.txt"), "one\ntwo\n").unwrap();
+        let patch_file = dir.join("fix.patch");
+        fs::write(&patch_file, lf_patch(&["crlf.txt", "mixed.txt", "lf.txt"])).unwrap();
+
+        let converted = normalize_line_endings(&dir, &patch_file).unwrap();
+        assert_eq!(converted, ["crlf.txt", "mixed.txt"]);
+        for path in ["crlf.txt", "mixed.txt", "lf.txt"] {
+            assert_eq!(fs::read_to_string(dir.join(path)).unwrap(), "one\ntwo\n");
+        }
+    }
+
+    #[test]
+    fn normalizes_to_crlf_when_the_patch_expects_it() {
+        let dir = crate::tests::temp_dir("normalize-line-endings-crlf");
+        fs::write(dir.join("mixed.txt"), "one\r\ntwo\n").unwrap();
+        let patch_file = dir.join("fix.patch");
+        let patch = lf_patch(&["mixed.txt"])
+            .replace("one\n", "one\r\n")
+            .replace("two\n", "two\r\n");
+        fs::write(&patch_file, patch).unwrap();
+
+        assert_eq!(
+            normalize_line_endings(&dir, &patch_file).unwrap(),
+            ["mixed.txt"]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("mixed.txt")).unwrap(),
+            "one\r\ntwo\r\n"
+        );
+    }
+
+    #[test]
+    fn finds_case_only_renames() {
+        let patch = "diff --git a/src/Foo.rs b/src/foo.rs\nsimilarity index 100%\nrename from src/Foo.rs\nrename to src/foo.rs\ndiff --git a/src/bar.rs b/src/baz.rs\nsimilarity index 100%\nrename from src/bar.rs\nrename to src/baz.rs\n";
+        assert_eq!(
+            case_only_renames(patch),
+            [("src/Foo.rs".to_string(), "src/foo.rs".to_string())]
+        );
+    }
+
+    #[test]
+    fn undoes_a_crlf_checkout_of_a_patch() {
+        let patch = lf_patch(&["lib.rs"]);
+        assert_eq!(undo_crlf_checkout(patch.as_bytes()), None);
+        let checked_out = patch.replace('\n', "\r\n");
+        assert_eq!(
+            undo_crlf_checkout(checked_out.as_bytes()),
+            Some(patch.into_bytes())
+        );
+    }
+
+    #[test]
+    fn replaces_only_whole_paths() {
+        assert_eq!(
+            replace_path("/home/alice/src and /home/alice", "/home/alice", "~"),
+            "~/src and ~"
+        );
+        assert_eq!(
+            replace_path("/home/alice/src", "/home/al", "~"),
+            "/home/alice/src"
+        );
+        assert_eq!(
+            replace_path("/home/al/x /home/alice", "/home/al", "~"),
+            "~/x /home/alice"
+        );
+    }
+
+    #[test]
+    fn sanitizes_the_paths_of_this_machine() {
+        let patch = "diff --git a/build.rs b/build.rs\n--- a/build.rs\n+++ b/build.rs\n@@ -1,2 +1,3 @@\n-const DIR: &str = \"/home/alice/old\";\n+const DIR: &str = \"/home/alice/project/out\";\n const HOME: &str = \"/home/alice\";\n+const OTHER: &str = \"/home/alice2\";\n";
+        let roots = [
+            ("/home/alice".to_string(), "~"),
+            ("/home/alice/project".to_string(), "$WORKSPACE"),
+        ];
+        let (sanitized, changed) = sanitize(patch, "/tmp/patch-tmp", &roots);
+        assert_eq!(changed, 1);
+        // only added lines, context and removed lines match the crate
+        assert!(sanitized.contains("+const DIR: &str = \"$WORKSPACE/out\";"));
+        assert!(sanitized.contains("-const DIR: &str = \"/home/alice/old\";"));
+        assert!(sanitized.contains(" const HOME: &str = \"/home/alice\";"));
+        assert!(sanitized.contains("+const OTHER: &str = \"/home/alice2\";"));
+    }
+
+    #[test]
+    fn normalizes_two_generations_of_a_patch_alike() {
+        let first = "diff --git a/src/lib.rs b/src/lib.rs\nindex 1111111..2222222 100644\n--- a/src/lib.rs\t2024-01-01 00:00:00\n+++ b/src/lib.rs\n@@ -1,2 +1,2 @@ fn hello() {\n-one\n+uno\n two\ndiff --git a/README.md b/README.md  \nindex 3333333..4444444 100644\n--- a/README.md\n+++ b/README.md\n@@ -1 +1 @@\n-old\n+new\n\n\n";
+        let second = "diff --git a/README.md b/README.md\nindex 5555555..6666666\n--- a/README.md\n+++ b/README.md\n@@ -1 +1 @@\n-old\n+new\ndiff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,2 +1,2 @@\n-one\n+uno\n two\n";
+        let normalized = normalize(first);
+        assert_eq!(normalized, normalize(second));
+        assert_eq!(normalize(&normalized), normalized);
+        assert!(
+            normalized.starts_with("diff --git a/README.md"),
+            "{}",
+            normalized
+        );
+        assert!(!normalized.contains("index "), "{}", normalized);
+        assert!(normalized.ends_with(" two\n"), "{}", normalized);
+    }
+}
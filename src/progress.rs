@@ -0,0 +1,60 @@
+//! A bar of the files copied while crates are copied to `target/patch`, drawn
+//! on a terminal unless `--quiet` or `--format json` is given.
+
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{io::IsTerminal, sync::Mutex};
+
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+fn current() -> Option<ProgressBar> {
+    BAR.lock().ok().and_then(|bar| bar.clone())
+}
+
+/// Starts a bar of `files` files, labeled with `label`.
+pub fn start(files: u64, label: &str) {
+    if super::log::quiet()
+        || super::report::enabled()
+        || !std::io::stdout().is_terminal()
+        || !std::io::stderr().is_terminal()
+    {
+        return;
+    }
+    let style = ProgressStyle::with_template("{prefix} [{bar:30}] {pos}/{len} files {msg}")
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ");
+    let bar = ProgressBar::new(files)
+        .with_style(style)
+        .with_prefix(label.to_string());
+    if let Ok(mut current) = BAR.lock() {
+        *current = Some(bar);
+    }
+}
+
+/// Shows the crate being copied next to the bar.
+pub fn message(name: &str) {
+    if let Some(bar) = current() {
+        bar.set_message(name.to_string());
+    }
+}
+
+/// Counts a copied file.
+pub fn inc() {
+    if let Some(bar) = current() {
+        bar.inc(1);
+    }
+}
+
+pub fn finish() {
+    if let Some(bar) = BAR.lock().ok().and_then(|mut bar| bar.take()) {
+        bar.finish_and_clear();
+    }
+}
+
+/// Runs `print` with the bar hidden, so that lines logged meanwhile don't
+/// mix with it.
+pub fn suspend(print: impl FnOnce()) {
+    match current() {
+        Some(bar) => bar.suspend(print),
+        None => print(),
+    }
+}
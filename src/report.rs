@@ -0,0 +1,73 @@
+//! The outcome of every crate of the run, printed as JSON with `--format json`
+//! once the run is over, failed or not.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ENTRIES: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records what happened to a crate: `action` is one of `applied`,
+/// `reversed`, `copied`, `created`, `skipped` or `failed`, `status` the
+/// same in words.
+pub fn push(
+    name: &str,
+    version: &str,
+    action: &str,
+    status: &str,
+    patch_file: Option<&Path>,
+    error: Option<String>,
+) {
+    if enabled() {
+        ENTRIES.lock().unwrap().push(serde_json::json!({
+            "name": name,
+            "version": version,
+            "action": action,
+            "status": status,
+            "patch_file": patch_file.map(|p| p.to_string_lossy()),
+            "error": error,
+        }));
+    }
+}
+
+pub fn warning(message: &str) {
+    if enabled() {
+        WARNINGS.lock().unwrap().push(message.to_string());
+    }
+}
+
+/// Prints the report to stdout. The warnings about a crate, which start
+/// with `crate: <name>,`, are attached to it, and turn a successful
+/// action into `warned`.
+pub fn print() -> anyhow::Result<()> {
+    let warnings = WARNINGS.lock().unwrap();
+    let mut entries = ENTRIES.lock().unwrap().clone();
+    for entry in entries.iter_mut() {
+        let prefix = format!("crate: {},", entry["name"].as_str().unwrap_or_default());
+        let own = warnings
+            .iter()
+            .filter(|w| w.starts_with(&prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !own.is_empty() && entry["action"] != "failed" && entry["action"] != "skipped" {
+            entry["action"] = "warned".into();
+        }
+        entry["warnings"] = own.into();
+    }
+    println!("{}", serde_json::to_string_pretty(&entries)?);
+    Ok(())
+}
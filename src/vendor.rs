@@ -0,0 +1,194 @@
+//! Applying the patches to the crates of a `cargo vendor` folder.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Ok, Result};
+use cargo::core::{package::PackageSet, PackageId, Resolve};
+
+use super::{
+    apply::apply_patch, cli::Cli, copy_dir, git, list_files, matches_spec, patches_fingerprint,
+    plan_crates, PatchWorkspace, WorkspaceExt,
+};
+
+/// The file in each crate folder of `--apply-to-vendor` that records the
+/// fingerprint of the patches it is patched with. Cargo only checks the files
+/// listed in `.cargo-checksum.json`, and `cargo vendor` removes it along with
+/// the crate when vendoring it again.
+const VENDOR_MARKER: &str = ".patch-crate";
+const VENDOR_CHECKSUM: &str = ".cargo-checksum.json";
+/// Applies the patches of the configured crates, or of `only`, in place to
+/// their folders in the `cargo vendor` output `vendor_dir`, instead of copies
+/// in `target/patch`, and lists the patched files with their new checksums
+/// in `.cargo-checksum.json`. The package checksum is kept, as it is the one
+/// of the `.crate` file in `Cargo.lock`.
+pub fn apply_to_vendor(
+    args: &Cli,
+    workspace: &PatchWorkspace<'_>,
+    pkg_set: &PackageSet<'_>,
+    resolve: &Resolve,
+    vendor_dir: &Path,
+    only: &[String],
+) -> Result<()> {
+    if !vendor_dir.is_dir() {
+        return Err(anyhow!(
+            "{:?} is not a directory, run `cargo vendor` first",
+            vendor_dir
+        ));
+    }
+    git::check_installed()?;
+    let normalize_eol = workspace.root_patch_metadata_strings("normalize_eol")?;
+    info!("applying patch to {:?}", vendor_dir);
+    for planned in plan_crates(args, workspace, pkg_set, resolve, only)? {
+        let pkg_id = planned.pkg_id;
+        let name = pkg_id.name();
+        let patch_files = planned.patch_files;
+        if !patch_files.iter().all(|file| file.exists()) {
+            crate_info!("crate: {}, skip, it has no patch.", name);
+            continue;
+        }
+        let Some(folder) = vendored_crate(vendor_dir, pkg_id)? else {
+            return Err(anyhow!(
+                "crate: {} {}, not found in {:?} as `{}-{}` or `{}`, vendor it again with `cargo vendor`",
+                name,
+                pkg_id.version(),
+                vendor_dir,
+                name,
+                pkg_id.version(),
+                name
+            ));
+        };
+        let fingerprint = patches_fingerprint(&patch_files)?;
+        let marker = folder.join(VENDOR_MARKER);
+        match fs::read_to_string(&marker) {
+            Result::Ok(recorded) if recorded.trim() == fingerprint => {
+                crate_info!("crate: {}, skip, {:?} is already patched.", name, folder);
+                continue;
+            }
+            Result::Ok(_) => {
+                return Err(anyhow!(
+                    "crate: {}, {:?} is patched with other patches, vendor it again with `cargo vendor` to apply these",
+                    name,
+                    folder
+                ));
+            }
+            Err(_) => {}
+        }
+        let edited = vendor_checksum_mismatches(&folder)?;
+        if !edited.is_empty() {
+            return Err(anyhow!(
+                "crate: {}, files in {:?} differ from its {}, vendor it again with `cargo vendor`:\n{}",
+                name,
+                folder,
+                VENDOR_CHECKSUM,
+                edited
+                    .iter()
+                    .map(|file| format!("  - {}", file))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        // patched aside, in a hidden folder cargo skips, so that a failing
+        // patch leaves the vendored crate as it was
+        let staged = vendor_dir.join(format!(
+            ".{}.tmp",
+            folder.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        if staged.exists() {
+            fs::remove_dir_all(&staged)?;
+        }
+        copy_dir(&folder, &staged)?;
+        let eol =
+            args.patching.normalize_eol || normalize_eol.iter().any(|n| matches_spec(n, pkg_id));
+        let result = patch_files.iter().try_for_each(|patch_file| {
+            apply_patch(args, &name, &staged, patch_file, eol)?;
+            crate_info!(
+                "crate: {}, successfully applied patch {:?}.",
+                name,
+                patch_file
+            );
+            Ok(())
+        });
+        if let Err(err) = result {
+            let _ = git::destroy(&staged);
+            fs::remove_dir_all(&staged)?;
+            return Err(err.context(format!("crate: {}, failed to apply its patches", name)));
+        }
+        write_vendor_checksums(&staged)?;
+        fs::write(staged.join(VENDOR_MARKER), format!("{}\n", fingerprint))?;
+        fs::remove_dir_all(&folder)?;
+        fs::rename(&staged, &folder)?;
+        crate_info!(
+            "crate: {}, patched {:?} and updated its {}.",
+            name,
+            folder,
+            VENDOR_CHECKSUM
+        );
+    }
+    Ok(())
+}
+
+/// The folder of `pkg_id` in the `cargo vendor` output `vendor_dir`: named
+/// `<name>-<version>`, as with `--versioned-dirs` or for all but the latest
+/// of several versions, or `<name>` when its `Cargo.toml` has the version.
+fn vendored_crate(vendor_dir: &Path, pkg_id: PackageId) -> Result<Option<PathBuf>> {
+    let versioned = vendor_dir.join(format!("{}-{}", pkg_id.name(), pkg_id.version()));
+    if versioned.join(VENDOR_CHECKSUM).exists() {
+        return Ok(Some(versioned));
+    }
+    let plain = vendor_dir.join(pkg_id.name().as_str());
+    let Result::Ok(manifest) = fs::read_to_string(plain.join("Cargo.toml")) else {
+        return Ok(None);
+    };
+    let version = manifest
+        .parse::<toml::Table>()?
+        .get("package")
+        .and_then(|p| p.get("version"))
+        .and_then(|v| v.as_str())
+        .map(str::to_string);
+    Ok((version == Some(pkg_id.version().to_string())).then_some(plain))
+}
+
+/// The files listed in the `.cargo-checksum.json` of the vendored crate
+/// `folder` that are missing or whose checksum differs, which cargo rejects.
+fn vendor_checksum_mismatches(folder: &Path) -> Result<Vec<String>> {
+    use sha2::{Digest, Sha256};
+    let checksum_file = folder.join(VENDOR_CHECKSUM);
+    let checksums: serde_json::Value = serde_json::from_str(&fs::read_to_string(&checksum_file)?)?;
+    let mut mismatches = Vec::new();
+    for (file, expected) in checksums["files"].as_object().into_iter().flatten() {
+        let actual = fs::read(folder.join(file))
+            .map(|content| format!("{:x}", Sha256::digest(content)))
+            .ok();
+        if actual.as_deref() != expected.as_str() {
+            mismatches.push(file.clone());
+        }
+    }
+    mismatches.sort();
+    Ok(mismatches)
+}
+
+/// Lists every file of the vendored crate `folder` with its checksum in its
+/// `.cargo-checksum.json`, like `cargo vendor` does, keeping the package
+/// checksum.
+fn write_vendor_checksums(folder: &Path) -> Result<()> {
+    use sha2::{Digest, Sha256};
+    let checksum_file = folder.join(VENDOR_CHECKSUM);
+    let mut checksums: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&checksum_file)?)?;
+    let mut files = serde_json::Map::new();
+    let mut paths = list_files(folder)?;
+    paths.sort();
+    for path in paths {
+        if path == VENDOR_CHECKSUM || path == VENDOR_MARKER {
+            continue;
+        }
+        let content = fs::read(folder.join(&path))?;
+        files.insert(path, format!("{:x}", Sha256::digest(content)).into());
+    }
+    checksums["files"] = files.into();
+    fs::write(checksum_file, serde_json::to_string(&checksums)?)?;
+    Ok(())
+}
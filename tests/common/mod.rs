@@ -0,0 +1,290 @@
+//! Fixtures for running cargo-patch-crate on throwaway workspaces: a folder of
+//! vendored crates standing in for crates.io, and a project resolving from it
+//! offline through its own `CARGO_HOME`.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::Write as _,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use sha2::{Digest, Sha256};
+
+/// An empty folder for `name` under the temporary folder of the tests.
+pub fn fixture_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes `files`, pairs of a path relative to `root` and its content.
+pub fn write_files(root: &Path, files: &[(&str, &str)]) {
+    for (path, content) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}
+
+/// Copies the folder `from` to `to`, leaving out the entries named in `skip`.
+pub fn copy_tree(from: &Path, to: &Path, skip: &[&str]) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if skip.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_tree(&entry.path(), &target, &[]);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// A crate to publish to a [`Registry`].
+pub struct Crate<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    /// `(name, version requirement)` of its normal dependencies
+    pub deps: &'a [(&'a str, &'a str)],
+    /// Extra lines of its `Cargo.toml`, after `[package]`
+    pub manifest: &'a str,
+    pub files: &'a [(&'a str, &'a str)],
+}
+
+impl<'a> Crate<'a> {
+    pub fn new(name: &'a str, version: &'a str) -> Self {
+        Crate {
+            name,
+            version,
+            deps: &[],
+            manifest: "",
+            files: &[("src/lib.rs", "pub fn hello() {}\n")],
+        }
+    }
+}
+
+/// A folder of vendored crates, as made by `cargo vendor`, that stands in
+/// for crates.io.
+pub struct Registry {
+    pub dir: PathBuf,
+}
+
+impl Registry {
+    pub fn new(dir: PathBuf) -> Self {
+        fs::create_dir_all(&dir).unwrap();
+        Registry { dir }
+    }
+
+    /// Vendors `krate` into `<name>-<version>`, as published, with a
+    /// `Cargo.toml.orig` next to its `Cargo.toml` and the checksums cargo
+    /// checks it against. Returns its package checksum, the one `Cargo.lock`
+    /// records.
+    pub fn publish(&self, krate: &Crate<'_>) -> String {
+        let mut manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"{}\"\nedition = \"2021\"\n{}\n",
+            krate.name, krate.version, krate.manifest
+        );
+        if !krate.deps.is_empty() {
+            manifest.push_str("\n[dependencies]\n");
+            for (name, req) in krate.deps {
+                manifest.push_str(&format!("{} = \"{}\"\n", name, req));
+            }
+        }
+        let mut files = vec![
+            ("Cargo.toml", manifest.as_str()),
+            ("Cargo.toml.orig", manifest.as_str()),
+        ];
+        files.extend(krate.files.iter().copied());
+
+        let root = self.dir.join(format!("{}-{}", krate.name, krate.version));
+        write_files(&root, &files);
+        let mut package = Sha256::new();
+        let mut checksums = serde_json::Map::new();
+        for (path, content) in files {
+            package.update(content);
+            checksums.insert(
+                path.to_string(),
+                format!("{:x}", Sha256::digest(content)).into(),
+            );
+        }
+        let package = format!("{:x}", package.finalize());
+        let checksum = serde_json::json!({
+            "files": checksums,
+            "package": package,
+        });
+        fs::write(root.join(".cargo-checksum.json"), checksum.to_string()).unwrap();
+        package
+    }
+}
+
+/// A workspace at `root` whose crates.io dependencies all come from a
+/// [`Registry`], with a `CARGO_HOME` of its own next to it.
+pub struct Project {
+    pub root: PathBuf,
+    pub cargo_home: PathBuf,
+}
+
+impl Project {
+    /// A project in a fresh fixture folder `name`, with `files` written to it
+    /// and `registry` replacing crates.io.
+    pub fn new(name: &str, registry: &Registry, files: &[(&str, &str)]) -> Self {
+        let dir = fixture_dir(name);
+        let project = Project {
+            root: dir.join("project"),
+            cargo_home: dir.join("cargo-home"),
+        };
+        write_files(&project.root, files);
+        write_files(
+            &project.cargo_home,
+            &[(
+                "config.toml",
+                &format!(
+                    "[source.crates-io]\nreplace-with = \"vendored\"\n\n[source.vendored]\ndirectory = {:?}\n",
+                    registry.dir
+                ),
+            )],
+        );
+        project
+    }
+
+    /// Runs cargo-patch-crate in the project root with `args`.
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.run_in(&self.root, args)
+    }
+
+    /// Runs cargo-patch-crate in `dir` with `args`, without any global or
+    /// system git config, like in a sandboxed CI job that has no identity to
+    /// commit with.
+    pub fn run_in(&self, dir: &Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_cargo-patch-crate"))
+            .args(args)
+            .current_dir(dir)
+            .env("CARGO_HOME", &self.cargo_home)
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("CARGO_NET_OFFLINE", "true")
+            .env("NO_COLOR", "1")
+            .env_remove("PATCH_CRATE_DIR")
+            .output()
+            .unwrap()
+    }
+
+    /// Like [`run`](Self::run), failing the test unless the run succeeds.
+    pub fn run_ok(&self, args: &[&str]) -> Output {
+        let output = self.run(args);
+        assert!(
+            output.status.success(),
+            "`cargo-patch-crate {}` failed:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    /// Appends `line` to the file at `path` in the project.
+    pub fn append(&self, path: &str, line: &str) {
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(self.path(path))
+            .unwrap();
+        writeln!(file, "{}", line).unwrap();
+    }
+}
+
+/// The `Cargo.toml` of a package `app` depending on `deps`, pairs of a name
+/// and a requirement, with `metadata` as its `[package.metadata.patch]`.
+pub fn app_manifest(deps: &[(&str, &str)], metadata: &str) -> String {
+    let mut manifest =
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n"
+            .to_string();
+    for (name, req) in deps {
+        manifest.push_str(&format!("{} = {}\n", name, req));
+    }
+    manifest.push_str(&format!("\n[package.metadata.patch]\n{}\n", metadata));
+    manifest
+}
+
+/// A project patching the crate `demo`, whose copy is ready to be edited.
+pub struct Demo {
+    pub project: Project,
+    pub registry: Registry,
+}
+
+impl Demo {
+    /// A project in the fixture folder `name` depending on `demo`, published
+    /// with `files`, and with its copy made.
+    pub fn new(name: &str, files: &[(&str, &str)]) -> Self {
+        let registry = Registry::new(fixture_dir(&format!("{}-registry", name)));
+        registry.publish(&Crate {
+            files,
+            ..Crate::new("demo", "0.1.0")
+        });
+        let project = Project::new(
+            name,
+            &registry,
+            &[
+                (
+                    "Cargo.toml",
+                    &app_manifest(&[("demo", "\"0.1\"")], "crates = [\"demo\"]"),
+                ),
+                ("src/main.rs", "fn main() {}\n"),
+            ],
+        );
+        project.run_ok(&[]);
+        Demo { project, registry }
+    }
+
+    /// Appends `line` to the file at `path` in the copy.
+    pub fn edit(&self, path: &str, line: &str) {
+        self.append(&copy(path), line);
+    }
+
+    pub fn read_copy(&self, path: &str) -> String {
+        self.read(&copy(path))
+    }
+
+    /// Creates the patch of `demo` with `args` and returns it.
+    pub fn create(&self, args: &[&str]) -> String {
+        self.run_ok(&[args, &["demo"]].concat());
+        self.read(PATCH)
+    }
+
+    /// Copies `demo` again and applies its patch to the fresh copy.
+    pub fn reapply(&self) {
+        self.run_ok(&["--force"]);
+    }
+}
+
+impl Deref for Demo {
+    type Target = Project;
+
+    fn deref(&self) -> &Project {
+        &self.project
+    }
+}
+
+/// The path of `path` in the copy of `demo`, relative to the project.
+pub fn copy(path: &str) -> String {
+    format!("target/patch/demo-0.1.0/{}", path)
+}
+
+/// The patch of `demo`, once created.
+pub const PATCH: &str = "patches/demo+0.1.0.patch";
@@ -0,0 +1,369 @@
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{app_manifest, copy, Crate, Demo, PATCH};
+
+#[test]
+fn records_a_case_only_rename() {
+    let demo = Demo::new(
+        "case-rename",
+        &[
+            ("src/lib.rs", "mod foo;\n"),
+            ("src/Foo.rs", "pub fn foo() {}\n"),
+        ],
+    );
+    fs::rename(
+        demo.path(&copy("src/Foo.rs")),
+        demo.path(&copy("src/foo.rs")),
+    )
+    .unwrap();
+    let output = demo.run_ok(&["demo"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("renamed to src/foo.rs by case only"));
+    let patch = demo.read(PATCH);
+    assert!(
+        patch.contains("rename from src/Foo.rs\nrename to src/foo.rs"),
+        "{}",
+        patch
+    );
+
+    demo.reapply();
+    let names = fs::read_dir(demo.path(&copy("src")))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert!(names.contains(&"foo.rs".to_string()), "{:?}", names);
+    assert!(!names.contains(&"Foo.rs".to_string()), "{:?}", names);
+}
+
+/// Only a case-sensitive file system can hold both files.
+#[cfg(target_os = "linux")]
+#[test]
+fn warns_about_paths_colliding_by_case() {
+    let demo = Demo::new("case-collision", &[("src/lib.rs", "\n")]);
+    fs::write(demo.path(&copy("src/Lib.rs")), "\n").unwrap();
+    let output = demo.run_ok(&["demo"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let warning = stdout
+        .lines()
+        .find(|line| line.contains("only differ in case"))
+        .unwrap_or_else(|| panic!("no warning:\n{}", stdout));
+    assert!(warning.contains("src/Lib.rs") && warning.contains("src/lib.rs"));
+}
+
+/// A patch that converts a file from CRLF to LF line endings is created and
+/// applied byte for byte, without git converting them back.
+#[test]
+fn applies_a_patch_converting_crlf_to_lf() {
+    let demo = Demo::new(
+        "crlf-to-lf",
+        &[("src/lib.rs", "\n"), ("data.txt", "one\r\ntwo\r\n")],
+    );
+    fs::write(demo.path(&copy("data.txt")), "one\ntwo\n").unwrap();
+    demo.create(&[]);
+
+    demo.reapply();
+    assert_eq!(
+        fs::read(demo.path(&copy("data.txt"))).unwrap(),
+        b"one\ntwo\n"
+    );
+}
+
+/// Patching never runs the build script of a crate, so one that depends on
+/// its environment, or fails outright, makes no difference.
+#[test]
+fn never_runs_build_scripts() {
+    let marker = common::fixture_dir("build-script-marker").join("ran");
+    let build = format!(
+        "fn main() {{\n    std::fs::write({:?}, \"\").unwrap();\n    let _ = env!(\"PATCH_CRATE_TEST_UNSET\");\n}}\n",
+        marker
+    );
+    let demo = Demo::new(
+        "build-script",
+        &[("build.rs", &build), ("src/lib.rs", "pub fn hello() {}\n")],
+    );
+    demo.edit("src/lib.rs", "// patched");
+    demo.create(&[]);
+    demo.reapply();
+    assert!(demo.read_copy("src/lib.rs").ends_with("// patched\n"));
+    assert!(!marker.exists());
+}
+
+/// A file deleted from the copy is recorded as deleted, and applying the
+/// patch deletes it again.
+#[test]
+fn records_a_deleted_file() {
+    let demo = Demo::new(
+        "deleted-file",
+        &[
+            ("src/lib.rs", "pub fn hello() {}\n"),
+            ("extra.txt", "extra\n"),
+        ],
+    );
+    fs::remove_file(demo.path(&copy("extra.txt"))).unwrap();
+    let patch = demo.create(&[]);
+    assert!(patch.contains("deleted file mode"), "{}", patch);
+    assert!(patch.contains("-extra"), "{}", patch);
+
+    demo.reapply();
+    assert!(!demo.path(&copy("extra.txt")).exists());
+    assert!(demo.path(&copy("src/lib.rs")).is_file());
+}
+
+/// A patch whose context doesn't match its crate, e.g. one edited by hand.
+const CONFLICTING_PATCH: &str = "diff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-pub fn goodbye() {}\n+pub fn hello() {}\n";
+
+/// A patch that doesn't apply fails the run with the error of `git apply`.
+#[test]
+fn fails_on_a_patch_that_does_not_apply() {
+    let demo = Demo::new("conflict", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    fs::create_dir_all(demo.path("patches")).unwrap();
+    fs::write(demo.path(PATCH), CONFLICTING_PATCH).unwrap();
+
+    let output = demo.run(&["--force"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("demo+0.1.0.patch"), "{}", stderr);
+}
+
+/// With `--continue-on-error`, the crate whose patch doesn't apply is left
+/// copied but unpatched, with a warning.
+#[test]
+fn leaves_the_crate_unpatched_with_continue_on_error() {
+    let demo = Demo::new(
+        "continue-on-error",
+        &[("src/lib.rs", "pub fn hello() {}\n")],
+    );
+    fs::create_dir_all(demo.path("patches")).unwrap();
+    fs::write(demo.path(PATCH), CONFLICTING_PATCH).unwrap();
+
+    let output = demo.run(&["--force", "--continue-on-error"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("left unpatched"), "{}", stdout);
+    assert_eq!(demo.read_copy("src/lib.rs"), "pub fn hello() {}\n");
+}
+
+/// Creating the patch of the same edit again writes the same bytes.
+#[test]
+fn creates_the_same_patch_twice() {
+    let demo = Demo::new(
+        "same-patch",
+        &[
+            ("src/lib.rs", "pub fn hello() {}\n"),
+            ("README.md", "demo\n"),
+        ],
+    );
+    demo.edit("src/lib.rs", "// patched");
+    demo.edit("README.md", "patched");
+    assert_eq!(demo.create(&[]), demo.create(&[]));
+}
+
+/// A copy without changes writes no patch, unless `--allow-empty` asks for a
+/// placeholder, which applying then skips.
+#[test]
+fn writes_no_patch_without_changes() {
+    let demo = Demo::new("no-changes", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    demo.run_ok(&["demo"]);
+    assert!(!demo.path(PATCH).exists());
+
+    demo.create(&["--allow-empty"]);
+    demo.reapply();
+    assert_eq!(demo.read_copy("src/lib.rs"), "pub fn hello() {}\n");
+}
+
+/// A patch created from a crate with LF line endings still applies to the
+/// crate checked out with CRLF ones, and so does the patch itself after a
+/// checkout gave it CRLF line endings.
+#[test]
+fn applies_a_patch_to_a_crate_with_crlf_line_endings() {
+    let demo = Demo::new("crlf-crate", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    demo.edit("src/lib.rs", "// patched");
+    let patch = demo.create(&[]);
+
+    demo.registry.publish(&Crate {
+        files: &[("src/lib.rs", "pub fn hello() {}\r\n")],
+        ..Crate::new("demo", "0.1.0")
+    });
+    fs::write(demo.path(PATCH), patch.replace('\n', "\r\n")).unwrap();
+    demo.reapply();
+    let lib = demo.read_copy("src/lib.rs");
+    assert!(lib.starts_with("pub fn hello() {}\r\n"), "{:?}", lib);
+    assert!(lib.contains("// patched"), "{:?}", lib);
+}
+
+/// A diff tool to create patches with, instead of git.
+const DIFF_TOOL: [&str; 2] = ["--diff-tool", "diff -ruN"];
+
+/// The files cargo adds to a crate when packaging it, such as
+/// `Cargo.toml.orig`, never go into a patch, even when edited, whatever
+/// creates it.
+#[test]
+fn leaves_cargo_toml_orig_out_of_the_patch() {
+    let demo = Demo::new("cargo-toml-orig", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    demo.edit("Cargo.toml.orig", "# edited");
+    demo.edit("src/lib.rs", "// patched");
+    for args in [&[][..], &DIFF_TOOL] {
+        let patch = demo.create(args);
+        assert!(patch.contains("+// patched"), "{}", patch);
+        assert!(!patch.contains("Cargo.toml.orig"), "{}", patch);
+    }
+}
+
+/// A copy that lost its `Cargo.toml`, e.g. to an interrupted run or a stray
+/// `rm`, is reported as incomplete, and copied and patched again once
+/// `--yes` allows overwriting what is left of it.
+#[test]
+fn copies_an_incomplete_copy_again() {
+    let demo = Demo::new("incomplete-copy", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    demo.edit("src/lib.rs", "// patched");
+    demo.create(&[]);
+    fs::remove_file(demo.path(&copy("Cargo.toml"))).unwrap();
+
+    let output = demo.run_ok(&["status"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("incomplete copy"), "{}", stdout);
+
+    // losing a file is an edit of the copy like any other
+    assert!(!demo.run(&[]).status.success());
+    demo.run_ok(&["--yes"]);
+    assert!(demo.path(&copy("Cargo.toml")).is_file());
+    assert!(demo.read_copy("src/lib.rs").ends_with("// patched\n"));
+}
+
+/// Files an editor or file manager leaves in the copy, such as `.DS_Store`,
+/// never go into a patch, whatever creates it, unless `junk_files` is
+/// emptied.
+#[test]
+fn leaves_stray_junk_files_out_of_the_patch() {
+    let demo = Demo::new("junk-files", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    common::write_files(
+        &demo.path(&copy("")),
+        &[(".DS_Store", "junk"), ("src/.lib.rs.swp", "junk")],
+    );
+    demo.edit("src/lib.rs", "// patched");
+    for args in [&[][..], &DIFF_TOOL] {
+        let patch = demo.create(args);
+        assert!(patch.contains("+// patched"), "{}", patch);
+        assert!(!patch.contains(".DS_Store"), "{}", patch);
+        assert!(!patch.contains(".swp"), "{}", patch);
+    }
+
+    let manifest = app_manifest(
+        &[("demo", "\"0.1\"")],
+        "crates = [\"demo\"]\njunk_files = []",
+    );
+    fs::write(demo.path("Cargo.toml"), manifest).unwrap();
+    let patch = demo.create(&[]);
+    assert!(patch.contains(".DS_Store"), "{}", patch);
+}
+
+/// `--sparse-apply` only copies the files the patch touches to apply it, but
+/// leaves a complete crate that builds, whose other files are copies too:
+/// editing one leaves the source in the registry as it was.
+#[test]
+fn sparse_apply_leaves_a_complete_crate() {
+    let demo = Demo::new(
+        "sparse-apply",
+        &[
+            ("src/lib.rs", "pub mod other;\npub fn hello() {}\n"),
+            ("src/other.rs", "pub fn other() {}\n"),
+        ],
+    );
+    demo.edit("src/lib.rs", "pub fn patched() {}");
+    demo.create(&[]);
+    demo.run_ok(&["--force", "--sparse-apply", "--write-manifest"]);
+    assert!(demo
+        .read_copy("src/lib.rs")
+        .ends_with("pub fn patched() {}\n"));
+
+    fs::write(
+        demo.path("src/main.rs"),
+        "fn main() {\n    demo::patched();\n    demo::other::other();\n}\n",
+    )
+    .unwrap();
+    let build = Command::new(env!("CARGO"))
+        .args(["build", "--offline", "--quiet"])
+        .current_dir(&demo.root)
+        .env("CARGO_HOME", &demo.cargo_home)
+        .output()
+        .unwrap();
+    assert!(
+        build.status.success(),
+        "{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    demo.edit("src/other.rs", "// edited");
+    assert_eq!(
+        fs::read_to_string(demo.registry.dir.join("demo-0.1.0/src/other.rs")).unwrap(),
+        "pub fn other() {}\n"
+    );
+}
+
+/// The temporary folder kept by `--keep-tmp` is left alone by the runs after
+/// it, also by those that work in it and succeed.
+#[test]
+fn keeps_the_temporary_folder_of_keep_tmp() {
+    let demo = Demo::new("keep-tmp", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    demo.edit("src/lib.rs", "// patched");
+    demo.create(&["--keep-tmp"]);
+    let tmp = demo.path("target/patch-tmp/demo-0.1.0");
+    assert!(tmp.is_dir());
+
+    for args in [&["list"][..], &["status"], &["--force"]] {
+        demo.run_ok(args);
+        assert!(tmp.is_dir(), "removed by {:?}", args);
+    }
+    let output = demo.run_ok(&["status"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("kept"), "{}", stdout);
+}
+
+#[test]
+fn populates_the_pristine_dir_only_when_copying() {
+    let demo = Demo::new("populate", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    demo.edit("src/lib.rs", "// patched");
+    demo.create(&[]);
+    let pristine = ["--pristine-dir", "pristine", "--populate-pristine-dir"];
+
+    demo.run_ok(&[&["--force", "--dry-run"][..], &pristine].concat());
+    demo.run_ok(&[&["status"][..], &pristine].concat());
+    assert!(!demo.path("pristine").exists());
+    demo.run_ok(&[&["--force"][..], &pristine].concat());
+    let manifest = demo.path("pristine/demo-0.1.0/Cargo.toml");
+    assert!(manifest.is_file());
+
+    let content = fs::read_to_string(&manifest).unwrap();
+    fs::write(&manifest, content.replace("0.1.0", "0.2.0")).unwrap();
+    let output = demo.run(&["status", "--pristine-dir", "pristine"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("not the resolved demo 0.1.0"), "{}", stderr);
+}
+
+/// A command only takes the flags it has a use for, and leaves out those of
+/// `default_args` it doesn't take, along with their values.
+#[test]
+fn scopes_flags_and_default_args_to_their_commands() {
+    let demo = Demo::new("scoped-flags", &[("src/lib.rs", "pub fn hello() {}\n")]);
+    let output = demo.run(&["list", "--release"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("unexpected argument '--release'"),
+        "{}",
+        stderr
+    );
+
+    demo.append(
+        "Cargo.toml",
+        "default_args = [\"--jobs\", \"2\", \"--release\", \"--context-lines=0\"]",
+    );
+    demo.run_ok(&["list"]);
+    demo.run_ok(&["apply"]);
+    demo.edit("src/lib.rs", "// patched");
+    demo.run_ok(&["create", "demo"]);
+    let patch = demo.read(PATCH);
+    assert!(patch.contains("+// patched"), "{}", patch);
+    assert!(!patch.contains(" pub fn hello() {}"), "{}", patch);
+}
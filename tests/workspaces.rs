@@ -0,0 +1,349 @@
+mod common;
+
+use std::fs;
+
+use common::{app_manifest, Crate, Project, Registry};
+
+/// A proc-macro crate that only comes in as the dependency of another crate
+/// is resolved as a package of its own, and patched like any other.
+#[test]
+fn patches_a_proc_macro_dependency() {
+    let registry = Registry::new(common::fixture_dir("proc-macro-registry"));
+    registry.publish(&Crate {
+        manifest: "\n[lib]\nproc-macro = true",
+        files: &[(
+            "src/lib.rs",
+            "use proc_macro::TokenStream;\n\n#[proc_macro]\npub fn noop(input: TokenStream) -> TokenStream {\n    input\n}\n",
+        )],
+        ..Crate::new("demo-macros", "0.1.0")
+    });
+    registry.publish(&Crate {
+        deps: &[("demo-macros", "0.1")],
+        ..Crate::new("demo", "0.1.0")
+    });
+    let project = Project::new(
+        "proc-macro",
+        &registry,
+        &[
+            (
+                "Cargo.toml",
+                &app_manifest(&[("demo", "\"0.1\"")], "crates = [\"demo-macros\"]"),
+            ),
+            ("src/main.rs", "fn main() {}\n"),
+        ],
+    );
+
+    project.run_ok(&[]);
+    let lib = "target/patch/demo-macros-0.1.0/src/lib.rs";
+    assert!(project.read(lib).contains("pub fn noop"));
+
+    project.append(lib, "// patched");
+    project.run_ok(&["demo-macros"]);
+    let patch = project.read("patches/demo-macros+0.1.0.patch");
+    assert!(patch.contains("+// patched"), "{}", patch);
+
+    project.run_ok(&["--force"]);
+    assert!(project.read(lib).ends_with("// patched\n"));
+}
+
+/// A project moved elsewhere, e.g. into a sandbox, applies the patches made
+/// at its old location, and neither the patches nor the markers of the
+/// copies record where it was.
+#[test]
+fn applies_patches_in_a_relocated_project() {
+    let registry = Registry::new(common::fixture_dir("relocated-registry"));
+    registry.publish(&Crate::new("demo", "0.1.0"));
+    let project = Project::new(
+        "relocated",
+        &registry,
+        &[
+            (
+                "Cargo.toml",
+                &app_manifest(&[("demo", "\"0.1\"")], "crates = [\"demo\"]"),
+            ),
+            ("src/main.rs", "fn main() {}\n"),
+        ],
+    );
+    project.run_ok(&[]);
+    project.append("target/patch/demo-0.1.0/src/lib.rs", "// patched");
+    project.run_ok(&["demo"]);
+
+    let relocated = Project {
+        root: project.root.with_file_name("relocated-project"),
+        cargo_home: project.cargo_home.clone(),
+    };
+    common::copy_tree(&project.root, &relocated.root, &["target"]);
+    relocated.run_ok(&[]);
+    assert!(relocated
+        .read("target/patch/demo-0.1.0/src/lib.rs")
+        .ends_with("// patched\n"));
+    for path in [
+        "patches/demo+0.1.0.patch",
+        "target/patch/demo-0.1.0.patch-crate-source",
+    ] {
+        let content = relocated.read(path);
+        for root in [&project.root, &relocated.root] {
+            assert!(
+                !content.contains(root.to_str().unwrap()),
+                "{} records {:?}:\n{}",
+                path,
+                root,
+                content
+            );
+        }
+    }
+}
+
+/// A virtual workspace lists the crates to patch in
+/// `[workspace.metadata.patch]`, as it has no package of its own.
+#[test]
+fn reads_the_crates_of_a_virtual_workspace() {
+    let registry = Registry::new(common::fixture_dir("virtual-registry"));
+    registry.publish(&Crate::new("demo", "0.1.0"));
+    let project = Project::new(
+        "virtual",
+        &registry,
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"app\"]\nresolver = \"2\"\n\n[workspace.metadata.patch]\ncrates = [\"demo\"]\n",
+            ),
+            (
+                "app/Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\ndemo = \"0.1\"\n",
+            ),
+            ("app/src/main.rs", "fn main() {}\n"),
+        ],
+    );
+    project.run_ok(&[]);
+    assert!(project.path("target/patch/demo-0.1.0/Cargo.toml").is_file());
+
+    project.append("target/patch/demo-0.1.0/src/lib.rs", "// patched");
+    project.run_ok(&["demo"]);
+    assert!(project
+        .read("patches/demo+0.1.0.patch")
+        .contains("+// patched"));
+}
+
+/// The `Cargo.lock` of `app` depending on `demo` in `version`, with the
+/// package `checksum` of its source.
+fn demo_lockfile(version: &str, checksum: &str) -> String {
+    format!(
+        "version = 3\n\n[[package]]\nname = \"app\"\nversion = \"0.1.0\"\ndependencies = [\n \"demo\",\n]\n\n[[package]]\nname = \"demo\"\nversion = \"{}\"\nsource = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"{}\"\n",
+        version, checksum
+    )
+}
+
+/// With `--locked`, the crate copied is the one `Cargo.lock` records, not
+/// the newest one, and a crate or source that differs from it is an error.
+#[test]
+fn copies_the_sources_of_the_lockfile() {
+    let registry = Registry::new(common::fixture_dir("locked-registry"));
+    let checksum = registry.publish(&Crate::new("demo", "0.1.0"));
+    registry.publish(&Crate::new("demo", "0.1.1"));
+    let project = Project::new(
+        "locked",
+        &registry,
+        &[
+            (
+                "Cargo.toml",
+                &app_manifest(&[("demo", "\"0.1\"")], "crates = [\"demo\"]"),
+            ),
+            ("Cargo.lock", &demo_lockfile("0.1.0", &checksum)),
+            ("src/main.rs", "fn main() {}\n"),
+        ],
+    );
+    project.run_ok(&["--locked"]);
+    assert!(project.path("target/patch/demo-0.1.0/Cargo.toml").is_file());
+    assert!(!project.path("target/patch/demo-0.1.1").exists());
+
+    let manifest = app_manifest(&[("demo", "\"=0.1.1\"")], "crates = [\"demo\"]");
+    fs::write(project.path("Cargo.toml"), manifest).unwrap();
+    let output = project.run(&["--locked"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("demo v0.1.1 is not in the lockfile"),
+        "{}",
+        stderr
+    );
+
+    fs::write(
+        project.path("Cargo.toml"),
+        app_manifest(&[("demo", "\"0.1\"")], "crates = [\"demo\"]"),
+    )
+    .unwrap();
+    let tampered = format!("{:0>64}", "");
+    fs::write(
+        project.path("Cargo.lock"),
+        demo_lockfile("0.1.0", &tampered),
+    )
+    .unwrap();
+    let output = project.run(&["--locked", "--force"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("checksum"), "{}", stderr);
+}
+
+/// Two versions of a crate are named with `name@version` specs, and their
+/// patches and copies live side by side; a bare name is an error listing
+/// them.
+#[test]
+fn patches_two_versions_of_a_crate() {
+    let registry = Registry::new(common::fixture_dir("versions-registry"));
+    registry.publish(&Crate::new("demo", "0.1.0"));
+    registry.publish(&Crate::new("demo", "0.2.0"));
+    let deps = [
+        ("demo", "\"0.1\""),
+        ("demo2", "{ package = \"demo\", version = \"0.2\" }"),
+    ];
+    let project = Project::new(
+        "versions",
+        &registry,
+        &[
+            (
+                "Cargo.toml",
+                &app_manifest(&deps, "crates = [\"demo@0.1.0\", \"demo@0.2.0\"]"),
+            ),
+            ("src/main.rs", "fn main() {}\n"),
+        ],
+    );
+    project.run_ok(&[]);
+    for version in ["0.1.0", "0.2.0"] {
+        assert!(project
+            .path(&format!("target/patch/demo-{}/Cargo.toml", version))
+            .is_file());
+    }
+
+    project.append("target/patch/demo-0.2.0/src/lib.rs", "// patched");
+    let output = project.run(&["demo"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("demo@0.1.0") && stderr.contains("demo@0.2.0"),
+        "{}",
+        stderr
+    );
+
+    project.run_ok(&["demo@0.2.0"]);
+    assert!(project
+        .read("patches/demo+0.2.0.patch")
+        .contains("+// patched"));
+    assert!(!project.path("patches/demo+0.1.0.patch").exists());
+
+    project.run_ok(&["--force"]);
+    assert!(project
+        .read("target/patch/demo-0.2.0/src/lib.rs")
+        .ends_with("// patched\n"));
+    assert_eq!(
+        project.read("target/patch/demo-0.1.0/src/lib.rs"),
+        "pub fn hello() {}\n"
+    );
+}
+
+/// The `Cargo.toml` of the member `name` depending on `demo`, with `crates`
+/// as the crates it patches.
+fn member_manifest(name: &str, crates: &str) -> String {
+    format!(
+        "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\ndemo = \"0.1\"\n\n[package.metadata.patch]\ncrates = {}\n",
+        name, crates
+    )
+}
+
+/// Members listing the same crate share its patch, a plain name taking the
+/// settings another member gives it, but tables with different settings are
+/// an error.
+#[test]
+fn shares_a_crate_listed_by_several_members() {
+    let registry = Registry::new(common::fixture_dir("members-registry"));
+    registry.publish(&Crate::new("demo", "0.1.0"));
+    let project = Project::new(
+        "members",
+        &registry,
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"a\", \"b\"]\nresolver = \"2\"\n",
+            ),
+            (
+                "a/Cargo.toml",
+                &member_manifest("a", "[{ name = \"demo\", optional = true }]"),
+            ),
+            ("a/src/lib.rs", ""),
+            ("b/Cargo.toml", &member_manifest("b", "[\"demo\"]")),
+            ("b/src/lib.rs", ""),
+        ],
+    );
+    project.run_ok(&[]);
+    project.append("target/patch/demo-0.1.0/src/lib.rs", "// patched");
+    project.run_ok(&["demo"]);
+    assert!(project
+        .read("patches/demo+0.1.0.patch")
+        .contains("+// patched"));
+    project.run_ok(&["--force"]);
+
+    fs::write(
+        project.path("b/Cargo.toml"),
+        member_manifest("b", "[{ name = \"demo\", optional = false }]"),
+    )
+    .unwrap();
+    let output = project.run(&[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("different settings"), "{}", stderr);
+}
+
+/// A glob in `crates` patches every resolved crate whose name it matches.
+#[test]
+fn patches_the_crates_a_glob_matches() {
+    let registry = Registry::new(common::fixture_dir("glob-registry"));
+    for name in ["tokio", "tokio-util", "serde"] {
+        registry.publish(&Crate::new(name, "0.1.0"));
+    }
+    let deps = [
+        ("tokio", "\"0.1\""),
+        ("tokio-util", "\"0.1\""),
+        ("serde", "\"0.1\""),
+    ];
+    let project = Project::new(
+        "glob",
+        &registry,
+        &[
+            ("Cargo.toml", &app_manifest(&deps, "crates = [\"tok*\"]")),
+            ("src/main.rs", "fn main() {}\n"),
+        ],
+    );
+    project.run_ok(&[]);
+    assert!(project
+        .path("target/patch/tokio-0.1.0/Cargo.toml")
+        .is_file());
+    assert!(project
+        .path("target/patch/tokio-util-0.1.0/Cargo.toml")
+        .is_file());
+    assert!(!project.path("target/patch/serde-0.1.0").exists());
+}
+
+/// A crate that is only a dev-dependency is resolved and patched like the
+/// normal ones.
+#[test]
+fn patches_a_dev_dependency() {
+    let registry = Registry::new(common::fixture_dir("dev-registry"));
+    registry.publish(&Crate::new("demo", "0.1.0"));
+    let manifest = "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dev-dependencies]\ndemo = \"0.1\"\n\n[package.metadata.patch]\ncrates = [\"demo\"]\n";
+    let project = Project::new(
+        "dev",
+        &registry,
+        &[("Cargo.toml", manifest), ("src/main.rs", "fn main() {}\n")],
+    );
+    project.run_ok(&[]);
+    project.append("target/patch/demo-0.1.0/src/lib.rs", "// patched");
+    project.run_ok(&["demo"]);
+    assert!(project
+        .read("patches/demo+0.1.0.patch")
+        .contains("+// patched"));
+
+    project.run_ok(&["--force"]);
+    assert!(project
+        .read("target/patch/demo-0.1.0/src/lib.rs")
+        .ends_with("// patched\n"));
+}